@@ -0,0 +1,65 @@
+#include "vcs.h"
+#include "df.h"
+
+unsigned char X, Y;
+
+#ifdef PAL
+#define BLANK 48
+#define KERNAL (192 + 36)
+#define OVERSCAN 36
+#else
+#define BLANK 40
+#define KERNAL 192
+#define OVERSCAN 30
+#endif
+
+unsigned char color, speed;
+
+// df.h selects the DF bankswitching scheme (bank0 to bank9 can be declared).
+// Calls between two banks other than bank0 go through stubs in both banks.
+bank9 void next_speed()
+{
+    speed++;
+    if (speed == 4) speed = 1;
+}
+
+bank2 void next_color()
+{
+    next_speed();
+    color += speed;
+}
+
+void main()
+{
+    speed = 0;
+    color = 0;
+    while(1) {
+        *VBLANK = 2; // Enable VBLANK
+        *VSYNC = 2; // Set VSYNC
+        strobe(WSYNC); // Hold it for 3 scanlines
+        strobe(WSYNC);
+        strobe(WSYNC);
+        *VSYNC = 0; // Turn VSYNC Off
+
+        // Blank
+        *TIM64T = ((BLANK - 3) * 76 + 13) / 64;
+        // Do some logic here
+        next_color();
+        while (*INTIM);
+        strobe(WSYNC);
+        *VBLANK = 0;
+
+        // Image
+        X = color;
+        for (Y = KERNAL + 1; Y != 0; Y--) {
+            strobe(WSYNC);
+            *COLUBK = X++;
+        }
+
+        // Overscan
+        *VBLANK = 2; // Enable VBLANK
+        *TIM64T = (OVERSCAN * 76 + 13) / 64;
+        // Do some logic here
+        while (*INTIM);
+    }
+}
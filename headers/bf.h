@@ -0,0 +1,12 @@
+// BF / BFSC (256KB) bankswitching scheme selection
+// Copyleft 2024 Bruno STEUX
+//
+#ifndef __BF__
+#define __BF__
+
+// The 256KB ROM is divided into 64 4KB banks (bank0 to bank63), selected by accessing 0x1f80 to 0x1fbf.
+// Bank switching is automatic when calling a function in another bank.
+// Only bank0 to bank9 can be declared. Use --auto-bank to make use of the other banks.
+// BFSC is selected if superchip variables are used.
+
+#endif // __BF__
@@ -0,0 +1,12 @@
+// DF / DFSC (128KB) bankswitching scheme selection
+// Copyleft 2024 Bruno STEUX
+//
+#ifndef __DF__
+#define __DF__
+
+// The 128KB ROM is divided into 32 4KB banks (bank0 to bank31), selected by accessing 0x1fc0 to 0x1fdf.
+// Bank switching is automatic when calling a function in another bank.
+// Only bank0 to bank9 can be declared. Use --auto-bank to make use of the other banks.
+// DFSC is selected if superchip variables are used.
+
+#endif // __DF__
@@ -0,0 +1,28 @@
+// E0 (Parker Brothers) bankswitching scheme macros
+// Copyleft 2024 Bruno STEUX
+//
+#ifndef __E0__
+#define __E0__
+
+// The 8KB ROM is divided into eight 1KB slices. The 4KB address space is divided into four 1KB segments.
+// The last slice is always mapped in segment 3 (0x1c00). It contains bank0.
+unsigned char * const E0_SEGMENT0 = 0x1fe0; // Slice selection for segment 0 (0x1000-0x13ff)
+unsigned char * const E0_SEGMENT1 = 0x1fe8; // Slice selection for segment 1 (0x1400-0x17ff)
+unsigned char * const E0_SEGMENT2 = 0x1ff0; // Slice selection for segment 2 (0x1800-0x1bff)
+
+// E0 slices are pre associated to banks and segments :
+// ROM Bank0 is slice 7, always mapped at 0x1c00 (segment 3)
+// ROM Bank1 is slice 0, mapped at 0x1000 (segment 0)
+// ROM Bank2 is slice 1, mapped at 0x1400 (segment 1)
+// ROM Bank3 is slice 2, mapped at 0x1800 (segment 2)
+// ROM Bank4 is slice 3, mapped at 0x1000 (segment 0)
+// etc. up to ROM Bank7.
+
+// On startup, bank1 to bank3 are selected for their respective segment.
+// Calling a function selects its bank (the previous bank of the segment is not restored on return).
+// Rule: never select a slice in the segment of the current executing bank !
+#define select_segment0(x) E0_SEGMENT0[x] = 0
+#define select_segment1(x) E0_SEGMENT1[x] = 0
+#define select_segment2(x) E0_SEGMENT2[x] = 0
+
+#endif // __E0__
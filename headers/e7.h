@@ -0,0 +1,27 @@
+// E7 (M-Network) bankswitching scheme macros
+// Copyleft 2024 Bruno STEUX
+//
+#ifndef __E7__
+#define __E7__
+
+// The 16KB ROM is divided into eight 2KB slices.
+// ROM Bank0 is slice 7, always mapped at 0x1a00 (only its last 1.5KB are visible)
+// ROM Bank1 to Bank7 are slices 0 to 6, mapped at 0x1000. Calling a function selects its bank.
+// Bank1 is selected on startup.
+unsigned char * const E7_ROM_SELECT = 0x1fe0; // ROM slice selection (0 to 6) for 0x1000-0x17ff
+unsigned char * const E7_RAM_SELECT = 0x1fe7; // 1KB RAM selection for 0x1000-0x17ff
+unsigned char * const E7_PAGE_SELECT = 0x1fe8; // 256 bytes RAM page selection (0 to 3) for 0x1800-0x19ff
+
+// E7 RAM variables are declared using bank0 to bank4 (without initialization) :
+// bank0 variables go to the 1KB RAM. Read at 0x1400-0x17ff, written at 0x1000-0x13ff.
+// bank1 to bank4 variables go to the 256 bytes RAM pages 0 to 3. Read at 0x1900-0x19ff, written at 0x1800-0x18ff.
+// In assembler, use the variable name for reading and the variable name with _W suffix for writing.
+
+// Rule 1: The 1KB RAM replaces the ROM banks 1 to 7. Select it only from bank0 code !
+#define select_ram() *E7_RAM_SELECT = 0
+// Rule 2: RAM page selection is manual. RAM page 0 (bank1 variables) is selected on startup.
+#define select_ram_page(x) E7_PAGE_SELECT[x] = 0
+// Rule 3: ROM selection is automatic when calling a function. Use select_rom(x) to select the xth bank (1 to 7) manually.
+#define select_rom(x) E7_ROM_SELECT[x - 1] = 0
+
+#endif // __E7__
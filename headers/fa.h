@@ -0,0 +1,15 @@
+// FA (CBS RAM+) bankswitching scheme macros
+// Copyleft 2024 Bruno STEUX
+//
+#ifndef __FA__
+#define __FA__
+
+// The 12KB ROM is divided into three 4KB banks (bank0 to bank2), selected by accessing 0x1ff8 to 0x1ffa.
+// Bank switching is automatic when calling a function in another bank.
+
+// The 256 bytes of RAM are used for superchip variables (declared with the superchip keyword).
+// Read at 0x1100-0x11ff, written at 0x1000-0x10ff.
+// In assembler, use the variable name for reading and the variable name with _W suffix for writing.
+// Rule: superchip variables can't be modified in place (no ++ or -- in assembler using INC/DEC).
+
+#endif // __FA__
@@ -0,0 +1,526 @@
+/*
+    cc2600 - a subset of C compiler for the Atari 2600
+    Copyright (C) 2023-2024 Bruno STEUX
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+    Contact info: bruno.steux@gmail.com
+*/
+
+// DASM compatible expression evaluator.
+// Operators precedences are the ones documented in the DASM manual. `[]` and `()` can both be
+// used for grouping, `[expr]d` yields the decimal string representation of expr (used by ECHO).
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Value {
+    pub value: i64,
+    pub unknown: bool,
+    pub string: Option<String>,
+}
+
+impl Value {
+    pub fn known(value: i64) -> Value {
+        Value {
+            value,
+            unknown: false,
+            string: None,
+        }
+    }
+
+    pub fn unknown() -> Value {
+        Value {
+            value: 0,
+            unknown: true,
+            string: None,
+        }
+    }
+}
+
+pub trait SymbolResolver {
+    /// Value of the given symbol. Unknown or undefined symbols must be flagged as unknown.
+    fn resolve(&mut self, name: &str) -> Value;
+    /// Current program counter (`.`)
+    fn pc(&self) -> Value;
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum BinOp {
+    Mul,
+    Div,
+    Mod,
+    Add,
+    Sub,
+    Shr,
+    Shl,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+    Eq,
+    Neq,
+    And,
+    Xor,
+    Or,
+    LAnd,
+    LOr,
+    Cond,
+}
+
+impl BinOp {
+    fn precedence(&self) -> u32 {
+        match self {
+            BinOp::Mul | BinOp::Div | BinOp::Mod => 20,
+            BinOp::Add | BinOp::Sub => 19,
+            BinOp::Shr | BinOp::Shl => 18,
+            BinOp::Gt | BinOp::Gte | BinOp::Lt | BinOp::Lte => 17,
+            BinOp::Eq => 16,
+            BinOp::Neq => 15,
+            BinOp::And => 14,
+            BinOp::Xor => 13,
+            BinOp::Or => 12,
+            BinOp::LAnd => 11,
+            BinOp::LOr => 10,
+            BinOp::Cond => 9,
+        }
+    }
+}
+
+struct Parser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    resolver: &'a mut dyn SymbolResolver,
+}
+
+pub fn is_symbol_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '.'
+}
+
+pub fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+/// Evaluates a comma separated list of expressions
+pub fn eval_list(s: &str, resolver: &mut dyn SymbolResolver) -> Result<Vec<Value>, String> {
+    let mut parser = Parser {
+        chars: s.chars().collect(),
+        pos: 0,
+        resolver,
+    };
+    let mut ret = Vec::new();
+    parser.skip_spaces();
+    if parser.peek().is_none() {
+        return Ok(ret);
+    }
+    loop {
+        ret.push(parser.expression(0)?);
+        parser.skip_spaces();
+        match parser.peek() {
+            None => break,
+            Some(',') => {
+                parser.pos += 1;
+            }
+            Some(c) => return Err(format!("Syntax error in expression '{}' near '{}'", s, c)),
+        }
+    }
+    Ok(ret)
+}
+
+/// Evaluates a single expression
+pub fn eval(s: &str, resolver: &mut dyn SymbolResolver) -> Result<Value, String> {
+    let mut l = eval_list(s, resolver)?;
+    if l.len() != 1 {
+        return Err(format!("Syntax error in expression '{}'", s));
+    }
+    Ok(l.remove(0))
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn skip_spaces(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == ' ' || c == '\t' {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn binop(&mut self) -> Option<(BinOp, usize)> {
+        self.skip_spaces();
+        let c = self.peek()?;
+        let c2 = self.peek_at(1);
+        Some(match (c, c2) {
+            ('*', _) => (BinOp::Mul, 1),
+            ('/', _) => (BinOp::Div, 1),
+            ('%', _) => (BinOp::Mod, 1),
+            ('+', _) => (BinOp::Add, 1),
+            ('-', _) => (BinOp::Sub, 1),
+            ('>', Some('>')) => (BinOp::Shr, 2),
+            ('<', Some('<')) => (BinOp::Shl, 2),
+            ('>', Some('=')) => (BinOp::Gte, 2),
+            ('<', Some('=')) => (BinOp::Lte, 2),
+            ('>', _) => (BinOp::Gt, 1),
+            ('<', _) => (BinOp::Lt, 1),
+            ('=', Some('=')) => (BinOp::Eq, 2),
+            ('=', _) => (BinOp::Eq, 1),
+            ('!', Some('=')) => (BinOp::Neq, 2),
+            ('&', Some('&')) => (BinOp::LAnd, 2),
+            ('&', _) => (BinOp::And, 1),
+            ('^', _) => (BinOp::Xor, 1),
+            ('|', Some('|')) => (BinOp::LOr, 2),
+            ('|', _) => (BinOp::Or, 1),
+            ('?', _) => (BinOp::Cond, 1),
+            _ => return None,
+        })
+    }
+
+    fn expression(&mut self, min_precedence: u32) -> Result<Value, String> {
+        let mut lhs = self.unary()?;
+        loop {
+            let save = self.pos;
+            let Some((op, len)) = self.binop() else {
+                self.pos = save;
+                break;
+            };
+            let precedence = op.precedence();
+            if precedence < min_precedence {
+                self.pos = save;
+                break;
+            }
+            self.pos += len;
+            let rhs = self.expression(precedence + 1)?;
+            lhs = apply(op, lhs, rhs)?;
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Value, String> {
+        self.skip_spaces();
+        let c = self
+            .peek()
+            .ok_or_else(|| "Unexpected end of expression".to_string())?;
+        let f: fn(i64) -> i64 = match c {
+            '-' => |v| -v,
+            '~' => |v| !v,
+            '!' => |v| (v == 0) as i64,
+            '<' => |v| v & 0xff,
+            '>' => |v| (v >> 8) & 0xff,
+            _ => return self.primary(),
+        };
+        self.pos += 1;
+        let v = self.unary()?;
+        Ok(Value {
+            value: f(v.value),
+            unknown: v.unknown,
+            string: None,
+        })
+    }
+
+    fn number(&mut self, radix: u32) -> Result<Value, String> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c.is_digit(radix) {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        if start == self.pos {
+            if radix == 8 {
+                // A single '0' is parsed as an empty octal number
+                return Ok(Value::known(0));
+            }
+            return Err("Malformed number".to_string());
+        }
+        let s: String = self.chars[start..self.pos].iter().collect();
+        i64::from_str_radix(&s, radix)
+            .map(Value::known)
+            .map_err(|_| format!("Malformed number '{}'", s))
+    }
+
+    fn primary(&mut self) -> Result<Value, String> {
+        self.skip_spaces();
+        let c = self
+            .peek()
+            .ok_or_else(|| "Unexpected end of expression".to_string())?;
+        match c {
+            '[' | '(' => {
+                let close = if c == '[' { ']' } else { ')' };
+                self.pos += 1;
+                let mut v = self.expression(0)?;
+                self.skip_spaces();
+                if self.peek() != Some(close) {
+                    return Err(format!("Missing '{}' in expression", close));
+                }
+                self.pos += 1;
+                if close == ']' && self.peek() == Some('d') {
+                    self.pos += 1;
+                    if !v.unknown {
+                        v.string = Some(format!("{}", v.value));
+                    }
+                }
+                Ok(v)
+            }
+            '$' => {
+                self.pos += 1;
+                self.number(16)
+            }
+            '%' => {
+                self.pos += 1;
+                self.number(2)
+            }
+            '0' if matches!(self.peek_at(1), Some('x') | Some('X')) => {
+                self.pos += 2;
+                self.number(16)
+            }
+            '0' => {
+                self.pos += 1;
+                self.number(8)
+            }
+            '1'..='9' => self.number(10),
+            '\'' => {
+                self.pos += 1;
+                let c = self
+                    .peek()
+                    .ok_or_else(|| "Malformed character constant".to_string())?;
+                self.pos += 1;
+                if self.peek() == Some('\'') {
+                    self.pos += 1;
+                }
+                Ok(Value::known(c as i64))
+            }
+            '"' => {
+                self.pos += 1;
+                let start = self.pos;
+                while let Some(c) = self.peek() {
+                    if c == '"' {
+                        break;
+                    }
+                    self.pos += 1;
+                }
+                let s: String = self.chars[start..self.pos].iter().collect();
+                if self.peek() == Some('"') {
+                    self.pos += 1;
+                }
+                Ok(Value {
+                    value: s.chars().next().map(|c| c as i64).unwrap_or(0),
+                    unknown: false,
+                    string: Some(s),
+                })
+            }
+            '*' => {
+                self.pos += 1;
+                Ok(self.resolver.pc())
+            }
+            '.' if !self.peek_at(1).map(is_symbol_char).unwrap_or(false) => {
+                self.pos += 1;
+                Ok(self.resolver.pc())
+            }
+            c if is_symbol_start(c) => {
+                let start = self.pos;
+                while let Some(c) = self.peek() {
+                    if is_symbol_char(c) {
+                        self.pos += 1;
+                    } else {
+                        break;
+                    }
+                }
+                let s: String = self.chars[start..self.pos].iter().collect();
+                Ok(self.resolver.resolve(&s))
+            }
+            c => Err(format!("Unexpected character '{}' in expression", c)),
+        }
+    }
+}
+
+fn apply(op: BinOp, lhs: Value, rhs: Value) -> Result<Value, String> {
+    let unknown = lhs.unknown || rhs.unknown;
+    let (a, b) = (lhs.value, rhs.value);
+    let value = match op {
+        BinOp::Mul => a.wrapping_mul(b),
+        BinOp::Div | BinOp::Mod => {
+            if b == 0 {
+                if unknown {
+                    0
+                } else {
+                    return Err("Division by zero".to_string());
+                }
+            } else if op == BinOp::Div {
+                a / b
+            } else {
+                a % b
+            }
+        }
+        BinOp::Add => a.wrapping_add(b),
+        BinOp::Sub => a.wrapping_sub(b),
+        BinOp::Shr => a >> (b & 63),
+        BinOp::Shl => a << (b & 63),
+        BinOp::Gt => (a > b) as i64,
+        BinOp::Gte => (a >= b) as i64,
+        BinOp::Lt => (a < b) as i64,
+        BinOp::Lte => (a <= b) as i64,
+        BinOp::Eq => (a == b) as i64,
+        BinOp::Neq => (a != b) as i64,
+        BinOp::And => a & b,
+        BinOp::Xor => a ^ b,
+        BinOp::Or => a | b,
+        BinOp::LAnd => (a != 0 && b != 0) as i64,
+        BinOp::LOr => (a != 0 || b != 0) as i64,
+        BinOp::Cond => {
+            if a != 0 {
+                b
+            } else {
+                0
+            }
+        }
+    };
+    Ok(Value {
+        value,
+        unknown,
+        string: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    struct Symbols(HashMap<&'static str, i64>);
+
+    impl SymbolResolver for Symbols {
+        fn resolve(&mut self, name: &str) -> Value {
+            self.0
+                .get(name)
+                .map_or_else(Value::unknown, |&v| Value::known(v))
+        }
+
+        fn pc(&self) -> Value {
+            Value::known(0xf010)
+        }
+    }
+
+    fn value(s: &str) -> Result<i64, String> {
+        let mut symbols = Symbols(HashMap::from([("label", 0xf123), ("ten", 10)]));
+        eval(s, &mut symbols).map(|v| {
+            assert!(!v.unknown, "{} is unknown", s);
+            v.value
+        })
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(value("$1F"), Ok(0x1f));
+        assert_eq!(value("0x1f"), Ok(0x1f));
+        assert_eq!(value("%101"), Ok(5));
+        assert_eq!(value("017"), Ok(15));
+        assert_eq!(value("0"), Ok(0));
+        assert_eq!(value("'A'"), Ok(65));
+        assert_eq!(value("$"), Err("Malformed number".to_string()));
+    }
+
+    #[test]
+    fn operators_and_precedence() {
+        assert_eq!(value("2 + 3 * 4"), Ok(14));
+        assert_eq!(value("[2 + 3] * 4"), Ok(20));
+        assert_eq!(value("(2 + 3) * 4"), Ok(20));
+        assert_eq!(value("10 - 4 - 3"), Ok(3));
+        assert_eq!(value("64 / 4 / 2"), Ok(8));
+        assert_eq!(value("1 + 2 << 1"), Ok(6));
+        assert_eq!(value("$10 | %11 & 2"), Ok(0x12));
+        assert_eq!(value("1 < 2 == 1"), Ok(1));
+        assert_eq!(value("3 = 3 && 2 != 2"), Ok(0));
+        assert_eq!(value("-1"), Ok(-1));
+        assert_eq!(value("~0 & $FF"), Ok(0xff));
+        assert_eq!(value("!0"), Ok(1));
+        assert_eq!(value("<$1234"), Ok(0x34));
+        assert_eq!(value(">$1234"), Ok(0x12));
+        assert_eq!(value("1 ? 5"), Ok(5));
+        assert_eq!(value("7 % 4"), Ok(3));
+    }
+
+    #[test]
+    fn symbols_and_program_counter() {
+        assert_eq!(value("label + ten"), Ok(0xf12d));
+        assert_eq!(value(">label"), Ok(0xf1));
+        assert_eq!(value("."), Ok(0xf010));
+        assert_eq!(value("* - 16"), Ok(0xf000));
+        let mut symbols = Symbols(HashMap::new());
+        let v = eval("undefined + 1", &mut symbols).unwrap();
+        assert!(v.unknown);
+        // No division by zero error while the divisor is unknown
+        assert!(eval("1 / undefined", &mut symbols).unwrap().unknown);
+    }
+
+    #[test]
+    fn decimal_strings() {
+        let mut symbols = Symbols(HashMap::from([("ten", 10)]));
+        let v = eval("[ten + 5]d", &mut symbols).unwrap();
+        assert_eq!((v.value, v.string), (15, Some("15".to_string())));
+        // Parentheses only group
+        assert_eq!(eval("(ten + 5)", &mut symbols).unwrap().string, None);
+        // Unknown values have no string yet
+        let v = eval("[undefined]d", &mut symbols).unwrap();
+        assert!(v.unknown && v.string.is_none());
+    }
+
+    #[test]
+    fn lists_and_strings() {
+        let mut symbols = Symbols(HashMap::new());
+        let l = eval_list("1, $20, \"ab\"", &mut symbols).unwrap();
+        assert_eq!(
+            l.iter().map(|v| v.value).collect::<Vec<_>>(),
+            [1, 0x20, 'a' as i64]
+        );
+        assert_eq!(l[2].string.as_deref(), Some("ab"));
+        assert!(eval_list("", &mut symbols).unwrap().is_empty());
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(value("1 / 0"), Err("Division by zero".to_string()));
+        assert_eq!(
+            value("(1 + 2"),
+            Err("Missing ')' in expression".to_string())
+        );
+        assert_eq!(
+            value("[1 + 2"),
+            Err("Missing ']' in expression".to_string())
+        );
+        assert_eq!(
+            value("1 +"),
+            Err("Unexpected end of expression".to_string())
+        );
+        assert_eq!(
+            value("1 2"),
+            Err("Syntax error in expression '1 2' near '2'".to_string())
+        );
+        assert_eq!(
+            value("1, 2"),
+            Err("Syntax error in expression '1, 2'".to_string())
+        );
+        assert_eq!(
+            value("1 + @"),
+            Err("Unexpected character '@' in expression".to_string())
+        );
+    }
+}
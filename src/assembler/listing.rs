@@ -0,0 +1,96 @@
+/*
+    cc2600 - a subset of C compiler for the Atari 2600
+    Copyright (C) 2023-2024 Bruno STEUX
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+    Contact info: bruno.steux@gmail.com
+*/
+
+// Listing and symbol files output, reproducing the layout of DASM's -l and -s files
+
+use super::AsmSymbol;
+
+/// Value and flags column, as printed by DASM
+pub fn sftos(value: i64, unknown: bool, string: bool, referenced: bool, set: bool) -> String {
+    let mut s = format!("{:04x} ", value);
+    s.push_str(if unknown { "???? " } else { "     " });
+    s.push_str(if string { "str " } else { "    " });
+    s.push_str("    ");
+    s.push_str(if referenced || set { "(" } else { " " });
+    s.push_str(if referenced { "R" } else { " " });
+    s.push_str(if set { "S" } else { " " });
+    s.push_str(if referenced || set { ")" } else { " " });
+    s
+}
+
+/// Replaces runs of spaces ending on a tab stop by tabs, and removes trailing whitespaces
+pub fn tabit(line: &str) -> String {
+    let mut out: Vec<u8> = Vec::with_capacity(line.len() + 1);
+    let mut j = 0;
+    for &c in line.as_bytes() {
+        if c == b'\n' {
+            break;
+        }
+        out.push(c);
+        if c == b'\t' {
+            // Optimize out spaces before tab
+            out.pop();
+            while j > 0 && out.last() == Some(&b' ') {
+                out.pop();
+                j -= 1;
+            }
+            out.push(b'\t');
+            j = 7;
+        }
+        let n = out.len();
+        if j == 7 && out[n - 1] == b' ' && n >= 2 && out[n - 2] == b' ' {
+            let mut k = 8;
+            while k > 0 && out.last() == Some(&b' ') {
+                out.pop();
+                k -= 1;
+            }
+            out.push(b'\t');
+        }
+        j = (j + 1) & 7;
+    }
+    while let Some(&c) = out.last() {
+        if c == b' ' || c == b'\t' {
+            out.pop();
+        } else {
+            break;
+        }
+    }
+    out.push(b'\n');
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+pub fn symbol_table(symbols: &[AsmSymbol]) -> String {
+    let mut s = String::from("--- Symbol List (sorted by symbol)\n");
+    for sym in symbols {
+        s.push_str(&format!(
+            "{:<24} {}\n",
+            sym.name,
+            sftos(
+                sym.value,
+                sym.unknown,
+                sym.string.is_some(),
+                sym.referenced,
+                sym.set
+            )
+        ));
+    }
+    s.push_str("--- End of Symbol List.\n");
+    s
+}
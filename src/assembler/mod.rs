@@ -0,0 +1,1341 @@
+/*
+    cc2600 - a subset of C compiler for the Atari 2600
+    Copyright (C) 2023-2024 Bruno STEUX
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+    Contact info: bruno.steux@gmail.com
+*/
+
+// Built-in 6502 assembler.
+//
+// Implements the subset of DASM used by the code generated by build_cartridge (and by the
+// assembler code usually included in cc2600 programs), with DASM semantics: multiple passes
+// until all symbols are resolved, SEG/SEG.U segments, ORG/RORG, local labels scoped by
+// SUBROUTINE and macros, and raw (-f3) binary output.
+
+pub mod expression;
+mod listing;
+pub mod opcodes;
+
+use log::debug;
+use std::collections::HashMap;
+use std::fmt;
+
+use expression::{eval, eval_list, SymbolResolver, Value};
+use opcodes::{has_mode, is_mnemonic, opcode, AddressingMode};
+
+const MAX_PASSES: u32 = 10;
+const DEFAULT_ORG_FILL: u8 = 255;
+// Size of DASM's code generation buffer. Only used to reproduce DASM's listing output.
+const GEN_BUFFER_SIZE: usize = 256;
+// Value given to undefined symbols when they are allowed. Not in zeropage, so that the sizes of
+// the instructions referencing them are the sizes of the final code.
+const UNDEFINED_SYMBOL_VALUE: i64 = 0xffff;
+
+#[derive(Debug)]
+pub struct Error {
+    pub filename: String,
+    pub line: usize,
+    pub msg: String,
+    /// ECHO output of the failing pass (used to report out of memory banks)
+    pub echo: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line != 0 {
+            write!(
+                f,
+                "Assembler error: {} on line {} of {}",
+                self.msg, self.line, self.filename
+            )
+        } else {
+            write!(f, "Assembler error: {}", self.msg)
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Debug, Clone)]
+pub struct AsmSymbol {
+    pub name: String,
+    pub value: i64,
+    pub unknown: bool,
+    pub string: Option<String>,
+    pub referenced: bool,
+    pub set: bool,
+}
+
+/// Result of a successful assembly
+#[derive(Debug)]
+pub struct Assembly {
+    /// Raw cartridge image (DASM -f3 format)
+    pub binary: Vec<u8>,
+    /// Output of the ECHO directives of the final pass
+    pub echo: String,
+    /// Listing file content (DASM -l format)
+    pub listing: String,
+    /// Symbols, sorted the way DASM sorts its symbol file
+    pub symbols: Vec<AsmSymbol>,
+    /// Location of the bytes generated by each line of the main source file
+    pub lines: Vec<LineAddress>,
+}
+
+/// Bytes generated by a source line
+#[derive(Debug, Clone)]
+pub struct LineAddress {
+    /// Line number (starting from 1)
+    pub line: usize,
+    /// Origin (offset in the cartridge image)
+    pub org: i64,
+    /// Address, as seen by the 6502 (RORG)
+    pub address: i64,
+    pub size: usize,
+    /// First bytes generated (up to 4), i.e. the opcode and operand of an instruction
+    pub bytes: Vec<u8>,
+}
+
+impl Assembly {
+    /// Symbol file content (DASM -s format)
+    pub fn symbol_table(&self) -> String {
+        listing::symbol_table(&self.symbols)
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<&AsmSymbol> {
+        self.symbols.iter().find(|s| s.name == name)
+    }
+}
+
+#[derive(Debug)]
+struct Symbol {
+    value: i64,
+    unknown: bool,
+    string: Option<String>,
+    referenced: bool,
+    set: bool,
+    defined_pass: u32,
+}
+
+#[derive(Debug)]
+struct Segment {
+    name: String,
+    bss: bool,
+    org: i64,
+    org_unknown: bool,
+    rorg: Option<i64>,
+    rorg_unknown: bool,
+    init_org: i64,
+    init_unknown: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Macro {
+    lines: Vec<String>,
+}
+
+#[derive(Debug)]
+struct IfState {
+    xtrue: bool,
+    acctrue: bool,
+}
+
+enum Flow {
+    Continue,
+    End,
+    MacroExit,
+}
+
+// A source line, split the way DASM does it
+struct Line {
+    label: String,
+    mnemonic: String,
+    extension: Option<String>,
+    args: String,
+    comment: String,
+}
+
+fn cleanup(raw: &str) -> (String, String) {
+    let mut text = String::new();
+    let mut comment = String::new();
+    let mut in_string = false;
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\r' | '\n' => break,
+            ';' if !in_string => {
+                comment = chars.take_while(|c| *c != '\r' && *c != '\n').collect();
+                break;
+            }
+            '"' => {
+                in_string = !in_string;
+                text.push(c);
+            }
+            '\'' if !in_string => {
+                text.push(c);
+                if let Some(c) = chars.next() {
+                    text.push(c);
+                }
+            }
+            '\t' => text.push(' '),
+            _ => text.push(c),
+        }
+    }
+    (text.trim_end().to_string(), comment)
+}
+
+fn parse(text: &str, comment: String) -> Line {
+    let mut label = String::new();
+    let mut rest = text;
+    if !text.is_empty() && !text.starts_with(' ') {
+        let end = text.find(' ').unwrap_or(text.len());
+        label = text[0..end].to_string();
+        rest = &text[end..];
+        // label=value syntax
+        if let Some(p) = label.find('=') {
+            rest = &text[p..];
+            label.truncate(p);
+        }
+    }
+    let rest = rest.trim_start();
+    let (mut mnemonic, args) = if let Some(stripped) = rest.strip_prefix('=') {
+        ("=".to_string(), stripped.trim_start())
+    } else {
+        let end = rest.find(' ').unwrap_or(rest.len());
+        (rest[0..end].to_string(), rest[end..].trim_start())
+    };
+    let mut extension = None;
+    if !mnemonic.starts_with('.') {
+        if let Some(p) = mnemonic.find('.') {
+            extension = Some(mnemonic[p + 1..].to_string());
+            mnemonic.truncate(p);
+        }
+    }
+    Line {
+        label,
+        mnemonic,
+        extension,
+        args: args.to_string(),
+        comment,
+    }
+}
+
+// Split at commas that are not inside parentheses, brackets or quotes
+fn split_top_level(s: &str) -> Vec<&str> {
+    let mut ret = Vec::new();
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut start = 0;
+    let mut quote_next = false;
+    for (i, c) in s.char_indices() {
+        if quote_next {
+            quote_next = false;
+            continue;
+        }
+        match c {
+            '"' => in_string = !in_string,
+            '\'' if !in_string => quote_next = true,
+            '(' | '[' if !in_string => depth += 1,
+            ')' | ']' if !in_string => depth -= 1,
+            ',' if !in_string && depth == 0 => {
+                ret.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    ret.push(&s[start..]);
+    ret
+}
+
+// Position of the parenthesis matching the one at position 0
+fn matching_parenthesis(s: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+pub struct Assembler {
+    symbols: HashMap<String, Symbol>,
+    segments: Vec<Segment>,
+    current_segment: usize,
+    macros: HashMap<String, Macro>,
+    if_stack: Vec<IfState>,
+    pass: u32,
+    redo: u32,
+    local_index: u32,
+    last_local_index: u32,
+    binary: Vec<u8>,
+    file_org: Option<i64>,
+    org_fill: u8,
+    echo: String,
+    listing: String,
+    lines: Vec<LineAddress>,
+    plab: i64,
+    plab_unknown: bool,
+    gen: Vec<u8>,
+    gen_len: usize,
+    pass_errors: Vec<Error>,
+    filename: String,
+    lineno: usize,
+    include_level: u32,
+    macro_level: u32,
+    allow_undefined: bool,
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SymbolResolver for Assembler {
+    fn resolve(&mut self, name: &str) -> Value {
+        let key = self.symbol_key(name);
+        match self.symbols.get_mut(&key) {
+            Some(s) if self.allow_undefined && s.defined_pass == 0 && self.pass > 1 => {
+                s.referenced = true;
+                Value::known(UNDEFINED_SYMBOL_VALUE)
+            }
+            Some(s) => {
+                s.referenced = true;
+                if s.unknown {
+                    self.redo += 1;
+                }
+                Value {
+                    value: s.value,
+                    unknown: s.unknown,
+                    string: s.string.clone(),
+                }
+            }
+            None => {
+                self.symbols.insert(
+                    key,
+                    Symbol {
+                        value: 0,
+                        unknown: true,
+                        string: None,
+                        referenced: true,
+                        set: false,
+                        defined_pass: 0,
+                    },
+                );
+                if self.allow_undefined && self.pass > 1 {
+                    return Value::known(UNDEFINED_SYMBOL_VALUE);
+                }
+                self.redo += 1;
+                Value::unknown()
+            }
+        }
+    }
+
+    fn pc(&self) -> Value {
+        let (value, unknown) = self.pc_value();
+        Value {
+            value,
+            unknown,
+            string: None,
+        }
+    }
+}
+
+impl Assembler {
+    pub fn new() -> Assembler {
+        Assembler {
+            symbols: HashMap::new(),
+            segments: Vec::new(),
+            current_segment: 0,
+            macros: HashMap::new(),
+            if_stack: Vec::new(),
+            pass: 0,
+            redo: 0,
+            local_index: 0,
+            last_local_index: 0,
+            binary: Vec::new(),
+            file_org: None,
+            org_fill: DEFAULT_ORG_FILL,
+            echo: String::new(),
+            listing: String::new(),
+            lines: Vec::new(),
+            plab: 0,
+            plab_unknown: true,
+            gen: Vec::new(),
+            gen_len: 0,
+            pass_errors: Vec::new(),
+            filename: String::new(),
+            lineno: 0,
+            include_level: 0,
+            macro_level: 0,
+            allow_undefined: false,
+        }
+    }
+
+    /// Lets symbols that are never defined evaluate to an absolute address instead of raising an
+    /// error. Used to size code fragments that reference symbols defined elsewhere.
+    pub fn allow_undefined(mut self, allow: bool) -> Assembler {
+        self.allow_undefined = allow;
+        self
+    }
+
+    /// Assembles the given source code. `filename` is only used for error messages and listing.
+    pub fn assemble(mut self, filename: &str, source: &str) -> Result<Assembly, Error> {
+        let lines: Vec<String> = source.lines().map(|l| l.to_string()).collect();
+        loop {
+            self.pass += 1;
+            self.start_pass();
+            debug!("Assembler pass #{}", self.pass);
+            self.listing.push_str(&format!(
+                "------- FILE {} LEVEL 1 PASS {}\n",
+                filename, self.pass
+            ));
+            self.include_level = 1;
+            self.process(filename, &lines, 0, None)?;
+            if !self.if_stack.is_empty() {
+                return Err(self.error("IF without ENDIF"));
+            }
+
+            let mut unresolved: Vec<&String> = self
+                .symbols
+                .iter()
+                .filter(|(_, s)| s.defined_pass == 0)
+                .map(|(n, _)| n)
+                .collect();
+            if !unresolved.is_empty() && self.pass > 1 && !self.allow_undefined {
+                unresolved.sort();
+                return Err(Error {
+                    filename: filename.to_string(),
+                    line: 0,
+                    echo: self.echo.clone(),
+                    msg: format!(
+                        "Unresolved symbol(s): {}",
+                        unresolved
+                            .iter()
+                            .map(|s| s.as_str())
+                            .collect::<Vec<&str>>()
+                            .join(", ")
+                    ),
+                });
+            }
+
+            if self.redo == 0 || self.pass == MAX_PASSES {
+                if !self.pass_errors.is_empty() {
+                    return Err(self.pass_errors.remove(0));
+                }
+                if self.redo != 0 {
+                    return Err(Error {
+                        filename: filename.to_string(),
+                        line: 0,
+                        msg: format!("Source is not resolvable after {} passes", MAX_PASSES),
+                        echo: self.echo.clone(),
+                    });
+                }
+                break;
+            }
+        }
+
+        let mut symbols: Vec<AsmSymbol> = self
+            .symbols
+            .into_iter()
+            .map(|(name, s)| AsmSymbol {
+                name,
+                value: s.value,
+                unknown: s.unknown,
+                string: s.string,
+                referenced: s.referenced,
+                set: s.set,
+            })
+            .collect();
+        symbols.sort_by(|a, b| {
+            a.name
+                .to_ascii_lowercase()
+                .cmp(&b.name.to_ascii_lowercase())
+                .then(a.name.cmp(&b.name))
+        });
+        Ok(Assembly {
+            binary: self.binary,
+            echo: self.echo,
+            listing: self.listing,
+            symbols,
+            lines: self.lines,
+        })
+    }
+
+    fn start_pass(&mut self) {
+        self.redo = 0;
+        self.local_index = 0;
+        self.last_local_index = 0;
+        self.binary.clear();
+        self.file_org = None;
+        self.org_fill = DEFAULT_ORG_FILL;
+        self.echo.clear();
+        self.listing.clear();
+        self.lines.clear();
+        self.plab = 0;
+        self.plab_unknown = true;
+        self.pass_errors.clear();
+        self.if_stack.clear();
+        self.macro_level = 0;
+        if self.segments.is_empty() {
+            self.segments.push(Segment {
+                name: "INITIAL CODE SEGMENT".to_string(),
+                bss: false,
+                org: 0,
+                org_unknown: true,
+                rorg: None,
+                rorg_unknown: false,
+                init_org: 0,
+                init_unknown: true,
+            });
+        }
+        for s in &mut self.segments {
+            s.org = s.init_org;
+            s.org_unknown = s.init_unknown;
+            s.rorg = None;
+            s.rorg_unknown = false;
+        }
+        self.current_segment = 0;
+    }
+
+    fn error(&self, msg: &str) -> Error {
+        Error {
+            filename: self.filename.clone(),
+            line: self.lineno,
+            msg: msg.to_string(),
+            echo: self.echo.clone(),
+        }
+    }
+
+    // Errors that may be caused by unresolved values. Only reported if they remain in the final pass.
+    fn pass_error(&mut self, msg: &str) {
+        let e = self.error(msg);
+        self.pass_errors.push(e);
+    }
+
+    fn symbol_key(&self, name: &str) -> String {
+        if name.starts_with('.') {
+            format!("{}{}", self.local_index, name)
+        } else {
+            name.to_string()
+        }
+    }
+
+    fn pc_value(&self) -> (i64, bool) {
+        let s = &self.segments[self.current_segment];
+        if let Some(rorg) = s.rorg {
+            (rorg, s.rorg_unknown)
+        } else {
+            (s.org, s.org_unknown)
+        }
+    }
+
+    fn active(&self) -> bool {
+        self.if_stack.iter().all(|i| i.xtrue && i.acctrue)
+    }
+
+    fn define_symbol(&mut self, name: &str, value: Value, set: bool) {
+        let key = self.symbol_key(name);
+        let pass = self.pass;
+        match self.symbols.get_mut(&key) {
+            Some(s) => {
+                if !set
+                    && s.defined_pass == pass
+                    && !s.unknown
+                    && !value.unknown
+                    && s.value != value.value
+                {
+                    let msg = format!(
+                        "Label mismatch for {} (${:04x} vs ${:04x})",
+                        name, s.value, value.value
+                    );
+                    self.pass_error(&msg);
+                    return;
+                }
+                if !set && s.defined_pass != pass && (s.unknown || s.value != value.value) {
+                    self.redo += 1;
+                }
+                s.value = value.value;
+                s.unknown = value.unknown;
+                s.string = value.string;
+                s.set = set;
+                s.defined_pass = pass;
+            }
+            None => {
+                self.symbols.insert(
+                    key,
+                    Symbol {
+                        value: value.value,
+                        unknown: value.unknown,
+                        string: value.string,
+                        referenced: false,
+                        set,
+                        defined_pass: pass,
+                    },
+                );
+            }
+        }
+        if value.unknown {
+            self.redo += 1;
+        }
+    }
+
+    fn program_label(&mut self, label: &str) {
+        let s = &self.segments[self.current_segment];
+        self.plab = s.org;
+        self.plab_unknown = s.org_unknown;
+        if label.is_empty() {
+            return;
+        }
+        let name = label.strip_suffix(':').unwrap_or(label);
+        let (value, unknown) = self.pc_value();
+        self.define_symbol(
+            name,
+            Value {
+                value,
+                unknown,
+                string: None,
+            },
+            false,
+        );
+    }
+
+    fn generate(&mut self, bytes: &[u8]) {
+        self.gen = bytes[0..bytes.len().min(4)].to_vec();
+        self.gen_len = bytes.len();
+        let seg = &mut self.segments[self.current_segment];
+        if !seg.bss {
+            if seg.org_unknown {
+                self.redo += 1;
+            } else {
+                let org = *self.file_org.get_or_insert(seg.org);
+                let current = org + self.binary.len() as i64;
+                if seg.org < current {
+                    let msg = format!(
+                        "Origin Reverse-indexed (segment {} at ${:04x}, current output at ${:04x})",
+                        seg.name, seg.org, current
+                    );
+                    self.pass_error(&msg);
+                } else {
+                    for _ in current..seg.org {
+                        self.binary.push(self.org_fill);
+                    }
+                    self.binary.extend_from_slice(bytes);
+                }
+            }
+        }
+        let seg = &mut self.segments[self.current_segment];
+        seg.org += bytes.len() as i64;
+        if let Some(rorg) = seg.rorg.as_mut() {
+            *rorg += bytes.len() as i64;
+        }
+    }
+
+    fn generate_fill(&mut self, fill: u8, count: i64) {
+        let mut count = count;
+        while count > GEN_BUFFER_SIZE as i64 {
+            self.generate(&[fill; GEN_BUFFER_SIZE]);
+            count -= GEN_BUFFER_SIZE as i64;
+        }
+        if count > 0 {
+            self.generate(&vec![fill; count as usize]);
+        }
+    }
+
+    fn eval(&mut self, s: &str) -> Result<Value, Error> {
+        eval(s, self).map_err(|e| self.error(&e))
+    }
+
+    fn eval_list(&mut self, s: &str) -> Result<Vec<Value>, Error> {
+        eval_list(s, self).map_err(|e| self.error(&e))
+    }
+
+    fn list_line(&mut self, lineno: usize, line: &Line) {
+        let c = if self.pass > 1 { ' ' } else { '*' };
+        let mut buf = format!(
+            "{:7} {}{}",
+            lineno,
+            c,
+            listing::sftos(self.plab, self.plab_unknown, false, false, false)
+        );
+        let mut xtrue = if self.active() { ' ' } else { '-' };
+        let shown = self.gen_len.min(4);
+        for b in &self.gen[0..shown] {
+            buf.push_str(&format!("{:02x} ", b));
+        }
+        if self.gen_len > 4 {
+            xtrue = '*';
+        }
+        for _ in shown..4 {
+            buf.push_str("   ");
+        }
+        buf.pop();
+        let (dot, ext) = match &line.extension {
+            Some(e) => (".", e.as_str()),
+            None => ("", ""),
+        };
+        buf.push_str(&format!(
+            "{}{:<10} {}{}{}\t{}",
+            xtrue, line.label, line.mnemonic, dot, ext, line.args
+        ));
+        if !line.comment.is_empty() {
+            buf.push_str(&format!("\t;{}", line.comment));
+        }
+        self.listing.push_str(&listing::tabit(&buf));
+        self.gen_len = 0;
+    }
+
+    fn process(
+        &mut self,
+        filename: &str,
+        lines: &[String],
+        first_lineno: usize,
+        macro_args: Option<&(String, Vec<String>)>,
+    ) -> Result<Flow, Error> {
+        let mut i = 0;
+        while i < lines.len() {
+            let lineno = first_lineno + i + 1;
+            let mut raw = lines[i].clone();
+            i += 1;
+            self.filename = filename.to_string();
+            self.lineno = lineno;
+            if let Some((all, args)) = macro_args {
+                raw = raw.replace("{0}", all);
+                for (n, a) in args.iter().enumerate() {
+                    raw = raw.replace(&format!("{{{}}}", n + 1), a);
+                }
+            }
+            let (text, comment) = cleanup(&raw);
+            let line = parse(&text, comment);
+            let mnemonic = line.mnemonic.to_ascii_lowercase();
+            self.gen_len = 0;
+
+            // Conditional assembly
+            match mnemonic.as_str() {
+                "if" | "ifconst" | "ifnconst" => {
+                    let acctrue = self.active();
+                    let xtrue = if acctrue {
+                        match mnemonic.as_str() {
+                            "if" => {
+                                let v = self.eval(&line.args)?;
+                                if v.unknown {
+                                    self.redo += 1;
+                                    false
+                                } else {
+                                    v.value != 0
+                                }
+                            }
+                            _ => {
+                                let key = self.symbol_key(line.args.trim());
+                                let known = self
+                                    .symbols
+                                    .get(&key)
+                                    .map(|s| s.defined_pass != 0 && !s.unknown)
+                                    .unwrap_or(false);
+                                (mnemonic == "ifconst") == known
+                            }
+                        }
+                    } else {
+                        false
+                    };
+                    self.if_stack.push(IfState { xtrue, acctrue });
+                    self.list_line(lineno, &line);
+                    continue;
+                }
+                "else" => {
+                    match self.if_stack.last_mut() {
+                        Some(s) => s.xtrue = !s.xtrue,
+                        None => return Err(self.error("ELSE without IF")),
+                    }
+                    self.list_line(lineno, &line);
+                    continue;
+                }
+                "endif" | "eif" => {
+                    if self.if_stack.pop().is_none() {
+                        return Err(self.error("ENDIF without IF"));
+                    }
+                    self.list_line(lineno, &line);
+                    continue;
+                }
+                _ => (),
+            }
+
+            if !self.active() {
+                self.list_line(lineno, &line);
+                continue;
+            }
+
+            match mnemonic.as_str() {
+                "mac" | "macro" => {
+                    // Collect the macro body until ENDM
+                    let start = i;
+                    let mut found = false;
+                    while i < lines.len() {
+                        let (t, _) = cleanup(&lines[i]);
+                        i += 1;
+                        let l = parse(&t, String::new());
+                        if l.mnemonic.eq_ignore_ascii_case("endm") {
+                            found = true;
+                            break;
+                        }
+                    }
+                    if !found {
+                        return Err(self.error("MAC without ENDM"));
+                    }
+                    let name = line.args.trim().to_ascii_lowercase();
+                    let already_defined = self.macros.contains_key(&name);
+                    if !already_defined {
+                        for (n, l) in lines[start..i - 1].iter().enumerate() {
+                            let (t, c) = cleanup(l);
+                            self.list_line(first_lineno + start + n + 1, &parse(&t, c));
+                        }
+                    }
+                    self.macros.insert(
+                        name,
+                        Macro {
+                            lines: lines[start..i - 1].to_vec(),
+                        },
+                    );
+                    let (t, c) = cleanup(&lines[i - 1]);
+                    self.list_line(first_lineno + i, &parse(&t, c));
+                    continue;
+                }
+                "endm" => return Err(self.error("ENDM without MAC")),
+                "mexit" => {
+                    if self.macro_level == 0 {
+                        return Err(self.error("MEXIT outside of a macro"));
+                    }
+                    self.list_line(lineno, &line);
+                    return Ok(Flow::MacroExit);
+                }
+                "repeat" | "rept" => {
+                    let start = i;
+                    let mut depth = 1;
+                    while i < lines.len() {
+                        let (t, _) = cleanup(&lines[i]);
+                        i += 1;
+                        let l = parse(&t, String::new()).mnemonic.to_ascii_lowercase();
+                        if l == "repeat" || l == "rept" {
+                            depth += 1;
+                        } else if l == "repend" {
+                            depth -= 1;
+                            if depth == 0 {
+                                break;
+                            }
+                        }
+                    }
+                    if depth != 0 {
+                        return Err(self.error("REPEAT without REPEND"));
+                    }
+                    let v = self.eval(&line.args)?;
+                    self.list_line(lineno, &line);
+                    if v.unknown {
+                        self.redo += 1;
+                    } else {
+                        for _ in 0..v.value.max(0) {
+                            match self.process(
+                                filename,
+                                &lines[start..i - 1],
+                                first_lineno + start,
+                                macro_args,
+                            )? {
+                                Flow::Continue => (),
+                                f => return Ok(f),
+                            }
+                        }
+                    }
+                    self.filename = filename.to_string();
+                    self.lineno = first_lineno + i;
+                    let (t, c) = cleanup(&lines[i - 1]);
+                    self.list_line(first_lineno + i, &parse(&t, c));
+                    continue;
+                }
+                "repend" => return Err(self.error("REPEND without REPEAT")),
+                "include" => {
+                    let name = line.args.trim().trim_matches('"').to_string();
+                    self.list_line(lineno, &line);
+                    let content = std::fs::read_to_string(&name)
+                        .map_err(|e| self.error(&format!("Can't open {}: {}", name, e)))?;
+                    let included: Vec<String> = content.lines().map(|l| l.to_string()).collect();
+                    self.include_level += 1;
+                    self.listing.push_str(&format!(
+                        "------- FILE {} LEVEL {} PASS {}\n",
+                        name, self.include_level, self.pass
+                    ));
+                    let flow = self.process(&name, &included, 0, None)?;
+                    self.include_level -= 1;
+                    self.listing
+                        .push_str(&format!("------- FILE {}\n", filename));
+                    if let Flow::End = flow {
+                        return Ok(Flow::End);
+                    }
+                    continue;
+                }
+                _ => (),
+            }
+
+            if let Some(m) = self.macros.get(&mnemonic).cloned() {
+                self.program_label(&line.label);
+                let args = (
+                    line.args.clone(),
+                    line.args
+                        .split(',')
+                        .map(|a| a.trim_start().to_string())
+                        .collect::<Vec<String>>(),
+                );
+                // The macro invocation is listed as line 0 of the macro
+                self.list_line(0, &line);
+                if self.macro_level == 100 {
+                    return Err(self.error("Infinite macro recursion"));
+                }
+                self.macro_level += 1;
+                let saved_local_index = self.local_index;
+                self.last_local_index += 1;
+                self.local_index = self.last_local_index;
+                let flow = self.process(&line.mnemonic, &m.lines, 0, Some(&args))?;
+                self.local_index = saved_local_index;
+                self.macro_level -= 1;
+                self.filename = filename.to_string();
+                self.lineno = lineno;
+                if let Flow::End = flow {
+                    return Ok(Flow::End);
+                }
+                continue;
+            }
+
+            let flow = self.directive_or_instruction(&mnemonic, &line)?;
+            if self.gen_len > 0 && self.macro_level == 0 && self.include_level == 1 {
+                let seg = &self.segments[self.current_segment];
+                self.lines.push(LineAddress {
+                    line: lineno,
+                    org: seg.org - self.gen_len as i64,
+                    address: seg.rorg.unwrap_or(seg.org) - self.gen_len as i64,
+                    size: self.gen_len,
+                    bytes: self.gen.clone(),
+                });
+            }
+            self.list_line(lineno, &line);
+            if let Flow::End = flow {
+                return Ok(Flow::End);
+            }
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn directive_or_instruction(&mut self, mnemonic: &str, line: &Line) -> Result<Flow, Error> {
+        let ext = line
+            .extension
+            .as_ref()
+            .map(|e| e.to_ascii_lowercase())
+            .unwrap_or_default();
+        match mnemonic {
+            "" => self.program_label(&line.label),
+            "processor" => {
+                if line.args.trim() != "6502" {
+                    return Err(self.error("Only the 6502 processor is supported"));
+                }
+            }
+            "seg" => {
+                let name = line.args.trim().to_string();
+                if let Some(p) = self.segments.iter().position(|s| s.name == name) {
+                    self.current_segment = p;
+                } else {
+                    self.segments.push(Segment {
+                        name,
+                        bss: ext == "u",
+                        org: 0,
+                        org_unknown: true,
+                        rorg: None,
+                        rorg_unknown: false,
+                        init_org: 0,
+                        init_unknown: true,
+                    });
+                    self.current_segment = self.segments.len() - 1;
+                }
+                self.program_label(&line.label);
+            }
+            "org" => {
+                let l = self.eval_list(&line.args)?;
+                if l.is_empty() {
+                    return Err(self.error("Missing ORG address"));
+                }
+                let seg = &mut self.segments[self.current_segment];
+                seg.org = l[0].value;
+                seg.org_unknown = l[0].unknown;
+                if seg.init_unknown {
+                    seg.init_org = l[0].value;
+                    seg.init_unknown = l[0].unknown;
+                }
+                if let Some(fill) = l.get(1) {
+                    self.org_fill = fill.value as u8;
+                }
+                self.program_label(&line.label);
+            }
+            "rorg" => {
+                let v = self.eval(&line.args)?;
+                let seg = &mut self.segments[self.current_segment];
+                seg.rorg = Some(v.value);
+                seg.rorg_unknown = v.unknown;
+                self.program_label(&line.label);
+            }
+            "rend" => {
+                self.segments[self.current_segment].rorg = None;
+                self.program_label(&line.label);
+            }
+            "align" => {
+                let l = self.eval_list(&line.args)?;
+                if l.is_empty() {
+                    return Err(self.error("Missing ALIGN value"));
+                }
+                let fill = l.get(1).map(|v| v.value as u8).unwrap_or(0);
+                let (pc, unknown) = self.pc_value();
+                if unknown || l[0].unknown {
+                    self.redo += 1;
+                } else if l[0].value > 0 {
+                    let n = l[0].value - (pc % l[0].value);
+                    if n != l[0].value {
+                        self.generate_fill(fill, n);
+                    }
+                }
+                self.program_label(&line.label);
+            }
+            "ds" => {
+                let mult = match ext.as_str() {
+                    "w" => 2,
+                    "l" => 4,
+                    _ => 1,
+                };
+                self.program_label(&line.label);
+                let l = self.eval_list(&line.args)?;
+                if l.is_empty() {
+                    return Err(self.error("Missing DS size"));
+                }
+                let fill = l.get(1).map(|v| v.value as u8).unwrap_or(0);
+                if l[0].unknown {
+                    self.redo += 1;
+                } else {
+                    self.generate_fill(fill, l[0].value * mult);
+                }
+            }
+            "hex" => {
+                self.program_label(&line.label);
+                let digits: Vec<u32> = line
+                    .args
+                    .chars()
+                    .filter(|c| !c.is_whitespace())
+                    .map(|c| c.to_digit(16))
+                    .collect::<Option<Vec<u32>>>()
+                    .ok_or_else(|| self.error("Bad hex digit"))?;
+                if !digits.len().is_multiple_of(2) {
+                    return Err(self.error("Odd number of hex digits"));
+                }
+                let bytes: Vec<u8> = digits.chunks(2).map(|d| (d[0] * 16 + d[1]) as u8).collect();
+                self.generate(&bytes);
+            }
+            "dc" | ".byte" | "byte" | ".word" | "word" | ".long" | "long" => {
+                let size = match (mnemonic, ext.as_str()) {
+                    (".word", _) | ("word", _) | ("dc", "w") => 2,
+                    (".long", _) | ("long", _) | ("dc", "l") => 4,
+                    _ => 1,
+                };
+                self.program_label(&line.label);
+                let l = self.eval_list(&line.args)?;
+                let mut bytes = Vec::new();
+                for v in l {
+                    if let Some(s) = &v.string {
+                        if size == 1 {
+                            bytes.extend(s.bytes());
+                            continue;
+                        }
+                    }
+                    for i in 0..size {
+                        bytes.push((v.value >> (8 * i)) as u8);
+                    }
+                }
+                self.generate(&bytes);
+            }
+            "equ" | "=" | "set" => {
+                if line.label.is_empty() {
+                    return Err(self.error("Missing label for EQU"));
+                }
+                let v = self.eval(&line.args)?;
+                let name = line.label.strip_suffix(':').unwrap_or(&line.label);
+                self.define_symbol(name, v, mnemonic == "set");
+            }
+            "subroutine" => {
+                self.last_local_index += 1;
+                self.local_index = self.last_local_index;
+                self.program_label(&line.label);
+            }
+            "echo" => {
+                let l = self.eval_list(&line.args)?;
+                let mut s = String::new();
+                for v in l {
+                    if !v.unknown {
+                        if let Some(st) = v.string {
+                            s.push_str(&format!(" {}", st));
+                        } else {
+                            s.push_str(&format!(" ${:x}", v.value));
+                        }
+                    }
+                }
+                s.push('\n');
+                self.listing.push_str(&s);
+                self.echo.push_str(&s);
+            }
+            "incbin" => {
+                self.program_label(&line.label);
+                let name = line.args.trim().trim_matches('"').to_string();
+                let content = std::fs::read(&name)
+                    .map_err(|e| self.error(&format!("Can't open {}: {}", name, e)))?;
+                for chunk in content.chunks(GEN_BUFFER_SIZE) {
+                    self.generate(chunk);
+                }
+            }
+            "err" => return Err(self.error("ERR pseudo-op encountered")),
+            "end" => return Ok(Flow::End),
+            "list" | "trace" => (),
+            _ => {
+                if is_mnemonic(mnemonic) {
+                    self.instruction(mnemonic, &ext, line)?;
+                } else {
+                    return Err(self.error(&format!("Unknown mnemonic '{}'", line.mnemonic)));
+                }
+            }
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn instruction(&mut self, mnemonic: &str, ext: &str, line: &Line) -> Result<(), Error> {
+        use AddressingMode::*;
+
+        self.program_label(&line.label);
+        let args = line.args.trim();
+
+        // Let's figure out the addressing mode from the operand syntax
+        let (mode, expr): (AddressingMode, &str) = if args.is_empty() {
+            if has_mode(mnemonic, Implied) {
+                (Implied, "")
+            } else if has_mode(mnemonic, Accumulator) {
+                (Accumulator, "")
+            } else {
+                return Err(self.error(&format!("Missing operand for {}", mnemonic)));
+            }
+        } else if args.eq_ignore_ascii_case("a") && has_mode(mnemonic, Accumulator) {
+            (Accumulator, "")
+        } else if let Some(e) = args.strip_prefix('#') {
+            (Immediate, e)
+        } else if has_mode(mnemonic, Relative) {
+            (Relative, args)
+        } else {
+            let indirect = if args.starts_with('(') {
+                matching_parenthesis(args).and_then(|p| {
+                    let inner = &args[1..p];
+                    let after = args[p + 1..].trim();
+                    let parts = split_top_level(inner);
+                    if after.is_empty()
+                        && parts.len() == 2
+                        && parts[1].trim().eq_ignore_ascii_case("x")
+                    {
+                        Some((IndirectX, parts[0]))
+                    } else if let Some(index) = after.strip_prefix(',') {
+                        if index.trim().eq_ignore_ascii_case("y") && parts.len() == 1 {
+                            Some((IndirectY, inner))
+                        } else {
+                            None
+                        }
+                    } else if after.is_empty() && parts.len() == 1 && has_mode(mnemonic, Indirect) {
+                        Some((Indirect, inner))
+                    } else {
+                        None
+                    }
+                })
+            } else {
+                None
+            };
+            if let Some(i) = indirect {
+                i
+            } else {
+                let parts = split_top_level(args);
+                match parts.len() {
+                    1 => (Absolute, args),
+                    2 => {
+                        let index = parts[1].trim();
+                        if index.eq_ignore_ascii_case("x") {
+                            (AbsoluteX, parts[0])
+                        } else if index.eq_ignore_ascii_case("y") {
+                            (AbsoluteY, parts[0])
+                        } else {
+                            return Err(self.error(&format!("Bad index register '{}'", index)));
+                        }
+                    }
+                    _ => return Err(self.error(&format!("Syntax error in operand '{}'", args))),
+                }
+            }
+        };
+
+        let value = if expr.is_empty() {
+            Value::known(0)
+        } else {
+            self.eval(expr)?
+        };
+
+        // Choose between zeropage and absolute addressing
+        let mode = match mode {
+            Absolute | AbsoluteX | AbsoluteY => {
+                let zp = match mode {
+                    Absolute => ZeroPage,
+                    AbsoluteX => ZeroPageX,
+                    _ => ZeroPageY,
+                };
+                let force_word = ext == "w";
+                let force_byte = ext == "b" || ext == "z";
+                let fits = !value.unknown && (0..0x100).contains(&value.value);
+                if has_mode(mnemonic, zp)
+                    && !force_word
+                    && (force_byte || fits || !has_mode(mnemonic, mode))
+                {
+                    zp
+                } else {
+                    mode
+                }
+            }
+            m => m,
+        };
+
+        let Some(op) = opcode(mnemonic, mode) else {
+            return Err(self.error(&format!(
+                "Illegal addressing mode for {} ({:?})",
+                mnemonic, mode
+            )));
+        };
+        let mut bytes = vec![op];
+        match mode {
+            Implied | Accumulator => (),
+            Immediate => {
+                if !value.unknown && !(-128..0x100).contains(&value.value) {
+                    let msg = format!("Value in '{} {}' must be <$100", mnemonic, args);
+                    self.pass_error(&msg);
+                }
+                bytes.push(value.value as u8);
+            }
+            ZeroPage | ZeroPageX | ZeroPageY | IndirectX | IndirectY => {
+                if !value.unknown && !(0..0x100).contains(&value.value) {
+                    let msg = format!("Address in '{} {}' must be <$100", mnemonic, args);
+                    self.pass_error(&msg);
+                }
+                bytes.push(value.value as u8);
+            }
+            Absolute | AbsoluteX | AbsoluteY | Indirect => {
+                bytes.push(value.value as u8);
+                bytes.push((value.value >> 8) as u8);
+            }
+            Relative => {
+                let (pc, pc_unknown) = self.pc_value();
+                let offset = value.value - (pc + 2);
+                if value.unknown || pc_unknown {
+                    bytes.push(0);
+                } else {
+                    if !(-128..128).contains(&offset) {
+                        let msg = format!("Branch out of range ({} bytes)", offset);
+                        self.pass_error(&msg);
+                    }
+                    bytes.push(offset as u8);
+                }
+            }
+        }
+        self.generate(&bytes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble(source: &str) -> Result<Assembly, Error> {
+        Assembler::new().assemble("test.a", source)
+    }
+
+    // The assembler files of tests/assembler are made by cc2600 -S from the examples of the same
+    // name (banked with macros, 3E+ and 4K). Their image, listing and symbol file must be the ones
+    // of DASM (-f3, -l and -s options)
+    #[test]
+    fn examples_match_their_reference_output() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/assembler");
+        for name in ["example_helloworld", "test_ef", "test_3eplus"] {
+            let filename = format!("{}.a", name);
+            let source = std::fs::read_to_string(dir.join(&filename)).unwrap();
+            let assembly = Assembler::new().assemble(&filename, &source).unwrap();
+            let reference = |extension: &str| dir.join(format!("{}.{}", name, extension));
+            assert!(
+                assembly.binary == std::fs::read(reference("bin")).unwrap(),
+                "{} image",
+                name
+            );
+            assert_eq!(
+                assembly.listing,
+                std::fs::read_to_string(reference("lst")).unwrap(),
+                "{} listing",
+                name
+            );
+            assert_eq!(
+                assembly.symbol_table(),
+                std::fs::read_to_string(reference("sym")).unwrap(),
+                "{} symbols",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn forward_references() {
+        // value is known at the end of the first pass only: LDA value is assembled as absolute
+        // first, then as zeropage, moving later
+        let source =
+            "\tPROCESSOR 6502\n\tORG $F000\n\tJMP later\n\tLDA value\nlater\tRTS\nvalue = $80\n";
+        let assembly = assemble(source).unwrap();
+        assert_eq!(assembly.binary, [0x4c, 0x05, 0xf0, 0xa5, 0x80, 0x60]);
+        assert_eq!(assembly.symbol("later").unwrap().value, 0xf005);
+    }
+
+    #[test]
+    fn decimal_strings_in_echo() {
+        let source = "\tPROCESSOR 6502\n\tORG $F000\n\tNOP\n\tECHO ([$F010-.]d), \"bytes free\"\n\tECHO [end-$F000]d\nend\n";
+        let assembly = assemble(source).unwrap();
+        assert_eq!(assembly.echo, " 15 bytes free\n 1\n");
+    }
+
+    #[test]
+    fn unresolvable_source() {
+        // The NOP is assembled when size is 0, which makes size 1, and so on
+        let source =
+            "\tPROCESSOR 6502\n\tORG $F000\n\tIF size == 0\n\tNOP\n\tENDIF\nsize = . - $F000\n";
+        let error = assemble(source).unwrap_err();
+        assert_eq!(
+            error.msg,
+            format!("Source is not resolvable after {} passes", MAX_PASSES)
+        );
+    }
+}
@@ -0,0 +1,330 @@
+/*
+    cc2600 - a subset of C compiler for the Atari 2600
+    Copyright (C) 2023-2024 Bruno STEUX
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+    Contact info: bruno.steux@gmail.com
+*/
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum AddressingMode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndirectX,
+    IndirectY,
+    Relative,
+}
+
+use AddressingMode::*;
+
+// (mnemonic, addressing mode, opcode)
+// Includes the undocumented opcodes that DASM accepts when assembling for the 6502, since they
+// are commonly used in kernels written in assembler.
+const OPCODES: &[(&str, AddressingMode, u8)] = &[
+    ("adc", Immediate, 0x69),
+    ("adc", ZeroPage, 0x65),
+    ("adc", ZeroPageX, 0x75),
+    ("adc", Absolute, 0x6d),
+    ("adc", AbsoluteX, 0x7d),
+    ("adc", AbsoluteY, 0x79),
+    ("adc", IndirectX, 0x61),
+    ("adc", IndirectY, 0x71),
+    ("and", Immediate, 0x29),
+    ("and", ZeroPage, 0x25),
+    ("and", ZeroPageX, 0x35),
+    ("and", Absolute, 0x2d),
+    ("and", AbsoluteX, 0x3d),
+    ("and", AbsoluteY, 0x39),
+    ("and", IndirectX, 0x21),
+    ("and", IndirectY, 0x31),
+    ("asl", Accumulator, 0x0a),
+    ("asl", ZeroPage, 0x06),
+    ("asl", ZeroPageX, 0x16),
+    ("asl", Absolute, 0x0e),
+    ("asl", AbsoluteX, 0x1e),
+    ("bcc", Relative, 0x90),
+    ("bcs", Relative, 0xb0),
+    ("beq", Relative, 0xf0),
+    ("bit", ZeroPage, 0x24),
+    ("bit", Absolute, 0x2c),
+    ("bmi", Relative, 0x30),
+    ("bne", Relative, 0xd0),
+    ("bpl", Relative, 0x10),
+    ("brk", Implied, 0x00),
+    ("bvc", Relative, 0x50),
+    ("bvs", Relative, 0x70),
+    ("clc", Implied, 0x18),
+    ("cld", Implied, 0xd8),
+    ("cli", Implied, 0x58),
+    ("clv", Implied, 0xb8),
+    ("cmp", Immediate, 0xc9),
+    ("cmp", ZeroPage, 0xc5),
+    ("cmp", ZeroPageX, 0xd5),
+    ("cmp", Absolute, 0xcd),
+    ("cmp", AbsoluteX, 0xdd),
+    ("cmp", AbsoluteY, 0xd9),
+    ("cmp", IndirectX, 0xc1),
+    ("cmp", IndirectY, 0xd1),
+    ("cpx", Immediate, 0xe0),
+    ("cpx", ZeroPage, 0xe4),
+    ("cpx", Absolute, 0xec),
+    ("cpy", Immediate, 0xc0),
+    ("cpy", ZeroPage, 0xc4),
+    ("cpy", Absolute, 0xcc),
+    ("dec", ZeroPage, 0xc6),
+    ("dec", ZeroPageX, 0xd6),
+    ("dec", Absolute, 0xce),
+    ("dec", AbsoluteX, 0xde),
+    ("dex", Implied, 0xca),
+    ("dey", Implied, 0x88),
+    ("eor", Immediate, 0x49),
+    ("eor", ZeroPage, 0x45),
+    ("eor", ZeroPageX, 0x55),
+    ("eor", Absolute, 0x4d),
+    ("eor", AbsoluteX, 0x5d),
+    ("eor", AbsoluteY, 0x59),
+    ("eor", IndirectX, 0x41),
+    ("eor", IndirectY, 0x51),
+    ("inc", ZeroPage, 0xe6),
+    ("inc", ZeroPageX, 0xf6),
+    ("inc", Absolute, 0xee),
+    ("inc", AbsoluteX, 0xfe),
+    ("inx", Implied, 0xe8),
+    ("iny", Implied, 0xc8),
+    ("jmp", Absolute, 0x4c),
+    ("jmp", Indirect, 0x6c),
+    ("jsr", Absolute, 0x20),
+    ("lda", Immediate, 0xa9),
+    ("lda", ZeroPage, 0xa5),
+    ("lda", ZeroPageX, 0xb5),
+    ("lda", Absolute, 0xad),
+    ("lda", AbsoluteX, 0xbd),
+    ("lda", AbsoluteY, 0xb9),
+    ("lda", IndirectX, 0xa1),
+    ("lda", IndirectY, 0xb1),
+    ("ldx", Immediate, 0xa2),
+    ("ldx", ZeroPage, 0xa6),
+    ("ldx", ZeroPageY, 0xb6),
+    ("ldx", Absolute, 0xae),
+    ("ldx", AbsoluteY, 0xbe),
+    ("ldy", Immediate, 0xa0),
+    ("ldy", ZeroPage, 0xa4),
+    ("ldy", ZeroPageX, 0xb4),
+    ("ldy", Absolute, 0xac),
+    ("ldy", AbsoluteX, 0xbc),
+    ("lsr", Accumulator, 0x4a),
+    ("lsr", ZeroPage, 0x46),
+    ("lsr", ZeroPageX, 0x56),
+    ("lsr", Absolute, 0x4e),
+    ("lsr", AbsoluteX, 0x5e),
+    ("nop", Implied, 0xea),
+    ("nop", Immediate, 0x80),
+    ("nop", ZeroPage, 0x04),
+    ("nop", ZeroPageX, 0x14),
+    ("nop", Absolute, 0x0c),
+    ("nop", AbsoluteX, 0x1c),
+    ("ora", Immediate, 0x09),
+    ("ora", ZeroPage, 0x05),
+    ("ora", ZeroPageX, 0x15),
+    ("ora", Absolute, 0x0d),
+    ("ora", AbsoluteX, 0x1d),
+    ("ora", AbsoluteY, 0x19),
+    ("ora", IndirectX, 0x01),
+    ("ora", IndirectY, 0x11),
+    ("pha", Implied, 0x48),
+    ("php", Implied, 0x08),
+    ("pla", Implied, 0x68),
+    ("plp", Implied, 0x28),
+    ("rol", Accumulator, 0x2a),
+    ("rol", ZeroPage, 0x26),
+    ("rol", ZeroPageX, 0x36),
+    ("rol", Absolute, 0x2e),
+    ("rol", AbsoluteX, 0x3e),
+    ("ror", Accumulator, 0x6a),
+    ("ror", ZeroPage, 0x66),
+    ("ror", ZeroPageX, 0x76),
+    ("ror", Absolute, 0x6e),
+    ("ror", AbsoluteX, 0x7e),
+    ("rti", Implied, 0x40),
+    ("rts", Implied, 0x60),
+    ("sbc", Immediate, 0xe9),
+    ("sbc", ZeroPage, 0xe5),
+    ("sbc", ZeroPageX, 0xf5),
+    ("sbc", Absolute, 0xed),
+    ("sbc", AbsoluteX, 0xfd),
+    ("sbc", AbsoluteY, 0xf9),
+    ("sbc", IndirectX, 0xe1),
+    ("sbc", IndirectY, 0xf1),
+    ("sec", Implied, 0x38),
+    ("sed", Implied, 0xf8),
+    ("sei", Implied, 0x78),
+    ("sta", ZeroPage, 0x85),
+    ("sta", ZeroPageX, 0x95),
+    ("sta", Absolute, 0x8d),
+    ("sta", AbsoluteX, 0x9d),
+    ("sta", AbsoluteY, 0x99),
+    ("sta", IndirectX, 0x81),
+    ("sta", IndirectY, 0x91),
+    ("stx", ZeroPage, 0x86),
+    ("stx", ZeroPageY, 0x96),
+    ("stx", Absolute, 0x8e),
+    ("sty", ZeroPage, 0x84),
+    ("sty", ZeroPageX, 0x94),
+    ("sty", Absolute, 0x8c),
+    ("tax", Implied, 0xaa),
+    ("tay", Implied, 0xa8),
+    ("tsx", Implied, 0xba),
+    ("txa", Implied, 0x8a),
+    ("txs", Implied, 0x9a),
+    ("tya", Implied, 0x98),
+    // Undocumented opcodes
+    ("alr", Immediate, 0x4b),
+    ("anc", Immediate, 0x0b),
+    ("arr", Immediate, 0x6b),
+    ("sbx", Immediate, 0xcb),
+    ("dcp", ZeroPage, 0xc7),
+    ("dcp", ZeroPageX, 0xd7),
+    ("dcp", Absolute, 0xcf),
+    ("dcp", AbsoluteX, 0xdf),
+    ("dcp", AbsoluteY, 0xdb),
+    ("dcp", IndirectX, 0xc3),
+    ("dcp", IndirectY, 0xd3),
+    ("isb", ZeroPage, 0xe7),
+    ("isb", ZeroPageX, 0xf7),
+    ("isb", Absolute, 0xef),
+    ("isb", AbsoluteX, 0xff),
+    ("isb", AbsoluteY, 0xfb),
+    ("isb", IndirectX, 0xe3),
+    ("isb", IndirectY, 0xf3),
+    ("lax", Immediate, 0xab),
+    ("lax", ZeroPage, 0xa7),
+    ("lax", ZeroPageY, 0xb7),
+    ("lax", Absolute, 0xaf),
+    ("lax", AbsoluteY, 0xbf),
+    ("lax", IndirectX, 0xa3),
+    ("lax", IndirectY, 0xb3),
+    ("rla", ZeroPage, 0x27),
+    ("rla", ZeroPageX, 0x37),
+    ("rla", Absolute, 0x2f),
+    ("rla", AbsoluteX, 0x3f),
+    ("rla", AbsoluteY, 0x3b),
+    ("rla", IndirectX, 0x23),
+    ("rla", IndirectY, 0x33),
+    ("rra", ZeroPage, 0x67),
+    ("rra", ZeroPageX, 0x77),
+    ("rra", Absolute, 0x6f),
+    ("rra", AbsoluteX, 0x7f),
+    ("rra", AbsoluteY, 0x7b),
+    ("rra", IndirectX, 0x63),
+    ("rra", IndirectY, 0x73),
+    ("sax", ZeroPage, 0x87),
+    ("sax", ZeroPageY, 0x97),
+    ("sax", Absolute, 0x8f),
+    ("sax", IndirectX, 0x83),
+    ("slo", ZeroPage, 0x07),
+    ("slo", ZeroPageX, 0x17),
+    ("slo", Absolute, 0x0f),
+    ("slo", AbsoluteX, 0x1f),
+    ("slo", AbsoluteY, 0x1b),
+    ("slo", IndirectX, 0x03),
+    ("slo", IndirectY, 0x13),
+    ("sre", ZeroPage, 0x47),
+    ("sre", ZeroPageX, 0x57),
+    ("sre", Absolute, 0x4f),
+    ("sre", AbsoluteX, 0x5f),
+    ("sre", AbsoluteY, 0x5b),
+    ("sre", IndirectX, 0x43),
+    ("sre", IndirectY, 0x53),
+];
+
+/// Returns the opcode of the given (lowercase) mnemonic for the given addressing mode
+pub fn opcode(mnemonic: &str, mode: AddressingMode) -> Option<u8> {
+    OPCODES
+        .iter()
+        .find(|o| o.0 == mnemonic && o.1 == mode)
+        .map(|o| o.2)
+}
+
+pub fn is_mnemonic(mnemonic: &str) -> bool {
+    OPCODES.iter().any(|o| o.0 == mnemonic)
+}
+
+pub fn has_mode(mnemonic: &str, mode: AddressingMode) -> bool {
+    opcode(mnemonic, mode).is_some()
+}
+
+/// Mnemonic and addressing mode of an opcode
+pub fn decode(opcode: u8) -> Option<(&'static str, AddressingMode)> {
+    OPCODES.iter().find(|o| o.2 == opcode).map(|o| (o.0, o.1))
+}
+
+/// Number of cycles taken by an instruction, and whether crossing a page boundary when indexing
+/// adds one more cycle. Branches take 2 cycles, one more when taken and another one when the
+/// branch goes to another page.
+pub fn cycles(mnemonic: &str, mode: AddressingMode) -> (u32, bool) {
+    let rmw = matches!(
+        mnemonic,
+        "asl"
+            | "lsr"
+            | "rol"
+            | "ror"
+            | "inc"
+            | "dec"
+            | "slo"
+            | "sre"
+            | "rla"
+            | "rra"
+            | "dcp"
+            | "isb"
+    );
+    let write = matches!(mnemonic, "sta" | "stx" | "sty" | "sax");
+    match mode {
+        Implied | Accumulator => match mnemonic {
+            "pha" | "php" => (3, false),
+            "pla" | "plp" => (4, false),
+            "rts" | "rti" => (6, false),
+            "brk" => (7, false),
+            _ => (2, false),
+        },
+        Immediate | Relative => (2, false),
+        ZeroPage if rmw => (5, false),
+        ZeroPage => (3, false),
+        ZeroPageX | ZeroPageY if rmw => (6, false),
+        ZeroPageX | ZeroPageY => (4, false),
+        Absolute => match mnemonic {
+            "jmp" => (3, false),
+            "jsr" => (6, false),
+            _ if rmw => (6, false),
+            _ => (4, false),
+        },
+        AbsoluteX | AbsoluteY if rmw => (7, false),
+        AbsoluteX | AbsoluteY if write => (5, false),
+        AbsoluteX | AbsoluteY => (4, true),
+        Indirect => (5, false),
+        IndirectX if rmw => (8, false),
+        IndirectX => (6, false),
+        IndirectY if rmw => (8, false),
+        IndirectY if write => (6, false),
+        IndirectY => (5, true),
+    }
+}
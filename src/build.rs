@@ -19,61 +19,1178 @@
 */
 
 use log::debug;
+use std::cell::RefCell;
 use std::io::Write;
+use std::rc::Rc;
 
 use cc6502::assemble::AssemblyCode;
 use cc6502::compile::*;
 use cc6502::error::Error;
 use cc6502::generate::*;
 use cc6502::Args;
-use std::collections::{HashMap, HashSet};
 
-fn compute_function_level(
-    function_name: &String,
-    node: &str,
-    current_level: usize,
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::assembler::Assembly;
+use crate::callgraph::{stack_pushes, Call, CallGraph, CallGraphFunction};
+use crate::cycles::{check_cycles, page_crossings};
+use crate::layout::{check_layout, compute_layout, Bank, ItemKind};
+use crate::placement::{place, Placement, PlacementItem};
+use crate::report::{memory_map, Overlay, RamRegion, RamVariable};
+
+/// cc2600 specific build options
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct BuildOptions {
+    /// Automatically place the functions and ROM tables that are not assigned to a bank
+    #[arg(long, default_value = "false")]
+    pub auto_bank: bool,
+    /// Force the bankswitching scheme (4K, F8, F8SC, F6, F6SC, F4, F4SC, EF, EFSC, DF, DFSC, BF,
+    /// BFSC, FA, E0, E7, 3E, 3E+, DPC or DPC+)
+    #[arg(long, value_name = "SCHEME")]
+    pub bankswitching: Option<String>,
+    /// Force the ROM size, in KB
+    #[arg(long, value_name = "KB")]
+    pub rom_size: Option<u32>,
+    /// Write the memory map (banks, functions, tables and variables) in JSON to the given file
+    #[arg(long, value_name = "FILE")]
+    pub memory_map: Option<String>,
+    /// Write the call graph from main, with the worst-case stack depth, to the given file
+    #[arg(long, value_name = "FILE")]
+    pub call_graph: Option<String>,
+    /// Write the call graph in Graphviz DOT format to the given file
+    #[arg(long, value_name = "FILE")]
+    pub call_graph_dot: Option<String>,
+    /// Allow the given functions to be called recursively. Their local variables get a dedicated
+    /// storage instead of sharing memory with the other functions
+    #[arg(long, value_name = "FUNCTION", value_delimiter = ',')]
+    pub allow_recursion: Vec<String>,
+    /// Write the number of cycles taken by the functions and by the annotated parts of the code
+    /// (;@loop, ;@block and ;@function) to the given file
+    #[arg(long, value_name = "FILE")]
+    pub cycle_report: Option<String>,
+    /// What to do when a branch of a timing-critical function (with cycle annotations) goes to
+    /// another page, or when a table it reads with X or Y indexing crosses a page boundary: warn
+    /// (default), error, or align the function or the table on a page boundary
+    #[arg(long, value_name = "ACTION", value_parser = ["warn", "error", "align"])]
+    pub page_crossing: Option<String>,
+}
+
+/// Information about the last built cartridge, used to write the debugging files
+#[derive(Debug, Clone, Default)]
+pub struct CartridgeInfo {
+    /// Bankswitching scheme, as named by Stella
+    pub bankswitching_scheme: String,
+    /// C names of the local variables and parameters (function.variable), by assembler name
+    pub c_names: HashMap<String, String>,
+    /// File, line number and text of each line of the preprocessed source code (debug only)
+    pub source_lines: Vec<(String, u32, String)>,
+    /// Origin of each ROM bank, as (bank, org)
+    pub banks: Vec<(u32, u32)>,
+    /// Functions, as (name, bank, address, size)
+    pub functions: Vec<(String, u32, u32, u32)>,
+    /// Variables in RAM
+    pub variables: Vec<DebugVariable>,
+}
+
+/// Variable in RAM, described in the debug information
+#[derive(Debug, Clone)]
+pub struct DebugVariable {
+    /// C name
+    pub name: String,
+    /// Assembler symbol
+    pub symbol: String,
+    /// Function of a local variable or parameter
+    pub function: Option<String>,
+    pub parameter: bool,
+    /// Offset of the read address from the symbol (superchip RAM is read 128 bytes further)
+    pub read_offset: u32,
+    pub var_type: VariableType,
+    pub signed: bool,
+    /// Number of elements of an array, 1 otherwise
+    pub size: usize,
+}
+
+// cc6502's compile() takes the cartridge builder as a plain function pointer, which can neither
+// capture the build options nor return the cartridge information. They are handed over to
+// build_cartridge through these variables, and passed explicitly everywhere else.
+thread_local! {
+    static OPTIONS: RefCell<BuildOptions> = RefCell::new(BuildOptions::default());
+    static INFO: RefCell<CartridgeInfo> = RefCell::new(CartridgeInfo::default());
+}
+
+/// Sets the options used by the next call to build_cartridge
+pub fn set_options(options: BuildOptions) {
+    OPTIONS.with(|o| *o.borrow_mut() = options);
+}
+
+/// Returns the information about the last built cartridge
+pub fn cartridge_info() -> CartridgeInfo {
+    INFO.with(|i| i.borrow().clone())
+}
+
+// C name of a local variable or parameter, and whether it's a parameter. The compiler names them
+// function_variable for parameters and function_scope_variable for local variables.
+fn local_variable_c_name(function: &str, name: &str) -> Option<(String, bool)> {
+    let short = name.strip_prefix(function)?.strip_prefix('_')?;
+    let (short, parameter) = match short.split_once('_') {
+        Some((scope, rest)) if !scope.is_empty() && scope.chars().all(|c| c.is_ascii_digit()) => {
+            (rest, false)
+        }
+        _ => (short, true),
+    };
+    Some((format!("{}.{}", function, short), parameter))
+}
+
+// Output buffer that can be read while the code generator writes into it
+#[derive(Clone, Default)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Level in the call tree and offset in LOCAL_VARIABLES of the local variables of each function
+// called from root. The local variables of a function go after the local variables of all the
+// functions that can call it, so that the functions that never run at the same time share the
+// same memory.
+fn allocate_local_variables(
     tree: &HashMap<String, Vec<String>>,
-    already_seen: &mut HashSet<String>,
-) -> Option<usize> {
-    let mut ret = None;
-    if let Some(calls) = tree.get(node) {
-        // Is the function name in current_level tree ?
-        if calls.contains(function_name) {
-            ret = Some(current_level);
-        }
-        // Look at the lower levels if it is possible to find it also
-        for nodex in calls {
-            //debug!("Function name: {}, {:?}", function_name, nodex);
-            if already_seen.contains(nodex) {
-                //debug!("Function {} has already been seen", nodex);
-                return None;
-            }
-            already_seen.insert(nodex.clone());
-            if let Some(lx) =
-                compute_function_level(function_name, nodex, current_level + 1, tree, already_seen)
-            {
-                if let Some(lxx) = ret {
-                    if lx > lxx {
-                        ret = Some(lx);
+    root: &str,
+    sizes: &HashMap<String, u32>,
+) -> HashMap<String, (usize, u32)> {
+    let mut nodes = reachable(tree, root);
+    nodes.insert(root.to_string());
+    let mut callers = HashMap::<&str, usize>::new();
+    for node in &nodes {
+        for callee in tree.get(node).into_iter().flatten() {
+            *callers.entry(callee.as_str()).or_default() += 1;
+        }
+    }
+    let mut frames = HashMap::new();
+    frames.insert(root.to_string(), (0, 0));
+    // Functions are allocated once all their callers are
+    let mut ready = vec![root.to_string()];
+    while let Some(node) = ready.pop() {
+        let (level, offset) = frames[&node];
+        let end = offset + sizes.get(&node).copied().unwrap_or_default();
+        for callee in tree.get(&node).into_iter().flatten() {
+            let frame = frames.entry(callee.clone()).or_insert((0, 0));
+            frame.0 = frame.0.max(level + 1);
+            frame.1 = frame.1.max(end);
+            let count = callers.get_mut(callee.as_str()).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                ready.push(callee.clone());
+            }
+        }
+    }
+    frames
+}
+
+// Functions that can be reached from the given function, through at least one call
+fn reachable(tree: &HashMap<String, Vec<String>>, from: &str) -> HashSet<String> {
+    let mut seen = HashSet::new();
+    let mut stack = vec![from];
+    while let Some(node) = stack.pop() {
+        for f in tree.get(node).into_iter().flatten() {
+            if seen.insert(f.clone()) {
+                stack.push(f);
+            }
+        }
+    }
+    seen
+}
+
+fn standard_bankswitching(
+    maxbank: u32,
+    superchip: bool,
+) -> Result<(&'static str, u32, u32), Error> {
+    match maxbank {
+        1 => Ok((if superchip { "F8S" } else { "F8" }, 1, 0x1FF8)),
+        2 | 3 => Ok((if superchip { "F6S" } else { "F6" }, 3, 0x1FF6)),
+        4..=7 => Ok((if superchip { "F4S" } else { "F4" }, 7, 0x1FF4)),
+        8..=15 => Ok((if superchip { "EFSC" } else { "EF" }, 15, 0x1FE0)),
+        16..=31 => Ok((if superchip { "DFSC" } else { "DF" }, 31, 0x1FC0)),
+        32..=63 => Ok((if superchip { "BFSC" } else { "BF" }, 63, 0x1F80)),
+        _ => Err(Error::Unimplemented {
+            feature: "Bankswitching scheme not implemented",
+        }),
+    }
+}
+
+// End of the room available for code and bankswitching stubs, below the hotspots. A byte is
+// left before them: RTS (and any single byte instruction) reads the byte that follows it, and that
+// dummy read would switch the bank if the last instruction of a bank was next to a hotspot
+fn stubs_end(bankswitching_scheme: &str) -> u32 {
+    match bankswitching_scheme {
+        "EF" | "EFSC" => 0x1fdf,
+        "DF" | "DFSC" => 0x1fbf,
+        "BF" | "BFSC" => 0x1f7f,
+        "FA" => 0x1ff7,
+        _ => 0x1fef,
+    }
+}
+
+// Last bank and superchip RAM of the standard schemes
+fn standard_scheme(bankswitching_scheme: &str) -> Option<(u32, bool)> {
+    match bankswitching_scheme {
+        "4K" => Some((0, false)),
+        "F8" => Some((1, false)),
+        "F8S" => Some((1, true)),
+        "F6" => Some((3, false)),
+        "F6S" => Some((3, true)),
+        "F4" => Some((7, false)),
+        "F4S" => Some((7, true)),
+        "EF" => Some((15, false)),
+        "EFSC" => Some((15, true)),
+        "DF" => Some((31, false)),
+        "DFSC" => Some((31, true)),
+        "BF" => Some((63, false)),
+        "BFSC" => Some((63, true)),
+        _ => None,
+    }
+}
+
+// Bankswitching scheme given on the command line
+fn parse_scheme(name: &str) -> Result<&'static str, Error> {
+    const SCHEMES: [&str; 20] = [
+        "4K", "F8", "F8S", "F6", "F6S", "F4", "F4S", "EF", "EFSC", "DF", "DFSC", "BF", "BFSC",
+        "FA", "E0", "E7", "3E", "3EP", "DPC", "DPC+",
+    ];
+    let name = name.to_ascii_uppercase();
+    SCHEMES
+        .into_iter()
+        .find(|s| scheme_name(s) == name || *s == name)
+        .ok_or(Error::Configuration {
+            error: format!("Unknown bankswitching scheme {}", name),
+        })
+}
+
+// Usual name of a bankswitching scheme
+fn scheme_name(bankswitching_scheme: &str) -> &str {
+    match bankswitching_scheme {
+        "3EP" => "3E+",
+        "F8S" => "F8SC",
+        "F6S" => "F6SC",
+        "F4S" => "F4SC",
+        _ => bankswitching_scheme,
+    }
+}
+
+// ROM size in KB of the fixed size schemes
+fn rom_size(bankswitching_scheme: &str) -> Option<u32> {
+    match bankswitching_scheme {
+        "FA" => Some(12),
+        "E0" => Some(8),
+        "E7" => Some(16),
+        "DPC" => Some(10),
+        "DPC+" => Some(32),
+        "3E" | "3EP" => None,
+        _ => standard_scheme(bankswitching_scheme).map(|(m, _)| (m + 1) * 4),
+    }
+}
+
+fn trampoline_macro(bankswitching_address: u32) -> String {
+    format!(
+        "
+; Macro that implements Bank Switching trampoline
+; X = bank number
+; A = hi byte of destination PC
+; Y = lo byte of destination PC
+        MAC BANK_SWITCH_TRAMPOLINE
+        pha     ; push hi byte
+        tya     ; Y -> A
+        pha     ; push lo byte
+        lda ${:04x},x ; do the bank switch
+        rts     ; return to target
+        ENDM
+        ",
+        bankswitching_address
+    )
+}
+
+// Offset in LOCAL_VARIABLES, function and its local variables
+type LocalOverlays = Vec<(u32, String, Vec<String>)>;
+
+// Size in bytes of a variable
+fn variable_size(v: &Variable) -> u32 {
+    let s = match v.var_type {
+        VariableType::Char => 1,
+        VariableType::CharPtr if v.size > 1 => 1,
+        _ => 2,
+    };
+    (v.size * s) as u32
+}
+
+// RAM area of a variable, as (name, start, size)
+fn ram_region(memory: VariableMemory, bankswitching_scheme: &str) -> Option<(String, u32, u32)> {
+    match (memory, bankswitching_scheme) {
+        (VariableMemory::Zeropage, _) => Some(("Zeropage RAM".to_string(), 0x80, 128)),
+        (VariableMemory::Superchip, "FA") => Some(("CBS RAM+".to_string(), 0x1100, 256)),
+        (VariableMemory::Superchip, _) => Some(("Superchip RAM".to_string(), 0x1000, 128)),
+        (VariableMemory::MemoryOnChip(b), "3E") => {
+            Some((format!("3E RAM bank {}", b), 0x1000, 1024))
+        }
+        (VariableMemory::MemoryOnChip(b), "3EP") => Some((
+            format!("3E+ RAM bank {}", b),
+            0x1000 + (3 - (b & 3)) * 0x400,
+            512,
+        )),
+        (VariableMemory::MemoryOnChip(0), "E7") => Some(("E7 RAM".to_string(), 0x1400, 1024)),
+        (VariableMemory::MemoryOnChip(b), "E7") => {
+            Some((format!("E7 RAM page {}", b - 1), 0x1900, 256))
+        }
+        _ => None,
+    }
+}
+
+// Label of the memory shared by the local variables in the given RAM
+fn local_variables_label(memory: VariableMemory, bankswitching_scheme: &str) -> String {
+    match memory {
+        VariableMemory::Superchip => "LOCAL_SUPERVARS".to_string(),
+        VariableMemory::MemoryOnChip(b) if bankswitching_scheme == "E7" => {
+            format!("LOCAL_RAM_E7_{}", b)
+        }
+        VariableMemory::MemoryOnChip(b) => format!("LOCAL_RAM_3E_{}", b),
+        _ => "LOCAL_VARIABLES".to_string(),
+    }
+}
+
+// RAM areas and the variables they hold, for the memory map. Addresses are given by the symbols
+// of the layout assembly.
+fn ram_regions(
+    compiler_state: &CompilerState,
+    bankswitching_scheme: &str,
+    overlays: &[(VariableMemory, LocalOverlays)],
+    assembly: &Assembly,
+) -> Vec<RamRegion> {
+    let address = |name: &str| assembly.symbol(name).map_or(0, |s| s.value as u32);
+    let mut regions: Vec<RamRegion> = Vec::new();
+    let region = |memory: VariableMemory, regions: &mut Vec<RamRegion>| {
+        let (name, start, size) = ram_region(memory, bankswitching_scheme)?;
+        match regions.iter().position(|r| r.name == name) {
+            Some(i) => Some(i),
+            None => {
+                regions.push(RamRegion::new(&name, start, size));
+                Some(regions.len() - 1)
+            }
+        }
+    };
+    let mut locals = HashSet::new();
+    for (memory, functions) in overlays {
+        if functions.is_empty() {
+            continue;
+        }
+        let label = local_variables_label(*memory, bankswitching_scheme);
+        let mut overlay = Overlay {
+            address: address(&label),
+            name: label,
+            size: 0,
+            functions: Vec::new(),
+        };
+        for (_, function, variables) in functions {
+            let mut vars = Vec::new();
+            for vx in variables {
+                if let Some(v) = compiler_state.variables.get(vx) {
+                    locals.insert(vx.clone());
+                    vars.push(RamVariable {
+                        name: vx.clone(),
+                        address: address(vx),
+                        size: variable_size(v),
+                    });
+                }
+            }
+            for v in &vars {
+                overlay.size = overlay.size.max(v.address + v.size - overlay.address);
+            }
+            overlay.functions.push((function.clone(), vars));
+        }
+        if let Some(i) = region(*memory, &mut regions) {
+            regions[i].overlays.push(overlay);
+        }
+    }
+    for (name, v) in compiler_state.sorted_variables().iter() {
+        // Local variables of the functions that are not used are not allocated
+        if v.def != VariableDefinition::None
+            || locals.contains(*name)
+            || assembly.symbol(name).is_none()
+        {
+            continue;
+        }
+        if let Some(i) = region(v.memory, &mut regions) {
+            regions[i].variables.push(RamVariable {
+                name: name.to_string(),
+                address: address(name),
+                size: variable_size(v),
+            });
+        }
+    }
+    regions
+}
+
+// Writes the local variables in extended RAM. They are declared after a label reserving the
+// memory they share, with their write address if the RAM has separate read and write ports.
+// Returns the size of the shared memory.
+fn write_local_variables(
+    gstate: &mut GeneratorState,
+    compiler_state: &CompilerState,
+    label: &str,
+    overlays: &LocalOverlays,
+    write_offset: Option<u32>,
+    ram_ports: &mut HashSet<String>,
+) -> Result<usize, Error> {
+    let mut declarations = String::new();
+    let mut size = 0;
+    for (offset, _, variables) in overlays {
+        let mut address = *offset;
+        for vx in variables {
+            declarations.push_str(&format!("{:23}\t= {} + {}\n", vx, label, address));
+            if let Some(write_offset) = write_offset {
+                declarations.push_str(&format!(
+                    "{:23}\t= {} - ${:x}\n",
+                    format!("{}_W", vx),
+                    vx,
+                    write_offset
+                ));
+                ram_ports.insert(vx.clone());
+            }
+            address += variable_size(&compiler_state.variables[vx]);
+        }
+        size = size.max(address);
+    }
+    if size > 0 {
+        gstate.write(&format!("{:23}\tds {}\n", label, size))?;
+        gstate.write(&declarations)?;
+    }
+    Ok(size as usize)
+}
+
+fn identifiers(code: &str) -> impl Iterator<Item = &str> {
+    code.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+        .filter(|s| s.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_'))
+}
+
+// Operand of a JSR instruction line
+fn called_function(line: &str) -> Option<&str> {
+    let mut words = line.split_whitespace();
+    match words.next() {
+        Some(m) if m.eq_ignore_ascii_case("jsr") => words.next(),
+        _ => None,
+    }
+}
+
+// E0 and E7 ROMs are cut into slices. The last slice, bank 0, is always mapped. The other banks
+// are selected by accessing a hotspot.
+fn sliced(bankswitching_scheme: &str) -> bool {
+    bankswitching_scheme == "E0" || bankswitching_scheme == "E7"
+}
+
+// Segment where the given bank (1 to 7, i.e. slice 0 to 6) is mapped
+fn slice_segment(bankswitching_scheme: &str, bank: u32) -> u32 {
+    if bankswitching_scheme == "E0" {
+        (bank - 1) % 3
+    } else {
+        0
+    }
+}
+
+// Hotspot that maps the given bank (1 to 7, i.e. slice 0 to 6) into its segment
+fn slice_hotspot(bankswitching_scheme: &str, bank: u32) -> u32 {
+    0x1fe0 + slice_segment(bankswitching_scheme, bank) * 8 + bank - 1
+}
+
+// Label of the bankswitching stub used to call the given function from the given bank
+fn far_call_label(bankswitching_scheme: &str, function: &str, bank: u32) -> String {
+    if bank == 0 || sliced(bankswitching_scheme) {
+        format!("Call{}", function)
+    } else {
+        format!("Call{}_bank{}", function, bank)
+    }
+}
+
+// With 3E, 3E+, E0 and E7 bankswitching, banks are switched in place of the code of the bank
+// sharing the same segment, and the calling bank is not mapped back on return. Checks that the
+// calling code stays mapped in memory, through every call path.
+fn check_segment_calls(
+    bankswitching_scheme: &str,
+    calls: &HashMap<String, Vec<String>>,
+    placement: &Placement,
+) -> Result<(), Error> {
+    let legal = |a: u32, b: u32| {
+        if bankswitching_scheme == "3E" {
+            // Only bank 0, always mapped in the last 2KB, can switch the first 2KB
+            a == b || a == 0
+        } else if sliced(bankswitching_scheme) {
+            // Bank 0 is always mapped
+            a == b
+                || a == 0
+                || b == 0
+                || slice_segment(bankswitching_scheme, a) != slice_segment(bankswitching_scheme, b)
+        } else {
+            // Banks sharing the same 1KB segment can't call each other
+            a == b || a & 3 != b & 3
+        }
+    };
+    let mut callers: Vec<_> = calls.keys().collect();
+    callers.sort();
+    for caller in callers {
+        let Some(&a) = placement.get(caller) else {
+            continue;
+        };
+        // Breadth first search of the functions called directly or indirectly, with the function
+        // they are called from
+        let mut from = HashMap::<&str, &str>::new();
+        let mut queue = VecDeque::from([caller.as_str()]);
+        while let Some(node) = queue.pop_front() {
+            let mut called: Vec<&String> = calls.get(node).into_iter().flatten().collect();
+            called.sort();
+            for callee in called {
+                if callee == caller || from.contains_key(callee.as_str()) {
+                    continue;
+                }
+                from.insert(callee, node);
+                queue.push_back(callee);
+                let Some(&b) = placement.get(callee) else {
+                    continue;
+                };
+                if !legal(a, b) {
+                    let mut path = vec![callee.as_str()];
+                    while let Some(&f) = from.get(path[path.len() - 1]) {
+                        path.push(f);
                     }
-                } else {
-                    ret = Some(lx)
+                    path.reverse();
+                    let through = if path.len() > 2 {
+                        format!(" (call path {})", path.join(" -> "))
+                    } else {
+                        String::new()
+                    };
+                    return Err(Error::Configuration {
+                        error: format!(
+                            "Function {} (bank {}) can't call function {} (bank {}) with {} bankswitching{}",
+                            caller,
+                            a,
+                            callee,
+                            b,
+                            scheme_name(bankswitching_scheme),
+                            through
+                        ),
+                    });
                 }
             }
-            already_seen.remove(nodex);
         }
     }
+    Ok(())
+}
+
+// Replaces the operand of an instruction line. Cycle counts comments stay aligned, like in
+// AssemblyCode::write
+fn replace_operand(line: &str, operand: &str, new_operand: &str) -> String {
+    let (mnemonic, rest) = line.split_at(line.find(operand).unwrap());
+    let comment = rest[operand.len()..].trim_start_matches(' ');
+    if comment.is_empty() {
+        format!("{}{}\n", mnemonic, new_operand)
+    } else {
+        format!("{}{:19}{}\n", mnemonic, new_operand, comment)
+    }
+}
+
+// E7 and FA RAM have separate read and write ports. Variables are declared at their read address,
+// so stores are rewritten to use the write address (variable name with _W suffix). The code
+// generator may also address reads at `read_offset` bytes after the variable (superchip RAM), so
+// it is removed.
+fn ram_ports_access(
+    code: &str,
+    variables: &HashSet<String>,
+    read_offset: u32,
+) -> Result<String, Error> {
+    let mut ret = String::with_capacity(code.len());
+    for line in code.lines() {
+        let mut words = line.split_whitespace();
+        if let (true, Some(mnemonic), Some(operand)) =
+            (line.starts_with('\t'), words.next(), words.next())
+        {
+            let start = operand.trim_start_matches(['(', '#', '<', '>']);
+            let variable = start
+                .split(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .next()
+                .unwrap_or_default();
+            if variables.contains(variable) {
+                let mnemonic = mnemonic.to_ascii_uppercase();
+                let new_operand = match mnemonic.as_str() {
+                    "STA" | "STX" | "STY" => {
+                        operand.replacen(variable, &format!("{}_W", variable), 1)
+                    }
+                    "INC" | "DEC" | "ASL" | "LSR" | "ROL" | "ROR" => {
+                        return Err(Error::Configuration {
+                            error: format!(
+                                "{} can't be modified in place ({} instruction), since it's in RAM with separate read and write ports",
+                                variable, mnemonic
+                            ),
+                        });
+                    }
+                    _ if read_offset != 0 => {
+                        // variable+offset
+                        let rest = &start[variable.len()..];
+                        let digits: String = rest
+                            .trim_start_matches('+')
+                            .chars()
+                            .take_while(|c| c.is_ascii_digit())
+                            .collect();
+                        let offset = digits.parse::<u32>().unwrap_or_default();
+                        let address = if digits.is_empty() || offset == read_offset {
+                            variable.to_string()
+                        } else if offset > read_offset {
+                            format!("{}+{}", variable, offset - read_offset)
+                        } else {
+                            return Err(Error::Configuration {
+                                error: format!(
+                                    "Can't read {} at {} ({} instruction), since it's in RAM with separate read and write ports, read {} bytes after the variable",
+                                    variable, operand, mnemonic, read_offset
+                                ),
+                            });
+                        };
+                        let end = if digits.is_empty() {
+                            variable.len()
+                        } else {
+                            variable.len() + 1 + digits.len()
+                        };
+                        let prefix = &operand[..operand.len() - start.len()];
+                        format!("{}{}{}", prefix, address, &start[end..])
+                    }
+                    _ => operand.to_string(),
+                };
+                ret.push_str(&replace_operand(line, operand, &new_operand));
+                continue;
+            }
+        }
+        ret.push_str(line);
+        ret.push('\n');
+    }
+    Ok(ret)
+}
+
+// Rewrites the calls made from the given bank according to the placement of the called functions
+fn retarget_calls(
+    code: &str,
+    bankswitching_scheme: &str,
+    bank: u32,
+    placement: &Placement,
+) -> String {
+    let mut ret = String::with_capacity(code.len());
+    for line in code.lines() {
+        if let Some(operand) = called_function(line) {
+            let callee = match operand.strip_prefix("Call") {
+                Some(f) if !placement.contains_key(operand) => f,
+                _ => operand,
+            };
+            if let Some(&callee_bank) = placement.get(callee) {
+                // E0 and E7 bank 0 is always mapped
+                let target =
+                    if callee_bank == bank || (callee_bank == 0 && sliced(bankswitching_scheme)) {
+                        callee.to_string()
+                    } else {
+                        far_call_label(bankswitching_scheme, callee, bank)
+                    };
+                ret.push_str(&replace_operand(line, operand, &target));
+                continue;
+            }
+        }
+        ret.push_str(line);
+        ret.push('\n');
+    }
     ret
 }
 
+// Returns (bank number, offset in the cartridge image, RORG) of the b-th bank in the cartridge image
+fn bank_location(bankswitching_scheme: &str, b: u32, maxbank: u32) -> (u32, u32, u32) {
+    if bankswitching_scheme == "3E" {
+        if b == maxbank {
+            (0, b * 0x0800, 0x1800)
+        } else {
+            (b + 1, b * 0x0800, 0x1000)
+        }
+    } else if bankswitching_scheme == "3EP" {
+        (b, b * 0x0400, 0x1000 + (3 - (b & 3)) * 0x400)
+    } else if bankswitching_scheme == "E0" {
+        if b == maxbank {
+            (0, b * 0x0400, 0x1c00)
+        } else {
+            (
+                b + 1,
+                b * 0x0400,
+                0x1000 + slice_segment("E0", b + 1) * 0x400,
+            )
+        }
+    } else if bankswitching_scheme == "E7" {
+        // The first 512 bytes of the last slice are hidden by the RAM pages
+        if b == maxbank {
+            (0, b * 0x0800 + 0x200, 0x1a00)
+        } else {
+            (b + 1, b * 0x0800, 0x1000)
+        }
+    } else {
+        (b, b * 0x1000, 0x1000)
+    }
+}
+
+fn rom_table(name: &str, v: &Variable) -> Option<String> {
+    let mut code = String::new();
+    match &v.def {
+        VariableDefinition::Array(arr) => {
+            if v.alignment != 1 {
+                code.push_str(&format!("\n\talign {}\n", v.alignment));
+            }
+            code.push_str(name);
+            let mut counter = 0;
+            for vx in arr {
+                match vx {
+                    VariableValue::Int(i) => {
+                        if counter == 0 {
+                            code.push_str("\n\thex ");
+                        }
+                        counter += 1;
+                        if counter == 16 {
+                            counter = 0;
+                        }
+                        code.push_str(&format!("{:02x}", i & 0xff));
+                    }
+                    VariableValue::LowPtr((s, offset)) => {
+                        counter = 0;
+                        if *offset != 0 {
+                            code.push_str(&format!("\n\t.byte <({} + {})", s, offset));
+                        } else {
+                            code.push_str(&format!("\n\t.byte <{}", s));
+                        }
+                    }
+                    VariableValue::HiPtr((s, offset)) => {
+                        counter = 0;
+                        if *offset != 0 {
+                            code.push_str(&format!("\n\t.byte >({} + {})", s, offset));
+                        } else {
+                            code.push_str(&format!("\n\t.byte >{}", s));
+                        }
+                    }
+                }
+            }
+            if v.var_type == VariableType::ShortPtr {
+                for vx in arr {
+                    if counter == 0 {
+                        code.push_str("\n\thex ");
+                    }
+                    counter += 1;
+                    if counter == 16 {
+                        counter = 0;
+                    }
+                    if let VariableValue::Int(i) = vx {
+                        code.push_str(&format!("{:02x}", (i >> 8) & 0xff));
+                    }
+                }
+            }
+            code.push('\n');
+        }
+        VariableDefinition::ArrayOfPointers(arr) => {
+            if v.alignment != 1 {
+                code.push_str(&format!("\n\talign {}\n", v.alignment));
+            }
+            code.push_str(name);
+
+            let mut counter = 0;
+            for i in arr {
+                if counter % 8 == 0 {
+                    code.push_str("\n\t.byte ");
+                }
+                counter += 1;
+                if i.0 == "__address__" {
+                    code.push_str(&format!("${:02x}", i.1 & 0xff));
+                } else if i.1 != 0 {
+                    code.push_str(&format!("<({} + {})", i.0, i.1));
+                } else {
+                    code.push_str(&format!("<{}", i.0));
+                }
+                if counter % 8 != 0 {
+                    code.push_str(", ");
+                }
+            }
+            for i in arr {
+                if counter % 8 == 0 {
+                    code.push_str("\n\t.byte ");
+                }
+                counter += 1;
+                if i.0 == "__address__" {
+                    code.push_str(&format!("${:02x}", i.1 >> 8));
+                } else if i.1 != 0 {
+                    code.push_str(&format!(">({} + {})", i.0, i.1));
+                } else {
+                    code.push_str(&format!(">{}", i.0));
+                }
+                if counter % 8 != 0 && counter < 2 * arr.len() {
+                    code.push_str(", ");
+                }
+            }
+            code.push('\n');
+        }
+        _ => return None,
+    }
+    Some(code)
+}
+
+// Size of a ROM table, alignment excluded
+fn rom_table_size(v: &Variable) -> u32 {
+    match &v.def {
+        VariableDefinition::Array(arr) if v.var_type == VariableType::ShortPtr => {
+            arr.len() as u32 * 2
+        }
+        VariableDefinition::Array(arr) => arr.len() as u32,
+        VariableDefinition::ArrayOfPointers(arr) => arr.len() as u32 * 2,
+        _ => 0,
+    }
+}
+
+// Tables and variables in DPC/DPC+ display memory and DPC+ frequency memory
+fn display_table(name: &str, v: &Variable) -> String {
+    let mut code = String::new();
+    if v.alignment != 1 {
+        code.push_str(&format!("\n\talign {}\n", v.alignment));
+    }
+    if let VariableDefinition::Array(arr) = &v.def {
+        code.push_str(name);
+        let mut counter = 0;
+        for vx in arr {
+            if counter == 0 || counter == 16 {
+                code.push_str("\n\thex ");
+            }
+            counter += 1;
+            if counter == 16 {
+                counter = 0;
+            }
+            if let VariableValue::Int(i) = vx {
+                code.push_str(&format!("{:02x}", i & 0xff));
+            }
+        }
+        code.push('\n');
+    } else if v.size > 1 {
+        let s = match v.var_type {
+            VariableType::CharPtr => 1,
+            VariableType::CharPtrPtr => 2,
+            VariableType::ShortPtr => 2,
+            _ => unreachable!(),
+        };
+        code.push_str(&format!("{:23}\tds {}\n", name, v.size * s));
+    } else {
+        let s = match v.var_type {
+            VariableType::Char => 1,
+            VariableType::Short => 2,
+            VariableType::CharPtr => 2,
+            VariableType::CharPtrPtr => 2,
+            VariableType::ShortPtr => 2,
+        };
+        code.push_str(&format!("{:23}\tds {}\n", name, s));
+    }
+    code
+}
+
+// Builds the content of all banks, with the functions and ROM tables in the bank given by the
+// placement. The code of the functions is post-processed by `rewrite`, given the bank.
+#[allow(clippy::too_many_arguments)]
+fn build_banks(
+    compiler_state: &CompilerState,
+    gstate: &GeneratorState,
+    args: &Args,
+    bankswitching_scheme: &str,
+    maxbank: u32,
+    superchip: bool,
+    placement: &Placement,
+    end_of_memory: u32,
+    far_calls: &[(String, u32, u32)],
+    rewrite: &dyn Fn(&str, u32) -> Result<String, Error>,
+) -> Result<Vec<Bank>, Error> {
+    let mut banks = Vec::new();
+    for b in 0..=maxbank {
+        let (bank, org, rorg) = bank_location(bankswitching_scheme, b, maxbank);
+        let end = if bankswitching_scheme == "3E" {
+            if bank == 0 {
+                0x1ff0
+            } else {
+                0x1800
+            }
+        } else if bankswitching_scheme == "3EP" {
+            if bank == 0 {
+                0x1ff0
+            } else if bank == maxbank {
+                rorg + 0x3ff
+            } else {
+                rorg + 0x400
+            }
+        } else if sliced(bankswitching_scheme) {
+            // Hotspots are at $1FE0-$1FF7 (E0) or $1FE0-$1FEB (E7)
+            if bank == 0 {
+                0x1fe0
+            } else if bankswitching_scheme == "E0" {
+                rorg + 0x400
+            } else {
+                rorg + 0x800
+            }
+        } else {
+            end_of_memory
+        };
+        let mut layout = Bank::new(format!("bank {}", bank), Some(bank), org, rorg, end);
+
+        // Prelude code for each bank
+        debug!("Generating code for bank #{}", bank);
+
+        if bankswitching_scheme == "FA" {
+            layout.push(
+                "CBS RAM+",
+                ItemKind::Startup,
+                "\n\tDS 512, $FF\n".to_string(),
+            );
+        } else if superchip {
+            layout.push(
+                "Superchip RAM",
+                ItemKind::Startup,
+                "\n\tDS 256, $FF\n".to_string(),
+            );
+        } else if bankswitching_scheme == "DPC" || bankswitching_scheme == "DPC+" {
+            layout.push(
+                "DPC data",
+                ItemKind::Startup,
+                "\n\tDS 128, $00\n".to_string(),
+            );
+        }
+
+        if maxbank > 0
+            && bankswitching_scheme != "3E"
+            && bankswitching_scheme != "3EP"
+            && !sliced(bankswitching_scheme)
+        {
+            // Generate trampoline code
+            layout.push(
+                "Trampoline",
+                ItemKind::Startup,
+                "
+;----The following code is the same on all banks----
+Start
+; Ensure that bank 0 is selected
+        LDX #$FF
+        TXS
+
+        lDA #>(Powerup-1)
+        lDY #<(Powerup-1)
+        lDX #0
+BankSwitch
+        BANK_SWITCH_TRAMPOLINE
+;----End of bank-identical code----
+        "
+                .to_string(),
+            );
+        }
+
+        // Generate startup code
+        if bank == 0 {
+            let mut code = String::from(
+                "
+Powerup
+        SEI		; Set the interrupt masking flag in the processor status register.
+        CLD		; Clear the BCD mode flag in the processor status register. 
+        LDX #$FF	
+        TXS
+
+        LDA #0
+.loop	  STA $00,X	
+        DEX
+        CPX #$40	
+        BNE .loop
+        ",
+            );
+
+            if bankswitching_scheme == "3EP" {
+                code.push_str(
+                    "
+        LDA #$81 ; ROM Bank 1 to segment 2
+        STA ROM_SELECT
+        LDA #$42 ; ROM Bank 2 to segment 1
+        STA ROM_SELECT
+        LDA #$03 ; ROM Bank 3 to segment 0
+        STA ROM_SELECT
+        ",
+                );
+            } else if bankswitching_scheme == "E0" {
+                code.push_str(&format!(
+                    "
+        STA ${:04x} ; ROM Bank 1 to segment 0
+        STA ${:04x} ; ROM Bank 2 to segment 1
+        STA ${:04x} ; ROM Bank 3 to segment 2
+        ",
+                    slice_hotspot("E0", 1),
+                    slice_hotspot("E0", 2),
+                    slice_hotspot("E0", 3)
+                ));
+            } else if bankswitching_scheme == "E7" {
+                code.push_str(
+                    "
+        STA $1FE7 ; 1KB RAM to segment 0
+        LDX #0
+.e7loop STA $1000,X ; Clear 1KB RAM and RAM pages
+        STA $1100,X
+        STA $1200,X
+        STA $1300,X
+        STA $1FEB
+        STA $1800,X
+        STA $1FEA
+        STA $1800,X
+        STA $1FE9
+        STA $1800,X
+        STA $1FE8 ; RAM page 0 selected
+        STA $1800,X
+        INX
+        BNE .e7loop
+        STA $1FE0 ; ROM Bank 1 to segment 0
+        ",
+                );
+            }
+
+            code.push_str(
+                "
+        JMP main
+        ",
+            );
+            layout.push("Powerup", ItemKind::Startup, code);
+
+            if sliced(bankswitching_scheme) && !far_calls.is_empty() {
+                // Calls to the other banks map the bank of the called function in its segment
+                let mut code = String::new();
+                let mut functions: Vec<_> = far_calls.iter().map(|c| (&c.0, c.1)).collect();
+                functions.dedup();
+                for (f, bank) in functions {
+                    code.push_str(&format!(
+                        "
+Call{}
+        BIT ${:04x}
+        JMP {}",
+                        f,
+                        slice_hotspot(bankswitching_scheme, bank),
+                        f
+                    ));
+                }
+                code.push('\n');
+                layout.push("Bankswitching", ItemKind::Startup, code);
+            }
+        }
+
+        // Generate included assembler
+        for (i, asm) in compiler_state.included_assembler.iter().enumerate() {
+            let basm = asm.3.unwrap_or_default();
+            debug!("assembler: {} {} {}", i, bank, basm);
+            if bank == basm {
+                let name = if let Some(n) = &asm.1 {
+                    n.as_str()
+                } else {
+                    "Unknown"
+                };
+                layout.push(name, ItemKind::Assembler, asm.0.clone());
+            }
+        }
+
+        // Generate functions code
+        for f in compiler_state.sorted_functions().iter() {
+            if f.1.code.is_some()
+                && !f.1.inline
+                && placement.get(f.0) == Some(&bank)
+                && gstate.functions_actually_in_use.contains(f.0)
+            {
+                debug!("Generating code for function {}", f.0);
+
+                let mut code = format!("\n{}\tSUBROUTINE\n", f.0).into_bytes();
+                gstate
+                    .functions_code
+                    .get(f.0)
+                    .unwrap()
+                    .write(&mut code, args.insert_code)?;
+                code.extend_from_slice(b"\tRTS\n");
+                let code = String::from_utf8_lossy(&code);
+                let code = rewrite(&code, bank)?;
+                layout.push(f.0, ItemKind::Function, code);
+            }
+        }
+
+        // Generate ROM tables
+        for v in compiler_state.sorted_variables().iter() {
+            if let VariableMemory::ROM(_) = v.1.memory {
+                if placement.get(v.0) == Some(&bank) {
+                    if let Some(code) = rom_table(v.0, v.1) {
+                        layout.push(v.0, ItemKind::Table, code);
+                    }
+                }
+            }
+        }
+        banks.push(layout);
+    }
+
+    if bankswitching_scheme == "DPC" {
+        let mut layout = Bank::new("DPC display memory".to_string(), None, 0x2000, 0, 0x7ff);
+        for v in compiler_state.sorted_variables().iter() {
+            if let (VariableMemory::Display, VariableDefinition::Array(_)) = (&v.1.memory, &v.1.def)
+            {
+                layout.push(v.0, ItemKind::Table, display_table(v.0, v.1));
+            }
+        }
+        banks.push(layout);
+    }
+
+    if bankswitching_scheme == "DPC+" {
+        let mut layout = Bank::new("DPC+ display memory".to_string(), None, 0x6000, 0, 0x1000);
+        for v in compiler_state.sorted_variables().iter() {
+            if let VariableMemory::Display = v.1.memory {
+                layout.push(v.0, ItemKind::Table, display_table(v.0, v.1));
+            }
+        }
+        banks.push(layout);
+        let mut layout = Bank::new("DPC+ frequency memory".to_string(), None, 0x7000, 0, 0x3ff);
+        for v in compiler_state.sorted_variables().iter() {
+            if let (VariableMemory::Frequency, VariableDefinition::Array(_)) =
+                (&v.1.memory, &v.1.def)
+            {
+                layout.push(v.0, ItemKind::Table, display_table(v.0, v.1));
+            }
+        }
+        banks.push(layout);
+    }
+    Ok(banks)
+}
+
+/// Cartridge builder given to cc6502's compile(), with the options set by set_options
 pub fn build_cartridge(
     compiler_state: &CompilerState,
     writer: &mut dyn Write,
     args: &Args,
 ) -> Result<(), Error> {
+    let options = OPTIONS.with(|o| o.borrow().clone());
+    let info = build(compiler_state, writer, args, &options)?;
+    INFO.with(|i| *i.borrow_mut() = info);
+    Ok(())
+}
+
+// Builds the cartridge, returning the information needed to write the debugging files
+fn build(
+    compiler_state: &CompilerState,
+    writer: &mut dyn Write,
+    args: &Args,
+    options: &BuildOptions,
+) -> Result<CartridgeInfo, Error> {
     let mut superchip = false;
     let mut bankswitching_scheme = "4K";
-    let mut banked_functions = HashSet::<String>::new();
     // Try to figure out what is the bankswitching method
 
     // Let's identitfy superchip
@@ -94,63 +1211,265 @@ pub fn build_cartridge(
         maxbank = 1;
     }
 
-    // Are we producing a DPC cartridge ?
-    if compiler_state.context.get_macro("__DPC__").is_some() {
-        bankswitching_scheme = "DPC";
-        if maxbank > 1 {
-            return Err(Error::Configuration {
-                error: "DPC chip only works with 8KB ROM".to_string(),
-            });
+    // Scheme selected by a header, which can be forced from the command line
+    let selected = [
+        ("__DPC__", "DPC"),
+        ("__DPCPLUS__", "DPC+"),
+        ("__3E__", "3E"),
+        ("__3E_PLUS__", "3EP"),
+        ("__E0__", "E0"),
+        ("__E7__", "E7"),
+        ("__DF__", if superchip { "DFSC" } else { "DF" }),
+        ("__BF__", if superchip { "BFSC" } else { "BF" }),
+        ("__FA__", "FA"),
+    ]
+    .into_iter()
+    .find(|(m, _)| compiler_state.context.get_macro(*m).is_some())
+    .map(|(_, s)| s);
+    let forced = match &options.bankswitching {
+        Some(name) => Some(parse_scheme(name)?),
+        None => None,
+    };
+    let scheme = match (forced, selected) {
+        (Some(f), Some(h)) => {
+            if f != h && f != format!("{}SC", h.trim_end_matches("SC")) {
+                return Err(Error::Configuration {
+                    error: format!(
+                        "{} bankswitching can't be forced, since the source code selects {} bankswitching",
+                        scheme_name(f),
+                        scheme_name(h)
+                    ),
+                });
+            }
+            Some(f)
         }
-        maxbank = 1;
-    } else if compiler_state.context.get_macro("__DPCPLUS__").is_some() {
-        bankswitching_scheme = "DPC+";
-        if maxbank > 5 {
+        (Some(f), None) => Some(f),
+        (None, h) => h,
+    };
+    // Standard scheme (or FA for 12KB) selected by the ROM size
+    let scheme = match (scheme, options.rom_size) {
+        (None, Some(size)) => Some(
+            ["4K", "F8", "FA", "F6", "F4", "EF", "DF", "BF"]
+                .into_iter()
+                .find(|s| rom_size(s) == Some(size))
+                .map(|s| match (s, superchip) {
+                    ("F8", true) => "F8S",
+                    ("F6", true) => "F6S",
+                    ("F4", true) => "F4S",
+                    ("EF", true) => "EFSC",
+                    ("DF", true) => "DFSC",
+                    ("BF", true) => "BFSC",
+                    _ => s,
+                })
+                .ok_or(Error::Configuration {
+                    error: format!("No bankswitching scheme with a {}KB ROM", size),
+                })?,
+        ),
+        (scheme, _) => scheme,
+    };
+    if let (Some(scheme), Some(size)) = (scheme, options.rom_size) {
+        if rom_size(scheme).is_some_and(|s| s != size) {
             return Err(Error::Configuration {
-                error: "DPC+ framework only works with 32KB ROM".to_string(),
+                error: format!(
+                    "{} cartridges have a {}KB ROM, not {}KB",
+                    scheme_name(scheme),
+                    rom_size(scheme).unwrap_or_default(),
+                    size
+                ),
             });
         }
-        maxbank = 5;
-    } else if compiler_state.context.get_macro("__3E__").is_some() {
-        bankswitching_scheme = "3E";
-        maxbank = ((maxbank / 8) + 1) * 8 - 1;
-    } else if compiler_state.context.get_macro("__3E_PLUS__").is_some() {
-        bankswitching_scheme = "3EP";
-        maxbank = ((maxbank / 4) + 1) * 4 - 1;
     }
 
-    let bankswitching_address: u32;
-    if bankswitching_scheme == "DPC+" {
-        bankswitching_address = 0x1FF6;
-    } else if bankswitching_scheme != "3E" && bankswitching_scheme != "3EP" {
-        if maxbank > 0 {
-            bankswitching_address = match maxbank {
-                1 => {
-                    if bankswitching_scheme == "4K" {
-                        bankswitching_scheme = if superchip { "F8S" } else { "F8" };
-                    }
-                    0x1FF8
-                }
-                2 | 3 => {
-                    if bankswitching_scheme == "4K" {
-                        bankswitching_scheme = if superchip { "F6S" } else { "F6" };
-                    }
-                    maxbank = 3;
-                    0x1FF6
-                }
-                4..=7 => {
-                    if bankswitching_scheme == "4K" {
-                        bankswitching_scheme = if superchip { "F4S" } else { "F4" };
-                    }
-                    maxbank = 7;
-                    0x1FF4
+    match scheme {
+        // Are we producing a DPC cartridge ?
+        Some("DPC") => {
+            bankswitching_scheme = "DPC";
+            if maxbank > 1 {
+                return Err(Error::Configuration {
+                    error: "DPC chip only works with 8KB ROM".to_string(),
+                });
+            }
+            maxbank = 1;
+        }
+        Some("DPC+") => {
+            bankswitching_scheme = "DPC+";
+            if maxbank > 5 {
+                return Err(Error::Configuration {
+                    error: "DPC+ framework only works with 32KB ROM".to_string(),
+                });
+            }
+            maxbank = 5;
+        }
+        Some("3E") => {
+            bankswitching_scheme = "3E";
+            maxbank = match options.rom_size {
+                Some(size) if size % 2 != 0 || size / 2 <= maxbank => {
+                    return Err(Error::Configuration {
+                        error: format!(
+                            "3E ROM size must be a multiple of 2KB, and at least {}KB",
+                            (maxbank + 1) * 2
+                        ),
+                    });
                 }
-                _ => {
-                    return Err(Error::Unimplemented {
-                        feature: "Bankswitching scheme not implemented",
+                Some(size) => size / 2 - 1,
+                None => ((maxbank / 8) + 1) * 8 - 1,
+            };
+        }
+        Some("3EP") => {
+            bankswitching_scheme = "3EP";
+            maxbank = match options.rom_size {
+                Some(size) if size <= maxbank => {
+                    return Err(Error::Configuration {
+                        error: format!("3E+ ROM size must be at least {}KB", maxbank + 1),
                     });
                 }
+                Some(size) => size - 1,
+                None => ((maxbank / 4) + 1) * 4 - 1,
             };
+        }
+        Some("E0") => {
+            bankswitching_scheme = "E0";
+            if superchip {
+                return Err(Error::Configuration {
+                    error: "Superchip is not compatible with E0 bankswitching".to_string(),
+                });
+            }
+            if maxbank > 7 {
+                return Err(Error::Configuration {
+                    error: "E0 bankswitching only works with 8KB ROM (bank0 to bank7)".to_string(),
+                });
+            }
+            maxbank = 7;
+        }
+        Some("E7") => {
+            bankswitching_scheme = "E7";
+            if superchip {
+                return Err(Error::Configuration {
+                    error: "Superchip is not compatible with E7 bankswitching".to_string(),
+                });
+            }
+            if maxbank > 7 {
+                return Err(Error::Configuration {
+                    error: "E7 bankswitching only works with 16KB ROM (bank0 to bank7)".to_string(),
+                });
+            }
+            maxbank = 7;
+        }
+        Some("FA") => {
+            bankswitching_scheme = "FA";
+            if maxbank > 2 {
+                return Err(Error::Configuration {
+                    error: "FA (CBS RAM+) bankswitching only works with 12KB ROM (bank0 to bank2)"
+                        .to_string(),
+                });
+            }
+            maxbank = 2;
+        }
+        Some(scheme) => {
+            // Standard scheme
+            let (last, with_superchip) = standard_scheme(scheme).unwrap_or_default();
+            if maxbank > last {
+                return Err(Error::Configuration {
+                    error: format!(
+                        "{} bankswitching only works with {}KB ROM (bank0 to bank{})",
+                        scheme_name(scheme),
+                        (last + 1) * 4,
+                        last
+                    ),
+                });
+            }
+            if superchip && !with_superchip {
+                return Err(Error::Configuration {
+                    error: format!(
+                        "Superchip variables can't be used with {} bankswitching",
+                        scheme_name(scheme)
+                    ),
+                });
+            }
+            superchip = with_superchip;
+            bankswitching_scheme = scheme;
+            maxbank = last;
+        }
+        None => (),
+    }
+
+    // 3E and 3E+ banks are selected through the ROM_SELECT register of their header
+    let header = match bankswitching_scheme {
+        "3E" => Some("3e.h"),
+        "3EP" => Some("3eplus.h"),
+        _ => None,
+    };
+    if let Some(header) = header {
+        if !compiler_state.variables.contains_key("ROM_SELECT") {
+            return Err(Error::Configuration {
+                error: format!(
+                    "{} bankswitching requires the \"{}\" header (ROM_SELECT is not declared)",
+                    scheme_name(bankswitching_scheme),
+                    header
+                ),
+            });
+        }
+    }
+
+    // Variables that need a special cartridge hardware
+    for v in compiler_state.sorted_variables().iter() {
+        let required = match v.1.memory {
+            VariableMemory::Display | VariableMemory::Frequency
+                if bankswitching_scheme != "DPC" && bankswitching_scheme != "DPC+" =>
+            {
+                Some("DPC or DPC+")
+            }
+            VariableMemory::MemoryOnChip(_)
+                if v.1.def == VariableDefinition::None
+                    && bankswitching_scheme != "3E"
+                    && bankswitching_scheme != "3EP"
+                    && bankswitching_scheme != "E7" =>
+            {
+                Some("3E, 3E+ or E7")
+            }
+            _ => None,
+        };
+        if let Some(required) = required {
+            return Err(Error::Configuration {
+                error: format!(
+                    "Variable {} requires {} bankswitching (not {})",
+                    v.0,
+                    required,
+                    scheme_name(bankswitching_scheme)
+                ),
+            });
+        }
+    }
+
+    if options.auto_bank
+        && (bankswitching_scheme == "3E"
+            || bankswitching_scheme == "3EP"
+            || sliced(bankswitching_scheme))
+    {
+        return Err(Error::Configuration {
+            error: format!(
+                "Automatic bank placement is not available with {} bankswitching",
+                scheme_name(bankswitching_scheme)
+            ),
+        });
+    }
+
+    let mut bankswitching_address: u32;
+    if bankswitching_scheme == "DPC+" {
+        bankswitching_address = 0x1FF6;
+    } else if bankswitching_scheme == "FA" {
+        bankswitching_address = 0x1FF8;
+    } else if bankswitching_scheme != "3E"
+        && bankswitching_scheme != "3EP"
+        && !sliced(bankswitching_scheme)
+    {
+        if maxbank > 0 {
+            let (scheme, m, address) = standard_bankswitching(maxbank, superchip)?;
+            if bankswitching_scheme == "4K" {
+                bankswitching_scheme = scheme;
+            }
+            maxbank = m;
+            bankswitching_address = address;
         } else {
             bankswitching_address = 0;
         }
@@ -159,10 +1478,26 @@ pub fn build_cartridge(
     }
 
     // Start generation
+    let header = SharedBuffer::default();
+    let mut header_writer = header.clone();
+    // C source lines are inserted as comments for the debug information. The code generator
+    // can't insert the last line of the source code if it includes a statement.
+    let insert_code = args.insert_code
+        || (args.debug
+            && compiler_state
+                .preprocessed_utf8
+                .lines()
+                .last()
+                .is_none_or(|l| l.trim().chars().all(|c| c == '}' || c == ';')));
+    if args.debug && !insert_code {
+        eprintln!(
+            "Warning: no line number debug information (code on the last line of the source)"
+        );
+    }
     let mut gstate = GeneratorState::new(
         compiler_state,
-        writer,
-        args.insert_code,
+        &mut header_writer,
+        insert_code,
         args.warnings.clone(),
         bankswitching_scheme,
     );
@@ -194,11 +1529,16 @@ pub fn build_cartridge(
         }
     }
 
-    let mut banked_function_address = 0;
-
     for f in compiler_state.sorted_functions().iter() {
-        if f.1.code.is_some() {
-            gstate.current_bank = f.1.bank;
+        if let Some(code) = &f.1.code {
+            // Calls are generated as if made from bank 0, and retargeted to the bankswitching
+            // stubs once the functions are placed (except for 3E and 3E+, which switch banks in
+            // the code)
+            gstate.current_bank = if bankswitching_scheme == "3E" || bankswitching_scheme == "3EP" {
+                f.1.bank
+            } else {
+                0
+            };
             gstate.local_label_counter_for = 0;
             gstate.local_label_counter_if = 0;
 
@@ -206,7 +1546,7 @@ pub fn build_cartridge(
                 .functions_code
                 .insert(f.0.clone(), AssemblyCode::new());
             gstate.current_function = Some(f.0.clone());
-            gstate.generate_statement(f.1.code.as_ref().unwrap())?;
+            gstate.generate_statement(code)?;
             gstate.current_function = None;
 
             if args.optimization_level > 0 {
@@ -218,17 +1558,16 @@ pub fn build_cartridge(
 
     gstate.compute_functions_actually_in_use()?;
 
+    // Declared banks of functions and ROM tables
+    let mut placement = Placement::new();
     for f in compiler_state.sorted_functions().iter() {
-        if f.1.code.is_some() && f.1.bank == 0 {
-            // Compute banked functions
-            if let Some(called_functions) = gstate.functions_call_tree.get(f.0) {
-                for i in called_functions {
-                    let fx = compiler_state.functions.get(i).unwrap();
-                    if fx.bank != 0 {
-                        banked_functions.insert(i.clone());
-                    }
-                }
-            }
+        if f.1.code.is_some() {
+            placement.insert(f.0.clone(), f.1.bank);
+        }
+    }
+    for v in compiler_state.sorted_variables().iter() {
+        if let VariableMemory::ROM(bank) = v.1.memory {
+            placement.insert(v.0.clone(), bank);
         }
     }
 
@@ -266,86 +1605,272 @@ pub fn build_cartridge(
         }
     }
 
-    // Compute in the call tree the level of each function
-    let mut function_levels: Vec<Vec<String>> = Vec::new();
+    // Recursive functions. Functions calling each other are represented in the call tree by the
+    // first of them, so that their local variables can be allocated.
+    let mut reached = HashMap::<String, HashSet<String>>::new();
     for f in compiler_state.sorted_functions().iter() {
-        let lev = if f.0 == "main" {
-            Some(0)
-        } else {
-            let mut already_seen = HashSet::new();
-            compute_function_level(
-                f.0,
-                "main",
-                1,
-                &gstate.functions_call_tree,
-                &mut already_seen,
-            )
-        };
-        if let Some(level) = lev {
-            let l = function_levels.get_mut(level);
-            if let Some(a) = l {
-                a.push(f.0.clone())
-            } else {
-                function_levels.resize(level + 1, Vec::new());
-                function_levels[level].push(f.0.clone());
+        if gstate.functions_actually_in_use.contains(f.0) {
+            reached.insert(f.0.clone(), reachable(&gstate.functions_call_tree, f.0));
+        }
+    }
+    let mut representative = HashMap::<String, String>::new();
+    let mut recursive = HashSet::<String>::new();
+    for f in compiler_state.sorted_functions().iter() {
+        if !reached.get(f.0).is_some_and(|r| r.contains(f.0)) || recursive.contains(f.0) {
+            continue;
+        }
+        let mut cycle: Vec<&String> = reached[f.0]
+            .iter()
+            .filter(|g| reached.get(*g).is_some_and(|r| r.contains(f.0)))
+            .collect();
+        cycle.sort();
+        if let Some(g) = cycle
+            .iter()
+            .find(|g| !options.allow_recursion.contains(**g))
+        {
+            return Err(Error::Configuration {
+                error: if cycle.len() == 1 {
+                    format!(
+                        "Function {} is recursive. Its local variables would be overwritten (use --allow-recursion {} to give them a dedicated storage)",
+                        g, g
+                    )
+                } else {
+                    let names: Vec<&str> = cycle.iter().map(|g| g.as_str()).collect();
+                    format!(
+                        "Functions {} call each other recursively. Their local variables would be overwritten (use --allow-recursion {} to give them a dedicated storage)",
+                        names.join(", "),
+                        names.join(",")
+                    )
+                },
+            });
+        }
+        for g in &cycle {
+            representative.insert(g.to_string(), cycle[0].clone());
+            recursive.insert(g.to_string());
+        }
+    }
+    let rep = |f: &str| representative.get(f).map_or(f, |r| r.as_str()).to_string();
+    let mut call_tree = HashMap::<String, Vec<String>>::new();
+    for (caller, called) in &gstate.functions_call_tree {
+        let r = rep(caller);
+        for callee in called {
+            let c = rep(callee);
+            let calls = call_tree.entry(r.clone()).or_default();
+            if c != r && !calls.contains(&c) {
+                calls.push(c);
             }
         }
     }
 
-    for (level, l) in function_levels.into_iter().enumerate() {
-        let mut maxbsize = 0;
-        let mut bsize = 0;
-        let mut ft = true;
-        for fx in l {
-            if let Some(f) = compiler_state.functions.get(&fx) {
-                if gstate.functions_actually_in_use.get(&fx).is_some()
-                    && !f.local_variables.is_empty()
-                {
-                    if ft {
-                        gstate.write(&format!("\nLOCAL_VARIABLES_{}\n\n", level))?;
-                        ft = false;
-                    }
-                    bsize = 0;
-                    gstate.write(&format!("\tORG LOCAL_VARIABLES_{}\n", level))?;
-                    for vx in &f.local_variables {
-                        if let Some(v) = compiler_state.variables.get(vx) {
-                            if v.memory == VariableMemory::Zeropage
-                                && v.def == VariableDefinition::None
-                            {
-                                if v.size > 1 {
-                                    let s = match v.var_type {
-                                        VariableType::CharPtr => 1,
-                                        VariableType::CharPtrPtr => 2,
-                                        VariableType::ShortPtr => 2,
-                                        _ => unreachable!(),
-                                    };
-                                    gstate.write(&format!("{:23}\tds {}\n", vx, v.size * s))?;
-                                    bsize += v.size * s;
-                                } else {
-                                    let s = match v.var_type {
-                                        VariableType::Char => 1,
-                                        VariableType::Short => 2,
-                                        VariableType::CharPtr => 2,
-                                        VariableType::CharPtrPtr => 2,
-                                        VariableType::ShortPtr => 2,
-                                    };
-                                    gstate.write(&format!("{:23}\tds {}\n", vx, s))?;
-                                    bsize += s;
-                                }
-                            }
-                        }
+    // Functions that can run while any other function is running: recursive functions, and
+    // interrupt routines and the functions they call. Their local variables get a dedicated
+    // storage.
+    let mut dedicated = recursive.clone();
+    let mut entry_points = vec![("main", reached.get("main").cloned().unwrap_or_default())];
+    for f in compiler_state.sorted_functions().iter() {
+        if f.1.interrupt && gstate.functions_actually_in_use.contains(f.0) {
+            let mut r = reached[f.0].clone();
+            r.insert(f.0.clone());
+            for (entry, other) in &entry_points {
+                let mut shared: Vec<_> = r
+                    .intersection(other)
+                    .filter(|g| {
+                        compiler_state
+                            .functions
+                            .get(*g)
+                            .is_some_and(|g| !g.local_variables.is_empty())
+                    })
+                    .collect();
+                shared.sort();
+                if let Some(g) = shared.first() {
+                    return Err(Error::Configuration {
+                        error: format!(
+                            "Function {} is called from {} and from interrupt routine {}. Its local variables would be overwritten",
+                            g, entry, f.0
+                        ),
+                    });
+                }
+            }
+            dedicated.extend(r.iter().cloned());
+            entry_points.push((f.0, r));
+        }
+    }
+    let zeropage_start = zeropage_bytes;
+    for f in compiler_state.sorted_functions().iter() {
+        if dedicated.contains(f.0) && gstate.functions_actually_in_use.contains(f.0) {
+            for vx in &f.1.local_variables {
+                if let Some(v) = compiler_state.variables.get(vx) {
+                    if v.memory == VariableMemory::Zeropage && v.def == VariableDefinition::None {
+                        gstate.write(&format!("{:23}\tds {}\n", vx, variable_size(v)))?;
+                        zeropage_bytes += variable_size(v) as usize;
                     }
-                    if bsize > maxbsize {
-                        maxbsize = bsize;
+                }
+            }
+        }
+    }
+    if args.verbose && zeropage_bytes > zeropage_start {
+        println!(
+            "Dedicated storage of the local variables of recursive functions and interrupt routines: {} bytes",
+            zeropage_bytes - zeropage_start
+        );
+    }
+
+    // Size of the local variables of each function (in the call tree, functions calling each
+    // other are represented by the first of them, and have a dedicated storage)
+    let mut frame_sizes = HashMap::<String, u32>::new();
+    for f in compiler_state.sorted_functions().iter() {
+        if gstate.functions_actually_in_use.contains(f.0) && !dedicated.contains(f.0) {
+            let size =
+                f.1.local_variables
+                    .iter()
+                    .filter_map(|vx| compiler_state.variables.get(vx))
+                    .filter(|v| {
+                        v.memory == VariableMemory::Zeropage && v.def == VariableDefinition::None
+                    })
+                    .map(variable_size)
+                    .sum();
+            frame_sizes.insert(f.0.clone(), size);
+        }
+    }
+    let frames = allocate_local_variables(&call_tree, &rep("main"), &frame_sizes);
+    let frame = |f: &str| frames.get(&rep(f)).copied();
+    let levels: HashMap<String, usize> = compiler_state
+        .sorted_functions()
+        .iter()
+        .filter_map(|f| frame(f.0).map(|(level, _)| (f.0.clone(), level)))
+        .collect();
+
+    // Functions and their local variables sharing the LOCAL_VARIABLES memory
+    let mut overlays: LocalOverlays = Vec::new();
+    let mut local_bytes = 0;
+    // Same allocation by call tree level (as in previous versions), to show the saved bytes
+    let mut level_sizes = Vec::<u32>::new();
+    for f in compiler_state.sorted_functions().iter() {
+        let (Some(&size), Some((level, offset))) = (frame_sizes.get(f.0), frame(f.0)) else {
+            continue;
+        };
+        if size > 0 {
+            overlays.push((offset, f.0.clone(), f.1.local_variables.clone()));
+            local_bytes = local_bytes.max(offset + size);
+            if level_sizes.len() <= level {
+                level_sizes.resize(level + 1, 0);
+            }
+            level_sizes[level] = level_sizes[level].max(size);
+        }
+    }
+    overlays.sort();
+    if !overlays.is_empty() {
+        gstate.write("\nLOCAL_VARIABLES\n\n")?;
+        for (offset, _, variables) in &overlays {
+            if *offset == 0 {
+                gstate.write("\tORG LOCAL_VARIABLES\n")?;
+            } else {
+                gstate.write(&format!("\tORG LOCAL_VARIABLES + {}\n", offset))?;
+            }
+            for vx in variables {
+                if let Some(v) = compiler_state.variables.get(vx) {
+                    if v.memory == VariableMemory::Zeropage && v.def == VariableDefinition::None {
+                        gstate.write(&format!("{:23}\tds {}\n", vx, variable_size(v)))?;
                     }
                 }
             }
         }
-        if maxbsize != bsize {
-            gstate.write(&format!("\tORG LOCAL_VARIABLES_{} + {}\n", level, maxbsize))?;
+        gstate.write(&format!("\tORG LOCAL_VARIABLES + {}\n", local_bytes))?;
+    }
+    // Zeropage RAM used by the variables that may be live in each function
+    let locals_start = zeropage_bytes as u32;
+    zeropage_bytes += local_bytes as usize;
+    if args.verbose {
+        let level_bytes: u32 = level_sizes.iter().sum();
+        println!(
+            "Local variables: {} bytes ({} bytes saved by sharing memory between functions that never run at the same time)",
+            local_bytes,
+            level_bytes - local_bytes
+        );
+    }
+
+    // Local variables in extended RAM (superchip, 3E, 3E+ or E7 RAM) are declared in functions
+    // as const variables. They are named function_scope_variable but are not in the local
+    // variables of the function. They share memory the same way, in each RAM.
+    let mut extended_locals = Vec::<(VariableMemory, String, String)>::new();
+    for v in compiler_state.sorted_variables().iter() {
+        if !matches!(
+            v.1.memory,
+            VariableMemory::Superchip | VariableMemory::MemoryOnChip(_)
+        ) || v.1.def != VariableDefinition::None
+        {
+            continue;
+        }
+        let owner = compiler_state.sorted_functions().into_iter().find(|f| {
+            frame_sizes.contains_key(f.0)
+                && frame(f.0).is_some()
+                && local_variable_c_name(f.0, v.0).is_some_and(|(_, parameter)| !parameter)
+        });
+        if let Some(f) = owner {
+            extended_locals.push((v.1.memory, f.0.clone(), v.0.clone()));
+        }
+    }
+    let mut memories: Vec<VariableMemory> = Vec::new();
+    for (memory, _, _) in &extended_locals {
+        if !memories.contains(memory) {
+            memories.push(*memory);
+        }
+    }
+    let mut overlays = vec![(VariableMemory::Zeropage, overlays)];
+    for memory in memories {
+        let mut variables = HashMap::<String, Vec<String>>::new();
+        for (m, f, vx) in &extended_locals {
+            if *m == memory {
+                variables.entry(f.clone()).or_default().push(vx.clone());
+            }
         }
-        zeropage_bytes += maxbsize;
+        let sizes = variables
+            .iter()
+            .map(|(f, vars)| {
+                let size = vars
+                    .iter()
+                    .map(|vx| variable_size(&compiler_state.variables[vx]))
+                    .sum();
+                (f.clone(), size)
+            })
+            .collect();
+        let frames = allocate_local_variables(&call_tree, &rep("main"), &sizes);
+        let mut functions: LocalOverlays = variables
+            .into_iter()
+            .map(|(f, vars)| (frames[&f].1, f, vars))
+            .collect();
+        functions.sort();
+        overlays.push((memory, functions));
     }
+    let overlaid: HashSet<&String> = extended_locals.iter().map(|(_, _, vx)| vx).collect();
+    let write_extended_locals = |gstate: &mut GeneratorState,
+                                 memory: VariableMemory,
+                                 write_offset: Option<u32>,
+                                 ram_ports: &mut HashSet<String>|
+     -> Result<usize, Error> {
+        let Some((_, functions)) = overlays.iter().find(|(m, _)| *m == memory) else {
+            return Ok(0);
+        };
+        let label = local_variables_label(memory, bankswitching_scheme);
+        let size = write_local_variables(
+            gstate,
+            compiler_state,
+            &label,
+            functions,
+            write_offset,
+            ram_ports,
+        )?;
+        if args.verbose {
+            let names: Vec<&str> = functions.iter().map(|(_, f, _)| f.as_str()).collect();
+            println!(
+                " - {} ({} bytes shared by the local variables of {})",
+                label,
+                size,
+                names.join(", ")
+            );
+        }
+        Ok(size)
+    };
 
     if args.verbose {
         println!("Atari 2600 zeropage RAM usage: {}/128", zeropage_bytes);
@@ -356,7 +1881,54 @@ pub fn build_cartridge(
         });
     }
 
-    if superchip {
+    // RAM with separate read and write ports, addressed in the code with the read address
+    let mut ram_ports = HashSet::new();
+    let mut ram_read_offset = 0;
+
+    if bankswitching_scheme == "FA" {
+        // The superchip variables go to the 256 bytes of CBS RAM+. The generated code reads
+        // superchip variables 128 bytes after their write address, so it has to be fixed.
+        if args.verbose {
+            println!("CBS RAM+ : 0x1100 onwards (written at 0x1000)");
+        }
+        let mut filled = 0;
+        gstate.write("\n\tSEG.U SUPERVARS\n\tORG $1100\n\tRORG $1100\n")?;
+        for v in compiler_state.sorted_variables().iter() {
+            if v.1.memory == VariableMemory::Superchip
+                && v.1.def == VariableDefinition::None
+                && !overlaid.contains(v.0)
+            {
+                let sx = variable_size(v.1) as usize;
+                filled += sx;
+                if filled > 256 {
+                    return Err(Error::Configuration {
+                        error: "Memory full. CBS RAM+ is limited to 256 bytes".to_string(),
+                    });
+                }
+                gstate.write(&format!("{:23}\tds {}\n", v.0, sx))?;
+                gstate.write(&format!("{:23}\t= {} - $100\n", format!("{}_W", v.0), v.0))?;
+                ram_ports.insert(v.0.clone());
+                if args.verbose {
+                    println!(" - {} ({} byte{})", v.0, sx, if sx > 1 { "s" } else { "" });
+                }
+            }
+        }
+        filled += write_extended_locals(
+            &mut gstate,
+            VariableMemory::Superchip,
+            Some(0x100),
+            &mut ram_ports,
+        )?;
+        if filled > 256 {
+            return Err(Error::Configuration {
+                error: "Memory full. CBS RAM+ is limited to 256 bytes".to_string(),
+            });
+        }
+        ram_read_offset = 0x80;
+        if args.verbose {
+            println!("CBS RAM+ usage: {}/256", filled);
+        }
+    } else if superchip {
         if args.verbose {
             println!("Superchip RAM : 0x1000 onwards");
         }
@@ -364,24 +1936,11 @@ pub fn build_cartridge(
         gstate.write("\n\tSEG.U SUPERVARS\n\tORG $1000\n\tRORG $1000\n")?;
         // Superchip variables
         for v in compiler_state.sorted_variables().iter() {
-            if v.1.memory == VariableMemory::Superchip && v.1.def == VariableDefinition::None {
-                let sx = if v.1.size > 1 {
-                    let s = match v.1.var_type {
-                        VariableType::CharPtr => 1,
-                        VariableType::CharPtrPtr => 2,
-                        VariableType::ShortPtr => 2,
-                        _ => unreachable!(),
-                    };
-                    v.1.size * s
-                } else {
-                    match v.1.var_type {
-                        VariableType::Char => 1,
-                        VariableType::Short => 2,
-                        VariableType::CharPtr => 2,
-                        VariableType::CharPtrPtr => 2,
-                        VariableType::ShortPtr => 2,
-                    }
-                };
+            if v.1.memory == VariableMemory::Superchip
+                && v.1.def == VariableDefinition::None
+                && !overlaid.contains(v.0)
+            {
+                let sx = variable_size(v.1) as usize;
                 filled += sx;
                 if filled > 128 {
                     return Err(Error::Configuration {
@@ -394,6 +1953,13 @@ pub fn build_cartridge(
                 }
             }
         }
+        filled +=
+            write_extended_locals(&mut gstate, VariableMemory::Superchip, None, &mut ram_ports)?;
+        if filled > 128 {
+            return Err(Error::Configuration {
+                error: "Memory full. Superchip RAM is limited to 128 bytes".to_string(),
+            });
+        }
         if args.verbose {
             println!("Superchip RAM usage: {}/128", filled);
         }
@@ -403,39 +1969,24 @@ pub fn build_cartridge(
     if bankswitching_scheme == "3E" {
         for bank in 1..=512 {
             // Max 512ko
-            let mut first = true;
             let mut filled = 0;
+            if compiler_state.variables.values().any(|v| {
+                v.memory == VariableMemory::MemoryOnChip(bank) && v.def == VariableDefinition::None
+            }) {
+                if args.verbose {
+                    println!("Bank #{bank} - 3E RAM : 0x1000 onwards");
+                }
+                gstate.write(&format!(
+                    "\n\tSEG.U RAM_3E_{}\n\tORG $1000\n\tRORG $1000\n",
+                    bank
+                ))?;
+            }
             for v in compiler_state.sorted_variables().iter() {
                 if v.1.memory == VariableMemory::MemoryOnChip(bank)
                     && v.1.def == VariableDefinition::None
+                    && !overlaid.contains(v.0)
                 {
-                    if first {
-                        first = false;
-                        if args.verbose {
-                            println!("Bank #{bank} - 3E RAM : 0x1000 onwards");
-                        }
-                        gstate.write(&format!(
-                            "\n\tSEG.U RAM_3E_{}\n\tORG $1000\n\tRORG $1000\n",
-                            bank
-                        ))?;
-                    }
-                    let sx = if v.1.size > 1 {
-                        let s = match v.1.var_type {
-                            VariableType::CharPtr => 1,
-                            VariableType::CharPtrPtr => 2,
-                            VariableType::ShortPtr => 2,
-                            _ => unreachable!(),
-                        };
-                        v.1.size * s
-                    } else {
-                        match v.1.var_type {
-                            VariableType::Char => 1,
-                            VariableType::Short => 2,
-                            VariableType::CharPtr => 2,
-                            VariableType::CharPtrPtr => 2,
-                            VariableType::ShortPtr => 2,
-                        }
-                    };
+                    let sx = variable_size(v.1) as usize;
                     filled += sx;
                     if filled > 1024 {
                         return Err(Error::Configuration {
@@ -449,6 +2000,17 @@ pub fn build_cartridge(
                     }
                 }
             }
+            filled += write_extended_locals(
+                &mut gstate,
+                VariableMemory::MemoryOnChip(bank),
+                None,
+                &mut ram_ports,
+            )?;
+            if filled > 1024 {
+                return Err(Error::Configuration {
+                    error: "Memory full. 3E RAM is limited to 1024 bytes per bank".to_string(),
+                });
+            }
         }
     }
 
@@ -456,41 +2018,26 @@ pub fn build_cartridge(
     if bankswitching_scheme == "3EP" {
         for bank in 0..64 {
             // Max 32ko
-            let mut first = true;
             let mut filled = 0;
+            if compiler_state.variables.values().any(|v| {
+                v.memory == VariableMemory::MemoryOnChip(bank) && v.def == VariableDefinition::None
+            }) {
+                let segment = 3 - (bank & 3);
+                let address = 0x1000 + segment * 0x400;
+                gstate.write(&format!(
+                    "\n\tSEG.U RAM_3E_{}\n\tORG ${:04x}\n\tRORG ${:04x}\n",
+                    bank, address, address
+                ))?;
+                if args.verbose {
+                    println!("Bank #{bank} - 3E+ RAM : 0x{:04x} onwards", address);
+                }
+            }
             for v in compiler_state.sorted_variables().iter() {
                 if v.1.memory == VariableMemory::MemoryOnChip(bank)
                     && v.1.def == VariableDefinition::None
+                    && !overlaid.contains(v.0)
                 {
-                    if first {
-                        first = false;
-                        let segment = 3 - (bank & 3);
-                        let address = 0x1000 + segment * 0x400;
-                        gstate.write(&format!(
-                            "\n\tSEG.U RAM_3E_{}\n\tORG ${:04x}\n\tRORG ${:04x}\n",
-                            bank, address, address
-                        ))?;
-                        if args.verbose {
-                            println!("Bank #{bank} - 3E+ RAM : 0x{:04x} onwards", address);
-                        }
-                    }
-                    let sx = if v.1.size > 1 {
-                        let s = match v.1.var_type {
-                            VariableType::CharPtr => 1,
-                            VariableType::CharPtrPtr => 2,
-                            VariableType::ShortPtr => 2,
-                            _ => unreachable!(),
-                        };
-                        v.1.size * s
-                    } else {
-                        match v.1.var_type {
-                            VariableType::Char => 1,
-                            VariableType::Short => 2,
-                            VariableType::CharPtr => 2,
-                            VariableType::CharPtrPtr => 2,
-                            VariableType::ShortPtr => 2,
-                        }
-                    };
+                    let sx = variable_size(v.1) as usize;
                     filled += sx;
                     if filled > 512 {
                         return Err(Error::Configuration {
@@ -504,398 +2051,723 @@ pub fn build_cartridge(
                     }
                 }
             }
+            filled += write_extended_locals(
+                &mut gstate,
+                VariableMemory::MemoryOnChip(bank),
+                None,
+                &mut ram_ports,
+            )?;
+            if filled > 512 {
+                return Err(Error::Configuration {
+                    error: "Memory full. 3E+ RAM is limited to 512 bytes per bank".to_string(),
+                });
+            }
         }
     }
 
-    if maxbank > 0 && bankswitching_scheme != "3E" && bankswitching_scheme != "3EP" {
-        gstate.write(&format!(
-            "
-; Macro that implements Bank Switching trampoline
-; X = bank number
-; A = hi byte of destination PC
-; Y = lo byte of destination PC
-        MAC BANK_SWITCH_TRAMPOLINE
-        pha     ; push hi byte
-        tya     ; Y -> A
-        pha     ; push lo byte
-        lda ${:04x},x ; do the bank switch
-        rts     ; return to target
-        ENDM
-        ",
-            bankswitching_address
-        ))?;
-    }
-
-    // Generate functions code
-    gstate.write("\n; Functions definitions\n\tSEG CODE\n")?;
-
-    // Generate code for all banks
-    for b in 0..=maxbank {
-        let mut filled = 0;
-        let (bank, banksize, rorg) = if bankswitching_scheme == "3E" {
-            if b == maxbank {
-                (0, 0x0800, 0x1800)
+    // Generate RAM for E7 bankswitching scheme. bank0 variables go to the 1KB RAM, and bank1 to
+    // bank4 variables to the 256 bytes RAM pages
+    if bankswitching_scheme == "E7" {
+        for bank in 0..=4 {
+            let (address, size, write_offset) = if bank == 0 {
+                (0x1400, 1024, 0x400)
             } else {
-                (b + 1, 0x0800, 0x1000)
+                (0x1900, 256, 0x100)
+            };
+            let mut filled = 0;
+            if compiler_state.variables.values().any(|v| {
+                v.memory == VariableMemory::MemoryOnChip(bank) && v.def == VariableDefinition::None
+            }) {
+                if args.verbose {
+                    println!(
+                        "Bank #{bank} - E7 RAM : 0x{:04x} onwards (written at 0x{:04x})",
+                        address,
+                        address - write_offset
+                    );
+                }
+                gstate.write(&format!(
+                    "\n\tSEG.U RAM_E7_{}\n\tORG ${:04x}\n\tRORG ${:04x}\n",
+                    bank, address, address
+                ))?;
             }
-        } else if bankswitching_scheme == "3EP" {
-            (b, 0x0400, 0x1000 + (3 - (b & 3)) * 0x400)
-        } else {
-            (b, 0x1000, 0x1000)
-        };
-
-        // Prelude code for each bank
-        debug!("Generating code for bank #{}", bank);
-        if args.verbose {
-            println!(
-                "Bank #{}: Filling memory at ${:04x} (RORG=${:04x})",
-                bank,
-                b * banksize,
-                rorg
-            );
-        }
-        if args.verbose {
-            println!("Bank #{bank}: Generating code at ${:04x}", rorg);
-        }
-        gstate.current_bank = bank;
-        gstate.write(&format!(
-            "\n\tORG ${:04x}\n\tRORG ${:04x}\n",
-            b * banksize,
-            rorg
-        ))?;
-
-        if superchip {
-            gstate.write("\n\tDS 256, $FF\n")?;
-            filled = 256;
-            if args.verbose {
-                println!(" - Superchip RAM data : 256/{banksize}");
+            for v in compiler_state.sorted_variables().iter() {
+                if v.1.memory == VariableMemory::MemoryOnChip(bank)
+                    && v.1.def == VariableDefinition::None
+                    && !overlaid.contains(v.0)
+                {
+                    let sx = variable_size(v.1) as usize;
+                    filled += sx;
+                    if filled > size {
+                        return Err(Error::Configuration {
+                            error: format!(
+                                "Memory full. E7 RAM is limited to {} bytes in bank{}",
+                                size, bank
+                            ),
+                        });
+                    }
+                    gstate.write(&format!("{:23}\tds {}\n", v.0, sx))?;
+                    gstate.write(&format!(
+                        "{:23}\t= {} - ${:x}\n",
+                        format!("{}_W", v.0),
+                        v.0,
+                        write_offset
+                    ))?;
+                    ram_ports.insert(v.0.clone());
+                    if args.verbose {
+                        println!(" - {} ({} byte{})", v.0, sx, if sx > 1 { "s" } else { "" });
+                    }
+                }
             }
-        } else if bankswitching_scheme == "DPC" || bankswitching_scheme == "DPC+" {
-            gstate.write("\n\tDS 128, $00\n")?;
-            filled = 128;
-            if args.verbose {
-                println!(" - DPC data : 128/{banksize}");
+            filled += write_extended_locals(
+                &mut gstate,
+                VariableMemory::MemoryOnChip(bank),
+                Some(write_offset),
+                &mut ram_ports,
+            )?;
+            if filled > size {
+                return Err(Error::Configuration {
+                    error: format!(
+                        "Memory full. E7 RAM is limited to {} bytes in bank{}",
+                        size, bank
+                    ),
+                });
             }
         }
-
-        if maxbank > 0 && bankswitching_scheme != "3E" && bankswitching_scheme != "3EP" {
-            // Generate trampoline code
-            gstate.write(
-                "
-;----The following code is the same on all banks----
-Start
-; Ensure that bank 0 is selected
-        LDX #$FF
-        TXS
-
-        lDA #>(Powerup-1)
-        lDY #<(Powerup-1)
-        lDX #0
-BankSwitch
-        BANK_SWITCH_TRAMPOLINE
-;----End of bank-identical code----
-        ",
-            )?;
-            filled += 9 + 7;
-            if args.verbose {
-                println!(" - Trampoline code : {filled}/{banksize}");
+        for v in compiler_state.sorted_variables().iter() {
+            if let VariableMemory::MemoryOnChip(bank) = v.1.memory {
+                if bank > 4 && v.1.def == VariableDefinition::None {
+                    return Err(Error::Configuration {
+                        error: format!(
+                            "E7 RAM is bank0 (1KB) and bank1 to bank4 (256 bytes). Variable {} can't go to bank{}",
+                            v.0, bank
+                        ),
+                    });
+                }
             }
         }
+    }
 
-        // Generate startup code
-        if bank == 0 {
-            gstate.write(
-                "
-Powerup
-        SEI		; Set the interrupt masking flag in the processor status register.
-        CLD		; Clear the BCD mode flag in the processor status register. 
-        LDX #$FF	
-        TXS
-
-        LDA #0
-.loop	  STA $00,X	
-        DEX
-        CPX #$40	
-        BNE .loop
-        ",
-            )?;
-
-            filled += 14;
-
-            if bankswitching_scheme == "3EP" {
-                gstate.write(
-                    "
-        LDA #$81 ; ROM Bank 1 to segment 2
-        STA ROM_SELECT
-        LDA #$42 ; ROM Bank 2 to segment 1
-        STA ROM_SELECT
-        LDA #$03 ; ROM Bank 3 to segment 0
-        STA ROM_SELECT
-        ",
-                )?;
-                filled += 12;
+    // Functions called by each function, and bytes they push on the stack
+    let mut calls = HashMap::<String, Vec<String>>::new();
+    let mut pushes = HashMap::<String, u32>::new();
+    for f in compiler_state.sorted_functions().iter() {
+        if f.1.code.is_some() && !f.1.inline && gstate.functions_actually_in_use.contains(f.0) {
+            let mut code = Vec::new();
+            gstate
+                .functions_code
+                .get(f.0)
+                .unwrap()
+                .write(&mut code, false)?;
+            let mut called = gstate
+                .functions_call_tree
+                .get(f.0)
+                .cloned()
+                .unwrap_or_default();
+            let code = String::from_utf8_lossy(&code);
+            pushes.insert(f.0.clone(), stack_pushes(&code));
+            for line in code.lines() {
+                if let Some(operand) = called_function(line) {
+                    let callee = match operand.strip_prefix("Call") {
+                        Some(fx) if !placement.contains_key(operand) => fx,
+                        _ => operand,
+                    };
+                    called.push(callee.to_string());
+                }
             }
+            // Inlined functions are not called
+            called.retain(|fx| {
+                compiler_state
+                    .functions
+                    .get(fx)
+                    .is_some_and(|fx| fx.code.is_some() && !fx.inline)
+            });
+            called.sort();
+            called.dedup();
+            calls.insert(f.0.clone(), called);
+        }
+    }
 
-            gstate.write(
-                "
-        JMP main
-        ",
-            )?;
-            filled += 3;
-            if args.verbose {
-                println!(" - Powerup code : {filled}/{banksize}");
+    if options.auto_bank {
+        let header_start = header.contents();
+        let mut items = Vec::new();
+        let mut references = HashMap::<String, HashSet<String>>::new();
+        let mut pinned = HashMap::<String, u32>::new();
+        pinned.insert("main".to_string(), 0);
+        pinned.insert("PLUSROM_API".to_string(), 0);
+        // Items used by included assembler stay in the bank of the assembler
+        for asm in &compiler_state.included_assembler {
+            for id in identifiers(&asm.0) {
+                if placement.contains_key(id) {
+                    pinned.insert(id.to_string(), asm.3.unwrap_or_default());
+                }
+            }
+        }
+        for f in compiler_state.sorted_functions().iter() {
+            if f.1.code.is_some() && !f.1.inline && gstate.functions_actually_in_use.contains(f.0) {
+                let mut code = Vec::new();
+                gstate
+                    .functions_code
+                    .get(f.0)
+                    .unwrap()
+                    .write(&mut code, false)?;
+                let code = String::from_utf8_lossy(&code);
+                references.insert(
+                    f.0.clone(),
+                    identifiers(&code)
+                        .filter(|id| {
+                            matches!(
+                                compiler_state.variables.get(*id).map(|v| &v.memory),
+                                Some(VariableMemory::ROM(_))
+                            )
+                        })
+                        .map(|id| id.to_string())
+                        .collect(),
+                );
+            }
+        }
+        for v in compiler_state.sorted_variables().iter() {
+            if let VariableDefinition::ArrayOfPointers(arr) = &v.1.def {
+                references.insert(v.0.clone(), arr.iter().map(|i| i.0.clone()).collect());
             }
         }
 
-        // Generate included assembler
-        for (i, asm) in compiler_state.included_assembler.iter().enumerate() {
-            let basm = if let Some(b) = asm.3 { b } else { 0 };
-            debug!("assembler: {} {} {}", i, bank, basm);
-            if bank == basm {
-                gstate.write(&asm.0)?;
-                let name;
-                if let Some(n) = &asm.1 {
-                    name = n.as_str();
-                } else {
-                    name = "Unknown";
+        // Try with the declared number of banks, then bigger cartridges
+        let mut candidate = maxbank;
+        loop {
+            let (scheme, address) = if bankswitching_scheme == "DPC"
+                || bankswitching_scheme == "DPC+"
+                || bankswitching_scheme == "FA"
+                || candidate == 0
+            {
+                (bankswitching_scheme, bankswitching_address)
+            } else {
+                let (scheme, _, address) = standard_bankswitching(candidate, superchip)?;
+                (scheme, address)
+            };
+            let mut banks = build_banks(
+                compiler_state,
+                &gstate,
+                args,
+                scheme,
+                candidate,
+                superchip,
+                &placement,
+                stubs_end(scheme),
+                &[],
+                &|code, _| Ok(code.to_string()),
+            )?;
+            let mut header_code = header_start.clone();
+            if candidate > 0 {
+                header_code.push_str(&trampoline_macro(address));
+            }
+            header_code.push_str("\n\tSEG CODE\n");
+            compute_layout(&header_code, &mut banks)?;
+
+            items.clear();
+            let mut free: Vec<u32> = banks
+                .iter()
+                .take(candidate as usize + 1)
+                .map(|b| b.capacity())
+                .collect();
+            for bank in banks.iter().take(candidate as usize + 1) {
+                for item in &bank.items {
+                    let movable = match item.kind {
+                        ItemKind::Function => {
+                            compiler_state.functions.get(&item.name).unwrap().bank == 0
+                                && !compiler_state.functions.get(&item.name).unwrap().interrupt
+                        }
+                        ItemKind::Table => {
+                            compiler_state.variables.get(&item.name).unwrap().memory
+                                == VariableMemory::ROM(0)
+                        }
+                        _ => false,
+                    } && !pinned.contains_key(&item.name);
+                    if movable {
+                        let v = compiler_state.variables.get(&item.name);
+                        let size = match v {
+                            Some(v) => rom_table_size(v) + v.alignment as u32 - 1,
+                            None => item.size,
+                        };
+                        items.push(PlacementItem {
+                            name: item.name.clone(),
+                            kind: item.kind,
+                            size,
+                            bank: None,
+                        });
+                    } else {
+                        let b = bank.bank.unwrap_or_default();
+                        free[b as usize] = free[b as usize].saturating_sub(item.size);
+                        if item.kind == ItemKind::Function || item.kind == ItemKind::Table {
+                            items.push(PlacementItem {
+                                name: item.name.clone(),
+                                kind: item.kind,
+                                size: item.size,
+                                bank: Some(b),
+                            });
+                        }
+                    }
                 }
-                if let Some(s) = asm.2 {
-                    filled += s as u32;
-                    if args.verbose {
-                        println!(
-                            " - Assembler {} code (filled {}/{})",
-                            name, filled, banksize
+            }
+            match place(&items, &calls, &references, &free, 10) {
+                Ok(p) => {
+                    placement.extend(p);
+                    maxbank = candidate;
+                    bankswitching_scheme = scheme;
+                    bankswitching_address = address;
+                    break;
+                }
+                Err(e) => {
+                    let growable = options.bankswitching.is_none()
+                        && options.rom_size.is_none()
+                        && !matches!(
+                            bankswitching_scheme,
+                            "DPC" | "DPC+" | "FA" | "DF" | "DFSC" | "BF" | "BFSC"
                         );
+                    candidate = match candidate {
+                        0 => 1,
+                        1 => 3,
+                        3 => 7,
+                        7 => 15,
+                        15 => 31,
+                        31 => 63,
+                        _ => 64,
+                    };
+                    if !growable || candidate > 63 {
+                        return Err(Error::Configuration {
+                            error: format!("Automatic bank placement failed. {}", e),
+                        });
                     }
-                } else {
-                    let nl = asm.0.lines().count() as u32;
-                    filled += nl * 3; // 3 bytes default per line estimate.
-                    if args.verbose {
-                        println!(
-                            " - Assembler {} code (filled {}/{} - estimated)",
-                            name, filled, banksize
-                        );
+                }
+            }
+        }
+
+        println!(
+            "Automatic bank placement ({} cartridge):",
+            bankswitching_scheme
+        );
+        let mut placed: Vec<&PlacementItem> = items.iter().filter(|i| i.bank.is_none()).collect();
+        placed.sort_by_key(|i| (placement[&i.name], i.name.clone()));
+        for item in placed {
+            println!(
+                " - {} {}: bank {}",
+                item.name,
+                item.kind.description(),
+                placement[&item.name]
+            );
+        }
+    }
+
+    // Calls between banks go through bankswitching stubs, as (function, bank, calling bank)
+    let mut far_calls = Vec::<(String, u32, u32)>::new();
+    if bankswitching_scheme == "3E" || bankswitching_scheme == "3EP" || sliced(bankswitching_scheme)
+    {
+        check_segment_calls(bankswitching_scheme, &calls, &placement)?;
+    }
+    if bankswitching_scheme != "3E" && bankswitching_scheme != "3EP" {
+        // E0 and E7 bank 0 is always mapped
+        let first_bank = if sliced(bankswitching_scheme) { 1 } else { 0 };
+        for bank in first_bank..=maxbank {
+            for f in compiler_state.sorted_functions().iter() {
+                if placement.get(f.0) != Some(&bank) {
+                    continue;
+                }
+                for caller_bank in 0..=maxbank {
+                    if caller_bank != bank
+                        && calls.iter().any(|(caller, called)| {
+                            placement.get(caller) == Some(&caller_bank) && called.contains(f.0)
+                        })
+                    {
+                        far_calls.push((f.0.clone(), bank, caller_bank));
                     }
                 }
             }
         }
+    }
 
-        // Generate functions code
-        for f in compiler_state.sorted_functions().iter() {
-            if f.1.code.is_some()
-                && !f.1.inline
-                && f.1.bank == bank
-                && gstate.functions_actually_in_use.get(f.0).is_some()
+    // Call graph from main. Calls through the bankswitching stubs push two return addresses.
+    let mut call_graph = CallGraph {
+        bankswitching_scheme: scheme_name(bankswitching_scheme).to_string(),
+        functions: Vec::new(),
+        calls: Vec::new(),
+    };
+    let mut sorted_calls: Vec<_> = calls.iter().collect();
+    sorted_calls.sort();
+    for (caller, called) in sorted_calls {
+        let bank = placement[caller];
+        call_graph.functions.push(CallGraphFunction {
+            name: caller.clone(),
+            bank,
+            level: levels.get(caller).copied(),
+            locals: frame(caller)
+                .map(|(_, offset)| (offset, frame_sizes.get(caller).copied().unwrap_or_default())),
+            pushes: pushes[caller],
+            ram: frame(caller).map_or(zeropage_bytes as u32, |(_, offset)| {
+                locals_start + offset + frame_sizes.get(caller).copied().unwrap_or_default()
+            }),
+            recursive: recursive.contains(caller),
+        });
+        for callee in called {
+            let Some(&callee_bank) = placement.get(callee) else {
+                continue;
+            };
+            let stub = if callee_bank == bank
+                || bankswitching_scheme == "3E"
+                || bankswitching_scheme == "3EP"
+                || (callee_bank == 0 && sliced(bankswitching_scheme))
             {
-                debug!("Generating code for function {}", f.0);
+                None
+            } else {
+                Some(far_call_label(bankswitching_scheme, callee, bank))
+            };
+            let cost = if stub.is_some() && !sliced(bankswitching_scheme) {
+                4
+            } else {
+                2
+            };
+            call_graph.calls.push(Call {
+                caller: caller.clone(),
+                callee: callee.clone(),
+                stub,
+                cost,
+            });
+        }
+    }
+    if let Some(filename) = &options.call_graph {
+        std::fs::write(filename, call_graph.text())?;
+    }
+    if let Some(filename) = &options.call_graph_dot {
+        std::fs::write(filename, call_graph.dot())?;
+    }
+    if args.verbose {
+        let (stack_depth, stack_path) = call_graph.stack_depth();
+        println!(
+            "Worst-case stack depth: {} bytes ({}){}",
+            stack_depth,
+            stack_path.join(" -> "),
+            if call_graph.recursive() {
+                ", not counting recursive calls"
+            } else {
+                ""
+            }
+        );
+    }
+    // The stack goes down from $FF, in the zeropage RAM left free by the variables. The depth of
+    // the recursive calls is not known at compile time.
+    if call_graph.recursive() {
+        eprintln!("Warning: the stack may overflow, since the depth of the recursive calls is not checked");
+    }
+    if let Some((depth, ram, path)) = call_graph.stack_overflow() {
+        return Err(Error::Configuration {
+            error: format!(
+                "Stack overflow. The stack may grow to {} bytes in {}, but only {} bytes of zeropage RAM are left by the variables",
+                depth,
+                path.join(" -> "),
+                128 - ram
+            ),
+        });
+    }
 
-                gstate.write(&format!("\n{}\tSUBROUTINE\n", f.0))?;
-                gstate.write_function(f.0)?;
-                gstate.write("\tRTS\n")?;
+    if maxbank > 0
+        && bankswitching_scheme != "3E"
+        && bankswitching_scheme != "3EP"
+        && !sliced(bankswitching_scheme)
+    {
+        gstate.write(&trampoline_macro(bankswitching_address))?;
+    }
 
-                if args.verbose {
-                    let s = gstate.functions_code.get(f.0).unwrap().size_bytes() + 1;
-                    filled += s;
-                    println!(" - {} function (filled {}/{})", f.0, filled, banksize);
+    // Generate functions code
+    gstate.write("\n; Functions definitions\n\tSEG CODE\n")?;
+
+    let end_of_memory = if !far_calls.is_empty() {
+        stubs_end(bankswitching_scheme) - far_calls.len() as u32 * 10
+    } else if compiler_state.variables.contains_key("PLUSROM_API") {
+        stubs_end(bankswitching_scheme)
+    } else if stubs_end(bankswitching_scheme) != 0x1fef {
+        // EF, DF and BF hotspots are below the vectors, FA hotspots are at $1FF8-$1FFA
+        stubs_end(bankswitching_scheme)
+    } else {
+        0x1ffa
+    };
+
+    let mut banks = build_banks(
+        compiler_state,
+        &gstate,
+        args,
+        bankswitching_scheme,
+        maxbank,
+        superchip,
+        &placement,
+        end_of_memory,
+        &far_calls,
+        &|code, bank| {
+            let code = if bankswitching_scheme != "3E" && bankswitching_scheme != "3EP" {
+                retarget_calls(code, bankswitching_scheme, bank, &placement)
+            } else {
+                code.to_string()
+            };
+            if ram_ports.is_empty() {
+                Ok(code)
+            } else {
+                ram_ports_access(&code, &ram_ports, ram_read_offset)
+            }
+        },
+    )?;
+
+    // Compute the exact position of every item before writing the code
+    drop(gstate);
+    let header = header.contents();
+    let mut assembly = compute_layout(&header, &mut banks)?;
+
+    // Page crossings in timing-critical functions. Realigning a function or a table moves the
+    // following items, so the layout is computed again until nothing more can be realigned
+    let mut realigned = Vec::new();
+    loop {
+        let crossings = page_crossings(&banks, &assembly);
+        if options.page_crossing.as_deref() == Some("align") {
+            let mut moved = false;
+            for (b, i) in crossings.iter().filter_map(|c| c.item) {
+                if !realigned.contains(&(b, i)) {
+                    banks[b].items[i].code.insert_str(0, "\n\talign 256\n");
+                    realigned.push((b, i));
+                    moved = true;
                 }
             }
+            if moved {
+                assembly = compute_layout(&header, &mut banks)?;
+                continue;
+            }
+        }
+        if let Some(c) = crossings.first() {
+            if options.page_crossing.as_deref() == Some("error") {
+                return Err(Error::Configuration {
+                    error: format!("Page crossing. {}", c.description),
+                });
+            }
+        }
+        for c in &crossings {
+            eprintln!("Warning: {}", c.description);
+        }
+        break;
+    }
+    if args.verbose {
+        for &(b, i) in &realigned {
+            let item = &banks[b].items[i];
+            let padding = assembly
+                .symbol(&item.name)
+                .map_or(0, |s| s.value as u32 & 0xffff)
+                .saturating_sub(item.address);
+            println!(
+                "Realigned {} {} on a page boundary ({} byte{} of padding)",
+                item.name,
+                item.kind.description(),
+                padding,
+                if padding > 1 { "s" } else { "" }
+            );
+        }
+    }
+    if let Some(filename) = &options.memory_map {
+        let ram = ram_regions(compiler_state, bankswitching_scheme, &overlays, &assembly);
+        std::fs::write(
+            filename,
+            memory_map(scheme_name(bankswitching_scheme), &banks, &ram),
+        )?;
+    }
+    if args.verbose {
+        for layout in &banks {
+            if let Some(bank) = layout.bank {
+                println!(
+                    "Bank #{}: Filling memory at ${:04x} (RORG=${:04x})",
+                    bank, layout.org, layout.rorg
+                );
+            } else {
+                println!("{}: Filling memory at ${:04x}", layout.name, layout.org);
+            }
+            for item in &layout.items {
+                println!(
+                    " - {} {} at ${:04x}, {} byte{} (filled {}/{})",
+                    item.name,
+                    item.kind.description(),
+                    item.address,
+                    item.size,
+                    if item.size > 1 { "s" } else { "" },
+                    layout.filled_after(item),
+                    layout.capacity()
+                );
+            }
+        }
+    }
+    check_layout(&banks)?;
+
+    // Cycle annotations are checked on the final layout, where the page crossings are known
+    if options.cycle_report.is_some()
+        || banks
+            .iter()
+            .any(|b| b.items.iter().any(|i| i.code.contains(";@")))
+    {
+        let resolve = |operand: &str| {
+            if placement.contains_key(operand) {
+                return Some((operand.to_string(), 0));
+            }
+            // Bankswitching stubs: BIT hotspot + JMP for E0 and E7, LDX hotspot + JSR + LDX
+            // hotspot + RTS otherwise
+            let f = operand.strip_prefix("Call")?;
+            if sliced(bankswitching_scheme) {
+                Some((f.to_string(), 7))
+            } else {
+                let f = match f.rsplit_once("_bank") {
+                    Some((g, _)) if !placement.contains_key(f) => g,
+                    _ => f,
+                };
+                Some((f.to_string(), 20))
+            }
+        };
+        let cycles = check_cycles(&banks, &assembly, &resolve);
+        if let Some(filename) = &options.cycle_report {
+            std::fs::write(filename, &cycles.report)?;
         }
-
-        // Generate ROM tables
-        gstate.write("\n; Tables in ROM\n")?;
         if args.verbose {
-            println!("Bank #{bank}: Inserting ROM tables");
+            for check in &cycles.checks {
+                println!("Cycles: {}", check);
+            }
+        }
+        if let Some(error) = cycles.errors.first() {
+            return Err(Error::Configuration {
+                error: error.clone(),
+            });
         }
+    }
 
-        for v in compiler_state.sorted_variables().iter() {
-            if let VariableMemory::ROM(rom_bank) = v.1.memory {
-                if rom_bank == bank {
-                    let s = if filled > 0 {
-                        (((filled - 1) / v.1.alignment as u32) + 1) * v.1.alignment as u32
-                    } else {
-                        0
-                    };
-                    let s2 = match &v.1.def {
-                        VariableDefinition::Array(arr) => {
-                            if v.1.alignment != 1 {
-                                gstate.write(&format!("\n\talign {}\n", v.1.alignment))?;
-                            }
-                            gstate.write(v.0)?;
-                            let mut counter = 0;
-                            for vx in arr {
-                                match vx {
-                                    VariableValue::Int(i) => {
-                                        if counter == 0 {
-                                            gstate.write("\n\thex ")?;
-                                        }
-                                        counter += 1;
-                                        if counter == 16 {
-                                            counter = 0;
-                                        }
-                                        gstate.write(&format!("{:02x}", i & 0xff))
-                                    }
-                                    VariableValue::LowPtr((s, offset)) => {
-                                        counter = 0;
-                                        if *offset != 0 {
-                                            gstate
-                                                .write(&format!("\n\t.byte <({} + {})", s, offset))
-                                        } else {
-                                            gstate.write(&format!("\n\t.byte <{}", s))
-                                        }
-                                    }
-                                    VariableValue::HiPtr((s, offset)) => {
-                                        counter = 0;
-                                        if *offset != 0 {
-                                            gstate
-                                                .write(&format!("\n\t.byte >({} + {})", s, offset))
-                                        } else {
-                                            gstate.write(&format!("\n\t.byte >{}", s))
-                                        }
-                                    }
-                                }?;
-                            }
-                            if v.1.var_type == VariableType::ShortPtr {
-                                for vx in arr {
-                                    if counter == 0 {
-                                        gstate.write("\n\thex ")?;
-                                    }
-                                    counter += 1;
-                                    if counter == 16 {
-                                        counter = 0;
-                                    }
-                                    if let VariableValue::Int(i) = vx {
-                                        gstate.write(&format!("{:02x}", (i >> 8) & 0xff))?;
-                                    }
-                                }
-                            }
-                            gstate.write("\n")?;
-                            if v.1.var_type == VariableType::ShortPtr {
-                                arr.len() * 2
-                            } else {
-                                arr.len()
-                            }
-                        }
-                        VariableDefinition::ArrayOfPointers(arr) => {
-                            if v.1.alignment != 1 {
-                                gstate.write(&format!("\n\talign {}\n", v.1.alignment))?;
-                            }
-                            gstate.write(v.0)?;
-
-                            let mut counter = 0;
-                            for i in arr {
-                                if counter % 8 == 0 {
-                                    gstate.write("\n\t.byte ")?;
-                                }
-                                counter += 1;
-                                if i.0 == "__address__" {
-                                    gstate.write(&format!("${:02x}", i.1 & 0xff))?;
-                                } else if i.1 != 0 {
-                                    gstate.write(&format!("<({} + {})", i.0, i.1))?;
-                                } else {
-                                    gstate.write(&format!("<{}", i.0))?;
-                                }
-                                if counter % 8 != 0 {
-                                    gstate.write(", ")?;
-                                }
-                            }
-                            for i in arr {
-                                if counter % 8 == 0 {
-                                    gstate.write("\n\t.byte ")?;
-                                }
-                                counter += 1;
-                                if i.0 == "__address__" {
-                                    gstate.write(&format!("${:02x}", i.1 >> 8))?;
-                                } else if i.1 != 0 {
-                                    gstate.write(&format!(">({} + {})", i.0, i.1))?;
-                                } else {
-                                    gstate.write(&format!(">{}", i.0))?;
-                                }
-                                if counter % 8 != 0 && counter < 2 * arr.len() {
-                                    gstate.write(", ")?;
-                                }
-                            }
-                            gstate.write("\n")?;
-                            arr.len() * 2
-                        }
-                        _ => 0,
-                    } as u32;
-                    filled = s + s2;
-                    if args.verbose {
-                        println!(" - {} array (filled {}/{})", v.0, filled, banksize);
-                    }
-                }
-            }
+    writer.write_all(header.as_bytes())?;
+
+    // Generate code for all banks
+    let far_calls_address = stubs_end(bankswitching_scheme) - 0x1000 - far_calls.len() as u32 * 10;
+    for b in 0..=maxbank {
+        let (bank, _, rorg) = bank_location(bankswitching_scheme, b, maxbank);
+        let layout = &banks[b as usize];
+        writer.write_all(
+            format!("\n\tORG ${:04x}\n\tRORG ${:04x}\n", layout.org, layout.rorg).as_bytes(),
+        )?;
+        let tables = layout
+            .items
+            .iter()
+            .position(|i| i.kind == ItemKind::Table)
+            .unwrap_or(layout.items.len());
+        for item in &layout.items[0..tables] {
+            writer.write_all(item.code.as_bytes())?;
+        }
+        writer.write_all("\n; Tables in ROM\n".as_bytes())?;
+        for item in &layout.items[tables..] {
+            writer.write_all(item.code.as_bytes())?;
         }
 
         // Epilogue code
         if bankswitching_scheme == "3E" {
             if bank == 0 {
-                gstate.write(
+                writer.write_all(
                     "
         ECHO ([$1FF0-.]d), \"bytes free in bank 0\"
-        ",
+        "
+                    .as_bytes(),
                 )?;
             } else {
-                gstate.write(&format!(
-                    "
+                writer.write_all(
+                    format!(
+                        "
         ECHO ([$1800-.]d), \"bytes free in bank {}\"
         ",
-                    bank
-                ))?;
+                        bank
+                    )
+                    .as_bytes(),
+                )?;
+                // The image starts at the first byte written, and bank 0 is the last one
+                if bank == 1 && layout.items.iter().all(|i| i.size == 0) {
+                    writer.write_all(
+                        "
+        ORG $0000
+        DS 1, 0x81
+        "
+                        .as_bytes(),
+                    )?;
+                }
             }
         } else if bankswitching_scheme == "3EP" {
             if bank == 0 {
-                gstate.write(
+                writer.write_all(
                     "
         ECHO ([$1FF0-.]d), \"bytes free in bank 0\"
-        ",
+        "
+                    .as_bytes(),
                 )?;
             } else {
-                gstate.write(&format!(
+                writer.write_all(
+                    format!(
+                        "
+        ECHO ([${:04x}-.]d), \"bytes free in bank {}\"
+        ",
+                        rorg + 0x400,
+                        bank
+                    )
+                    .as_bytes(),
+                )?;
+            }
+        } else if sliced(bankswitching_scheme) {
+            writer.write_all(
+                format!(
                     "
         ECHO ([${:04x}-.]d), \"bytes free in bank {}\"
         ",
-                    rorg + 0x400,
-                    bank
-                ))?;
+                    layout.end, bank
+                )
+                .as_bytes(),
+            )?;
+            // The image starts at the first byte written, so empty slices get one byte to keep
+            // the full 8KB or 16KB image
+            if layout.items.iter().all(|i| i.size == 0) {
+                writer.write_all(
+                    format!(
+                        "
+        ORG ${:04x}
+        DS 1, 0x81
+        ",
+                        layout.org
+                    )
+                    .as_bytes(),
+                )?;
             }
         } else {
-            let end_of_memory = if !banked_functions.is_empty() {
-                0x1fef - banked_functions.len() * 10
-            } else if compiler_state.variables.get("PLUSROM_API").is_some() {
-                0x1fef
-            } else {
-                0x1ffa
-            };
-            gstate.write(&format!(
-                "
+            writer.write_all(
+                format!(
+                    "
         ECHO ([${:04x}-.]d), \"bytes free in bank {}\"
         ",
-                end_of_memory, bank
-            ))?;
+                    end_of_memory, bank
+                )
+                .as_bytes(),
+            )?;
 
-            if bank == 0 {
-                if !banked_functions.is_empty() {
-                    // Generate bankswitching functions code
-                    banked_function_address = 0x0FEF - banked_functions.len() * 10;
-                    debug!("Banked function address={:04x}", banked_function_address);
-                    gstate.write(&format!(
-                        "
+            // Bankswitching stubs. The calling bank switches to the bank of the function, which
+            // calls it and switches back to the calling bank, 6 bytes further
+            let mut next_address = None;
+            for (i, (f, callee_bank, caller_bank)) in far_calls.iter().enumerate() {
+                let address = far_calls_address + i as u32 * 10;
+                if *caller_bank == bank {
+                    debug!("#{} Banked function address={:04x}", bank, address);
+                    if next_address != Some(address) {
+                        writer.write_all(
+                            format!(
+                                "
         ORG ${:04x}
         RORG ${:04x}",
-                        banked_function_address,
-                        0x1000 + banked_function_address
-                    ))?;
-                    for bank_ex in 1..=maxbank {
-                        for f in compiler_state.sorted_functions().iter() {
-                            if f.1.code.is_some()
-                                && !f.1.inline
-                                && f.1.bank == bank_ex
-                                && banked_functions.contains(f.0)
-                            {
-                                gstate.write(&format!(
-                                    "
-Call{}
+                                address + bank * 0x1000,
+                                0x1000 + address
+                            )
+                            .as_bytes(),
+                        )?;
+                    }
+                    writer.write_all(
+                        format!(
+                            "
+{}
         LDX ${:04x}+{}
         NOP
         NOP
@@ -904,38 +2776,31 @@ Call{}
         NOP
         NOP
         RTS",
-                                    f.0, bankswitching_address, f.1.bank
-                                ))?;
-                            }
-                        }
-                    }
-                }
-            } else {
-                for f in compiler_state.sorted_functions().iter() {
-                    let address = banked_function_address;
-                    if f.1.code.is_some()
-                        && !f.1.inline
-                        && f.1.bank == bank
-                        && banked_functions.contains(f.0)
-                    {
-                        debug!(
-                            "#{} Banked function address={:04x}",
-                            bank, banked_function_address
-                        );
-                        gstate.write(&format!(
+                            far_call_label(bankswitching_scheme, f, bank),
+                            bankswitching_address,
+                            callee_bank
+                        )
+                        .as_bytes(),
+                    )?;
+                    next_address = Some(address + 10);
+                } else if *callee_bank == bank {
+                    debug!("#{} Banked function address={:04x}", bank, address);
+                    writer.write_all(
+                        format!(
                             "
         ORG ${:04x}
         RORG ${:04x}
         JSR {}
         LDX ${:04x}
                     ",
-                            address + f.1.bank as usize * 0x1000 + 3,
+                            address + bank * 0x1000 + 3,
                             0x1000 + address + 3,
-                            f.0,
-                            bankswitching_address
-                        ))?;
-                        banked_function_address += 10;
-                    }
+                            f,
+                            bankswitching_address + caller_bank
+                        )
+                        .as_bytes(),
+                    )?;
+                    next_address = None;
                 }
             }
         }
@@ -946,29 +2811,32 @@ Call{}
             "Powerup"
         };
 
-        if b == maxbank && compiler_state.variables.get("PLUSROM_API").is_some() {
+        if b == maxbank && compiler_state.variables.contains_key("PLUSROM_API") {
             let v = compiler_state.get_variable("PLUSROM_API");
             let offset = match v.memory {
                 VariableMemory::ROM(bank) => bank,
                 _ => 0,
             };
-            gstate.write(&format!(
-                "
-        ORG ${}FFA
+            writer.write_all(
+                format!(
+                    "
+        ORG ${:x}FFA
         RORG $1FFA
 
         .word PLUSROM_API + ${:04x}\t
         .word {}\t; RESET
         .word {}\t; IRQ
         \n",
-                bank,
-                offset * 0x1000,
-                starting_code,
-                starting_code
-            ))?;
+                    bank,
+                    offset * 0x1000,
+                    starting_code,
+                    starting_code
+                )
+                .as_bytes(),
+            )?;
         } else if bankswitching_scheme == "3EP" {
             if bank == 0 {
-                gstate.write(
+                writer.write_all(
                     "
         ORG $03FA
         RORG $1FFA
@@ -976,32 +2844,43 @@ Call{}
         .word Powerup\t; NMI
         .word Powerup\t; RESET
         .word Powerup\t; IRQ
-        \n",
+        \n"
+                    .as_bytes(),
                 )?;
             } else if bank == maxbank {
-                gstate.write(&format!(
-                    "
+                writer.write_all(
+                    format!(
+                        "
             ORG ${:04x} 
             DS 1, 0x81
             ",
-                    bank * 0x400 + 0x3ff
-                ))?;
+                        bank * 0x400 + 0x3ff
+                    )
+                    .as_bytes(),
+                )?;
             }
-        } else if bankswitching_scheme != "DPC+" && bankswitching_scheme != "3E" {
-            gstate.write(&format!(
-                "
-        ORG ${}FFA
+        } else if bankswitching_scheme != "DPC+"
+            && bankswitching_scheme != "3E"
+            && !sliced(bankswitching_scheme)
+        {
+            writer.write_all(
+                format!(
+                    "
+        ORG ${:x}FFA
         RORG $1FFA
 
         .word {}\t; NMI
         .word {}\t; RESET
         .word {}\t; IRQ
         \n",
-                bank, starting_code, starting_code, starting_code
-            ))?;
+                    bank, starting_code, starting_code, starting_code
+                )
+                .as_bytes(),
+            )?;
         } else if b == maxbank {
-            gstate.write(&format!(
-                "
+            writer.write_all(
+                format!(
+                    "
         ORG ${:04x}
         RORG $1FFA
 
@@ -1009,164 +2888,224 @@ Call{}
         .word {}\t; RESET
         .word {}\t; IRQ
         \n",
-                (b + 1) * banksize - 6,
-                starting_code,
-                starting_code,
-                starting_code
-            ))?;
+                    layout.org + 0x1ffa - layout.rorg,
+                    starting_code,
+                    starting_code,
+                    starting_code
+                )
+                .as_bytes(),
+            )?;
         }
     }
 
     if bankswitching_scheme == "DPC" {
-        gstate.write(
+        writer.write_all(
             "
             SEG DISPLAY
             ORG $2000
             RORG $0000
-            ",
+            "
+            .as_bytes(),
         )?;
 
         // Generate display tables
-        gstate.write("\n; Display in ROM\n")?;
-        for v in compiler_state.sorted_variables().iter() {
-            if let VariableMemory::Display = v.1.memory {
-                if let VariableDefinition::Array(arr) = &v.1.def {
-                    if v.1.alignment != 1 {
-                        gstate.write(&format!("\n\talign {}\n", v.1.alignment))?;
-                    }
-                    gstate.write(v.0)?;
-                    let mut counter = 0;
-                    for vx in arr {
-                        if counter == 0 || counter == 16 {
-                            gstate.write("\n\thex ")?;
-                        }
-                        counter += 1;
-                        if counter == 16 {
-                            counter = 0;
-                        }
-                        if let VariableValue::Int(i) = vx {
-                            gstate.write(&format!("{:02x}", i & 0xff))?;
-                        }
-                    }
-                    gstate.write("\n")?;
-                }
-            }
+        writer.write_all("\n; Display in ROM\n".as_bytes())?;
+        for item in &banks[maxbank as usize + 1].items {
+            writer.write_all(item.code.as_bytes())?;
         }
-        gstate.write(
+        writer.write_all(
             "
             ECHO ([$800-.]d), \"bytes free in DPC display memory\"
 
             ORG $27FF
             DS 1, 0x81
-            ",
+            "
+            .as_bytes(),
         )?;
     }
 
     if bankswitching_scheme == "DPC+" {
-        gstate.write(
+        writer.write_all(
             "
             SEG DISPLAY
             ORG $6000
             RORG $0000
-            ",
+            "
+            .as_bytes(),
         )?;
 
         // Generate display tables
-        gstate.write("\n; Display in RAM\n")?;
-        for v in compiler_state.sorted_variables().iter() {
-            if let VariableMemory::Display = v.1.memory {
-                if v.1.alignment != 1 {
-                    gstate.write(&format!("\n\talign {}\n", v.1.alignment))?;
-                }
-                if let VariableDefinition::Array(arr) = &v.1.def {
-                    gstate.write(v.0)?;
-                    let mut counter = 0;
-                    for vx in arr {
-                        if counter == 0 || counter == 16 {
-                            gstate.write("\n\thex ")?;
-                        }
-                        counter += 1;
-                        if counter == 16 {
-                            counter = 0;
-                        }
-                        if let VariableValue::Int(i) = vx {
-                            gstate.write(&format!("{:02x}", i & 0xff))?;
-                        }
-                    }
-                    gstate.write("\n")?;
-                } else if v.1.size > 1 {
-                    let s = match v.1.var_type {
-                        VariableType::CharPtr => 1,
-                        VariableType::CharPtrPtr => 2,
-                        VariableType::ShortPtr => 2,
-                        _ => unreachable!(),
-                    };
-                    gstate.write(&format!("{:23}\tds {}\n", v.0, v.1.size * s))?;
-                } else {
-                    let s = match v.1.var_type {
-                        VariableType::Char => 1,
-                        VariableType::Short => 2,
-                        VariableType::CharPtr => 2,
-                        VariableType::CharPtrPtr => 2,
-                        VariableType::ShortPtr => 2,
-                    };
-                    gstate.write(&format!("{:23}\tds {}\n", v.0, s))?;
-                }
-            }
+        writer.write_all("\n; Display in RAM\n".as_bytes())?;
+        for item in &banks[maxbank as usize + 1].items {
+            writer.write_all(item.code.as_bytes())?;
         }
-        gstate.write(
+        writer.write_all(
             "
             ECHO ([$1000-.]d), \"bytes free in DPC+ display memory\"
-            ",
+            "
+            .as_bytes(),
         )?;
 
-        gstate.write(
+        writer.write_all(
             "
             SEG FREQUENCIES
             ORG $7000
             RORG $0000
-            ",
+            "
+            .as_bytes(),
         )?;
 
-        // Generate display tables
-        gstate.write("\n; Frequencies in ROM\n")?;
-        for v in compiler_state.sorted_variables().iter() {
-            if let VariableMemory::Frequency = v.1.memory {
-                if let VariableDefinition::Array(arr) = &v.1.def {
-                    if v.1.alignment != 1 {
-                        gstate.write(&format!("\n\talign {}\n", v.1.alignment))?;
-                    }
-                    gstate.write(v.0)?;
-                    let mut counter = 0;
-                    for vx in arr {
-                        if counter == 0 || counter == 16 {
-                            gstate.write("\n\thex ")?;
-                        }
-                        counter += 1;
-                        if counter == 16 {
-                            counter = 0;
-                        }
-                        if let VariableValue::Int(i) = vx {
-                            gstate.write(&format!("{:02x}", i & 0xff))?;
-                        }
-                    }
-                    gstate.write("\n")?;
-                }
-            }
+        // Generate frequency tables
+        writer.write_all("\n; Frequencies in ROM\n".as_bytes())?;
+        for item in &banks[maxbank as usize + 2].items {
+            writer.write_all(item.code.as_bytes())?;
         }
-        gstate.write(
+        writer.write_all(
             "
             ECHO ([$400-.]d), \"bytes free in DPC+ frequency memory\"
 
             ORG $73FF
             DS 1, 0x81
-            ",
+            "
+            .as_bytes(),
         )?;
     }
-    gstate.write("\tEND\n")?;
-
+    writer.write_all("\tEND\n".as_bytes())?;
+
+    // Debugging information
+    let mut c_names = HashMap::new();
+    let mut variables = Vec::new();
+    for (name, f) in compiler_state.sorted_functions().iter() {
+        for vx in &f.local_variables {
+            if let (Some((c_name, parameter)), Some(v)) = (
+                local_variable_c_name(name, vx),
+                compiler_state.variables.get(vx),
+            ) {
+                c_names.insert(vx.clone(), c_name.clone());
+                variables.push(DebugVariable {
+                    name: c_name[name.len() + 1..].to_string(),
+                    symbol: vx.clone(),
+                    function: Some(name.to_string()),
+                    parameter,
+                    read_offset: 0,
+                    var_type: v.var_type,
+                    signed: v.signed,
+                    size: v.size,
+                });
+            }
+        }
+    }
+    for (name, v) in compiler_state.sorted_variables().iter() {
+        let read_offset = match v.memory {
+            VariableMemory::Superchip if bankswitching_scheme != "FA" => 0x80,
+            VariableMemory::Zeropage
+            | VariableMemory::Superchip
+            | VariableMemory::MemoryOnChip(_) => 0,
+            _ => continue,
+        };
+        if v.def == VariableDefinition::None && !c_names.contains_key(*name) {
+            variables.push(DebugVariable {
+                name: name.to_string(),
+                symbol: name.to_string(),
+                function: None,
+                parameter: false,
+                read_offset,
+                var_type: v.var_type,
+                signed: v.signed,
+                size: v.size,
+            });
+        }
+    }
+    let source_lines = if args.debug {
+        compiler_state
+            .preprocessed_utf8
+            .lines()
+            .zip(compiler_state.mapped_lines.iter())
+            .map(|(text, (file, line, _))| (file.to_string(), *line, text.to_string()))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let mut functions = Vec::new();
+    for bank in banks.iter() {
+        if let Some(b) = bank.bank {
+            for item in bank.items.iter() {
+                if item.kind == ItemKind::Function {
+                    functions.push((item.name.clone(), b, item.address, item.size));
+                }
+            }
+        }
+    }
     if args.verbose {
         println!("Generated a {} ATARI 2600 cartridge", bankswitching_scheme);
     }
-    Ok(())
+    Ok(CartridgeInfo {
+        bankswitching_scheme: scheme_name(bankswitching_scheme).to_string(),
+        c_names,
+        source_lines,
+        banks: banks
+            .iter()
+            .filter_map(|bank| bank.bank.map(|b| (b, bank.org)))
+            .collect(),
+        functions,
+        variables,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(calls: &[(&str, &[&str])]) -> HashMap<String, Vec<String>> {
+        calls
+            .iter()
+            .map(|(f, callees)| {
+                (
+                    f.to_string(),
+                    callees.iter().map(|c| c.to_string()).collect(),
+                )
+            })
+            .collect()
+    }
+
+    fn sizes(sizes: &[(&str, u32)]) -> HashMap<String, u32> {
+        sizes.iter().map(|(f, s)| (f.to_string(), *s)).collect()
+    }
+
+    #[test]
+    fn functions_never_running_together_share_memory() {
+        // main calls a and b, b calls c: c is deeper than a, but they never run at the same time
+        let frames = allocate_local_variables(
+            &tree(&[("main", &["a", "b"]), ("b", &["c"])]),
+            "main",
+            &sizes(&[("main", 1), ("a", 2), ("c", 2)]),
+        );
+        assert_eq!(frames["main"], (0, 0));
+        assert_eq!(frames["a"], (1, 1));
+        assert_eq!(frames["b"], (1, 1));
+        assert_eq!(frames["c"], (2, 1));
+    }
+
+    #[test]
+    fn callees_come_after_all_their_callers() {
+        // d is called by a (2 bytes) and by c, itself after b (3 bytes)
+        let frames = allocate_local_variables(
+            &tree(&[
+                ("main", &["a", "b"]),
+                ("a", &["d"]),
+                ("b", &["c"]),
+                ("c", &["d"]),
+            ]),
+            "main",
+            &sizes(&[("a", 2), ("b", 3), ("c", 1), ("d", 1)]),
+        );
+        assert_eq!(frames["a"], (1, 0));
+        assert_eq!(frames["b"], (1, 0));
+        assert_eq!(frames["c"], (2, 3));
+        assert_eq!(frames["d"], (3, 4));
+        // Functions not called from the root are not allocated
+        let frames =
+            allocate_local_variables(&tree(&[("irq", &["a"])]), "main", &sizes(&[("a", 2)]));
+        assert_eq!(frames.len(), 1);
+    }
 }
@@ -0,0 +1,287 @@
+/*
+    cc2600 - a subset of C compiler for the Atari 2600
+    Copyright (C) 2023-2024 Bruno STEUX
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+    Contact info: bruno.steux@gmail.com
+*/
+
+// Call graph of the cartridge, from main, and worst-case hardware stack depth
+
+use std::collections::{HashMap, HashSet};
+
+pub struct CallGraphFunction {
+    pub name: String,
+    pub bank: u32,
+    /// Level in the call tree (length of the longest call path from main)
+    pub level: Option<usize>,
+    /// Offset in LOCAL_VARIABLES and size of the local variables
+    pub locals: Option<(u32, u32)>,
+    /// Bytes pushed on the stack by the function itself (PHA/PHP)
+    pub pushes: u32,
+    /// Zeropage RAM used by the variables that may be live in the function (global variables,
+    /// and local variables of the function and of its callers)
+    pub ram: u32,
+    /// Recursive function, with a dedicated storage for its local variables
+    pub recursive: bool,
+}
+
+pub struct Call {
+    pub caller: String,
+    pub callee: String,
+    /// Bankswitching stub (Call{name}) used for calls between banks
+    pub stub: Option<String>,
+    /// Bytes pushed on the stack by the call (return addresses)
+    pub cost: u32,
+}
+
+pub struct CallGraph {
+    pub bankswitching_scheme: String,
+    pub functions: Vec<CallGraphFunction>,
+    pub calls: Vec<Call>,
+}
+
+impl CallGraph {
+    fn function(&self, name: &str) -> Option<&CallGraphFunction> {
+        self.functions.iter().find(|f| f.name == name)
+    }
+
+    fn callees<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Call> + 'a {
+        self.calls.iter().filter(move |c| c.caller == name)
+    }
+
+    // Worst-case stack depth of a function and of the functions it calls, with the deepest path,
+    // and whether a recursive call was left out. Recursive calls are not followed, so a depth
+    // that left one out depends on the current path, and is not memoized.
+    fn depth(
+        &self,
+        name: &str,
+        path: &mut HashSet<String>,
+        memo: &mut HashMap<String, (u32, Vec<String>)>,
+    ) -> (u32, Vec<String>, bool) {
+        if let Some((d, p)) = memo.get(name) {
+            return (*d, p.clone(), false);
+        }
+        path.insert(name.to_string());
+        let mut deepest = (0, Vec::new());
+        let mut cut = false;
+        for call in self.callees(name) {
+            if path.contains(&call.callee) {
+                cut = true;
+                continue;
+            }
+            let (d, p, c) = self.depth(&call.callee, path, memo);
+            cut |= c;
+            if call.cost + d > deepest.0 {
+                deepest = (call.cost + d, p);
+            }
+        }
+        path.remove(name);
+        let pushes = self.function(name).map_or(0, |f| f.pushes);
+        let mut p = vec![name.to_string()];
+        p.append(&mut deepest.1);
+        if !cut {
+            memo.insert(name.to_string(), (pushes + deepest.0, p.clone()));
+        }
+        (pushes + deepest.0, p, cut)
+    }
+
+    /// Worst-case stack depth from main, in bytes, and the corresponding call path. With
+    /// recursive functions, the recursive calls are not counted.
+    pub fn stack_depth(&self) -> (u32, Vec<String>) {
+        let (d, p, _) = self.depth("main", &mut HashSet::new(), &mut HashMap::new());
+        (d, p)
+    }
+
+    /// Some functions are called recursively, so the stack depth is unbounded
+    pub fn recursive(&self) -> bool {
+        self.functions.iter().any(|f| f.recursive)
+    }
+
+    // Looks for a call path where the stack goes down into live variables
+    fn overflow(
+        &self,
+        name: &str,
+        depth: u32,
+        path: &mut Vec<String>,
+        seen: &mut HashSet<(String, u32)>,
+    ) -> Option<(u32, u32, Vec<String>)> {
+        if path.iter().any(|f| f == name) || !seen.insert((name.to_string(), depth)) {
+            return None;
+        }
+        path.push(name.to_string());
+        let mut ret = None;
+        if let Some(f) = self.function(name) {
+            if depth + f.pushes + f.ram > 128 {
+                ret = Some((depth + f.pushes, f.ram, path.clone()));
+            }
+        }
+        for call in self.callees(name) {
+            if ret.is_some() {
+                break;
+            }
+            ret = self.overflow(&call.callee, depth + call.cost, path, seen);
+        }
+        path.pop();
+        ret
+    }
+
+    /// Call path from main where the stack, going down from $FF, may overwrite variables. Returns
+    /// the stack depth, the zeropage RAM used by the live variables and the path.
+    pub fn stack_overflow(&self) -> Option<(u32, u32, Vec<String>)> {
+        self.overflow("main", 0, &mut Vec::new(), &mut HashSet::new())
+    }
+
+    fn description(&self, f: &CallGraphFunction) -> String {
+        let mut s = format!("bank {}", f.bank);
+        if let Some(level) = f.level {
+            s.push_str(&format!(", level {}", level));
+        }
+        if let Some((offset, size)) = f.locals.filter(|l| l.1 > 0) {
+            s.push_str(&format!(
+                ", {} byte{} of locals at LOCAL_VARIABLES + {}",
+                size,
+                plural(size),
+                offset
+            ));
+        }
+        if f.recursive {
+            s.push_str(", recursive");
+        }
+        if f.pushes > 0 {
+            s.push_str(&format!(", pushes {} byte{}", f.pushes, plural(f.pushes)));
+        }
+        s
+    }
+
+    fn write_tree(&self, name: &str, indent: usize, seen: &mut HashSet<String>, out: &mut String) {
+        for call in self.callees(name) {
+            let Some(f) = self.function(&call.callee) else {
+                continue;
+            };
+            out.push_str(&format!(
+                "{}{} [{}]",
+                "  ".repeat(indent),
+                f.name,
+                self.description(f)
+            ));
+            if let Some(stub) = &call.stub {
+                out.push_str(&format!(" via {}", stub));
+            }
+            if seen.insert(f.name.clone()) {
+                out.push('\n');
+                self.write_tree(&f.name, indent + 1, seen, out);
+            } else {
+                out.push_str(" (see above)\n");
+            }
+        }
+    }
+
+    /// Text report: call tree from main, and worst-case stack depth
+    pub fn text(&self) -> String {
+        let mut out = format!(
+            "Call graph ({} bankswitching)\n\n",
+            self.bankswitching_scheme
+        );
+        if let Some(main) = self.function("main") {
+            out.push_str(&format!("main [{}]\n", self.description(main)));
+            let mut seen = HashSet::from(["main".to_string()]);
+            self.write_tree("main", 1, &mut seen, &mut out);
+        }
+        let uncalled: Vec<_> = self
+            .functions
+            .iter()
+            .filter(|f| f.name != "main" && f.level.is_none())
+            .map(|f| f.name.as_str())
+            .collect();
+        if !uncalled.is_empty() {
+            out.push_str(&format!(
+                "\nNot called from main: {}\n",
+                uncalled.join(", ")
+            ));
+        }
+        let (depth, path) = self.stack_depth();
+        out.push_str(&format!(
+            "\nWorst-case stack depth: {} byte{} ({}){}\n",
+            depth,
+            plural(depth),
+            path.join(" -> "),
+            if self.recursive() {
+                ", not counting recursive calls"
+            } else {
+                ""
+            }
+        ));
+        out
+    }
+
+    /// Graphviz DOT graph, with the functions grouped by bank
+    pub fn dot(&self) -> String {
+        let mut out = String::from("digraph \"call graph\" {\n\tnode [shape=box];\n");
+        let mut banks: Vec<u32> = self.functions.iter().map(|f| f.bank).collect();
+        banks.sort();
+        banks.dedup();
+        for bank in banks {
+            out.push_str(&format!(
+                "\tsubgraph cluster_bank{} {{\n\t\tlabel=\"bank {}\";\n",
+                bank, bank
+            ));
+            for f in self.functions.iter().filter(|f| f.bank == bank) {
+                let level = f.level.map_or(String::new(), |l| format!("\\nlevel {}", l));
+                out.push_str(&format!(
+                    "\t\t\"{}\" [label=\"{}{}\"];\n",
+                    f.name, f.name, level
+                ));
+            }
+            out.push_str("\t}\n");
+        }
+        for call in &self.calls {
+            out.push_str(&format!("\t\"{}\" -> \"{}\"", call.caller, call.callee));
+            if let Some(stub) = &call.stub {
+                out.push_str(&format!(" [style=dashed, label=\"{}\"]", stub));
+            }
+            out.push_str(";\n");
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+fn plural(n: u32) -> &'static str {
+    if n > 1 {
+        "s"
+    } else {
+        ""
+    }
+}
+
+/// Bytes pushed on the stack by a function (PHA and PHP not yet pulled), in the worst case
+pub fn stack_pushes(code: &str) -> u32 {
+    let mut pushed = 0i32;
+    let mut max = 0;
+    for line in code.lines() {
+        match line
+            .split_whitespace()
+            .next()
+            .map(|m| m.to_ascii_uppercase())
+        {
+            Some(m) if m == "PHA" || m == "PHP" => pushed += 1,
+            Some(m) if m == "PLA" || m == "PLP" => pushed -= 1,
+            _ => (),
+        }
+        max = max.max(pushed);
+    }
+    max as u32
+}
@@ -0,0 +1,835 @@
+/*
+    cc2600 - a subset of C compiler for the Atari 2600
+    Copyright (C) 2023-2024 Bruno STEUX
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+    Contact info: bruno.steux@gmail.com
+*/
+
+// Static cycle counting of the functions, from the final layout of the cartridge, and checking of
+// the cycle annotations found in the code:
+//   asm(";@loop == 76", 0);      every iteration of the enclosing loop takes exactly 76 cycles
+//   asm(";@block <= 2000", 0);   the code up to the next ;@end (or return) takes at most 2000 cycles
+//   asm(";@function <= 500", 0); a call to the function takes at most 500 cycles (RTS included)
+//   asm(";@end", 0);             end of a block
+//   asm(";@critical", 0);        timing-critical function, without any cycle check
+// The comparison can be ==, <= or >=. Branches and table reads of the functions with annotations
+// are checked for page crossings.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+use crate::assembler::opcodes::{cycles, decode, is_mnemonic, AddressingMode};
+use crate::assembler::Assembly;
+use crate::layout::{Bank, ItemKind};
+
+// Best and worst case number of cycles
+type Range = (u32, u32);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Kind {
+    Loop,
+    Block,
+    Function,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Comparison {
+    Exactly,
+    AtMost,
+    AtLeast,
+}
+
+struct Annotation {
+    text: String,
+    kind: Kind,
+    comparison: Comparison,
+    cycles: u32,
+    // Index of the instruction following the annotation
+    position: usize,
+}
+
+struct Instruction {
+    address: u32,
+    mnemonic: &'static str,
+    mode: AddressingMode,
+    // Operand value (target address for branches)
+    operand: u32,
+    // Operand as written in the source code
+    symbol: String,
+}
+
+struct Function {
+    name: String,
+    bank: Option<u32>,
+    instructions: Vec<Instruction>,
+    index: HashMap<u32, usize>,
+    annotations: Vec<Annotation>,
+    ends: HashSet<usize>,
+    critical: bool,
+}
+
+enum Marker {
+    Check(Annotation),
+    End,
+    Critical,
+}
+
+enum Next {
+    Instruction(usize),
+    Exit,
+}
+
+// Part of a function being measured
+struct Region<'a> {
+    function: &'a Function,
+    start: usize,
+    kind: Kind,
+}
+
+pub struct CycleChecks {
+    /// Text report: cycles taken by each function and by each annotated part of the code
+    pub report: String,
+    /// One line per annotation, with the result of the check
+    pub checks: Vec<String>,
+    /// Failed checks and invalid annotations
+    pub errors: Vec<String>,
+}
+
+struct Analysis<'a> {
+    functions: HashMap<String, Function>,
+    // Bank, address and size of the tables in ROM
+    tables: Vec<(Option<u32>, u32, u32)>,
+    // Function called by a JSR (possibly through a bankswitching stub), and the cycles taken by
+    // the stub
+    resolve: &'a dyn Fn(&str) -> Option<(String, u32)>,
+    totals: RefCell<HashMap<String, Result<Range, String>>>,
+    in_progress: RefCell<HashSet<String>>,
+}
+
+fn parse_annotation(text: &str, position: usize) -> Result<Marker, String> {
+    let mut words = text.split_whitespace();
+    let kind = match words.next() {
+        Some("loop") => Kind::Loop,
+        Some("block") => Kind::Block,
+        Some("function") => Kind::Function,
+        Some("end") => return Ok(Marker::End),
+        Some("critical") => return Ok(Marker::Critical),
+        _ => return Err(format!("Unknown cycle annotation ;@{}", text)),
+    };
+    let comparison = match words.next() {
+        Some("==") | Some("=") => Comparison::Exactly,
+        Some("<=") => Comparison::AtMost,
+        Some(">=") => Comparison::AtLeast,
+        _ => return Err(format!("Missing comparison (==, <= or >=) in ;@{}", text)),
+    };
+    let cycles = words
+        .next()
+        .and_then(|n| n.parse::<u32>().ok())
+        .ok_or(format!("Missing number of cycles in ;@{}", text))?;
+    Ok(Marker::Check(Annotation {
+        text: format!(";@{}", text),
+        kind,
+        comparison,
+        cycles,
+        position,
+    }))
+}
+
+fn parse_function(
+    name: &str,
+    bank: Option<u32>,
+    code: &str,
+    first_line: usize,
+    addresses: &HashMap<usize, (u32, &[u8])>,
+    errors: &mut Vec<String>,
+) -> Function {
+    let mut f = Function {
+        name: name.to_string(),
+        bank,
+        instructions: Vec::new(),
+        index: HashMap::new(),
+        annotations: Vec::new(),
+        ends: HashSet::new(),
+        critical: false,
+    };
+    for (i, line) in code.lines().enumerate() {
+        if let Some(text) = line.trim().strip_prefix(";@") {
+            f.critical = true;
+            match parse_annotation(text, f.instructions.len()) {
+                Ok(Marker::Check(a)) => f.annotations.push(a),
+                Ok(Marker::End) => {
+                    f.ends.insert(f.instructions.len());
+                }
+                Ok(Marker::Critical) => (),
+                Err(e) => errors.push(format!("{} in function {}", e, name)),
+            }
+        }
+        let Some(&(address, bytes)) = addresses.get(&(first_line + i)) else {
+            continue;
+        };
+        // Data lines stop the analysis of the function (alignment padding comes first)
+        let instruction = line
+            .split_whitespace()
+            .next()
+            .is_some_and(|m| is_mnemonic(&m.to_ascii_lowercase()));
+        let Some((mnemonic, mode)) = bytes
+            .first()
+            .and_then(|b| decode(*b))
+            .filter(|_| instruction)
+        else {
+            if f.instructions.is_empty() {
+                continue;
+            }
+            break;
+        };
+        let size = match mode {
+            AddressingMode::Implied | AddressingMode::Accumulator => 1,
+            AddressingMode::Absolute
+            | AddressingMode::AbsoluteX
+            | AddressingMode::AbsoluteY
+            | AddressingMode::Indirect => 3,
+            _ => 2,
+        };
+        if bytes.len() != size {
+            if f.instructions.is_empty() {
+                continue;
+            }
+            break;
+        }
+        let operand = match bytes.len() {
+            2 if mode == AddressingMode::Relative => {
+                (address as i32 + 2 + bytes[1] as i8 as i32) as u32 & 0xffff
+            }
+            2 => bytes[1] as u32,
+            3 => bytes[1] as u32 | (bytes[2] as u32) << 8,
+            _ => 0,
+        };
+        let symbol = line.split_whitespace().nth(1).unwrap_or_default();
+        f.index.insert(address, f.instructions.len());
+        f.instructions.push(Instruction {
+            address,
+            mnemonic,
+            mode,
+            operand,
+            symbol: symbol.to_string(),
+        });
+    }
+    f
+}
+
+fn crosses_page(a: u32, b: u32) -> bool {
+    a & 0xff00 != b & 0xff00
+}
+
+impl Analysis<'_> {
+    // Cycles of a call to a function, from its first instruction to its RTS
+    fn total(&self, name: &str) -> Result<Range, String> {
+        if let Some(t) = self.totals.borrow().get(name) {
+            return t.clone();
+        }
+        let Some(f) = self.functions.get(name) else {
+            return Err(format!("call to {} (unknown number of cycles)", name));
+        };
+        if !self.in_progress.borrow_mut().insert(name.to_string()) {
+            return Err(format!("recursive call to {}", name));
+        }
+        let region = Region {
+            function: f,
+            start: 0,
+            kind: Kind::Function,
+        };
+        let t = self
+            .measure(&region, &mut HashSet::new())
+            .and_then(|r| r.ok_or(format!("{} never returns", name)));
+        self.in_progress.borrow_mut().remove(name);
+        self.totals.borrow_mut().insert(name.to_string(), t.clone());
+        t
+    }
+
+    // Instructions that may follow the given one, with the number of cycles it takes to get there
+    fn successors(&self, f: &Function, n: usize) -> Result<Vec<(Next, u32, u32)>, String> {
+        let i = &f.instructions[n];
+        let target = |address: u32| {
+            f.index
+                .get(&address)
+                .copied()
+                .ok_or(format!("jump out of {} at ${:04x}", f.name, i.address))
+        };
+        let call = |extra: u32| -> Result<Range, String> {
+            let (callee, stub) = (self.resolve)(&i.symbol)
+                .ok_or(format!("call to {} (unknown number of cycles)", i.symbol))?;
+            let (best, worst) = self.total(&callee)?;
+            Ok((best + stub + extra, worst + stub + extra))
+        };
+        Ok(match (i.mnemonic, i.mode) {
+            (_, AddressingMode::Relative) => {
+                let taken = if crosses_page(i.address + 2, i.operand) {
+                    4
+                } else {
+                    3
+                };
+                vec![
+                    (Next::Instruction(n + 1), 2, 2),
+                    (Next::Instruction(target(i.operand)?), taken, taken),
+                ]
+            }
+            ("jmp", AddressingMode::Absolute) => match f.index.get(&i.operand) {
+                Some(&t) => vec![(Next::Instruction(t), 3, 3)],
+                None => {
+                    // Tail call
+                    let (best, worst) = call(3)?;
+                    vec![(Next::Exit, best, worst)]
+                }
+            },
+            ("jmp", _) => return Err(format!("indirect jump at ${:04x}", i.address)),
+            ("jsr", _) => {
+                let (best, worst) = call(6)?;
+                vec![(Next::Instruction(n + 1), best, worst)]
+            }
+            ("rts", _) | ("rti", _) | ("brk", _) => {
+                let (c, _) = cycles(i.mnemonic, i.mode);
+                vec![(Next::Exit, c, c)]
+            }
+            (m, mode) => {
+                let (c, penalty) = cycles(m, mode);
+                // Indexing a table that doesn't cross a page boundary takes no extra cycle
+                let aligned = matches!(mode, AddressingMode::AbsoluteX | AddressingMode::AbsoluteY)
+                    && self.tables.iter().any(|&(bank, address, size)| {
+                        bank == f.bank
+                            && (address..address + size).contains(&i.operand)
+                            && !crosses_page(i.operand, address + size - 1)
+                    });
+                let extra = if penalty && !aligned { 1 } else { 0 };
+                vec![(Next::Instruction(n + 1), c, c + extra)]
+            }
+        })
+    }
+
+    fn path(
+        &self,
+        r: &Region,
+        n: usize,
+        memo: &mut HashMap<usize, Option<Range>>,
+        stack: &mut HashSet<usize>,
+        visited: &mut HashSet<usize>,
+    ) -> Result<Option<Range>, String> {
+        let f = r.function;
+        if n == r.start && !stack.is_empty() {
+            return match r.kind {
+                Kind::Loop => Ok(Some((0, 0))),
+                _ => Err(format!(
+                    "loop in {} at ${:04x}",
+                    f.name,
+                    f.instructions.get(n).map_or(0, |i| i.address)
+                )),
+            };
+        }
+        if n >= f.instructions.len() || (r.kind != Kind::Function && f.ends.contains(&n)) {
+            return Ok(if r.kind == Kind::Loop {
+                None
+            } else {
+                Some((0, 0))
+            });
+        }
+        if let Some(m) = memo.get(&n) {
+            return Ok(*m);
+        }
+        if !stack.insert(n) {
+            return Err(format!(
+                "loop in {} at ${:04x}",
+                f.name, f.instructions[n].address
+            ));
+        }
+        visited.insert(n);
+        let mut ret: Option<Range> = None;
+        for (next, best, worst) in self.successors(f, n)? {
+            let sub = match next {
+                Next::Instruction(m) => self.path(r, m, memo, stack, visited)?,
+                Next::Exit if r.kind == Kind::Loop => None,
+                Next::Exit => Some((0, 0)),
+            };
+            if let Some((b, w)) = sub {
+                ret = Some(match ret {
+                    Some((rb, rw)) => (rb.min(b + best), rw.max(w + worst)),
+                    None => (b + best, w + worst),
+                });
+            }
+        }
+        stack.remove(&n);
+        memo.insert(n, ret);
+        Ok(ret)
+    }
+
+    fn measure(&self, r: &Region, visited: &mut HashSet<usize>) -> Result<Option<Range>, String> {
+        self.path(
+            r,
+            r.start,
+            &mut HashMap::new(),
+            &mut HashSet::new(),
+            visited,
+        )
+    }
+}
+
+fn describe(range: Result<Range, String>) -> String {
+    match range {
+        Ok((best, worst)) if best == worst => format!("{} cycles", best),
+        Ok((best, worst)) => format!("{} to {} cycles", best, worst),
+        Err(e) => format!("unbounded ({})", e),
+    }
+}
+
+// Address and bytes of each line of the layout source
+fn line_addresses(assembly: &Assembly) -> HashMap<usize, (u32, &[u8])> {
+    assembly
+        .lines
+        .iter()
+        .map(|l| {
+            (
+                l.line,
+                (
+                    l.address as u32 & 0xffff,
+                    &l.bytes[..l.size.min(l.bytes.len())],
+                ),
+            )
+        })
+        .collect()
+}
+
+/// Branch or indexed table read of a timing-critical function crossing a page boundary
+pub struct PageCrossing {
+    pub description: String,
+    /// Bank and item indexes of the function or table to realign, if it fits in a page
+    pub item: Option<(usize, usize)>,
+}
+
+/// Looks for the branches of the timing-critical functions (with cycle annotations) that go to
+/// another page, and for the tables they read with X or Y indexing that cross a page boundary
+pub fn page_crossings(banks: &[Bank], assembly: &Assembly) -> Vec<PageCrossing> {
+    let addresses = line_addresses(assembly);
+    let fits = |b: usize, i: usize, start: u32| {
+        let item = &banks[b].items[i];
+        (item.address + item.size - start <= 256).then_some((b, i))
+    };
+    let mut crossings = Vec::new();
+    for (b, bank) in banks.iter().enumerate() {
+        for (i, item) in bank.items.iter().enumerate() {
+            if item.kind != ItemKind::Function {
+                continue;
+            }
+            let f = parse_function(
+                &item.name,
+                bank.bank,
+                &item.code,
+                item.line,
+                &addresses,
+                &mut Vec::new(),
+            );
+            if !f.critical {
+                continue;
+            }
+            let start = f.instructions.first().map_or(item.address, |i| i.address);
+            let mut tables = HashSet::new();
+            for ins in &f.instructions {
+                if ins.mode == AddressingMode::Relative {
+                    if crosses_page(ins.address + 2, ins.operand) {
+                        crossings.push(PageCrossing {
+                            description: format!(
+                                "{} at ${:04x} in {} branches to another page (${:04x})",
+                                ins.mnemonic.to_uppercase(),
+                                ins.address,
+                                f.name,
+                                ins.operand
+                            ),
+                            item: fits(b, i, start),
+                        });
+                    }
+                    continue;
+                }
+                if !matches!(
+                    ins.mode,
+                    AddressingMode::AbsoluteX | AddressingMode::AbsoluteY
+                ) || !cycles(ins.mnemonic, ins.mode).1
+                {
+                    continue;
+                }
+                let name: String = ins
+                    .symbol
+                    .chars()
+                    .take_while(|c| c.is_alphanumeric() || *c == '_')
+                    .collect();
+                if !tables.insert(name.clone()) {
+                    continue;
+                }
+                // Tables of the same bank first, then of the other banks
+                let table = banks
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(tb, bank)| {
+                        bank.items
+                            .iter()
+                            .enumerate()
+                            .map(move |(ti, t)| (tb, ti, t))
+                    })
+                    .filter(|(_, _, t)| t.kind == ItemKind::Table && t.name == name)
+                    .min_by_key(|(tb, _, _)| *tb != b);
+                let (Some((tb, ti, t)), Some(symbol)) = (table, assembly.symbol(&name)) else {
+                    continue;
+                };
+                let address = symbol.value as u32 & 0xffff;
+                let end = t.address + t.size;
+                if end > address && crosses_page(address, end - 1) {
+                    crossings.push(PageCrossing {
+                        description: format!(
+                            "table {} (${:04x}, {} bytes) read by {} at ${:04x} in {} crosses a page boundary",
+                            name,
+                            address,
+                            end - address,
+                            ins.mnemonic.to_uppercase(),
+                            ins.address,
+                            f.name
+                        ),
+                        item: fits(tb, ti, address),
+                    });
+                }
+            }
+        }
+    }
+    crossings
+}
+
+/// Computes the number of cycles taken by every function from the final layout, and checks the
+/// cycle annotations. Branches going to another page take one more cycle.
+pub fn check_cycles(
+    banks: &[Bank],
+    assembly: &Assembly,
+    resolve: &dyn Fn(&str) -> Option<(String, u32)>,
+) -> CycleChecks {
+    let addresses = line_addresses(assembly);
+    let mut errors = Vec::new();
+    let mut functions = HashMap::new();
+    let mut names = Vec::new();
+    let mut tables = Vec::new();
+    for bank in banks {
+        for item in &bank.items {
+            match item.kind {
+                ItemKind::Function => {
+                    let f = parse_function(
+                        &item.name,
+                        bank.bank,
+                        &item.code,
+                        item.line,
+                        &addresses,
+                        &mut errors,
+                    );
+                    names.push(item.name.clone());
+                    functions.insert(item.name.clone(), f);
+                }
+                ItemKind::Table => tables.push((bank.bank, item.address, item.size)),
+                _ => (),
+            }
+        }
+    }
+    let analysis = Analysis {
+        functions,
+        tables,
+        resolve,
+        totals: RefCell::new(HashMap::new()),
+        in_progress: RefCell::new(HashSet::new()),
+    };
+
+    let mut report = String::from("Cycles taken by the functions (JSR and RTS included)\n\n");
+    for name in &names {
+        let total = analysis
+            .total(name)
+            .map(|(best, worst)| (best + 6, worst + 6));
+        report.push_str(&format!("{}: {}\n", name, describe(total)));
+    }
+
+    let mut checks = Vec::new();
+    for name in &names {
+        let f = &analysis.functions[name];
+        for a in &f.annotations {
+            let region = Region {
+                function: f,
+                start: if a.kind == Kind::Function {
+                    0
+                } else {
+                    a.position
+                },
+                kind: a.kind,
+            };
+            let mut visited = HashSet::new();
+            let measured = analysis.measure(&region, &mut visited).and_then(|r| {
+                r.ok_or(match a.kind {
+                    Kind::Loop => "not in a loop".to_string(),
+                    _ => "never ends".to_string(),
+                })
+            });
+            let location = format!(
+                "{} in {} at ${:04x}",
+                a.text,
+                f.name,
+                f.instructions.get(region.start).map_or(0, |i| i.address)
+            );
+            let per = if a.kind == Kind::Loop {
+                " per iteration"
+            } else {
+                ""
+            };
+            let ok = match measured {
+                Ok((best, worst)) => match a.comparison {
+                    Comparison::Exactly => best == a.cycles && worst == a.cycles,
+                    Comparison::AtMost => worst <= a.cycles,
+                    Comparison::AtLeast => best >= a.cycles,
+                },
+                Err(_) => false,
+            };
+            let check = format!("{}: {}{}", location, describe(measured.clone()), per);
+            if !ok {
+                errors.push(format!("Cycle check failed. {}", check));
+            }
+            report.push_str(&format!(
+                "\n{}, {}\n",
+                check,
+                if ok { "OK" } else { "FAILED" }
+            ));
+            let mut branches: Vec<_> = visited
+                .iter()
+                .map(|&n| &f.instructions[n])
+                .filter(|i| {
+                    i.mode == AddressingMode::Relative && crosses_page(i.address + 2, i.operand)
+                })
+                .collect();
+            branches.sort_by_key(|i| i.address);
+            for i in branches {
+                report.push_str(&format!(
+                    "  {} at ${:04x} crosses a page boundary (4 cycles when taken)\n",
+                    i.mnemonic.to_uppercase(),
+                    i.address
+                ));
+            }
+            checks.push(check);
+        }
+    }
+    CycleChecks {
+        report,
+        checks,
+        errors,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    const KERNEL: &str = "kernel\tSUBROUTINE
+\t;@loop == 11
+.loop
+\tLDA table,X
+\tDEX
+\tBNE .loop
+\t;@end
+\tJSR Callother
+\tRTS
+table
+\t.byte 1, 2, 3";
+
+    const OTHER: &str = "other\tSUBROUTINE
+\tNOP
+\tRTS
+Callother = other";
+
+    // BNE at $F1FF, going back to $F1FC
+    const CROSS: &str = "\tORG $F1FC
+cross\tSUBROUTINE
+.loop
+\tDEX
+\tNOP
+\tNOP
+\tBNE .loop
+\tRTS";
+
+    // Assembles the given pieces of code from $F000 (the symbols not defined get an absolute
+    // address), and parses each of them as a function
+    fn functions(pieces: &[&str], errors: &mut Vec<String>) -> HashMap<String, Function> {
+        let mut source = String::from("\tPROCESSOR 6502\n\tORG $F000\n");
+        let mut first_lines = Vec::new();
+        for piece in pieces {
+            first_lines.push(source.lines().count() + 1);
+            source.push_str(piece);
+            source.push('\n');
+        }
+        let assembly = Assembler::new()
+            .allow_undefined(true)
+            .assemble("test.a", &source)
+            .unwrap();
+        let addresses = line_addresses(&assembly);
+        pieces
+            .iter()
+            .zip(first_lines)
+            .map(|(piece, line)| {
+                let name = piece
+                    .lines()
+                    .find_map(|l| l.strip_suffix("\tSUBROUTINE"))
+                    .unwrap();
+                let f = parse_function(name, None, piece, line, &addresses, errors);
+                (name.to_string(), f)
+            })
+            .collect()
+    }
+
+    fn resolve(symbol: &str) -> Option<(String, u32)> {
+        // 10 cycles of bankswitching stub
+        (symbol == "Callother").then(|| ("other".to_string(), 10))
+    }
+
+    fn analysis(
+        functions: HashMap<String, Function>,
+        tables: Vec<(Option<u32>, u32, u32)>,
+    ) -> Analysis<'static> {
+        Analysis {
+            functions,
+            tables,
+            resolve: &resolve,
+            totals: RefCell::new(HashMap::new()),
+            in_progress: RefCell::new(HashSet::new()),
+        }
+    }
+
+    fn steps(next: &[(Next, u32, u32)]) -> Vec<(Option<usize>, u32, u32)> {
+        next.iter()
+            .map(|(n, best, worst)| match n {
+                Next::Instruction(i) => (Some(*i), *best, *worst),
+                Next::Exit => (None, *best, *worst),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parse_function_reads_instructions_and_annotations() {
+        let mut errors = Vec::new();
+        let functions = functions(&[KERNEL], &mut errors);
+        let f = &functions["kernel"];
+        assert!(errors.is_empty());
+        assert!(f.critical);
+        // The table stops the function
+        let instructions: Vec<(u32, &str, &str)> = f
+            .instructions
+            .iter()
+            .map(|i| (i.address, i.mnemonic, i.symbol.as_str()))
+            .collect();
+        assert_eq!(
+            instructions,
+            [
+                (0xf000, "lda", "table,X"),
+                (0xf003, "dex", ""),
+                (0xf004, "bne", ".loop"),
+                (0xf006, "jsr", "Callother"),
+                (0xf009, "rts", ""),
+            ]
+        );
+        assert_eq!(f.instructions[0].mode, AddressingMode::AbsoluteX);
+        assert_eq!(f.instructions[0].operand, 0xf00a);
+        assert_eq!(f.instructions[2].operand, 0xf000);
+        assert_eq!(f.index[&0xf006], 3);
+        assert_eq!(f.annotations.len(), 1);
+        let a = &f.annotations[0];
+        assert_eq!(a.text, ";@loop == 11");
+        assert_eq!(
+            (a.kind, a.comparison, a.cycles, a.position),
+            (Kind::Loop, Comparison::Exactly, 11, 0)
+        );
+        assert_eq!(f.ends, HashSet::from([3]));
+    }
+
+    #[test]
+    fn parse_function_reports_bad_annotations() {
+        let mut errors = Vec::new();
+        let code = "bad\tSUBROUTINE\n\t;@loop 11\n\t;@block <= many\n\t;@wait\n\tRTS";
+        let f = &functions(&[code], &mut errors)["bad"];
+        assert!(f.critical);
+        assert!(f.annotations.is_empty());
+        assert_eq!(
+            errors,
+            [
+                "Missing comparison (==, <= or >=) in ;@loop 11 in function bad",
+                "Missing number of cycles in ;@block <= many in function bad",
+                "Unknown cycle annotation ;@wait in function bad",
+            ]
+        );
+    }
+
+    #[test]
+    fn successors_count_branches_and_calls() {
+        let mut errors = Vec::new();
+        let a = analysis(functions(&[KERNEL, OTHER, CROSS], &mut errors), Vec::new());
+        let kernel = &a.functions["kernel"];
+        // Not taken, or taken in the same page
+        assert_eq!(
+            steps(&a.successors(kernel, 2).unwrap()),
+            [(Some(3), 2, 2), (Some(0), 3, 3)]
+        );
+        // JSR (6), stub (10), NOP and RTS of other (8)
+        assert_eq!(
+            steps(&a.successors(kernel, 3).unwrap()),
+            [(Some(4), 24, 24)]
+        );
+        assert_eq!(steps(&a.successors(kernel, 4).unwrap()), [(None, 6, 6)]);
+        // Taken to another page
+        let cross = &a.functions["cross"];
+        assert_eq!(cross.instructions[3].address, 0xf1ff);
+        assert_eq!(
+            steps(&a.successors(cross, 3).unwrap()),
+            [(Some(4), 2, 2), (Some(0), 4, 4)]
+        );
+    }
+
+    #[test]
+    fn successors_count_indexed_table_reads() {
+        let mut errors = Vec::new();
+        // Unknown table: may cross a page boundary
+        let a = analysis(functions(&[KERNEL, OTHER], &mut errors), Vec::new());
+        let kernel = &a.functions["kernel"];
+        assert_eq!(steps(&a.successors(kernel, 0).unwrap()), [(Some(1), 4, 5)]);
+        // Table within a page
+        let a = analysis(
+            functions(&[KERNEL, OTHER], &mut errors),
+            vec![(None, 0xf00a, 3)],
+        );
+        let kernel = &a.functions["kernel"];
+        assert_eq!(steps(&a.successors(kernel, 0).unwrap()), [(Some(1), 4, 4)]);
+        // Table of another bank
+        let a = analysis(
+            functions(&[KERNEL, OTHER], &mut errors),
+            vec![(Some(1), 0xf00a, 3)],
+        );
+        let kernel = &a.functions["kernel"];
+        assert_eq!(steps(&a.successors(kernel, 0).unwrap()), [(Some(1), 4, 5)]);
+    }
+
+    #[test]
+    fn successors_of_unknown_calls() {
+        let mut errors = Vec::new();
+        let a = analysis(functions(&[KERNEL], &mut errors), Vec::new());
+        let kernel = &a.functions["kernel"];
+        assert_eq!(
+            a.successors(kernel, 3).err().unwrap(),
+            "call to other (unknown number of cycles)"
+        );
+    }
+}
@@ -0,0 +1,606 @@
+/*
+    cc2600 - a subset of C compiler for the Atari 2600
+    Copyright (C) 2023-2024 Bruno STEUX
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+    Contact info: bruno.steux@gmail.com
+*/
+
+// DWARF debug information (line numbers, functions and variables), in an ELF file, for source
+// level debugging with Gopher2600.
+//
+// Since the banks share the same 6502 addresses, code addresses are the RORG addresses with the
+// bank number in the upper 16 bits. Variables use their 6502 read address. A program header per
+// bank gives its address and its offset in the cartridge image (physical address), so that the
+// code addresses can be mapped to the image.
+//
+// C source lines are known from the `;(l.N)` comments inserted into the assembler code before
+// each statement, and the address of the code of each assembler line from the assembler.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use cc6502::compile::VariableType;
+
+use crate::assembler::{AsmSymbol, LineAddress};
+use crate::build::{CartridgeInfo, DebugVariable};
+
+// Tags, attributes and forms
+const DW_TAG_ARRAY_TYPE: u8 = 0x01;
+const DW_TAG_FORMAL_PARAMETER: u8 = 0x05;
+const DW_TAG_POINTER_TYPE: u8 = 0x0f;
+const DW_TAG_COMPILE_UNIT: u8 = 0x11;
+const DW_TAG_SUBRANGE_TYPE: u8 = 0x21;
+const DW_TAG_BASE_TYPE: u8 = 0x24;
+const DW_TAG_SUBPROGRAM: u8 = 0x2e;
+const DW_TAG_VARIABLE: u8 = 0x34;
+const DW_AT_LOCATION: u8 = 0x02;
+const DW_AT_NAME: u8 = 0x03;
+const DW_AT_BYTE_SIZE: u8 = 0x0b;
+const DW_AT_STMT_LIST: u8 = 0x10;
+const DW_AT_LOW_PC: u8 = 0x11;
+const DW_AT_HIGH_PC: u8 = 0x12;
+const DW_AT_LANGUAGE: u8 = 0x13;
+const DW_AT_COMP_DIR: u8 = 0x1b;
+const DW_AT_PRODUCER: u8 = 0x25;
+const DW_AT_UPPER_BOUND: u8 = 0x2f;
+const DW_AT_ENCODING: u8 = 0x3e;
+const DW_AT_EXTERNAL: u8 = 0x3f;
+const DW_AT_TYPE: u8 = 0x49;
+const DW_FORM_ADDR: u8 = 0x01;
+const DW_FORM_DATA2: u8 = 0x05;
+const DW_FORM_DATA4: u8 = 0x06;
+const DW_FORM_STRING: u8 = 0x08;
+const DW_FORM_DATA1: u8 = 0x0b;
+const DW_FORM_UDATA: u8 = 0x0f;
+const DW_FORM_REF4: u8 = 0x13;
+const DW_FORM_SEC_OFFSET: u8 = 0x17;
+const DW_FORM_EXPRLOC: u8 = 0x18;
+const DW_FORM_FLAG_PRESENT: u8 = 0x19;
+
+// Abbreviation codes
+const ABBREV_COMPILE_UNIT: u8 = 1;
+const ABBREV_BASE_TYPE: u8 = 2;
+const ABBREV_POINTER_TYPE: u8 = 3;
+const ABBREV_ARRAY_TYPE: u8 = 4;
+const ABBREV_SUBRANGE_TYPE: u8 = 5;
+const ABBREV_SUBPROGRAM: u8 = 6;
+const ABBREV_VARIABLE: u8 = 7;
+const ABBREV_FORMAL_PARAMETER: u8 = 8;
+
+const DW_ATE_SIGNED: u8 = 0x05;
+const DW_ATE_SIGNED_CHAR: u8 = 0x06;
+const DW_ATE_UNSIGNED: u8 = 0x07;
+const DW_ATE_UNSIGNED_CHAR: u8 = 0x08;
+const DW_LANG_C89: u16 = 0x0001;
+const DW_OP_ADDR: u8 = 0x03;
+
+// ELF machine number of the 6502 (as used by llvm-mos)
+const EM_MOS: u16 = 6502;
+
+fn uleb(buf: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+fn sleb(buf: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+fn string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(s.as_bytes());
+    buf.push(0);
+}
+
+// Abbreviation code, tag, children and (attribute, form) pairs
+type Abbreviation = (u8, u8, bool, &'static [(u8, u8)]);
+
+fn abbreviations() -> Vec<u8> {
+    let table: [Abbreviation; 8] = [
+        (
+            ABBREV_COMPILE_UNIT,
+            DW_TAG_COMPILE_UNIT,
+            true,
+            &[
+                (DW_AT_PRODUCER, DW_FORM_STRING),
+                (DW_AT_LANGUAGE, DW_FORM_DATA2),
+                (DW_AT_NAME, DW_FORM_STRING),
+                (DW_AT_COMP_DIR, DW_FORM_STRING),
+                (DW_AT_STMT_LIST, DW_FORM_SEC_OFFSET),
+                (DW_AT_LOW_PC, DW_FORM_ADDR),
+            ],
+        ),
+        (
+            ABBREV_BASE_TYPE,
+            DW_TAG_BASE_TYPE,
+            false,
+            &[
+                (DW_AT_NAME, DW_FORM_STRING),
+                (DW_AT_ENCODING, DW_FORM_DATA1),
+                (DW_AT_BYTE_SIZE, DW_FORM_DATA1),
+            ],
+        ),
+        (
+            ABBREV_POINTER_TYPE,
+            DW_TAG_POINTER_TYPE,
+            false,
+            &[(DW_AT_BYTE_SIZE, DW_FORM_DATA1), (DW_AT_TYPE, DW_FORM_REF4)],
+        ),
+        (
+            ABBREV_ARRAY_TYPE,
+            DW_TAG_ARRAY_TYPE,
+            true,
+            &[(DW_AT_TYPE, DW_FORM_REF4)],
+        ),
+        (
+            ABBREV_SUBRANGE_TYPE,
+            DW_TAG_SUBRANGE_TYPE,
+            false,
+            &[(DW_AT_UPPER_BOUND, DW_FORM_UDATA)],
+        ),
+        (
+            ABBREV_SUBPROGRAM,
+            DW_TAG_SUBPROGRAM,
+            true,
+            &[
+                (DW_AT_NAME, DW_FORM_STRING),
+                (DW_AT_LOW_PC, DW_FORM_ADDR),
+                (DW_AT_HIGH_PC, DW_FORM_DATA4),
+                (DW_AT_EXTERNAL, DW_FORM_FLAG_PRESENT),
+            ],
+        ),
+        (
+            ABBREV_VARIABLE,
+            DW_TAG_VARIABLE,
+            false,
+            &[
+                (DW_AT_NAME, DW_FORM_STRING),
+                (DW_AT_TYPE, DW_FORM_REF4),
+                (DW_AT_LOCATION, DW_FORM_EXPRLOC),
+            ],
+        ),
+        (
+            ABBREV_FORMAL_PARAMETER,
+            DW_TAG_FORMAL_PARAMETER,
+            false,
+            &[
+                (DW_AT_NAME, DW_FORM_STRING),
+                (DW_AT_TYPE, DW_FORM_REF4),
+                (DW_AT_LOCATION, DW_FORM_EXPRLOC),
+            ],
+        ),
+    ];
+    let mut buf = Vec::new();
+    for (code, tag, children, attributes) in table {
+        uleb(&mut buf, code as u64);
+        uleb(&mut buf, tag as u64);
+        buf.push(children as u8);
+        for (attribute, form) in attributes {
+            uleb(&mut buf, *attribute as u64);
+            uleb(&mut buf, *form as u64);
+        }
+        buf.extend_from_slice(&[0, 0]);
+    }
+    buf.push(0);
+    buf
+}
+
+// Offsets of the type entries shared by all the variables
+struct Types {
+    char: [u32; 2],
+    short: [u32; 2],
+    char_ptr: u32,
+    char_ptr_ptr: u32,
+    short_ptr: u32,
+}
+
+// Debugging information entries of the compile unit
+struct Entries {
+    buf: Vec<u8>,
+}
+
+impl Entries {
+    fn offset(&self) -> u32 {
+        self.buf.len() as u32
+    }
+
+    fn base_type(&mut self, name: &str, encoding: u8, size: u8) -> u32 {
+        let offset = self.offset();
+        self.buf.push(ABBREV_BASE_TYPE);
+        string(&mut self.buf, name);
+        self.buf.extend_from_slice(&[encoding, size]);
+        offset
+    }
+
+    fn pointer_type(&mut self, to: u32) -> u32 {
+        let offset = self.offset();
+        self.buf.extend_from_slice(&[ABBREV_POINTER_TYPE, 2]);
+        self.buf.extend_from_slice(&to.to_le_bytes());
+        offset
+    }
+
+    fn array_type(&mut self, of: u32, size: usize) -> u32 {
+        let offset = self.offset();
+        self.buf.push(ABBREV_ARRAY_TYPE);
+        self.buf.extend_from_slice(&of.to_le_bytes());
+        self.buf.push(ABBREV_SUBRANGE_TYPE);
+        uleb(&mut self.buf, size as u64 - 1);
+        self.buf.push(0);
+        offset
+    }
+
+    fn variable(&mut self, types: &Types, v: &DebugVariable, address: u32) {
+        let signed = v.signed as usize;
+        let array = v.size > 1;
+        let t = match v.var_type {
+            VariableType::Char => types.char[signed],
+            VariableType::Short => types.short[signed],
+            VariableType::CharPtr if array => self.array_type(types.char[signed], v.size),
+            VariableType::CharPtr => types.char_ptr,
+            VariableType::CharPtrPtr if array => self.array_type(types.char_ptr, v.size),
+            VariableType::CharPtrPtr => types.char_ptr_ptr,
+            VariableType::ShortPtr if array => self.array_type(types.short[signed], v.size),
+            VariableType::ShortPtr => types.short_ptr,
+        };
+        self.buf.push(if v.parameter {
+            ABBREV_FORMAL_PARAMETER
+        } else {
+            ABBREV_VARIABLE
+        });
+        string(&mut self.buf, &v.name);
+        self.buf.extend_from_slice(&t.to_le_bytes());
+        self.buf.extend_from_slice(&[5, DW_OP_ADDR]);
+        self.buf.extend_from_slice(&address.to_le_bytes());
+    }
+}
+
+// Row of the line number table
+struct Row {
+    address: u32,
+    size: u32,
+    file: usize,
+    line: u32,
+}
+
+fn collapse(s: &str) -> String {
+    s.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+// Line number program, with a sequence per bank
+fn line_program(files: &[String], sequences: &[Vec<Row>]) -> Vec<u8> {
+    let mut header = vec![1, 1, 1, (-5i8) as u8, 14, 13];
+    header.extend_from_slice(&[0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1]);
+    header.push(0); // No include directories
+    for f in files {
+        string(&mut header, f);
+        header.extend_from_slice(&[0, 0, 0]);
+    }
+    header.push(0);
+
+    let mut program = Vec::new();
+    let set_address = |program: &mut Vec<u8>, address: u32| {
+        program.extend_from_slice(&[0, 5, 2]);
+        program.extend_from_slice(&address.to_le_bytes());
+    };
+    for sequence in sequences {
+        let (mut file, mut line) = (1, 1);
+        for row in sequence {
+            set_address(&mut program, row.address);
+            if row.file != file {
+                program.push(4);
+                uleb(&mut program, row.file as u64);
+                file = row.file;
+            }
+            if row.line != line {
+                program.push(3);
+                sleb(&mut program, row.line as i64 - line as i64);
+                line = row.line;
+            }
+            program.push(1);
+        }
+        if let Some(last) = sequence.last() {
+            set_address(&mut program, last.address + last.size);
+            program.extend_from_slice(&[0, 1, 1]);
+        }
+    }
+
+    let mut buf = Vec::new();
+    let unit_length = 2 + 4 + header.len() + program.len();
+    buf.extend_from_slice(&(unit_length as u32).to_le_bytes());
+    buf.extend_from_slice(&4u16.to_le_bytes());
+    buf.extend_from_slice(&(header.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&header);
+    buf.extend_from_slice(&program);
+    buf
+}
+
+// Bank of the program headers: address of its first byte, offset in the image and size
+struct Segment {
+    address: u32,
+    org: u32,
+    size: u32,
+}
+
+// ELF file with the given sections and program headers
+fn elf(sections: &[(&str, Vec<u8>)], segments: &[Segment]) -> Vec<u8> {
+    let mut shstrtab = vec![0];
+    let mut names = Vec::new();
+    for (name, _) in sections {
+        names.push(shstrtab.len() as u32);
+        string(&mut shstrtab, name);
+    }
+    let shstrtab_name = shstrtab.len() as u32;
+    string(&mut shstrtab, ".shstrtab");
+
+    let start = 52 + 32 * segments.len() as u32;
+    let mut data = Vec::new();
+    let mut headers = vec![0u8; 40];
+    let mut section_header = |name: u32, kind: u32, content: &[u8], data: &mut Vec<u8>| {
+        let offset = start + data.len() as u32;
+        data.extend_from_slice(content);
+        for field in [name, kind, 0, 0, offset, content.len() as u32, 0, 0, 1, 0] {
+            headers.extend_from_slice(&field.to_le_bytes());
+        }
+    };
+    for ((_, content), name) in sections.iter().zip(names) {
+        section_header(name, 1, content, &mut data);
+    }
+    section_header(shstrtab_name, 3, &shstrtab, &mut data);
+    let nb_sections = sections.len() as u16 + 2;
+
+    let mut buf = vec![0x7f, b'E', b'L', b'F', 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    buf.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+    buf.extend_from_slice(&EM_MOS.to_le_bytes());
+    buf.extend_from_slice(&1u32.to_le_bytes());
+    buf.extend_from_slice(&0u32.to_le_bytes()); // Entry
+    let phoff = if segments.is_empty() { 0u32 } else { 52 };
+    buf.extend_from_slice(&phoff.to_le_bytes());
+    buf.extend_from_slice(&(start + data.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&0u32.to_le_bytes());
+    let nb_segments = segments.len() as u16;
+    for field in [52, 32, nb_segments, 40, nb_sections, nb_sections - 1] {
+        buf.extend_from_slice(&field.to_le_bytes());
+    }
+    // PT_LOAD program headers, without content in the file
+    for s in segments {
+        for field in [1, 0, s.address, s.org, 0, s.size, 5, 1] {
+            buf.extend_from_slice(&field.to_le_bytes());
+        }
+    }
+    buf.extend_from_slice(&data);
+    buf.extend_from_slice(&headers);
+    buf
+}
+
+// Path of an included file, found as the preprocessor does (in the current directory, then in
+// the include directories)
+fn included_file(file: &str, include_directories: &[String]) -> String {
+    if !Path::new(file).exists() {
+        for d in include_directories {
+            let path = format!("{}/{}", d, file);
+            if Path::new(&path).exists() {
+                return path;
+            }
+        }
+    }
+    file.to_string()
+}
+
+/// ELF file with the DWARF debug information of the cartridge. `source` is the assembler code,
+/// `lines` and `symbols` the result of its assembly.
+pub fn debug_file(
+    source: &str,
+    lines: &[LineAddress],
+    symbols: &[AsmSymbol],
+    info: &CartridgeInfo,
+    name: &str,
+    comp_dir: &str,
+    include_directories: &[String],
+) -> Vec<u8> {
+    // C source line of each assembler line
+    let mut by_text = HashMap::new();
+    for (file, line, text) in &info.source_lines {
+        by_text
+            .entry((*line, collapse(text)))
+            .or_insert((file.as_str(), *line));
+    }
+    let mut files: Vec<String> = vec![name.to_string()];
+    // Names of the files as included, in the same order
+    let mut included: Vec<&str> = vec![name];
+    let mut c_lines = vec![None; source.lines().count() + 2];
+    let mut current = None;
+    for (i, l) in source.lines().enumerate() {
+        if let Some(marker) = l.strip_prefix(";(l.") {
+            let (n, text) = marker.split_once(')').unwrap_or((marker, ""));
+            let n = n.parse::<u32>().unwrap_or_default();
+            current = by_text
+                .get(&(n, collapse(text)))
+                .map(|(file, line)| {
+                    let index = match included.iter().position(|f| f == file) {
+                        Some(index) => index,
+                        None => {
+                            included.push(file);
+                            files.push(included_file(file, include_directories));
+                            files.len() - 1
+                        }
+                    };
+                    (index + 1, *line)
+                })
+                .or(Some((1, n)));
+        } else if l.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            || l.split_whitespace()
+                .next()
+                .is_some_and(|w| w.eq_ignore_ascii_case("ORG"))
+        {
+            // Start of a function, a table or a bankswitching stub
+            current = None;
+        } else if l.starts_with(".ifend") || l.starts_with(".else") {
+            // The code after an if (or its else part) is not in the if body: it's either the next
+            // statement, with its own line, or the return at the end of the function
+            current = None;
+        }
+        c_lines[i + 1] = current;
+    }
+
+    // Line number table
+    let mut banks = info.banks.clone();
+    banks.sort_by_key(|(_, org)| *org);
+    let bank_of = |org: i64| {
+        banks
+            .iter()
+            .rev()
+            .find(|(_, o)| *o as i64 <= org)
+            .map_or(0, |(b, _)| *b)
+    };
+    let mut sequences: Vec<Vec<Row>> = Vec::new();
+    let mut by_bank: HashMap<u32, usize> = HashMap::new();
+    for l in lines {
+        if let Some(Some((file, line))) = c_lines.get(l.line) {
+            let bank = bank_of(l.org);
+            let s = *by_bank.entry(bank).or_insert_with(|| {
+                sequences.push(Vec::new());
+                sequences.len() - 1
+            });
+            let address = (bank << 16) | (l.address as u32 & 0xffff);
+            let sequence = &mut sequences[s];
+            match sequence.last_mut() {
+                // Same C line, just after the previous row
+                Some(last)
+                    if last.file == *file
+                        && last.line == *line
+                        && last.address + last.size == address =>
+                {
+                    last.size += l.size as u32;
+                }
+                _ => sequence.push(Row {
+                    address,
+                    size: l.size as u32,
+                    file: *file,
+                    line: *line,
+                }),
+            }
+        }
+    }
+    for sequence in sequences.iter_mut() {
+        sequence.sort_by_key(|r| r.address);
+    }
+
+    // Address, origin and size of each bank, from its C code (the variables declared in the
+    // uninitialized segments have no origin in the image)
+    let mut segments: Vec<Segment> = Vec::new();
+    for l in lines
+        .iter()
+        .filter(|l| l.size > 0 && matches!(c_lines.get(l.line), Some(Some(_))))
+    {
+        let bank = bank_of(l.org);
+        let org = banks
+            .iter()
+            .find(|(b, _)| *b == bank)
+            .map_or(0, |(_, o)| *o);
+        let end = (l.org + l.size as i64 - org as i64) as u32;
+        match segments.iter_mut().find(|s| s.address >> 16 == bank) {
+            Some(s) => s.size = s.size.max(end),
+            None => segments.push(Segment {
+                address: (bank << 16) | ((l.address - (l.org - org as i64)) as u32 & 0xffff),
+                org,
+                size: end,
+            }),
+        }
+    }
+    let debug_line = line_program(&files, &sequences);
+
+    // Compile unit
+    let symbols: HashMap<&str, i64> = symbols.iter().map(|s| (s.name.as_str(), s.value)).collect();
+    let address = |v: &DebugVariable| {
+        symbols
+            .get(v.symbol.as_str())
+            .map(|a| *a as u32 + v.read_offset)
+    };
+    let mut entries = Entries {
+        buf: vec![0, 0, 0, 0, 4, 0, 0, 0, 0, 0, 4],
+    };
+    entries.buf.push(ABBREV_COMPILE_UNIT);
+    string(
+        &mut entries.buf,
+        &format!("cc2600 v{}", env!("CARGO_PKG_VERSION")),
+    );
+    entries.buf.extend_from_slice(&DW_LANG_C89.to_le_bytes());
+    string(&mut entries.buf, name);
+    string(&mut entries.buf, comp_dir);
+    entries.buf.extend_from_slice(&0u32.to_le_bytes());
+    entries.buf.extend_from_slice(&0u32.to_le_bytes());
+    let unsigned_char = entries.base_type("unsigned char", DW_ATE_UNSIGNED_CHAR, 1);
+    let signed_char = entries.base_type("signed char", DW_ATE_SIGNED_CHAR, 1);
+    let unsigned_short = entries.base_type("unsigned short", DW_ATE_UNSIGNED, 2);
+    let signed_short = entries.base_type("short", DW_ATE_SIGNED, 2);
+    let char_ptr = entries.pointer_type(unsigned_char);
+    let types = Types {
+        char: [unsigned_char, signed_char],
+        short: [unsigned_short, signed_short],
+        char_ptr,
+        char_ptr_ptr: entries.pointer_type(char_ptr),
+        short_ptr: entries.pointer_type(unsigned_short),
+    };
+    for v in info.variables.iter().filter(|v| v.function.is_none()) {
+        if let Some(a) = address(v) {
+            entries.variable(&types, v, a);
+        }
+    }
+    for (function, bank, start, size) in &info.functions {
+        entries.buf.push(ABBREV_SUBPROGRAM);
+        string(&mut entries.buf, function);
+        entries
+            .buf
+            .extend_from_slice(&((bank << 16) | start).to_le_bytes());
+        entries.buf.extend_from_slice(&size.to_le_bytes());
+        for v in info
+            .variables
+            .iter()
+            .filter(|v| v.function.as_ref() == Some(function))
+        {
+            if let Some(a) = address(v) {
+                entries.variable(&types, v, a);
+            }
+        }
+        entries.buf.push(0);
+    }
+    entries.buf.push(0);
+    let unit_length = entries.buf.len() as u32 - 4;
+    entries.buf[0..4].copy_from_slice(&unit_length.to_le_bytes());
+
+    elf(
+        &[
+            (".debug_abbrev", abbreviations()),
+            (".debug_info", entries.buf),
+            (".debug_line", debug_line),
+        ],
+        &segments,
+    )
+}
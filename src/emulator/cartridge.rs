@@ -0,0 +1,674 @@
+/*
+    cc2600 - a subset of C compiler for the Atari 2600
+    Copyright (C) 2023-2024 Bruno STEUX
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+    Contact info: bruno.steux@gmail.com
+*/
+
+// Cartridge bankswitching schemes, as generated by build_cartridge. The scheme names are the
+// Stella ones (as written in the .pro file).
+
+pub const SCHEMES: [&str; 21] = [
+    "2K", "4K", "F8", "F8SC", "F6", "F6SC", "F4", "F4SC", "EF", "EFSC", "DF", "DFSC", "BF", "BFSC",
+    "FA", "E0", "E7", "3E", "3E+", "DPC", "DPC+",
+];
+
+// DPC+ file without the ARM driver: 6 banks, 4K display memory and 1K of frequencies
+const DPCPLUS_SIZE: usize = 29696;
+
+enum Mapper {
+    Plain,
+    /// Hotspot of the first bank, number of banks and superchip RAM
+    Standard(usize, usize, bool),
+    FA,
+    E0,
+    E7,
+    ThreeE,
+    ThreeEPlus,
+    Dpc(Box<Dpc>),
+    DpcPlus(Box<DpcPlus>),
+}
+
+#[derive(Copy, Clone)]
+enum Segment {
+    Rom(usize),
+    Ram(usize),
+}
+
+struct Dpc {
+    tops: [u8; 8],
+    bottoms: [u8; 8],
+    counters: [u16; 8],
+    flags: [u8; 8],
+    random: u8,
+}
+
+struct DpcPlus {
+    // Offset of the first bank (after the ARM driver, if any)
+    start: usize,
+    display: Vec<u8>,
+    tops: [u8; 8],
+    bottoms: [u8; 8],
+    counters: [u16; 8],
+    fractional: [u32; 8],
+    increments: [u8; 8],
+    random: u32,
+    fast_fetch: bool,
+    // The last byte read was the opcode of LDA #
+    lda_immediate: bool,
+    parameters: Vec<u8>,
+}
+
+/// What an access to the cartridge address space reaches
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Access {
+    /// Offset in the image
+    Rom(usize),
+    /// Index in the cartridge RAM
+    RamRead(usize),
+    RamWrite(usize),
+    /// Bankswitching hotspot or coprocessor register
+    Register,
+}
+
+pub struct Cartridge {
+    rom: Vec<u8>,
+    mapper: Mapper,
+    /// ROM (or RAM for 3E, 3E+ and E7) mapped on each 1K segment of $1000-$1FFF
+    segments: [Segment; 4],
+    ram: Vec<u8>,
+    // Size of the RAM used so far (3E and 3E+ RAM size is not known from the image)
+    ram_used: usize,
+    // E7 RAM page mapped at $1800-$19FF
+    ram_page: usize,
+}
+
+/// Size of the ROM of a scheme (None if variable)
+fn rom_size(scheme: &str) -> Option<usize> {
+    Some(match scheme {
+        "2K" => 2048,
+        "4K" => 4096,
+        "F8" | "F8SC" | "E0" => 8192,
+        "FA" => 12288,
+        "F6" | "F6SC" | "E7" => 16384,
+        "F4" | "F4SC" => 32768,
+        "EF" | "EFSC" => 65536,
+        "DF" | "DFSC" => 131072,
+        "BF" | "BFSC" => 262144,
+        "DPC" => 10240,
+        _ => return None,
+    })
+}
+
+fn contains(rom: &[u8], pattern: &[u8]) -> bool {
+    rom.windows(pattern.len()).any(|w| w == pattern)
+}
+
+// Absolute accesses (LDA, LDX, LDY, STA, STX, STY, BIT) to the given cartridge addresses
+fn accesses(rom: &[u8], first: u16, last: u16) -> bool {
+    rom.windows(3).any(|w| {
+        let address = (w[2] as u16) << 8 | w[1] as u16;
+        [0xad, 0xae, 0xac, 0x8d, 0x8e, 0x8c, 0x2c].contains(&w[0])
+            && address & 0x1000 != 0
+            && (first..=last).contains(&(address & 0x1fff))
+    })
+}
+
+/// Guesses the bankswitching scheme from the size and content of the image
+pub fn detect_scheme(rom: &[u8]) -> Option<&'static str> {
+    let size = rom.len();
+    if contains(rom, b"TJ3E") {
+        return Some("3E+");
+    }
+    if size == DPCPLUS_SIZE || contains(rom, b"DPC+") {
+        return Some("DPC+");
+    }
+    if size == 10240 || size == 10495 {
+        return Some("DPC");
+    }
+    // Write to $3F (zeropage or absolute)
+    if size > 4096
+        && size.is_multiple_of(2048)
+        && [0x85, 0x86, 0x84]
+            .iter()
+            .any(|&o| contains(rom, &[o, 0x3f]))
+    {
+        return Some("3E");
+    }
+    // The first 256 bytes of all the banks are reserved for the superchip RAM
+    let superchip = size >= 8192
+        && rom
+            .chunks(4096)
+            .all(|b| b[..256].iter().all(|&v| v == rom[0]));
+    Some(match size {
+        2048 => "2K",
+        4096 => "4K",
+        8192 if accesses(rom, 0x1fe0, 0x1ff7) => "E0",
+        8192 if superchip => "F8SC",
+        8192 => "F8",
+        12288 => "FA",
+        16384 if accesses(rom, 0x1fe0, 0x1feb) => "E7",
+        16384 if superchip => "F6SC",
+        16384 => "F6",
+        32768 if superchip => "F4SC",
+        32768 => "F4",
+        65536 if superchip => "EFSC",
+        65536 => "EF",
+        131072 if superchip => "DFSC",
+        131072 => "DF",
+        262144 if superchip => "BFSC",
+        262144 => "BF",
+        _ => return None,
+    })
+}
+
+/// Scheme written in the Stella properties file (.pro) of a cartridge
+pub fn properties_scheme(properties: &str) -> Option<String> {
+    properties.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split('"').collect();
+        if fields.len() >= 4 && fields[1] == "Cart.Type" {
+            Some(fields[3].to_string())
+        } else {
+            None
+        }
+    })
+}
+
+impl Cartridge {
+    pub fn new(scheme: &str, rom: Vec<u8>) -> Result<Cartridge, String> {
+        if !SCHEMES.contains(&scheme) {
+            return Err(format!(
+                "Unknown bankswitching scheme {} (available schemes: {})",
+                scheme,
+                SCHEMES.join(", ")
+            ));
+        }
+        let valid_size = match scheme {
+            "3E" => rom.len() >= 4096 && rom.len().is_multiple_of(2048),
+            "3E+" => rom.len() >= 4096 && rom.len().is_multiple_of(1024),
+            "DPC" => rom.len() == 10240 || rom.len() == 10495,
+            "DPC+" => rom.len() == DPCPLUS_SIZE || rom.len() == 32768,
+            _ => Some(rom.len()) == rom_size(scheme),
+        };
+        if !valid_size {
+            return Err(format!(
+                "The cartridge size ({} bytes) doesn't match the {} bankswitching scheme",
+                rom.len(),
+                scheme
+            ));
+        }
+        let standard =
+            |hotspot: usize, banks: usize| Mapper::Standard(hotspot, banks, scheme.ends_with("SC"));
+        let mapper = match scheme {
+            "F8" | "F8SC" => standard(0xff8, 2),
+            "F6" | "F6SC" => standard(0xff6, 4),
+            "F4" | "F4SC" => standard(0xff4, 8),
+            "EF" | "EFSC" => standard(0xfe0, 16),
+            "DF" | "DFSC" => standard(0xfc0, 32),
+            "BF" | "BFSC" => standard(0xf80, 64),
+            "FA" => Mapper::FA,
+            "E0" => Mapper::E0,
+            "E7" => Mapper::E7,
+            "3E" => Mapper::ThreeE,
+            "3E+" => Mapper::ThreeEPlus,
+            "DPC" => Mapper::Dpc(Box::new(Dpc {
+                tops: [0; 8],
+                bottoms: [0; 8],
+                counters: [0; 8],
+                flags: [0; 8],
+                random: 1,
+            })),
+            "DPC+" => {
+                // The ARM driver (3K) may precede the banks
+                let start = rom.len() - DPCPLUS_SIZE;
+                Mapper::DpcPlus(Box::new(DpcPlus {
+                    start,
+                    display: rom[start + 0x6000..start + 0x7000].to_vec(),
+                    tops: [0; 8],
+                    bottoms: [0; 8],
+                    counters: [0; 8],
+                    fractional: [0; 8],
+                    increments: [0; 8],
+                    random: 0x2b435044,
+                    fast_fetch: false,
+                    lda_immediate: false,
+                    parameters: Vec::new(),
+                }))
+            }
+            _ => Mapper::Plain,
+        };
+        let ram = match scheme {
+            "FA" => 256,
+            "E7" => 2048,
+            "3E" => 32768,
+            "3E+" => 32768,
+            _ if scheme.ends_with("SC") => 128,
+            _ => 0,
+        };
+        let mut cartridge = Cartridge {
+            rom,
+            mapper,
+            segments: [Segment::Rom(0); 4],
+            ram: vec![0; ram],
+            ram_used: 0,
+            ram_page: 0,
+        };
+        cartridge.reset();
+        Ok(cartridge)
+    }
+
+    // Maps a 4K bank on the whole cartridge address space
+    fn select(&mut self, bank: usize) {
+        for (i, s) in self.segments.iter_mut().enumerate() {
+            *s = Segment::Rom(bank * 0x1000 + i * 0x400);
+        }
+    }
+
+    // Power-on mapping. The standard schemes start with their last bank (cc2600 puts the
+    // startup code in every bank for them)
+    fn reset(&mut self) {
+        let size = self.rom.len();
+        match &self.mapper {
+            Mapper::Plain => {
+                for (i, s) in self.segments.iter_mut().enumerate() {
+                    *s = Segment::Rom((i * 0x400) % size);
+                }
+            }
+            Mapper::Standard(_, banks, _) => self.select(banks - 1),
+            Mapper::FA => self.select(2),
+            Mapper::Dpc(_) => self.select(1),
+            Mapper::DpcPlus(d) => {
+                let start = d.start;
+                self.select(5);
+                for s in self.segments.iter_mut() {
+                    if let Segment::Rom(o) = s {
+                        *o += start;
+                    }
+                }
+            }
+            Mapper::E0 => {
+                self.segments = [
+                    Segment::Rom(0),
+                    Segment::Rom(0x400),
+                    Segment::Rom(0x800),
+                    Segment::Rom(0x1c00),
+                ]
+            }
+            Mapper::E7 => {
+                self.segments = [
+                    Segment::Rom(0),
+                    Segment::Rom(0x400),
+                    Segment::Rom(0x3800),
+                    Segment::Rom(0x3c00),
+                ]
+            }
+            Mapper::ThreeE => {
+                self.segments = [
+                    Segment::Rom(0),
+                    Segment::Rom(0x400),
+                    Segment::Rom(size - 0x800),
+                    Segment::Rom(size - 0x400),
+                ]
+            }
+            Mapper::ThreeEPlus => self.segments = [Segment::Rom(0); 4],
+        }
+    }
+
+    /// Cartridge RAM used (superchip, FA, E7, 3E or 3E+ RAM)
+    pub fn ram(&self) -> &[u8] {
+        match self.mapper {
+            Mapper::ThreeE | Mapper::ThreeEPlus => &self.ram[0..self.ram_used],
+            _ => &self.ram,
+        }
+    }
+
+    /// Size of the cartridge RAM
+    pub fn ram_size(&self) -> usize {
+        self.ram.len()
+    }
+
+    /// Cartridge image
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn is_hotspot(&self, a: usize) -> bool {
+        match &self.mapper {
+            Mapper::Standard(hotspot, banks, _) => a >= *hotspot && a < *hotspot + *banks,
+            Mapper::FA => (0xff8..=0xffa).contains(&a),
+            Mapper::Dpc(_) => (0xff8..=0xff9).contains(&a),
+            Mapper::DpcPlus(_) => (0xff6..=0xffb).contains(&a),
+            Mapper::E0 => (0xfe0..=0xff7).contains(&a),
+            Mapper::E7 => (0xfe0..=0xfeb).contains(&a),
+            _ => false,
+        }
+    }
+
+    /// What an access to the given cartridge address reaches with the current mapping
+    pub fn access(&self, address: u16) -> Access {
+        let a = (address & 0xfff) as usize;
+        match self.mapper {
+            Mapper::Standard(_, _, true) if a < 0x80 => Access::RamWrite(a),
+            Mapper::Standard(_, _, true) if a < 0x100 => Access::RamRead(a & 0x7f),
+            Mapper::FA if a < 0x100 => Access::RamWrite(a),
+            Mapper::FA if a < 0x200 => Access::RamRead(a & 0xff),
+            Mapper::E7 if (0x800..0x900).contains(&a) => {
+                Access::RamWrite(0x400 + self.ram_page * 0x100 + (a & 0xff))
+            }
+            Mapper::E7 if (0x900..0xa00).contains(&a) => {
+                Access::RamRead(0x400 + self.ram_page * 0x100 + (a & 0xff))
+            }
+            Mapper::Dpc(_) | Mapper::DpcPlus(_) if a < 0x80 => Access::Register,
+            _ if self.is_hotspot(a) => Access::Register,
+            _ => match self.segments[a >> 10] {
+                Segment::Rom(offset) => Access::Rom((offset + (a & 0x3ff)) % self.rom.len()),
+                Segment::Ram(offset) => match self.ram_port(a, offset) {
+                    (true, index) => Access::RamRead(index),
+                    (false, index) => Access::RamWrite(index),
+                },
+            },
+        }
+    }
+
+    // Bankswitching on access to a hotspot (offset in the cartridge address space)
+    fn hotspot(&mut self, a: usize) {
+        match &mut self.mapper {
+            Mapper::Standard(hotspot, banks, _) if a >= *hotspot && a < *hotspot + *banks => {
+                let bank = a - *hotspot;
+                self.select(bank);
+            }
+            Mapper::FA if (0xff8..=0xffa).contains(&a) => self.select(a - 0xff8),
+            Mapper::Dpc(_) if (0xff8..=0xff9).contains(&a) => self.select(a - 0xff8),
+            Mapper::DpcPlus(d) if (0xff6..=0xffb).contains(&a) => {
+                let start = d.start;
+                self.select(a - 0xff6);
+                for s in self.segments.iter_mut() {
+                    if let Segment::Rom(o) = s {
+                        *o += start;
+                    }
+                }
+            }
+            Mapper::E0 if (0xfe0..=0xff7).contains(&a) => {
+                self.segments[(a - 0xfe0) / 8] = Segment::Rom((a & 7) * 0x400);
+            }
+            Mapper::E7 if (0xfe0..=0xfe7).contains(&a) => {
+                let slice = a - 0xfe0;
+                if slice == 7 {
+                    self.segments[0] = Segment::Ram(0);
+                    self.segments[1] = Segment::Ram(0);
+                } else {
+                    self.segments[0] = Segment::Rom(slice * 0x800);
+                    self.segments[1] = Segment::Rom(slice * 0x800 + 0x400);
+                }
+            }
+            Mapper::E7 if (0xfe8..=0xfeb).contains(&a) => self.ram_page = a - 0xfe8,
+            _ => (),
+        }
+    }
+
+    // RAM port (read or write) and RAM index of an access to a segment mapped on RAM. 3E RAM is
+    // read on the first 1K and written on the second one, E7 RAM the other way round, and 3E+ RAM
+    // is read on the first 512 bytes of the segment
+    fn ram_port(&self, a: usize, offset: usize) -> (bool, usize) {
+        match self.mapper {
+            Mapper::ThreeE => (a & 0x400 == 0, offset + (a & 0x3ff)),
+            Mapper::E7 => (a & 0x400 != 0, a & 0x3ff),
+            _ => (a & 0x200 == 0, offset + (a & 0x1ff)),
+        }
+    }
+
+    /// Read access in the cartridge address space ($1000-$1FFF)
+    pub fn read(&mut self, address: u16) -> u8 {
+        let a = (address & 0xfff) as usize;
+        let rom = match self.segments[a >> 10] {
+            Segment::Rom(offset) => self.rom[(offset + (a & 0x3ff)) % self.rom.len()],
+            Segment::Ram(_) => 0,
+        };
+        let value = match &mut self.mapper {
+            Mapper::Standard(_, _, true) if a < 0x100 => self.ram[a & 0x7f],
+            Mapper::FA if a < 0x200 => self.ram[a & 0xff],
+            Mapper::E7 if (0x800..0xa00).contains(&a) => {
+                self.ram[0x400 + self.ram_page * 0x100 + (a & 0xff)]
+            }
+            Mapper::Dpc(d) if a < 0x40 => d.read(&self.rom[0x2000..0x2800], a),
+            Mapper::DpcPlus(d) if a < 0x28 => {
+                d.lda_immediate = false;
+                d.read(a)
+            }
+            Mapper::DpcPlus(d) => {
+                // Fast fetch: the operand of LDA # is replaced by the register value
+                let value = if d.fast_fetch && d.lda_immediate && rom < 0x28 {
+                    d.read(rom as usize)
+                } else {
+                    rom
+                };
+                d.lda_immediate = d.fast_fetch && !d.lda_immediate && rom == 0xa9;
+                value
+            }
+            _ => match self.segments[a >> 10] {
+                Segment::Rom(_) => rom,
+                Segment::Ram(offset) => match self.ram_port(a, offset) {
+                    (true, index) => self.ram[index],
+                    _ => 0,
+                },
+            },
+        };
+        self.hotspot(a);
+        value
+    }
+
+    /// Write access to any address (3E and 3E+ watch the writes to $3E and $3F)
+    pub fn write(&mut self, address: u16, value: u8) {
+        let v = value as usize;
+        if address & 0x1000 == 0 {
+            match (&self.mapper, address & 0x1fff) {
+                (Mapper::ThreeE, 0x3f) => {
+                    let offset = v * 0x800 % self.rom.len();
+                    self.segments[0] = Segment::Rom(offset);
+                    self.segments[1] = Segment::Rom(offset + 0x400);
+                }
+                (Mapper::ThreeE, 0x3e) => {
+                    let offset = v * 0x400 % self.ram.len();
+                    self.segments[0] = Segment::Ram(offset);
+                    self.segments[1] = Segment::Ram(offset);
+                    self.ram_used = self.ram_used.max(offset + 0x400);
+                }
+                (Mapper::ThreeEPlus, 0x3f) => {
+                    self.segments[v >> 6] = Segment::Rom((v & 0x3f) * 0x400);
+                }
+                (Mapper::ThreeEPlus, 0x3e) => {
+                    let offset = (v & 0x3f) * 0x200;
+                    self.segments[v >> 6] = Segment::Ram(offset);
+                    self.ram_used = self.ram_used.max(offset + 0x200);
+                }
+                _ => (),
+            }
+            return;
+        }
+        let a = (address & 0xfff) as usize;
+        match &mut self.mapper {
+            Mapper::Standard(_, _, true) if a < 0x80 => self.ram[a] = value,
+            Mapper::FA if a < 0x100 => self.ram[a] = value,
+            Mapper::E7 if (0x800..0x900).contains(&a) => {
+                self.ram[0x400 + self.ram_page * 0x100 + (a & 0xff)] = value
+            }
+            Mapper::Dpc(d) if (0x40..0x80).contains(&a) => d.write(a, value),
+            Mapper::DpcPlus(d) if (0x28..0x80).contains(&a) => {
+                let start = d.start;
+                d.write(&self.rom[start..start + 0x6000], a, value)
+            }
+            _ => {
+                if let Segment::Ram(offset) = self.segments[a >> 10] {
+                    if let (false, index) = self.ram_port(a, offset) {
+                        self.ram[index] = value;
+                    }
+                }
+            }
+        }
+        self.hotspot(a);
+    }
+}
+
+impl Dpc {
+    fn read(&mut self, display: &[u8], a: usize) -> u8 {
+        let i = a & 7;
+        let low = self.counters[i] as u8;
+        if low == self.tops[i] {
+            self.flags[i] = 0xff;
+        } else if low == self.bottoms[i] {
+            self.flags[i] = 0;
+        }
+        let data = display[2047 - self.counters[i] as usize];
+        let value = match a >> 3 {
+            0 if i < 4 => {
+                let r = self.random;
+                let bit = !((r >> 7) ^ (r >> 5) ^ (r >> 4) ^ (r >> 3)) & 1;
+                self.random = r << 1 | bit;
+                self.random
+            }
+            0 => 0,
+            1 => data,
+            2 => data & self.flags[i],
+            7 => self.flags[i],
+            _ => 0,
+        };
+        if a >> 3 != 0 {
+            self.counters[i] = self.counters[i].wrapping_sub(1) & 0x7ff;
+        }
+        value
+    }
+
+    fn write(&mut self, a: usize, value: u8) {
+        let i = a & 7;
+        match (a >> 3) & 7 {
+            0 => {
+                self.tops[i] = value;
+                self.flags[i] = 0;
+            }
+            1 => self.bottoms[i] = value,
+            2 => self.counters[i] = (self.counters[i] & 0x700) | value as u16,
+            3 => self.counters[i] = (self.counters[i] & 0xff) | ((value as u16 & 7) << 8),
+            6 => self.random = 1,
+            _ => (),
+        }
+    }
+}
+
+impl DpcPlus {
+    fn flag(&self, i: usize) -> u8 {
+        let top = self.tops[i];
+        let distance = top.wrapping_sub(self.counters[i] as u8);
+        if distance > top.wrapping_sub(self.bottoms[i]) {
+            0xff
+        } else {
+            0
+        }
+    }
+
+    fn read(&mut self, a: usize) -> u8 {
+        let i = a & 7;
+        let counter = self.counters[i] as usize;
+        match a >> 3 {
+            0 => match i {
+                0 => {
+                    let r = self.random;
+                    self.random =
+                        (if r & (1 << 10) != 0 { 0x10adab1e } else { 0 }) ^ r.rotate_right(11);
+                    self.random as u8
+                }
+                1 => {
+                    let r = self.random;
+                    self.random = if r & (1 << 31) != 0 {
+                        (0x10adab1e ^ r).rotate_left(11)
+                    } else {
+                        r.rotate_left(11)
+                    };
+                    self.random as u8
+                }
+                2..=4 => (self.random >> (8 * (i - 1))) as u8,
+                _ => 0,
+            },
+            1 => {
+                self.counters[i] = (self.counters[i] + 1) & 0xfff;
+                self.display[counter]
+            }
+            2 => {
+                let flag = self.flag(i);
+                self.counters[i] = (self.counters[i] + 1) & 0xfff;
+                self.display[counter] & flag
+            }
+            3 => {
+                let value = self.display[((self.fractional[i] >> 8) & 0xfff) as usize];
+                self.fractional[i] = (self.fractional[i] + self.increments[i] as u32) & 0xfffff;
+                value
+            }
+            _ => self.flag(i),
+        }
+    }
+
+    fn write(&mut self, program: &[u8], a: usize, value: u8) {
+        let i = a & 7;
+        let v = value as u32;
+        match a >> 3 {
+            5 => self.fractional[i] = (self.fractional[i] & 0x0f0000) | (v << 8),
+            6 => self.fractional[i] = ((v & 0x0f) << 16) | (self.fractional[i] & 0x00ffff),
+            7 => {
+                self.increments[i] = value;
+                self.fractional[i] &= 0x0fff00;
+            }
+            8 => self.tops[i] = value,
+            9 => self.bottoms[i] = value,
+            10 => self.counters[i] = (self.counters[i] & 0x0f00) | value as u16,
+            11 => match i {
+                0 => self.fast_fetch = value == 0,
+                1 => self.parameters.push(value),
+                2 => {
+                    let p = &self.parameters;
+                    if (value == 1 || value == 2) && p.len() >= 4 {
+                        let target = self.counters[p[2] as usize & 7] as usize;
+                        let source = (p[1] as usize) << 8 | p[0] as usize;
+                        for j in 0..p[3] as usize {
+                            self.display[(target + j) & 0xfff] = if value == 1 {
+                                program[(source + j) % program.len()]
+                            } else {
+                                p[0]
+                            };
+                        }
+                    }
+                    self.parameters.clear();
+                }
+                _ => (),
+            },
+            12 => {
+                self.counters[i] = self.counters[i].wrapping_sub(1) & 0xfff;
+                self.display[self.counters[i] as usize] = value;
+            }
+            13 => self.counters[i] = ((value as u16 & 0x0f) << 8) | (self.counters[i] & 0xff),
+            14 => match i {
+                0 => self.random = 0x2b435044,
+                1..=4 => {
+                    let shift = 8 * (i - 1);
+                    self.random = (self.random & !(0xff << shift)) | (v << shift);
+                }
+                _ => (),
+            },
+            _ => {
+                self.display[self.counters[i] as usize] = value;
+                self.counters[i] = (self.counters[i] + 1) & 0xfff;
+            }
+        }
+    }
+}
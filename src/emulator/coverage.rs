@@ -0,0 +1,113 @@
+/*
+    cc2600 - a subset of C compiler for the Atari 2600
+    Copyright (C) 2023-2024 Bruno STEUX
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+    Contact info: bruno.steux@gmail.com
+*/
+
+// Source code coverage. The instructions executed are counted by offset in the cartridge image,
+// whatever the bank they are run from, and mapped to the C source lines and functions of the
+// debug information written with -g. The result is written in the LCOV tracefile format.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use super::cartridge::{Access, Cartridge};
+use super::debug::DebugInfo;
+
+// Hits by line, and functions (line, name and calls) of a source file
+#[derive(Default, Clone)]
+struct FileCoverage<'a> {
+    lines: BTreeMap<u32, u32>,
+    functions: Vec<(u32, &'a str, u32)>,
+}
+
+pub struct Coverage {
+    // Number of executions of the instruction starting at each offset of the image
+    counts: Vec<u32>,
+}
+
+impl Coverage {
+    pub fn new(size: usize) -> Coverage {
+        Coverage {
+            counts: vec![0; size],
+        }
+    }
+
+    /// Start of an instruction
+    pub fn instruction(&mut self, pc: u16, cartridge: &Cartridge) {
+        if let Access::Rom(offset) = cartridge.access(pc) {
+            if let Some(count) = self.counts.get_mut(offset) {
+                *count = count.saturating_add(1);
+            }
+        }
+    }
+
+    // Executions of the code between two offsets (the most executed of its instructions)
+    fn hits(&self, start: usize, end: usize) -> u32 {
+        self.counts
+            .get(start..end.min(self.counts.len()))
+            .and_then(|c| c.iter().max().copied())
+            .unwrap_or(0)
+    }
+
+    /// LCOV tracefile of the run, with the number of lines and the number of lines executed
+    pub fn lcov(&self, debug: &DebugInfo, test_name: &str) -> (String, usize, usize) {
+        let mut files = vec![FileCoverage::default(); debug.files.len()];
+        for l in &debug.lines {
+            let hits = self.hits(l.start, l.end);
+            let count = files[l.file].lines.entry(l.line).or_default();
+            *count = (*count).max(hits);
+        }
+        for f in &debug.functions {
+            if let Some(l) = debug.lines.iter().find(|l| l.start == f.start) {
+                let calls = self.counts.get(f.start).copied().unwrap_or(0);
+                files[l.file].functions.push((l.line, &f.name, calls));
+            }
+        }
+
+        let mut s = String::new();
+        let (mut found, mut hit) = (0, 0);
+        for (name, file) in debug.files.iter().zip(files.iter_mut()) {
+            let (lines, functions) = (&file.lines, &mut file.functions);
+            if lines.is_empty() {
+                continue;
+            }
+            writeln!(s, "TN:{}", test_name).unwrap();
+            writeln!(s, "SF:{}", name).unwrap();
+            functions.sort();
+            for (line, name, _) in functions.iter() {
+                writeln!(s, "FN:{},{}", line, name).unwrap();
+            }
+            for (_, name, calls) in functions.iter() {
+                writeln!(s, "FNDA:{},{}", calls, name).unwrap();
+            }
+            writeln!(s, "FNF:{}", functions.len()).unwrap();
+            let called = functions.iter().filter(|(_, _, c)| *c > 0).count();
+            writeln!(s, "FNH:{}", called).unwrap();
+            for (line, hits) in lines.iter() {
+                writeln!(s, "DA:{},{}", line, hits).unwrap();
+            }
+            let executed = lines.values().filter(|h| **h > 0).count();
+            writeln!(s, "LF:{}", lines.len()).unwrap();
+            writeln!(s, "LH:{}", executed).unwrap();
+            writeln!(s, "end_of_record").unwrap();
+            found += lines.len();
+            hit += executed;
+        }
+        (s, found, hit)
+    }
+}
@@ -0,0 +1,616 @@
+/*
+    cc2600 - a subset of C compiler for the Atari 2600
+    Copyright (C) 2023-2024 Bruno STEUX
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+    Contact info: bruno.steux@gmail.com
+*/
+
+// NMOS 6502 (6507) CPU. Every bus access takes one cycle, including the dummy accesses of the
+// real processor, so that the TIA and the RIOT see the accesses at the right time and the
+// bankswitching hotspots are triggered as on the real hardware.
+
+use crate::assembler::opcodes::{decode, AddressingMode};
+use AddressingMode::*;
+
+pub trait Memory {
+    fn read(&mut self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+    /// Read whose value is not used by the processor
+    fn dummy_read(&mut self, address: u16) {
+        self.read(address);
+    }
+}
+
+const C: u8 = 0x01;
+const Z: u8 = 0x02;
+const I: u8 = 0x04;
+const D: u8 = 0x08;
+const B: u8 = 0x10;
+const U: u8 = 0x20;
+const V: u8 = 0x40;
+const N: u8 = 0x80;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Op {
+    Adc,
+    Alr,
+    Anc,
+    And,
+    Arr,
+    Asl,
+    Bcc,
+    Bcs,
+    Beq,
+    Bit,
+    Bmi,
+    Bne,
+    Bpl,
+    Brk,
+    Bvc,
+    Bvs,
+    Clc,
+    Cld,
+    Cli,
+    Clv,
+    Cmp,
+    Cpx,
+    Cpy,
+    Dcp,
+    Dec,
+    Dex,
+    Dey,
+    Eor,
+    Inc,
+    Inx,
+    Iny,
+    Isb,
+    Jmp,
+    Jsr,
+    Lax,
+    Lda,
+    Ldx,
+    Ldy,
+    Lsr,
+    Nop,
+    Ora,
+    Pha,
+    Php,
+    Pla,
+    Plp,
+    Rla,
+    Rol,
+    Ror,
+    Rra,
+    Rti,
+    Rts,
+    Sax,
+    Sbc,
+    Sbx,
+    Sec,
+    Sed,
+    Sei,
+    Slo,
+    Sre,
+    Sta,
+    Stx,
+    Sty,
+    Tax,
+    Tay,
+    Tsx,
+    Txa,
+    Txs,
+    Tya,
+}
+
+fn op(mnemonic: &str) -> Option<Op> {
+    Some(match mnemonic {
+        "adc" => Op::Adc,
+        "alr" => Op::Alr,
+        "anc" => Op::Anc,
+        "and" => Op::And,
+        "arr" => Op::Arr,
+        "asl" => Op::Asl,
+        "bcc" => Op::Bcc,
+        "bcs" => Op::Bcs,
+        "beq" => Op::Beq,
+        "bit" => Op::Bit,
+        "bmi" => Op::Bmi,
+        "bne" => Op::Bne,
+        "bpl" => Op::Bpl,
+        "brk" => Op::Brk,
+        "bvc" => Op::Bvc,
+        "bvs" => Op::Bvs,
+        "clc" => Op::Clc,
+        "cld" => Op::Cld,
+        "cli" => Op::Cli,
+        "clv" => Op::Clv,
+        "cmp" => Op::Cmp,
+        "cpx" => Op::Cpx,
+        "cpy" => Op::Cpy,
+        "dcp" => Op::Dcp,
+        "dec" => Op::Dec,
+        "dex" => Op::Dex,
+        "dey" => Op::Dey,
+        "eor" => Op::Eor,
+        "inc" => Op::Inc,
+        "inx" => Op::Inx,
+        "iny" => Op::Iny,
+        "isb" => Op::Isb,
+        "jmp" => Op::Jmp,
+        "jsr" => Op::Jsr,
+        "lax" => Op::Lax,
+        "lda" => Op::Lda,
+        "ldx" => Op::Ldx,
+        "ldy" => Op::Ldy,
+        "lsr" => Op::Lsr,
+        "nop" => Op::Nop,
+        "ora" => Op::Ora,
+        "pha" => Op::Pha,
+        "php" => Op::Php,
+        "pla" => Op::Pla,
+        "plp" => Op::Plp,
+        "rla" => Op::Rla,
+        "rol" => Op::Rol,
+        "ror" => Op::Ror,
+        "rra" => Op::Rra,
+        "rti" => Op::Rti,
+        "rts" => Op::Rts,
+        "sax" => Op::Sax,
+        "sbc" => Op::Sbc,
+        "sbx" => Op::Sbx,
+        "sec" => Op::Sec,
+        "sed" => Op::Sed,
+        "sei" => Op::Sei,
+        "slo" => Op::Slo,
+        "sre" => Op::Sre,
+        "sta" => Op::Sta,
+        "stx" => Op::Stx,
+        "sty" => Op::Sty,
+        "tax" => Op::Tax,
+        "tay" => Op::Tay,
+        "tsx" => Op::Tsx,
+        "txa" => Op::Txa,
+        "txs" => Op::Txs,
+        "tya" => Op::Tya,
+        _ => return None,
+    })
+}
+
+// Indexed address, with the dummy read of the partially computed address
+fn indexed(m: &mut impl Memory, base: u16, index: u8, write: bool) -> u16 {
+    let address = base.wrapping_add(index as u16);
+    if write || (base & 0xff00) != (address & 0xff00) {
+        m.dummy_read((base & 0xff00) | (address & 0xff));
+    }
+    address
+}
+
+pub struct Cpu {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub s: u8,
+    pub p: u8,
+    pub pc: u16,
+    // Operation and addressing mode of each opcode (the opcodes unknown to the assembler jam the
+    // processor)
+    table: Vec<Option<(Op, AddressingMode)>>,
+}
+
+impl Cpu {
+    pub fn new() -> Cpu {
+        Cpu {
+            a: 0,
+            x: 0,
+            y: 0,
+            s: 0xfd,
+            p: I | U,
+            pc: 0,
+            table: (0..=255)
+                .map(|o| decode(o).and_then(|(m, mode)| op(m).map(|op| (op, mode))))
+                .collect(),
+        }
+    }
+
+    pub fn reset(&mut self, m: &mut impl Memory) {
+        self.s = 0xfd;
+        self.p = I | U;
+        let lo = m.read(0xfffc) as u16;
+        let hi = m.read(0xfffd) as u16;
+        self.pc = hi << 8 | lo;
+    }
+
+    fn fetch(&mut self, m: &mut impl Memory) -> u8 {
+        let v = m.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        v
+    }
+
+    fn fetch16(&mut self, m: &mut impl Memory) -> u16 {
+        let lo = self.fetch(m) as u16;
+        let hi = self.fetch(m) as u16;
+        hi << 8 | lo
+    }
+
+    fn push(&mut self, m: &mut impl Memory, v: u8) {
+        m.write(0x100 | self.s as u16, v);
+        self.s = self.s.wrapping_sub(1);
+    }
+
+    fn pull(&mut self, m: &mut impl Memory) -> u8 {
+        self.s = self.s.wrapping_add(1);
+        m.read(0x100 | self.s as u16)
+    }
+
+    fn flag(&mut self, flag: u8, set: bool) {
+        if set {
+            self.p |= flag;
+        } else {
+            self.p &= !flag;
+        }
+    }
+
+    fn nz(&mut self, v: u8) -> u8 {
+        self.flag(Z, v == 0);
+        self.flag(N, v & 0x80 != 0);
+        v
+    }
+
+    // Effective address of the operand. Indexed writes always take the extra cycle, while reads
+    // take it only when crossing a page boundary
+    fn address(&mut self, m: &mut impl Memory, mode: AddressingMode, write: bool) -> u16 {
+        match mode {
+            ZeroPage => self.fetch(m) as u16,
+            ZeroPageX | ZeroPageY => {
+                let z = self.fetch(m);
+                m.dummy_read(z as u16);
+                let index = if mode == ZeroPageX { self.x } else { self.y };
+                z.wrapping_add(index) as u16
+            }
+            Absolute => self.fetch16(m),
+            AbsoluteX | AbsoluteY => {
+                let base = self.fetch16(m);
+                let index = if mode == AbsoluteX { self.x } else { self.y };
+                indexed(m, base, index, write)
+            }
+            IndirectX => {
+                let z = self.fetch(m);
+                m.dummy_read(z as u16);
+                let z = z.wrapping_add(self.x);
+                let lo = m.read(z as u16) as u16;
+                let hi = m.read(z.wrapping_add(1) as u16) as u16;
+                hi << 8 | lo
+            }
+            IndirectY => {
+                let z = self.fetch(m);
+                let lo = m.read(z as u16) as u16;
+                let hi = m.read(z.wrapping_add(1) as u16) as u16;
+                indexed(m, hi << 8 | lo, self.y, write)
+            }
+            _ => 0,
+        }
+    }
+
+    fn adc(&mut self, v: u8) {
+        let c = (self.p & C) as u16;
+        let a = self.a as u16;
+        let v16 = v as u16;
+        let binary = a + v16 + c;
+        if self.p & D != 0 {
+            let mut lo = (a & 0x0f) + (v16 & 0x0f) + c;
+            let mut hi = (a >> 4) + (v16 >> 4);
+            if lo > 9 {
+                lo += 6;
+            }
+            if lo > 0x0f {
+                hi += 1;
+            }
+            self.flag(Z, binary & 0xff == 0);
+            self.flag(N, (hi << 4) & 0x80 != 0);
+            self.flag(V, !(a ^ v16) & (a ^ (hi << 4)) & 0x80 != 0);
+            if hi > 9 {
+                hi += 6;
+            }
+            self.flag(C, hi > 0x0f);
+            self.a = ((hi << 4) | (lo & 0x0f)) as u8;
+        } else {
+            self.flag(V, !(a ^ v16) & (a ^ binary) & 0x80 != 0);
+            self.flag(C, binary > 0xff);
+            self.a = self.nz(binary as u8);
+        }
+    }
+
+    fn sbc(&mut self, v: u8) {
+        if self.p & D != 0 {
+            let borrow = 1 - (self.p & C) as i16;
+            let a = self.a as i16;
+            let v16 = v as i16;
+            let binary = a - v16 - borrow;
+            let mut lo = (a & 0x0f) - (v16 & 0x0f) - borrow;
+            let mut hi = (a >> 4) - (v16 >> 4);
+            if lo < 0 {
+                lo -= 6;
+                hi -= 1;
+            }
+            if hi < 0 {
+                hi -= 6;
+            }
+            self.flag(V, (a ^ v16) & (a ^ binary) & 0x80 != 0);
+            self.flag(C, binary >= 0);
+            self.nz(binary as u8);
+            self.a = ((hi << 4) | (lo & 0x0f)) as u8;
+        } else {
+            self.adc(!v);
+        }
+    }
+
+    fn compare(&mut self, register: u8, v: u8) {
+        self.flag(C, register >= v);
+        self.nz(register.wrapping_sub(v));
+    }
+
+    fn asl(&mut self, v: u8) -> u8 {
+        self.flag(C, v & 0x80 != 0);
+        self.nz(v << 1)
+    }
+
+    fn lsr(&mut self, v: u8) -> u8 {
+        self.flag(C, v & 0x01 != 0);
+        self.nz(v >> 1)
+    }
+
+    fn rol(&mut self, v: u8) -> u8 {
+        let c = self.p & C;
+        self.flag(C, v & 0x80 != 0);
+        self.nz(v << 1 | c)
+    }
+
+    fn ror(&mut self, v: u8) -> u8 {
+        let c = self.p & C;
+        self.flag(C, v & 0x01 != 0);
+        self.nz(v >> 1 | c << 7)
+    }
+
+    fn branch(&mut self, m: &mut impl Memory, taken: bool) {
+        let offset = self.fetch(m) as i8;
+        if taken {
+            m.dummy_read(self.pc);
+            let target = self.pc.wrapping_add(offset as u16);
+            if (target & 0xff00) != (self.pc & 0xff00) {
+                m.dummy_read((self.pc & 0xff00) | (target & 0xff));
+            }
+            self.pc = target;
+        }
+    }
+
+    /// Executes one instruction. Returns the address of the instruction if it jams the processor.
+    pub fn step(&mut self, m: &mut impl Memory) -> Result<(), u16> {
+        let pc = self.pc;
+        let opcode = self.fetch(m);
+        let Some((op, mode)) = self.table[opcode as usize] else {
+            return Err(pc);
+        };
+        match op {
+            // Branches
+            Op::Bcc => self.branch(m, self.p & C == 0),
+            Op::Bcs => self.branch(m, self.p & C != 0),
+            Op::Bne => self.branch(m, self.p & Z == 0),
+            Op::Beq => self.branch(m, self.p & Z != 0),
+            Op::Bpl => self.branch(m, self.p & N == 0),
+            Op::Bmi => self.branch(m, self.p & N != 0),
+            Op::Bvc => self.branch(m, self.p & V == 0),
+            Op::Bvs => self.branch(m, self.p & V != 0),
+
+            // Jumps and stack
+            Op::Jmp if mode == Absolute => self.pc = self.fetch16(m),
+            Op::Jmp => {
+                let pointer = self.fetch16(m);
+                let lo = m.read(pointer) as u16;
+                let hi = m.read((pointer & 0xff00) | (pointer.wrapping_add(1) & 0xff)) as u16;
+                self.pc = hi << 8 | lo;
+            }
+            Op::Jsr => {
+                let lo = self.fetch(m) as u16;
+                m.dummy_read(0x100 | self.s as u16);
+                self.push(m, (self.pc >> 8) as u8);
+                self.push(m, self.pc as u8);
+                let hi = m.read(self.pc) as u16;
+                self.pc = hi << 8 | lo;
+            }
+            Op::Rts => {
+                m.dummy_read(self.pc);
+                m.dummy_read(0x100 | self.s as u16);
+                let lo = self.pull(m) as u16;
+                let hi = self.pull(m) as u16;
+                self.pc = hi << 8 | lo;
+                m.dummy_read(self.pc);
+                self.pc = self.pc.wrapping_add(1);
+            }
+            Op::Rti => {
+                m.dummy_read(self.pc);
+                m.dummy_read(0x100 | self.s as u16);
+                self.p = (self.pull(m) & !B) | U;
+                let lo = self.pull(m) as u16;
+                let hi = self.pull(m) as u16;
+                self.pc = hi << 8 | lo;
+            }
+            Op::Brk => {
+                self.fetch(m);
+                self.push(m, (self.pc >> 8) as u8);
+                self.push(m, self.pc as u8);
+                self.push(m, self.p | B | U);
+                self.p |= I;
+                let lo = m.read(0xfffe) as u16;
+                let hi = m.read(0xffff) as u16;
+                self.pc = hi << 8 | lo;
+            }
+            Op::Pha | Op::Php => {
+                m.dummy_read(self.pc);
+                let v = if op == Op::Pha {
+                    self.a
+                } else {
+                    self.p | B | U
+                };
+                self.push(m, v);
+            }
+            Op::Pla | Op::Plp => {
+                m.dummy_read(self.pc);
+                m.dummy_read(0x100 | self.s as u16);
+                let v = self.pull(m);
+                if op == Op::Pla {
+                    self.a = self.nz(v);
+                } else {
+                    self.p = (v & !B) | U;
+                }
+            }
+
+            // Stores
+            Op::Sta | Op::Stx | Op::Sty | Op::Sax => {
+                let address = self.address(m, mode, true);
+                let v = match op {
+                    Op::Sta => self.a,
+                    Op::Stx => self.x,
+                    Op::Sty => self.y,
+                    _ => self.a & self.x,
+                };
+                m.write(address, v);
+            }
+
+            // Read-modify-write
+            Op::Asl | Op::Lsr | Op::Rol | Op::Ror if mode == Accumulator => {
+                m.dummy_read(self.pc);
+                self.a = match op {
+                    Op::Asl => self.asl(self.a),
+                    Op::Lsr => self.lsr(self.a),
+                    Op::Rol => self.rol(self.a),
+                    _ => self.ror(self.a),
+                };
+            }
+            Op::Asl
+            | Op::Lsr
+            | Op::Rol
+            | Op::Ror
+            | Op::Inc
+            | Op::Dec
+            | Op::Slo
+            | Op::Sre
+            | Op::Rla
+            | Op::Rra
+            | Op::Dcp
+            | Op::Isb => {
+                let address = self.address(m, mode, true);
+                let v = m.read(address);
+                m.write(address, v);
+                let r = match op {
+                    Op::Asl | Op::Slo => self.asl(v),
+                    Op::Lsr | Op::Sre => self.lsr(v),
+                    Op::Rol | Op::Rla => self.rol(v),
+                    Op::Ror | Op::Rra => self.ror(v),
+                    Op::Inc | Op::Isb => self.nz(v.wrapping_add(1)),
+                    _ => self.nz(v.wrapping_sub(1)),
+                };
+                m.write(address, r);
+                match op {
+                    Op::Slo => self.a = self.nz(self.a | r),
+                    Op::Sre => self.a = self.nz(self.a ^ r),
+                    Op::Rla => self.a = self.nz(self.a & r),
+                    Op::Rra => self.adc(r),
+                    Op::Dcp => self.compare(self.a, r),
+                    Op::Isb => self.sbc(r),
+                    _ => (),
+                }
+            }
+
+            // Implied
+            _ if mode == Implied => {
+                m.dummy_read(self.pc);
+                match op {
+                    Op::Clc => self.p &= !C,
+                    Op::Cld => self.p &= !D,
+                    Op::Cli => self.p &= !I,
+                    Op::Clv => self.p &= !V,
+                    Op::Sec => self.p |= C,
+                    Op::Sed => self.p |= D,
+                    Op::Sei => self.p |= I,
+                    Op::Dex => self.x = self.nz(self.x.wrapping_sub(1)),
+                    Op::Dey => self.y = self.nz(self.y.wrapping_sub(1)),
+                    Op::Inx => self.x = self.nz(self.x.wrapping_add(1)),
+                    Op::Iny => self.y = self.nz(self.y.wrapping_add(1)),
+                    Op::Tax => self.x = self.nz(self.a),
+                    Op::Tay => self.y = self.nz(self.a),
+                    Op::Tsx => self.x = self.nz(self.s),
+                    Op::Txa => self.a = self.nz(self.x),
+                    Op::Txs => self.s = self.x,
+                    Op::Tya => self.a = self.nz(self.y),
+                    _ => (),
+                }
+            }
+
+            // Reads
+            _ => {
+                let v = if mode == Immediate {
+                    self.fetch(m)
+                } else {
+                    let address = self.address(m, mode, false);
+                    m.read(address)
+                };
+                match op {
+                    Op::Adc => self.adc(v),
+                    Op::Sbc => self.sbc(v),
+                    Op::And => self.a = self.nz(self.a & v),
+                    Op::Ora => self.a = self.nz(self.a | v),
+                    Op::Eor => self.a = self.nz(self.a ^ v),
+                    Op::Cmp => self.compare(self.a, v),
+                    Op::Cpx => self.compare(self.x, v),
+                    Op::Cpy => self.compare(self.y, v),
+                    Op::Lda => self.a = self.nz(v),
+                    Op::Ldx => self.x = self.nz(v),
+                    Op::Ldy => self.y = self.nz(v),
+                    Op::Lax => {
+                        self.a = self.nz(v);
+                        self.x = v;
+                    }
+                    Op::Bit => {
+                        self.flag(Z, self.a & v == 0);
+                        self.flag(N, v & 0x80 != 0);
+                        self.flag(V, v & 0x40 != 0);
+                    }
+                    Op::Anc => {
+                        self.a = self.nz(self.a & v);
+                        self.flag(C, self.a & 0x80 != 0);
+                    }
+                    Op::Alr => {
+                        let a = self.a & v;
+                        self.a = self.lsr(a);
+                    }
+                    Op::Arr => {
+                        let a = self.a & v;
+                        self.a = self.ror(a);
+                        self.flag(C, self.a & 0x40 != 0);
+                        self.flag(V, ((self.a >> 6) ^ (self.a >> 5)) & 1 != 0);
+                    }
+                    Op::Sbx => {
+                        let ax = self.a & self.x;
+                        self.flag(C, ax >= v);
+                        self.x = self.nz(ax.wrapping_sub(v));
+                    }
+                    _ => (),
+                }
+            }
+        }
+        Ok(())
+    }
+}
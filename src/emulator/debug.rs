@@ -0,0 +1,434 @@
+/*
+    cc2600 - a subset of C compiler for the Atari 2600
+    Copyright (C) 2023-2024 Bruno STEUX
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+    Contact info: bruno.steux@gmail.com
+*/
+
+// Reader of the debug information written with -g (see dwarf.rs): C source lines and functions,
+// located by their offset in the cartridge image. Code addresses are the RORG addresses with the
+// bank number in the upper 16 bits, mapped to the image with the program header of their bank.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+const PT_LOAD: u32 = 1;
+const DW_TAG_COMPILE_UNIT: u64 = 0x11;
+const DW_TAG_SUBPROGRAM: u64 = 0x2e;
+const DW_AT_NAME: u64 = 0x03;
+const DW_AT_LOW_PC: u64 = 0x11;
+const DW_AT_COMP_DIR: u64 = 0x1b;
+
+pub struct SourceLine {
+    /// Code of the line in the image (start and end offsets)
+    pub start: usize,
+    pub end: usize,
+    /// Index in the source files
+    pub file: usize,
+    pub line: u32,
+}
+
+pub struct Function {
+    pub name: String,
+    /// Offset of the entry point in the image
+    pub start: usize,
+}
+
+#[derive(Default)]
+pub struct DebugInfo {
+    /// Source files, in the compilation directory when the path is relative
+    pub files: Vec<String>,
+    pub lines: Vec<SourceLine>,
+    pub functions: Vec<Function>,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], position: usize) -> Reader<'a> {
+        Reader { data, position }
+    }
+
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.position..self.position.checked_add(n)?)?;
+        self.position += n;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    fn uleb(&mut self) -> Option<u64> {
+        let (mut value, mut shift) = (0u64, 0);
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Option<i64> {
+        let (mut value, mut shift) = (0i64, 0);
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Some(value);
+            }
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let rest = self.data.get(self.position..)?;
+        let length = rest.iter().position(|&c| c == 0)?;
+        self.position += length + 1;
+        Some(String::from_utf8_lossy(&rest[..length]).into_owned())
+    }
+
+    fn at_end(&self) -> bool {
+        self.position >= self.data.len()
+    }
+}
+
+// Banks of the program headers: address of the first byte, offset in the image and size
+struct Segment {
+    address: u32,
+    org: u32,
+    size: u32,
+}
+
+// Content of the sections of an ELF file, by name
+type Sections<'a> = HashMap<String, &'a [u8]>;
+
+// Sections and program headers of an ELF file
+fn elf(data: &[u8]) -> Option<(Sections<'_>, Vec<Segment>)> {
+    if data.get(0..6)? != [0x7f, b'E', b'L', b'F', 1, 1] {
+        return None;
+    }
+    let mut r = Reader::new(data, 28);
+    let (phoff, shoff) = (r.u32()? as usize, r.u32()? as usize);
+    r.position = 42;
+    let (phentsize, phnum) = (r.u16()? as usize, r.u16()? as usize);
+    let (shentsize, shnum, shstrndx) = (r.u16()? as usize, r.u16()? as usize, r.u16()? as usize);
+
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        let mut r = Reader::new(data, phoff + i * phentsize);
+        let kind = r.u32()?;
+        r.u32()?;
+        let (address, org) = (r.u32()?, r.u32()?);
+        r.u32()?;
+        let size = r.u32()?;
+        if kind == PT_LOAD {
+            segments.push(Segment { address, org, size });
+        }
+    }
+
+    let section = |i: usize| -> Option<(u32, &[u8])> {
+        let mut r = Reader::new(data, shoff + i * shentsize);
+        let name = r.u32()?;
+        r.position += 12;
+        let (offset, size) = (r.u32()? as usize, r.u32()? as usize);
+        Some((name, data.get(offset..offset.checked_add(size)?)?))
+    };
+    let (_, names) = section(shstrndx)?;
+    let mut sections = HashMap::new();
+    for i in 0..shnum {
+        let (name, content) = section(i)?;
+        sections.insert(Reader::new(names, name as usize).string()?, content);
+    }
+    Some((sections, segments))
+}
+
+// Compilation directory and functions (name and address) of the .debug_info section
+fn debug_info(info: &[u8], abbrev: &[u8]) -> Option<(String, Vec<(String, u32)>)> {
+    let mut comp_dir = String::new();
+    let mut functions = Vec::new();
+    let mut r = Reader::new(info, 0);
+    while !r.at_end() {
+        let length = r.u32()? as usize;
+        let end = r.position + length;
+        r.u16()?;
+        let abbrev_offset = r.u32()? as usize;
+        let address_size = r.u8()? as usize;
+
+        // Abbreviations: tag and (attribute, form) pairs by code
+        let mut abbreviations = HashMap::new();
+        let mut a = Reader::new(abbrev, abbrev_offset);
+        loop {
+            let code = a.uleb()?;
+            if code == 0 {
+                break;
+            }
+            let tag = a.uleb()?;
+            a.u8()?;
+            let mut attributes = Vec::new();
+            loop {
+                let (attribute, form) = (a.uleb()?, a.uleb()?);
+                if attribute == 0 {
+                    break;
+                }
+                attributes.push((attribute, form));
+            }
+            abbreviations.insert(code, (tag, attributes));
+        }
+
+        while r.position < end {
+            let code = r.uleb()?;
+            if code == 0 {
+                continue;
+            }
+            let (tag, attributes) = abbreviations.get(&code)?;
+            let (mut name, mut low) = (String::new(), None);
+            for &(attribute, form) in attributes {
+                // Value of the numbers and strings, the blocks are skipped
+                let (number, string) = match form {
+                    0x01 if address_size == 2 => (Some(r.u16()? as u64), None),
+                    0x01 => (Some(r.u32()? as u64), None),
+                    0x0b | 0x0c => (Some(r.u8()? as u64), None),
+                    0x05 => (Some(r.u16()? as u64), None),
+                    0x06 | 0x0e | 0x10 | 0x13 | 0x17 => (Some(r.u32()? as u64), None),
+                    0x07 | 0x14 => (Some(u64::from_le_bytes(r.bytes(8)?.try_into().ok()?)), None),
+                    0x08 => (None, Some(r.string()?)),
+                    0x0d => (Some(r.sleb()? as u64), None),
+                    0x0f | 0x15 => (Some(r.uleb()?), None),
+                    0x03 | 0x04 | 0x09 | 0x0a | 0x18 => {
+                        let n = match form {
+                            0x0a => r.u8()? as usize,
+                            0x03 => r.u16()? as usize,
+                            0x04 => r.u32()? as usize,
+                            _ => r.uleb()? as usize,
+                        };
+                        r.bytes(n)?;
+                        (None, None)
+                    }
+                    0x19 => (None, None),
+                    _ => return None,
+                };
+                match attribute {
+                    DW_AT_NAME => name = string.unwrap_or_default(),
+                    DW_AT_COMP_DIR if *tag == DW_TAG_COMPILE_UNIT => {
+                        comp_dir = string.unwrap_or_default()
+                    }
+                    DW_AT_LOW_PC => low = number,
+                    _ => (),
+                }
+            }
+            if let (DW_TAG_SUBPROGRAM, Some(low)) = (*tag, low) {
+                functions.push((name, low as u32));
+            }
+        }
+        r.position = end;
+    }
+    Some((comp_dir, functions))
+}
+
+// Source files and lines (start and end addresses, file, line) of the .debug_line section
+type Lines = Vec<(u32, u32, usize, u32)>;
+
+fn debug_line(data: &[u8]) -> Option<(Vec<String>, Lines)> {
+    let mut files = Vec::new();
+    let mut lines = Vec::new();
+    let mut r = Reader::new(data, 0);
+    while !r.at_end() {
+        let length = r.u32()? as usize;
+        let end = r.position + length;
+        let version = r.u16()?;
+        let header_length = r.u32()? as usize;
+        let program = r.position + header_length;
+        let min_instruction_length = r.u8()? as u32;
+        if version >= 4 {
+            r.u8()?;
+        }
+        r.u8()?;
+        let line_base = r.u8()? as i8 as i64;
+        let line_range = r.u8()?;
+        let opcode_base = r.u8()?;
+        let lengths = r.bytes(opcode_base.checked_sub(1)? as usize)?.to_vec();
+        let mut directories = vec![String::new()];
+        loop {
+            let directory = r.string()?;
+            if directory.is_empty() {
+                break;
+            }
+            directories.push(directory);
+        }
+        // Files of this unit, as indices in the files of all units
+        let mut unit_files = vec![0];
+        loop {
+            let name = r.string()?;
+            if name.is_empty() {
+                break;
+            }
+            let path = match directories.get(r.uleb()? as usize) {
+                Some(d) if !d.is_empty() => Path::new(d).join(&name).to_string_lossy().into(),
+                _ => name,
+            };
+            r.uleb()?;
+            r.uleb()?;
+            unit_files.push(files.len());
+            files.push(path);
+        }
+
+        r.position = program;
+        let (mut address, mut file, mut line) = (0u32, 1usize, 1i64);
+        // Row being built (address, file, line), ended by the next one
+        let mut row: Option<(u32, usize, u32)> = None;
+        while r.position < end {
+            let opcode = r.u8()?;
+            let (mut new_row, mut end_sequence) = (false, false);
+            if opcode >= opcode_base {
+                let adjusted = opcode - opcode_base;
+                address += (adjusted / line_range) as u32 * min_instruction_length;
+                line += line_base + (adjusted % line_range) as i64;
+                new_row = true;
+            } else {
+                match opcode {
+                    0 => {
+                        let length = r.uleb()? as usize;
+                        let next = r.position + length;
+                        match r.u8()? {
+                            1 => end_sequence = true,
+                            2 if length == 3 => address = r.u16()? as u32,
+                            2 => address = r.u32()?,
+                            _ => (),
+                        }
+                        r.position = next;
+                    }
+                    1 => new_row = true,
+                    2 => address += r.uleb()? as u32 * min_instruction_length,
+                    3 => line += r.sleb()?,
+                    4 => file = r.uleb()? as usize,
+                    8 => {
+                        address +=
+                            ((255 - opcode_base) / line_range) as u32 * min_instruction_length
+                    }
+                    9 => address += r.u16()? as u32,
+                    _ => {
+                        for _ in 0..lengths[opcode as usize - 1] {
+                            r.uleb()?;
+                        }
+                    }
+                }
+            }
+            if new_row || end_sequence {
+                if let Some((start, f, l)) = row.take() {
+                    if address > start {
+                        lines.push((start, address, f, l));
+                    }
+                }
+            }
+            if new_row {
+                row = Some((address, *unit_files.get(file)?, line as u32));
+            }
+            if end_sequence {
+                (address, file, line) = (0, 1, 1);
+            }
+        }
+        r.position = end;
+    }
+    Some((files, lines))
+}
+
+impl DebugInfo {
+    /// Reads the ELF debug file written by cc2600 with -g
+    pub fn read(filename: &str) -> Result<DebugInfo, String> {
+        let data = std::fs::read(filename)
+            .map_err(|e| format!("Can't read {}: {} (compile with -g)", filename, e))?;
+        let error = |e: &str| format!("{}: {}", filename, e);
+        let (sections, segments) = elf(&data).ok_or_else(|| error("not an ELF file"))?;
+        if segments.is_empty() {
+            return Err(error(
+                "no bank program headers (recompile with -g to update the debug file)",
+            ));
+        }
+        let section = |name: &str| {
+            sections
+                .get(name)
+                .copied()
+                .ok_or_else(|| error(&format!("no {} section", name)))
+        };
+        let (comp_dir, functions) = debug_info(section(".debug_info")?, section(".debug_abbrev")?)
+            .ok_or_else(|| error("invalid .debug_info section"))?;
+        let (files, lines) = debug_line(section(".debug_line")?)
+            .ok_or_else(|| error("invalid .debug_line section"))?;
+
+        // Offset in the image of a code address, found with the program header of its bank
+        let offset = |address: u32| {
+            segments
+                .iter()
+                .find(|s| {
+                    s.address >> 16 == address >> 16
+                        && address >= s.address
+                        && address - s.address < s.size
+                })
+                .map(|s| (s.org + address - s.address) as usize)
+        };
+        let mut info = DebugInfo {
+            files: files
+                .into_iter()
+                .map(
+                    |f| match comp_dir.is_empty() || Path::new(&f).is_absolute() {
+                        true => f,
+                        false => Path::new(&comp_dir).join(f).to_string_lossy().into(),
+                    },
+                )
+                .collect(),
+            ..Default::default()
+        };
+        for (low, high, file, line) in lines {
+            if let Some(start) = offset(low) {
+                info.lines.push(SourceLine {
+                    start,
+                    end: start + (high - low) as usize,
+                    file,
+                    line,
+                });
+            }
+        }
+        for (name, address) in functions {
+            if let Some(start) = offset(address) {
+                info.functions.push(Function { name, start });
+            }
+        }
+        Ok(info)
+    }
+}
@@ -0,0 +1,162 @@
+/*
+    cc2600 - a subset of C compiler for the Atari 2600
+    Copyright (C) 2023-2024 Bruno STEUX
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+    Contact info: bruno.steux@gmail.com
+*/
+
+// Input scripts. Each line gives a frame number followed by the inputs held from the start of
+// that frame up to the next line:
+//
+//   # Press RESET for one frame, then push the joystick right with the button pressed
+//   10 reset
+//   11
+//   50 right fire paddle0=120
+//
+// Joystick directions and buttons (up, down, left, right, fire for the left joystick, p1.up,
+// p1.fire... for the right one, paddle0.fire to paddle3.fire) and the reset and select console
+// buttons are released when not listed. Paddle positions (paddle0=N, the number of scanlines
+// taken by the paddle capacitor to charge), difficulty switches (difficulty0=a|b,
+// difficulty1=a|b) and the TV type switch (tv=color|bw) keep their value until changed.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Input {
+    /// SWCHA inputs (joysticks and paddle buttons, active low)
+    pub swcha: u8,
+    /// SWCHB inputs (console switches)
+    pub swchb: u8,
+    /// INPT4 and INPT5 (joystick buttons)
+    pub buttons: [bool; 2],
+    /// Paddles charge time, in scanlines
+    pub paddles: [u32; 4],
+}
+
+impl Default for Input {
+    fn default() -> Self {
+        Input {
+            swcha: 0xff,
+            swchb: 0x0b,
+            buttons: [false; 2],
+            paddles: [0; 4],
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct InputScript {
+    // Inputs by starting frame, in frame order
+    events: Vec<(u32, Input)>,
+}
+
+// SWCHA bit of a joystick direction
+fn direction(player: u32, name: &str) -> Option<u8> {
+    let bit = match name {
+        "right" => 7,
+        "left" => 6,
+        "down" => 5,
+        "up" => 4,
+        _ => return None,
+    };
+    Some(1 << (bit - 4 * player))
+}
+
+// Paddle number of paddle0 to paddle3
+fn paddle(name: &str) -> Option<usize> {
+    name.strip_prefix("paddle")
+        .and_then(|p| p.parse::<usize>().ok())
+        .filter(|&p| p < 4)
+}
+
+impl InputScript {
+    pub fn parse(script: &str) -> Result<InputScript, String> {
+        let mut events: Vec<(u32, Input)> = Vec::new();
+        let mut last = Input::default();
+        for (n, line) in script.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let mut words = line.split_whitespace();
+            let Some(frame) = words.next() else {
+                continue;
+            };
+            let error = |e: String| format!("Input script, line {}: {}", n + 1, e);
+            let frame: u32 = frame
+                .parse()
+                .map_err(|_| error(format!("{} is not a frame number", frame)))?;
+            if events.last().is_some_and(|(f, _)| *f >= frame) {
+                return Err(error(format!("frame {} is not in increasing order", frame)));
+            }
+            // Momentary inputs are released, switches and paddles keep their value
+            let mut input = Input {
+                swcha: 0xff,
+                swchb: last.swchb | 0x03,
+                buttons: [false; 2],
+                paddles: last.paddles,
+            };
+            for word in words {
+                let (item, value) = match word.split_once('=') {
+                    Some((i, v)) => (i, Some(v)),
+                    None => (word, None),
+                };
+                // Inputs of a player (p1.fire) or of a paddle button (paddle0.fire)
+                let (player, button, name) = match item.split_once('.') {
+                    Some(("p0", n)) => (0, None, n),
+                    Some(("p1", n)) => (1, None, n),
+                    Some((p, n)) if paddle(p).is_some() => (0, paddle(p), n),
+                    _ => (0, None, item),
+                };
+                let unknown = || error(format!("unknown input {}", word));
+                match (name, value) {
+                    ("fire", None) if button.is_some() => {
+                        input.swcha &= ![0x80, 0x40, 0x08, 0x04][button.unwrap()];
+                    }
+                    _ if button.is_some() => return Err(unknown()),
+                    ("fire", None) => input.buttons[player as usize] = true,
+                    (_, None) if direction(player, name).is_some() => {
+                        input.swcha &= !direction(player, name).unwrap();
+                    }
+                    ("reset", None) => input.swchb &= !0x01,
+                    ("select", None) => input.swchb &= !0x02,
+                    (_, Some(v)) if paddle(name).is_some() => {
+                        input.paddles[paddle(name).unwrap()] = v
+                            .parse()
+                            .map_err(|_| error(format!("{} is not a paddle position", v)))?;
+                    }
+                    ("difficulty0" | "difficulty1", Some(v)) => {
+                        let bit = if name == "difficulty0" { 0x40 } else { 0x80 };
+                        match v {
+                            "a" | "A" => input.swchb |= bit,
+                            "b" | "B" => input.swchb &= !bit,
+                            _ => return Err(unknown()),
+                        }
+                    }
+                    ("tv", Some("color")) => input.swchb |= 0x08,
+                    ("tv", Some("bw")) => input.swchb &= !0x08,
+                    _ => return Err(unknown()),
+                }
+            }
+            events.push((frame, input.clone()));
+            last = input;
+        }
+        Ok(InputScript { events })
+    }
+
+    /// Inputs given for the start of a frame, if any
+    pub fn at(&self, frame: u32) -> Option<&Input> {
+        self.events
+            .binary_search_by_key(&frame, |(f, _)| *f)
+            .ok()
+            .map(|i| &self.events[i].1)
+    }
+}
@@ -0,0 +1,102 @@
+/*
+    cc2600 - a subset of C compiler for the Atari 2600
+    Copyright (C) 2023-2024 Bruno STEUX
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+    Contact info: bruno.steux@gmail.com
+*/
+
+// ROM banks of the memory map written by build_cartridge (--memory-map), with the functions
+// (SUBROUTINE labels), included assembler code and tables they hold.
+
+use crate::report::Json;
+
+pub struct Item {
+    pub name: String,
+    /// "code" (startup code), "assembler code", "function" or "array"
+    pub kind: String,
+    /// RORG address
+    pub address: u32,
+    pub size: u32,
+}
+
+pub struct Bank {
+    pub name: String,
+    /// Offset in the image
+    pub org: usize,
+    pub rorg: u32,
+    pub size: usize,
+    pub items: Vec<Item>,
+}
+
+/// Items of an array field of the memory map
+pub fn list<'a>(json: &'a Json, key: &str) -> &'a [Json] {
+    json.get(key).map_or(&[], |l| l.items())
+}
+
+pub fn number(json: &Json, key: &str) -> i64 {
+    json.get(key).and_then(|n| n.number()).unwrap_or(0)
+}
+
+pub fn string(json: &Json, key: &str) -> String {
+    json.get(key)
+        .and_then(|s| s.string())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Parses a memory map, checking it has banks and RAM regions
+pub fn parse(memory_map: &str) -> Result<Json, String> {
+    let map = Json::parse(memory_map)?;
+    if map.get("banks").is_none() || map.get("ram").is_none() {
+        return Err("not a memory map (written with --memory-map)".to_string());
+    }
+    Ok(map)
+}
+
+/// ROM banks of a memory map
+pub fn banks(map: &Json) -> Vec<Bank> {
+    list(map, "banks")
+        .iter()
+        .map(|b| Bank {
+            name: string(b, "name"),
+            org: number(b, "org") as usize,
+            rorg: number(b, "rorg") as u32,
+            size: number(b, "size") as usize,
+            items: list(b, "items")
+                .iter()
+                .map(|i| Item {
+                    name: string(i, "name"),
+                    kind: string(i, "kind"),
+                    address: number(i, "address") as u32,
+                    size: number(i, "size") as u32,
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+/// Bank and item (function, table...) at an offset of the image
+pub fn item(banks: &[Bank], offset: usize) -> Option<(&Bank, Option<&Item>)> {
+    let bank = banks
+        .iter()
+        .find(|b| (b.org..b.org + b.size).contains(&offset))?;
+    let address = bank.rorg + (offset - bank.org) as u32;
+    let item = bank
+        .items
+        .iter()
+        .find(|i| (i.address..i.address + i.size).contains(&address));
+    Some((bank, item))
+}
@@ -0,0 +1,497 @@
+/*
+    cc2600 - a subset of C compiler for the Atari 2600
+    Copyright (C) 2023-2024 Bruno STEUX
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+    Contact info: bruno.steux@gmail.com
+*/
+
+// Headless Atari 2600 emulator, to test the generated cartridges without Stella: runs a number
+// of frames and reports the scanline counts, the RAM content and the TIA register writes.
+// Inputs (joysticks, paddles and console switches) can be scripted frame by frame, and the
+// frames can be checked against golden images or frame hashes for regression testing. The
+// memory accesses can be checked by a sanitizer, the C source line coverage of the run written
+// (with the debug information of -g), and the CPU cycles profiled by function.
+
+mod cartridge;
+mod coverage;
+mod cpu;
+mod debug;
+mod input;
+mod memory_map;
+mod png;
+mod profiler;
+mod riot;
+mod sanitizer;
+mod tia;
+
+use std::fmt::Write;
+use std::path::Path;
+
+use cartridge::{detect_scheme, properties_scheme, Cartridge};
+use coverage::Coverage;
+use cpu::{Cpu, Memory};
+use debug::DebugInfo;
+use input::{Input, InputScript};
+use profiler::Profiler;
+use riot::Riot;
+use sanitizer::Sanitizer;
+use tia::{Frame, Tia, REGISTERS, WIDTH};
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct EmulateOptions {
+    /// Cartridge image
+    #[arg(value_name = "FILE")]
+    pub cartridge: String,
+    /// Bankswitching scheme (default: the one of the .pro file written with -g, or guessed from
+    /// the image)
+    #[arg(long, value_name = "SCHEME")]
+    pub bankswitching: Option<String>,
+    /// Number of frames to run
+    #[arg(long, value_name = "N", default_value = "1")]
+    pub frames: u32,
+    /// Write all the TIA register writes to FILE
+    #[arg(long, value_name = "FILE")]
+    pub tia_writes: Option<String>,
+    /// Write the last frame to FILE (PNG image)
+    #[arg(long, value_name = "FILE")]
+    pub png: Option<String>,
+    /// Palette of the PNG images
+    #[arg(long, value_name = "PALETTE", value_parser = ["ntsc", "pal"], default_value = "ntsc")]
+    pub palette: String,
+    /// Check that the last frame is the PNG image FILE (a golden image written with --png)
+    #[arg(long, value_name = "FILE")]
+    pub check_png: Option<String>,
+    /// Write the scanline count and the hash of every frame to FILE
+    #[arg(long, value_name = "FILE")]
+    pub frame_hashes: Option<String>,
+    /// Check the frames against the hashes of FILE (written with --frame-hashes)
+    #[arg(long, value_name = "FILE")]
+    pub check_hashes: Option<String>,
+    /// Input script: lines of a frame number followed by the inputs held from that frame (up,
+    /// down, left, right, fire, p1.up..., reset, select, paddle0=N, paddle0.fire...,
+    /// difficulty0=a|b, difficulty1=a|b, tv=color|bw)
+    #[arg(long, value_name = "FILE")]
+    pub input: Option<String>,
+    /// Write the RAM content at the end of every frame to FILE
+    #[arg(long, value_name = "FILE")]
+    pub ram_dump: Option<String>,
+    /// Check the memory accesses (RAM ports, writes into ROM, uninitialized RAM, unselected RAM
+    /// banks, stack overflow), naming the variables after the memory map FILE (--memory-map)
+    #[arg(long, value_name = "FILE")]
+    pub sanitize: Option<String>,
+    /// Write the C source line coverage to FILE (LCOV format), using the debug information of
+    /// the .elf file written with -g next to the cartridge
+    #[arg(long, value_name = "FILE")]
+    pub coverage: Option<String>,
+    /// Profile the CPU cycles per function and frame section (VBLANK, kernel, overscan), naming
+    /// the functions after the memory map FILE (--memory-map)
+    #[arg(long, value_name = "FILE")]
+    pub profile: Option<String>,
+    /// Write the call stacks of the profile with their cycles to FILE, in the folded format of
+    /// flamegraph tools
+    #[arg(long, value_name = "FILE", requires = "profile")]
+    pub flamegraph: Option<String>,
+}
+
+pub struct Bus {
+    pub tia: Tia,
+    pub riot: Riot,
+    pub cartridge: Cartridge,
+    /// CPU cycles since power-on
+    pub cycles: u64,
+    pub sanitizer: Option<Sanitizer>,
+}
+
+impl Bus {
+    fn tick(&mut self) {
+        self.tia.tick();
+        self.tia.tick();
+        self.tia.tick();
+        self.riot.tick();
+        self.cycles += 1;
+    }
+
+    fn load(&mut self, address: u16) -> u8 {
+        let value = if address & 0x1000 != 0 {
+            self.cartridge.read(address)
+        } else if address & 0x80 == 0 {
+            self.tia.read(address)
+        } else if address & 0x200 == 0 {
+            self.riot.ram[address as usize & 0x7f]
+        } else {
+            self.riot.read(address)
+        };
+        self.tick();
+        value
+    }
+}
+
+impl Memory for Bus {
+    fn read(&mut self, address: u16) -> u8 {
+        if let Some(sanitizer) = &mut self.sanitizer {
+            sanitizer.read(address, &self.cartridge, &self.tia);
+        }
+        self.load(address)
+    }
+
+    fn dummy_read(&mut self, address: u16) {
+        self.load(address);
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        if let Some(sanitizer) = &mut self.sanitizer {
+            sanitizer.write(address, &self.cartridge, &self.tia);
+        }
+        self.cartridge.write(address, value);
+        if address & 0x1000 == 0 {
+            if address & 0x80 == 0 {
+                self.tia.write(address, value);
+            } else if address & 0x200 == 0 {
+                self.riot.ram[address as usize & 0x7f] = value;
+            } else {
+                self.riot.write(address, value);
+            }
+        }
+        self.tick();
+    }
+}
+
+pub struct Emulator {
+    pub cpu: Cpu,
+    pub bus: Bus,
+    pub coverage: Option<Coverage>,
+    pub profiler: Option<Profiler>,
+}
+
+impl Emulator {
+    pub fn new(cartridge: Cartridge) -> Emulator {
+        let mut emulator = Emulator {
+            cpu: Cpu::new(),
+            bus: Bus {
+                tia: Tia::new(),
+                riot: Riot::new(),
+                cartridge,
+                cycles: 0,
+                sanitizer: None,
+            },
+            coverage: None,
+            profiler: None,
+        };
+        emulator.cpu.reset(&mut emulator.bus);
+        emulator
+    }
+
+    /// Sets the state of the joysticks, paddles and console switches
+    pub fn set_input(&mut self, input: &Input) {
+        self.bus.riot.swcha = input.swcha;
+        self.bus.riot.swchb = input.swchb;
+        self.bus.tia.buttons = input.buttons;
+        self.bus.tia.paddles = input.paddles;
+    }
+
+    /// Runs until the end of the current frame (next VSYNC)
+    pub fn run_frame(&mut self) -> Result<Frame, String> {
+        loop {
+            if let Some(sanitizer) = &mut self.bus.sanitizer {
+                sanitizer.instruction(self.cpu.pc, &self.bus.cartridge);
+            }
+            if let Some(coverage) = &mut self.coverage {
+                coverage.instruction(self.cpu.pc, &self.bus.cartridge);
+            }
+            if let Some(profiler) = &mut self.profiler {
+                profiler.instruction(&self.cpu, &self.bus);
+            }
+            let start = self.bus.cycles;
+            if let Err(pc) = self.cpu.step(&mut self.bus) {
+                return Err(format!(
+                    "CPU jammed at ${:04x} (frame {}, line {})",
+                    pc, self.bus.tia.frame, self.bus.tia.line
+                ));
+            }
+            let end = self.bus.cycles;
+            while self.bus.tia.wsync {
+                self.bus.tick();
+            }
+            if let Some(profiler) = &mut self.profiler {
+                profiler.executed(&self.cpu, end - start, self.bus.cycles - end);
+            }
+            if let Some(frame) = self.bus.tia.completed.take() {
+                return Ok(frame);
+            }
+        }
+    }
+}
+
+// Bankswitching scheme of a cartridge: the one given on the command line, or the one of the
+// Stella properties file written next to the cartridge with -g, or a guess
+fn scheme(options: &EmulateOptions, rom: &[u8]) -> Result<String, String> {
+    if let Some(s) = &options.bankswitching {
+        return Ok(s.clone());
+    }
+    let properties = Path::new(&options.cartridge).with_extension("pro");
+    if let Some(s) = std::fs::read_to_string(properties)
+        .ok()
+        .and_then(|p| properties_scheme(&p))
+    {
+        return Ok(s);
+    }
+    detect_scheme(rom).map(|s| s.to_string()).ok_or(format!(
+        "Can't guess the bankswitching scheme of {}. Use --bankswitching",
+        options.cartridge
+    ))
+}
+
+fn hex_dump(s: &mut String, data: &[u8], start: usize) {
+    for (i, line) in data.chunks(16).enumerate() {
+        let bytes: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        writeln!(s, "${:04x}: {}", start + i * 16, bytes.join(" ")).unwrap();
+    }
+}
+
+fn ram_dump(s: &mut String, emulator: &Emulator) {
+    hex_dump(s, &emulator.bus.riot.ram, 0x80);
+    let ram = emulator.bus.cartridge.ram();
+    if !ram.is_empty() {
+        writeln!(s, "Cartridge RAM ({} bytes):", ram.len()).unwrap();
+        hex_dump(s, ram, 0);
+    }
+}
+
+// Compares the frames with the reference frame hashes file written with --frame-hashes
+fn check_hashes(filename: &str, hashes: &[(u32, u32)]) -> Result<(), String> {
+    let reference =
+        std::fs::read_to_string(filename).map_err(|e| format!("Can't read {}: {}", filename, e))?;
+    let mut errors = Vec::new();
+    let mut last = 0;
+    for (n, line) in reference.lines().enumerate() {
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (Some(frame), Some(lines), Some(hash)) = (
+            fields.first().and_then(|f| f.parse::<usize>().ok()),
+            fields.get(1).and_then(|l| l.parse::<u32>().ok()),
+            fields.get(2).and_then(|h| u32::from_str_radix(h, 16).ok()),
+        ) else {
+            return Err(format!("{}, line {}: syntax error", filename, n + 1));
+        };
+        match frame.checked_sub(1).and_then(|i| hashes.get(i)) {
+            None => last = last.max(frame),
+            Some(&(l, h)) if (l, h) != (lines, hash) => errors.push(format!(
+                "Frame {}: {} scanlines, hash {:08x} (expected {} scanlines, hash {:08x})",
+                frame, l, h, lines, hash
+            )),
+            _ => (),
+        }
+    }
+    if last > hashes.len() {
+        errors.push(format!(
+            "Frames {} to {}: not run (use --frames {})",
+            hashes.len() + 1,
+            last,
+            last
+        ));
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "Frames don't match {}:\n{}",
+            filename,
+            errors.join("\n")
+        ))
+    }
+}
+
+/// Runs a cartridge for the given number of frames, and reports the scanline count of each
+/// frame and the final RAM content
+pub fn emulate(options: &EmulateOptions) -> Result<(), String> {
+    let script = match &options.input {
+        Some(filename) => InputScript::parse(
+            &std::fs::read_to_string(filename)
+                .map_err(|e| format!("Can't read {}: {}", filename, e))?,
+        )?,
+        None => InputScript::default(),
+    };
+    let rom = std::fs::read(&options.cartridge)
+        .map_err(|e| format!("Can't read {}: {}", options.cartridge, e))?;
+    let size = rom.len();
+    let scheme = scheme(options, &rom)?;
+    let mut emulator = Emulator::new(Cartridge::new(&scheme, rom)?);
+    emulator.bus.tia.log_writes = options.tia_writes.is_some();
+    if let Some(filename) = &options.sanitize {
+        let map = std::fs::read_to_string(filename)
+            .map_err(|e| format!("Can't read {}: {}", filename, e))?;
+        emulator.bus.sanitizer = Some(
+            Sanitizer::new(&map, emulator.bus.cartridge.ram_size())
+                .map_err(|e| format!("{}: {}", filename, e))?,
+        );
+    }
+    if let Some(filename) = &options.profile {
+        let map = std::fs::read_to_string(filename)
+            .map_err(|e| format!("Can't read {}: {}", filename, e))?;
+        emulator.profiler =
+            Some(Profiler::new(&map, size).map_err(|e| format!("{}: {}", filename, e))?);
+    }
+    let debug = match &options.coverage {
+        Some(_) => {
+            let filename = Path::new(&options.cartridge).with_extension("elf");
+            emulator.coverage = Some(Coverage::new(size));
+            Some(DebugInfo::read(&filename.to_string_lossy())?)
+        }
+        None => None,
+    };
+    println!(
+        "Cartridge {}: {} bankswitching, {} bytes",
+        options.cartridge, scheme, size
+    );
+
+    // The partial frame before the first VSYNC is not counted (it is frame 0 of the input
+    // script). Inputs are changed on the VSYNC starting their frame
+    let mut dump = String::new();
+    let mut hashes = Vec::new();
+    if let Some(input) = script.at(0) {
+        emulator.set_input(input);
+    }
+    let mut result = emulator.run_frame().map(|_| ());
+    let mut last = None;
+    for n in 1..=options.frames {
+        if result.is_err() {
+            break;
+        }
+        if let Some(input) = script.at(n) {
+            emulator.set_input(input);
+        }
+        result = emulator.run_frame().map(|frame| {
+            println!(
+                "Frame {}: {} scanlines{}",
+                n,
+                frame.lines,
+                if frame.vsync { "" } else { " (no VSYNC)" }
+            );
+            hashes.push((frame.lines, png::crc32(&frame.pixels)));
+            last = Some(frame);
+        });
+        if options.ram_dump.is_some() && result.is_ok() {
+            writeln!(dump, "Frame {}:", n).unwrap();
+            ram_dump(&mut dump, &emulator);
+        }
+    }
+
+    let mut s = String::from("RAM:\n");
+    ram_dump(&mut s, &emulator);
+    print!("{}", s);
+
+    if let Some(filename) = &options.ram_dump {
+        std::fs::write(filename, dump).map_err(|e| format!("Can't write {}: {}", filename, e))?;
+    }
+
+    if let Some(filename) = &options.tia_writes {
+        let mut s = String::from("# frame line clock register value\n");
+        for w in &emulator.bus.tia.writes {
+            let register = REGISTERS
+                .get(w.register as usize)
+                .map_or(format!("${:02x}", w.register), |r| r.to_string());
+            writeln!(
+                s,
+                "{} {} {} {} ${:02x}",
+                w.frame, w.line, w.clock, register, w.value
+            )
+            .unwrap();
+        }
+        std::fs::write(filename, s).map_err(|e| format!("Can't write {}: {}", filename, e))?;
+    }
+
+    if let Some(filename) = &options.frame_hashes {
+        let mut s = String::from("# frame scanlines hash\n");
+        for (n, (lines, hash)) in hashes.iter().enumerate() {
+            writeln!(s, "{} {} {:08x}", n + 1, lines, hash).unwrap();
+        }
+        std::fs::write(filename, s).map_err(|e| format!("Can't write {}: {}", filename, e))?;
+    }
+
+    if let (Some(filename), Some(coverage), Some(debug)) =
+        (&options.coverage, &emulator.coverage, &debug)
+    {
+        let name = Path::new(&options.cartridge)
+            .file_stem()
+            .map_or(String::new(), |n| {
+                n.to_string_lossy().replace(['-', '.'], "_")
+            });
+        let (s, found, hit) = coverage.lcov(debug, &name);
+        std::fs::write(filename, s).map_err(|e| format!("Can't write {}: {}", filename, e))?;
+        println!(
+            "Coverage: {} of {} source lines executed ({:.1}%)",
+            hit,
+            found,
+            if found > 0 {
+                100.0 * hit as f64 / found as f64
+            } else {
+                0.0
+            }
+        );
+    }
+
+    if let Some(profiler) = &mut emulator.profiler {
+        profiler.end(emulator.bus.tia.frame);
+        print!("\n{}", profiler.report());
+        if let Some(filename) = &options.flamegraph {
+            std::fs::write(filename, profiler.flamegraph())
+                .map_err(|e| format!("Can't write {}: {}", filename, e))?;
+        }
+    }
+
+    let palette = if options.palette == "pal" {
+        &png::PAL_PALETTE
+    } else {
+        &png::NTSC_PALETTE
+    };
+    let image = last
+        .as_ref()
+        .map(|frame| png::png(&frame.pixels, WIDTH, palette));
+    if let (Some(filename), Some(image)) = (&options.png, &image) {
+        std::fs::write(filename, image).map_err(|e| format!("Can't write {}: {}", filename, e))?;
+    }
+    result?;
+
+    if let Some(sanitizer) = &emulator.bus.sanitizer {
+        if sanitizer.errors > 0 {
+            return Err(format!(
+                "Sanitizer: {} memory access error{}",
+                sanitizer.errors,
+                if sanitizer.errors > 1 { "s" } else { "" }
+            ));
+        }
+    }
+
+    if let Some(filename) = &options.check_hashes {
+        check_hashes(filename, &hashes)?;
+        println!("Frames match {}", filename);
+    }
+    if let Some(filename) = &options.check_png {
+        let image = image.ok_or("No frame to check (--frames 0)")?;
+        let golden =
+            std::fs::read(filename).map_err(|e| format!("Can't read {}: {}", filename, e))?;
+        if golden != image {
+            return Err(format!(
+                "Frame {} doesn't match {}",
+                options.frames, filename
+            ));
+        }
+        println!("Frame {} matches {}", options.frames, filename);
+    }
+    Ok(())
+}
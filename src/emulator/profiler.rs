@@ -0,0 +1,416 @@
+/*
+    cc2600 - a subset of C compiler for the Atari 2600
+    Copyright (C) 2023-2024 Bruno STEUX
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+    Contact info: bruno.steux@gmail.com
+*/
+
+// Cycle profiler. The CPU cycles are counted by call stack, the functions being the SUBROUTINE
+// labels (and included assembler code) of the memory map written by build_cartridge
+// (--memory-map). The call stack follows JSR and RTS: a call starts with the JSR and ends when
+// the stack pointer gets back above its return address. A call that doesn't land into a function
+// is a bankswitching trampoline (Call{name}): it belongs to the function it reaches, by a JSR from
+// the stub of the other bank (F8, F6, F4...) or by a JMP (E0, E7, 3E...). A jump into another
+// function continues the call in that function. The cycles the CPU is halted by WSYNC are counted
+// apart, as a [WSYNC] callee.
+//
+// Frames start with VSYNC, and are split in VBLANK (up to the end of the vertical blank), kernel
+// (the visible lines) and overscan (the vertical blank at the bottom) sections. The partial frame
+// before the first VSYNC (with the startup code) is not counted.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use super::cartridge::Access;
+use super::cpu::Cpu;
+use super::memory_map;
+use super::Bus;
+
+const SECTIONS: [&str; 4] = ["VBLANK", "Kernel", "Overscan", "Frame"];
+const VBLANK: usize = 0;
+const KERNEL: usize = 1;
+const OVERSCAN: usize = 2;
+const FRAME: usize = 3;
+const JSR: u8 = 0x20;
+const RTI: u8 = 0x40;
+const RTS: u8 = 0x60;
+const WSYNC: &str = "[WSYNC]";
+
+// Call tree node: function called from the parent node
+struct Node {
+    parent: Option<usize>,
+    function: usize,
+}
+
+struct Call {
+    /// Node of the call tree, None for a trampoline (or the startup code) whose function is not
+    /// known yet
+    node: Option<usize>,
+    /// Node of the caller
+    parent: Option<usize>,
+    /// Stack pointer before the call: the call ends when the stack gets back to it
+    sp: u16,
+    /// JSR of a trampoline to its function, that doesn't appear in the call stack
+    hidden: bool,
+    /// Cycles spent before the function is known, by section
+    pending: [u64; 3],
+}
+
+#[derive(Default, Clone)]
+struct Stats {
+    calls: u64,
+    self_cycles: u64,
+    /// Cycles of the function and its callees, by section: sum and worst case over the frames
+    sum: [u64; 4],
+    worst: [u64; 4],
+}
+
+pub struct Profiler {
+    names: Vec<String>,
+    // Function (index + 1) at each offset of the image, and the entry points
+    functions: Vec<usize>,
+    entries: Vec<bool>,
+    wsync: usize,
+    nodes: Vec<Node>,
+    children: HashMap<(Option<usize>, usize), usize>,
+    stack: Vec<Call>,
+    // Cycles of the current frame by node and section, and of all the frames by node
+    frame_cycles: Vec<[u64; 3]>,
+    cycles: Vec<u64>,
+    stats: Vec<Stats>,
+    /// Number of frames counted
+    pub frames: u32,
+    frame: u32,
+    section: usize,
+    line: u32,
+    // First lines of the kernel and of the overscan in the current frame
+    kernel_start: Option<u32>,
+    overscan_start: Option<u32>,
+    lines_sum: [u64; 4],
+    lines_worst: [u64; 4],
+    // Cycles by section (including WSYNC halts)
+    cycles_sum: [u64; 4],
+    cycles_worst: [u64; 4],
+    // Instruction being executed
+    opcode: Option<u8>,
+    sp: u8,
+}
+
+impl Profiler {
+    pub fn new(memory_map: &str, rom_size: usize) -> Result<Profiler, String> {
+        let map = memory_map::parse(memory_map)?;
+        let mut names = Vec::new();
+        let mut functions = vec![0; rom_size];
+        let mut entries = vec![false; rom_size];
+        for bank in memory_map::banks(&map) {
+            for item in bank.items {
+                if item.kind != "function" && item.kind != "assembler code" {
+                    continue;
+                }
+                let start = bank.org + (item.address - bank.rorg) as usize;
+                names.push(item.name);
+                for f in functions.iter_mut().skip(start).take(item.size as usize) {
+                    *f = names.len();
+                }
+                if let Some(e) = entries.get_mut(start) {
+                    *e = true;
+                }
+            }
+        }
+        names.push(WSYNC.to_string());
+        let wsync = names.len() - 1;
+        let stats = vec![Stats::default(); names.len()];
+        Ok(Profiler {
+            names,
+            functions,
+            entries,
+            wsync,
+            nodes: Vec::new(),
+            children: HashMap::new(),
+            // The startup code is a trampoline to main
+            stack: vec![Call {
+                node: None,
+                parent: None,
+                sp: u16::MAX,
+                hidden: false,
+                pending: [0; 3],
+            }],
+            frame_cycles: Vec::new(),
+            cycles: Vec::new(),
+            stats,
+            frames: 0,
+            frame: 0,
+            section: VBLANK,
+            line: 0,
+            kernel_start: None,
+            overscan_start: None,
+            lines_sum: [0; 4],
+            lines_worst: [0; 4],
+            cycles_sum: [0; 4],
+            cycles_worst: [0; 4],
+            opcode: None,
+            sp: 0,
+        })
+    }
+
+    fn node(&mut self, parent: Option<usize>, function: usize) -> usize {
+        if let Some(&n) = self.children.get(&(parent, function)) {
+            return n;
+        }
+        self.nodes.push(Node { parent, function });
+        self.frame_cycles.push([0; 3]);
+        self.cycles.push(0);
+        self.children
+            .insert((parent, function), self.nodes.len() - 1);
+        self.nodes.len() - 1
+    }
+
+    // Call of the function being executed (the trampolines JSR are not)
+    fn current(&mut self) -> &mut Call {
+        self.stack.iter_mut().rev().find(|c| !c.hidden).unwrap()
+    }
+
+    // Counts cycles of a section for the function being executed, or one of its callees
+    fn count(&mut self, section: usize, cycles: u64, callee: Option<usize>) {
+        match (self.current().node, callee) {
+            (Some(node), Some(f)) => {
+                let n = self.node(Some(node), f);
+                self.frame_cycles[n][section] += cycles;
+            }
+            (Some(node), None) => self.frame_cycles[node][section] += cycles,
+            (None, _) => self.current().pending[section] += cycles,
+        }
+    }
+
+    // Sets the function of the current call (resolving a trampoline, or after a jump)
+    fn enter(&mut self, function: usize, entry: bool) {
+        let call = self.current();
+        let (node, parent, pending) = (call.node, call.parent, call.pending);
+        if node.is_some_and(|n| self.nodes[n].function == function) {
+            return;
+        }
+        let n = self.node(parent, function);
+        let call = self.current();
+        call.node = Some(n);
+        call.pending = [0; 3];
+        for (s, c) in pending.iter().enumerate() {
+            self.frame_cycles[n][s] += c;
+        }
+        if self.frame > 0 && (node.is_none() || entry) {
+            self.stats[function].calls += 1;
+        }
+    }
+
+    /// Start of an instruction
+    pub fn instruction(&mut self, cpu: &Cpu, bus: &Bus) {
+        let tia = &bus.tia;
+        if tia.frame != self.frame {
+            self.end_frame();
+            self.frame = tia.frame;
+            self.section = VBLANK;
+            self.kernel_start = None;
+            self.overscan_start = None;
+        }
+        self.line = tia.line;
+        if !tia.vblank() && self.section == VBLANK {
+            self.section = KERNEL;
+            self.kernel_start = Some(tia.line);
+        } else if tia.vblank() && self.section == KERNEL {
+            self.section = OVERSCAN;
+            self.overscan_start = Some(tia.line);
+        }
+
+        self.sp = cpu.s;
+        self.opcode = None;
+        if let Access::Rom(offset) = bus.cartridge.access(cpu.pc) {
+            self.opcode = bus.cartridge.rom().get(offset).copied();
+            if let Some(&f) = self.functions.get(offset).filter(|&&f| f != 0) {
+                self.enter(f - 1, self.entries[offset]);
+            }
+        }
+    }
+
+    /// End of an instruction, that took `cycles` cycles and was halted `halted` cycles by WSYNC
+    pub fn executed(&mut self, cpu: &Cpu, cycles: u64, halted: u64) {
+        self.count(self.section, cycles, None);
+        if halted > 0 {
+            self.count(self.section, halted, Some(self.wsync));
+        }
+        match self.opcode {
+            Some(JSR) => {
+                let call = self.current();
+                let (node, hidden) = (call.node, call.node.is_none());
+                self.stack.push(Call {
+                    node: None,
+                    parent: node,
+                    sp: self.sp as u16,
+                    hidden,
+                    pending: [0; 3],
+                });
+            }
+            Some(RTS) | Some(RTI) => {
+                while self.stack.len() > 1 && self.stack.last().unwrap().sp <= cpu.s as u16 {
+                    // A call whose function is never known is counted in its caller
+                    let call = self.stack.pop().unwrap();
+                    for (s, c) in call.pending.iter().enumerate() {
+                        self.count(s, *c, None);
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// End of the run, after the given frame
+    pub fn end(&mut self, frame: u32) {
+        if frame != self.frame {
+            self.end_frame();
+            self.frame = frame;
+        }
+    }
+
+    // Adds the cycles of the frame to the statistics
+    fn end_frame(&mut self) {
+        if self.frame == 0 {
+            for c in self.frame_cycles.iter_mut() {
+                *c = [0; 3];
+            }
+            return;
+        }
+        self.frames += 1;
+        let mut frame = vec![[0u64; 4]; self.names.len()];
+        let mut sections = [0u64; 4];
+        for (n, c) in self.frame_cycles.iter_mut().enumerate() {
+            let total: u64 = c.iter().sum();
+            if total == 0 {
+                continue;
+            }
+            self.cycles[n] += total;
+            for (section, cycles) in sections.iter_mut().zip(c.iter()) {
+                *section += cycles;
+            }
+            sections[FRAME] += total;
+            let f = self.nodes[n].function;
+            self.stats[f].self_cycles += total;
+            // Cycles of the function and of its callers (counted once with recursion)
+            let mut functions = Vec::new();
+            let mut node = Some(n);
+            while let Some(i) = node {
+                if !functions.contains(&self.nodes[i].function) {
+                    functions.push(self.nodes[i].function);
+                }
+                node = self.nodes[i].parent;
+            }
+            for f in functions {
+                for s in 0..3 {
+                    frame[f][s] += c[s];
+                }
+                frame[f][FRAME] += total;
+            }
+            *c = [0; 3];
+        }
+        for (stats, cycles) in self.stats.iter_mut().zip(frame.iter()) {
+            for (s, c) in cycles.iter().enumerate() {
+                stats.sum[s] += c;
+                stats.worst[s] = stats.worst[s].max(*c);
+            }
+        }
+
+        let lines = self.line as u64 + 1;
+        let kernel = self.kernel_start.map_or(lines, |l| l as u64);
+        let overscan = self.overscan_start.map_or(lines, |l| l as u64);
+        for (s, l) in [kernel, overscan - kernel, lines - overscan, lines]
+            .iter()
+            .enumerate()
+        {
+            self.lines_sum[s] += l;
+            self.lines_worst[s] = self.lines_worst[s].max(*l);
+            self.cycles_sum[s] += sections[s];
+            self.cycles_worst[s] = self.cycles_worst[s].max(sections[s]);
+        }
+    }
+
+    /// Report of the cycles per frame, by section and function (average and worst case)
+    pub fn report(&self) -> String {
+        let frames = self.frames.max(1) as u64;
+        let cell = |sum: u64, worst: u64| format!("{}/{}", (sum + frames / 2) / frames, worst);
+        let width = self.names.iter().map(|n| n.len()).max().unwrap_or(0).max(9);
+        let mut s = format!(
+            "Profile of {} frame{} (average/worst case per frame):\n\n{:width$}",
+            self.frames,
+            if self.frames > 1 { "s" } else { "" },
+            "",
+        );
+        for section in SECTIONS {
+            write!(s, " {:>14}", section).unwrap();
+        }
+        write!(s, "\n{:width$}", "Scanlines").unwrap();
+        for i in 0..4 {
+            write!(s, " {:>14}", cell(self.lines_sum[i], self.lines_worst[i])).unwrap();
+        }
+        write!(s, "\n{:width$}", "Cycles").unwrap();
+        for i in 0..4 {
+            let cycles = cell(self.cycles_sum[i], self.cycles_worst[i]);
+            write!(s, " {:>14}", cycles).unwrap();
+        }
+
+        write!(s, "\n\n{:width$} {:>8} {:>8}", "Function", "Calls", "Self").unwrap();
+        for section in SECTIONS {
+            write!(s, " {:>14}", section).unwrap();
+        }
+        s.push('\n');
+        let mut functions: Vec<usize> = (0..self.names.len())
+            .filter(|&f| self.stats[f].sum[FRAME] > 0)
+            .collect();
+        functions.sort_by_key(|&f| std::cmp::Reverse(self.stats[f].sum[FRAME]));
+        for f in functions {
+            let stats = &self.stats[f];
+            write!(
+                s,
+                "{:width$} {:>8.1} {:>8}",
+                self.names[f],
+                stats.calls as f64 / frames as f64,
+                (stats.self_cycles + frames / 2) / frames
+            )
+            .unwrap();
+            for i in 0..4 {
+                write!(s, " {:>14}", cell(stats.sum[i], stats.worst[i])).unwrap();
+            }
+            s.push('\n');
+        }
+        s
+    }
+
+    /// Call stacks with their cycles, in the folded format of flamegraph.pl and inferno
+    pub fn flamegraph(&self) -> String {
+        let mut lines = Vec::new();
+        for (n, &cycles) in self.cycles.iter().enumerate() {
+            if cycles == 0 {
+                continue;
+            }
+            let mut names = Vec::new();
+            let mut node = Some(n);
+            while let Some(i) = node {
+                names.push(self.names[self.nodes[i].function].as_str());
+                node = self.nodes[i].parent;
+            }
+            names.reverse();
+            lines.push(format!("{} {}\n", names.join(";"), cycles));
+        }
+        lines.sort();
+        lines.concat()
+    }
+}
@@ -0,0 +1,115 @@
+/*
+    cc2600 - a subset of C compiler for the Atari 2600
+    Copyright (C) 2023-2024 Bruno STEUX
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+    Contact info: bruno.steux@gmail.com
+*/
+
+// RIOT (6532): 128 bytes of RAM, interval timer and I/O ports (joysticks and console switches).
+
+pub struct Riot {
+    pub ram: [u8; 128],
+    /// Port A inputs (joysticks directions, active low)
+    pub swcha: u8,
+    /// Port B inputs (console switches, active low for reset and select)
+    pub swchb: u8,
+    timer: u8,
+    interval: u32,
+    count: u32,
+    underflow: bool,
+    flag: bool,
+    porta: u8,
+    swacnt: u8,
+    portb: u8,
+    swbcnt: u8,
+}
+
+impl Riot {
+    pub fn new() -> Riot {
+        Riot {
+            ram: [0; 128],
+            swcha: 0xff,
+            swchb: 0x0b,
+            timer: 0,
+            interval: 1024,
+            count: 1024,
+            underflow: false,
+            flag: false,
+            porta: 0,
+            swacnt: 0,
+            portb: 0,
+            swbcnt: 0,
+        }
+    }
+
+    /// Advances one CPU cycle
+    pub fn tick(&mut self) {
+        if self.underflow {
+            self.timer = self.timer.wrapping_sub(1);
+            return;
+        }
+        self.count -= 1;
+        if self.count == 0 {
+            self.count = self.interval;
+            if self.timer == 0 {
+                self.timer = 0xff;
+                self.underflow = true;
+                self.flag = true;
+            } else {
+                self.timer -= 1;
+            }
+        }
+    }
+
+    pub fn read(&mut self, address: u16) -> u8 {
+        if address & 4 == 0 {
+            match address & 3 {
+                0 => (self.porta & self.swacnt) | (self.swcha & !self.swacnt),
+                1 => self.swacnt,
+                2 => (self.portb & self.swbcnt) | (self.swchb & !self.swbcnt),
+                _ => self.swbcnt,
+            }
+        } else if address & 1 == 0 {
+            // INTIM
+            self.flag = false;
+            self.timer
+        } else {
+            // TIMINT
+            if self.flag {
+                0x80
+            } else {
+                0
+            }
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        if address & 4 == 0 {
+            match address & 3 {
+                0 => self.porta = value,
+                1 => self.swacnt = value,
+                2 => self.portb = value,
+                _ => self.swbcnt = value,
+            }
+        } else if address & 0x10 != 0 {
+            self.timer = value;
+            self.interval = [1, 8, 64, 1024][address as usize & 3];
+            self.count = 1;
+            self.underflow = false;
+            self.flag = false;
+        }
+    }
+}
@@ -0,0 +1,318 @@
+/*
+    cc2600 - a subset of C compiler for the Atari 2600
+    Copyright (C) 2023-2024 Bruno STEUX
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+    Contact info: bruno.steux@gmail.com
+*/
+
+// Memory sanitizer. Checks the memory accesses of a cartridge, and reports the reads of RAM
+// write ports (and writes to RAM read ports), the writes into ROM, the reads of RAM never
+// written since power-on, the accesses to extended RAM variables whose bank is not selected and
+// the stack overflowing into the variables. Variables and functions are named after the memory
+// map written by build_cartridge (--memory-map). The dummy reads of the processor are not checked.
+
+use std::collections::HashSet;
+
+use super::cartridge::{Access, Cartridge};
+use super::memory_map::{self, list, number, string, Bank};
+use super::tia::Tia;
+use crate::report::Json;
+
+struct Variable {
+    name: String,
+    /// RAM area of the memory map (zeropage RAM, superchip RAM, 3E RAM bank 1...)
+    region: String,
+    read: u16,
+    write: u16,
+    size: u16,
+    /// Index in the cartridge RAM (None for the zeropage RAM)
+    index: Option<usize>,
+}
+
+pub struct Sanitizer {
+    variables: Vec<Variable>,
+    banks: Vec<Bank>,
+    ram_written: [bool; 128],
+    cartridge_ram_written: Vec<bool>,
+    // Address of the instruction being executed, and its offset in the image
+    pc: u16,
+    pc_offset: Option<usize>,
+    reported: HashSet<(u16, String)>,
+    // Instructions that made the stack overflow (reported once, whatever the depth)
+    stack_overflows: HashSet<u16>,
+    /// Number of errors reported
+    pub errors: usize,
+}
+
+// Read and write addresses and cartridge RAM index of a variable of the memory map. The address
+// of a superchip variable is its write address, while it is the read address for the other
+// extended RAMs
+fn ports(region: &str, start: u32, address: u32) -> Option<(u16, u16, Option<usize>)> {
+    let a = address as u16;
+    let offset = (address - start) as usize;
+    let bank = region.rsplit(' ').next()?.parse::<usize>();
+    Some(match region {
+        "Zeropage RAM" => (a, a, None),
+        "Superchip RAM" => (a + 0x80, a, Some(offset)),
+        "CBS RAM+" => (a, a - 0x100, Some(offset)),
+        "E7 RAM" => (a, a - 0x400, Some(offset)),
+        _ if region.starts_with("E7 RAM page") => {
+            (a, a - 0x100, Some(0x400 + bank.ok()? * 0x100 + offset))
+        }
+        _ if region.starts_with("3E RAM bank") => {
+            (a, a + 0x400, Some((bank.ok()? - 1) * 0x400 + offset))
+        }
+        _ if region.starts_with("3E+ RAM bank") => {
+            (a, a + 0x200, Some(bank.ok()? * 0x200 + offset))
+        }
+        _ => return None,
+    })
+}
+
+// Cartridge address, as in the memory map
+fn normalize(address: u16) -> u16 {
+    0x1000 | (address & 0xfff)
+}
+
+impl Sanitizer {
+    pub fn new(memory_map: &str, cartridge_ram_size: usize) -> Result<Sanitizer, String> {
+        let map = memory_map::parse(memory_map)?;
+        let banks = memory_map::banks(&map);
+        let mut variables = Vec::new();
+        for region in list(&map, "ram") {
+            let name = string(region, "name");
+            let start = number(region, "start") as u32;
+            // Variables, and the memory shared by local variables with the variables sharing it
+            let mut all: Vec<&Json> = list(region, "variables").iter().collect();
+            for overlay in list(region, "overlays") {
+                all.push(overlay);
+                for function in list(overlay, "functions") {
+                    all.extend(list(function, "variables"));
+                }
+            }
+            for v in all {
+                if let Some((read, write, index)) = ports(&name, start, number(v, "address") as u32)
+                {
+                    variables.push(Variable {
+                        name: string(v, "name"),
+                        region: name.clone(),
+                        read,
+                        write,
+                        size: number(v, "size") as u16,
+                        index,
+                    });
+                }
+            }
+        }
+        Ok(Sanitizer {
+            variables,
+            banks,
+            ram_written: [false; 128],
+            cartridge_ram_written: vec![false; cartridge_ram_size],
+            pc: 0,
+            pc_offset: None,
+            reported: HashSet::new(),
+            stack_overflows: HashSet::new(),
+            errors: 0,
+        })
+    }
+
+    // Bank and ROM item (function, table...) at an offset of the image
+    fn item(&self, offset: usize) -> Option<(&Bank, Option<&memory_map::Item>)> {
+        memory_map::item(&self.banks, offset)
+    }
+
+    /// Start of an instruction
+    pub fn instruction(&mut self, pc: u16, cartridge: &Cartridge) {
+        self.pc = pc;
+        self.pc_offset = match cartridge.access(pc) {
+            Access::Rom(offset) => Some(offset),
+            _ => None,
+        };
+    }
+
+    fn report(&mut self, tia: &Tia, message: String) {
+        if self.reported.insert((self.pc, message.clone())) {
+            self.errors += 1;
+            let location = match self.pc_offset.and_then(|o| self.item(o)) {
+                Some((bank, Some(item))) => format!(" in {} ({})", item.name, bank.name),
+                Some((bank, None)) => format!(" ({})", bank.name),
+                None => String::new(),
+            };
+            eprintln!(
+                "Sanitizer: {} at ${:04x}{} (frame {}, line {})",
+                message, self.pc, location, tia.frame, tia.line
+            );
+        }
+    }
+
+    // Names of the variables (with the offset in the variable) at the given address
+    fn names(&self, contains: impl Fn(&Variable) -> Option<u16>) -> String {
+        let names: Vec<String> = self
+            .variables
+            .iter()
+            .filter_map(|v| {
+                contains(v).map(|o| match o {
+                    0 => v.name.clone(),
+                    o => format!("{}+{}", v.name, o),
+                })
+            })
+            .collect();
+        if names.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", names.join(", "))
+        }
+    }
+
+    fn zeropage_names(&self, address: u16) -> String {
+        self.names(|v| {
+            (v.index.is_none() && (v.read..v.read + v.size).contains(&address))
+                .then(|| address - v.read)
+        })
+    }
+
+    fn cartridge_ram_names(&self, index: usize) -> String {
+        self.names(|v| {
+            v.index
+                .filter(|&i| (i..i + v.size as usize).contains(&index))
+                .map(|i| (index - i) as u16)
+        })
+    }
+
+    // Checks that the extended RAM variables at an address (read or write port) are mapped, or
+    // that a ROM item is (ROM and RAM banks share the same addresses with 3E, 3E+ and E7).
+    // Returns true if an error was reported
+    fn check_selected(&mut self, tia: &Tia, address: u16, write: bool, access: Access) -> bool {
+        let port = |v: &Variable| if write { v.write } else { v.read };
+        let variables: Vec<&Variable> = self
+            .variables
+            .iter()
+            .filter(|v| v.index.is_some() && (port(v)..port(v) + v.size).contains(&address))
+            .collect();
+        // The startup code clears all the RAM banks
+        let startup = || {
+            self.pc_offset
+                .and_then(|o| self.item(o))
+                .and_then(|(_, item)| item)
+                .is_some_and(|item| item.kind == "code")
+        };
+        let selected = match access {
+            Access::RamRead(index) | Access::RamWrite(index) => {
+                startup()
+                    || variables.iter().any(|v| {
+                        let i = v.index.unwrap();
+                        (i..i + v.size as usize).contains(&index)
+                    })
+            }
+            Access::Rom(offset) => {
+                !write && self.item(offset).is_some_and(|(_, item)| item.is_some())
+            }
+            Access::Register => true,
+        };
+        if variables.is_empty() || selected {
+            return false;
+        }
+        let names: Vec<String> = variables
+            .iter()
+            .map(|v| format!("{} ({})", v.name, v.region))
+            .collect();
+        let message = format!(
+            "{} ${:04x} while {} {} not selected",
+            if write { "Write to" } else { "Read of" },
+            address,
+            names.join(" or "),
+            if variables.len() > 1 { "are" } else { "is" }
+        );
+        self.report(tia, message);
+        true
+    }
+
+    /// Read access (before the access is done)
+    pub fn read(&mut self, address: u16, cartridge: &Cartridge, tia: &Tia) {
+        if address & 0x1000 != 0 {
+            let address = normalize(address);
+            let access = cartridge.access(address);
+            if self.check_selected(tia, address, false, access) {
+                return;
+            }
+            match access {
+                Access::RamWrite(index) => {
+                    let names = self.cartridge_ram_names(index);
+                    self.report(
+                        tia,
+                        format!("Read of the RAM write port ${:04x}{}", address, names),
+                    );
+                }
+                Access::RamRead(index) if !self.cartridge_ram_written[index] => {
+                    let names = self.cartridge_ram_names(index);
+                    self.report(
+                        tia,
+                        format!("Read of uninitialized RAM ${:04x}{}", address, names),
+                    );
+                }
+                _ => (),
+            }
+        } else if address & 0x280 == 0x80 {
+            let index = (address & 0x7f) as usize;
+            if !self.ram_written[index] {
+                let names = self.zeropage_names(0x80 | index as u16);
+                self.report(
+                    tia,
+                    format!("Read of uninitialized RAM ${:04x}{}", 0x80 | index, names),
+                );
+            }
+        }
+    }
+
+    /// Write access (before the access is done)
+    pub fn write(&mut self, address: u16, cartridge: &Cartridge, tia: &Tia) {
+        if address & 0x1000 != 0 {
+            let address = normalize(address);
+            let access = cartridge.access(address);
+            if let Access::RamWrite(index) = access {
+                self.cartridge_ram_written[index] = true;
+            }
+            if self.check_selected(tia, address, true, access) {
+                return;
+            }
+            match access {
+                Access::RamRead(index) => {
+                    let names = self.cartridge_ram_names(index);
+                    self.report(
+                        tia,
+                        format!("Write to the RAM read port ${:04x}{}", address, names),
+                    );
+                }
+                Access::Rom(_) => {
+                    self.report(tia, format!("Write into ROM at ${:04x}", address));
+                }
+                _ => (),
+            }
+        } else if address & 0x280 == 0x80 {
+            let index = (address & 0x7f) as usize;
+            self.ram_written[index] = true;
+            // The stack is on page 1, the variables on page 0 (the same RAM)
+            let names = self.zeropage_names(0x80 | index as u16);
+            if address & 0x100 != 0 && !names.is_empty() && self.stack_overflows.insert(self.pc) {
+                self.report(
+                    tia,
+                    format!("Stack overflow into ${:04x}{}", 0x80 | index, names),
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,398 @@
+/*
+    cc2600 - a subset of C compiler for the Atari 2600
+    Copyright (C) 2023-2024 Bruno STEUX
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+    Contact info: bruno.steux@gmail.com
+*/
+
+// TIA video and input ports. The objects are drawn pixel per pixel from their horizontal
+// position, which is accurate enough for kernels that position their objects with RESxx and
+// HMOVE at the start of the line (the usual way), but doesn't reproduce the artefacts of mid-line
+// HMOVEs.
+
+/// Color clocks per scanline
+pub const LINE_CLOCKS: u32 = 228;
+/// Color clocks of the horizontal blank
+pub const HBLANK: u32 = 68;
+/// Visible pixels per scanline
+pub const WIDTH: usize = 160;
+const W: u32 = WIDTH as u32;
+/// Frames are cut after this number of lines when the program doesn't generate VSYNC
+pub const MAX_LINES: u32 = 400;
+
+pub const REGISTERS: [&str; 0x2d] = [
+    "VSYNC", "VBLANK", "WSYNC", "RSYNC", "NUSIZ0", "NUSIZ1", "COLUP0", "COLUP1", "COLUPF",
+    "COLUBK", "CTRLPF", "REFP0", "REFP1", "PF0", "PF1", "PF2", "RESP0", "RESP1", "RESM0", "RESM1",
+    "RESBL", "AUDC0", "AUDC1", "AUDF0", "AUDF1", "AUDV0", "AUDV1", "GRP0", "GRP1", "ENAM0",
+    "ENAM1", "ENABL", "HMP0", "HMP1", "HMM0", "HMM1", "HMBL", "VDELP0", "VDELP1", "VDELBL",
+    "RESMP0", "RESMP1", "HMOVE", "HMCLR", "CXCLR",
+];
+
+const VSYNC: usize = 0x00;
+const VBLANK: usize = 0x01;
+const WSYNC: usize = 0x02;
+const NUSIZ0: usize = 0x04;
+const COLUP0: usize = 0x06;
+const COLUP1: usize = 0x07;
+const COLUPF: usize = 0x08;
+const COLUBK: usize = 0x09;
+const CTRLPF: usize = 0x0a;
+const REFP0: usize = 0x0b;
+const PF0: usize = 0x0d;
+const PF1: usize = 0x0e;
+const PF2: usize = 0x0f;
+const RESP0: usize = 0x10;
+const RESBL: usize = 0x14;
+const GRP0: usize = 0x1b;
+const GRP1: usize = 0x1c;
+const ENAM0: usize = 0x1d;
+const ENABL: usize = 0x1f;
+const HMP0: usize = 0x20;
+const HMBL: usize = 0x24;
+const VDELP0: usize = 0x25;
+const VDELBL: usize = 0x27;
+const RESMP0: usize = 0x28;
+const HMOVE: usize = 0x2a;
+const HMCLR: usize = 0x2b;
+const CXCLR: usize = 0x2c;
+
+// Objects, as indexes of the position array
+const P0: usize = 0;
+const P1: usize = 1;
+const M0: usize = 2;
+const BL: usize = 4;
+
+#[derive(Debug, Clone)]
+pub struct TiaWrite {
+    pub frame: u32,
+    pub line: u32,
+    pub clock: u32,
+    pub register: u8,
+    pub value: u8,
+}
+
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub lines: u32,
+    /// false if the frame was cut because no VSYNC was generated
+    pub vsync: bool,
+    /// Color register values of the pixels (WIDTH per line)
+    pub pixels: Vec<u8>,
+}
+
+pub struct Tia {
+    pub clock: u32,
+    pub line: u32,
+    /// Number of frames started (VSYNC count)
+    pub frame: u32,
+    /// The CPU is halted until the start of the next line
+    pub wsync: bool,
+    /// Last completed frame, taken by the emulator
+    pub completed: Option<Frame>,
+    pub log_writes: bool,
+    pub writes: Vec<TiaWrite>,
+    /// Paddles charge time, in scanlines
+    pub paddles: [u32; 4],
+    /// Fire buttons (INPT4 and INPT5) pressed
+    pub buttons: [bool; 2],
+    registers: [u8; 0x40],
+    position: [u32; 5],
+    old_grp: [u8; 2],
+    old_enabl: u8,
+    collisions: [u8; 8],
+    hmove_blank: bool,
+    pixels: Vec<u8>,
+    total_lines: u64,
+    // Line where the paddles capacitors dump was released
+    dump_release: u64,
+}
+
+// Collision register value (bits 7 and 6)
+fn bits(bit7: bool, bit6: bool) -> u8 {
+    ((bit7 as u8) << 7) | ((bit6 as u8) << 6)
+}
+
+// Copies of players and missiles, by NUSIZ value
+fn copies(nusiz: u8) -> &'static [u32] {
+    match nusiz & 7 {
+        1 => &[0, 16],
+        2 => &[0, 32],
+        3 => &[0, 16, 32],
+        4 => &[0, 64],
+        6 => &[0, 32, 64],
+        _ => &[0],
+    }
+}
+
+impl Tia {
+    pub fn new() -> Tia {
+        Tia {
+            clock: 0,
+            line: 0,
+            frame: 0,
+            wsync: false,
+            completed: None,
+            log_writes: false,
+            writes: Vec::new(),
+            paddles: [0; 4],
+            buttons: [false; 2],
+            registers: [0; 0x40],
+            position: [0; 5],
+            old_grp: [0; 2],
+            old_enabl: 0,
+            collisions: [0; 8],
+            hmove_blank: false,
+            pixels: vec![0; WIDTH * MAX_LINES as usize],
+            total_lines: 0,
+            dump_release: 0,
+        }
+    }
+
+    fn end_frame(&mut self, vsync: bool) {
+        let lines = self.line.min(MAX_LINES);
+        self.completed = Some(Frame {
+            lines,
+            vsync,
+            pixels: self.pixels[0..WIDTH * lines as usize].to_vec(),
+        });
+        self.pixels.fill(0);
+        self.line = 0;
+        self.frame += 1;
+    }
+
+    /// The beam is blanked (VBLANK bit 1 set)
+    pub fn vblank(&self) -> bool {
+        self.registers[VBLANK] & 2 != 0
+    }
+
+    /// Advances one color clock
+    pub fn tick(&mut self) {
+        if self.clock >= HBLANK && self.line < MAX_LINES {
+            let x = self.clock - HBLANK;
+            let color = self.pixel(x);
+            self.pixels[self.line as usize * WIDTH + x as usize] = color;
+        }
+        self.clock += 1;
+        if self.clock == LINE_CLOCKS {
+            self.clock = 0;
+            self.line += 1;
+            self.total_lines += 1;
+            self.wsync = false;
+            self.hmove_blank = false;
+            if self.line == MAX_LINES {
+                self.end_frame(false);
+            }
+        }
+    }
+
+    fn player(&self, n: usize, x: u32) -> bool {
+        let grp = if self.registers[VDELP0 + n] & 1 != 0 {
+            self.old_grp[n]
+        } else {
+            self.registers[GRP0 + n]
+        };
+        if grp == 0 {
+            return false;
+        }
+        let nusiz = self.registers[NUSIZ0 + n] & 7;
+        let scale = match nusiz {
+            5 => 2,
+            7 => 4,
+            _ => 1,
+        };
+        let d = (x + W - self.position[P0 + n]) % W;
+        for &offset in copies(nusiz) {
+            if d >= offset && d - offset < 8 * scale {
+                let bit = (d - offset) / scale;
+                let mask = if self.registers[REFP0 + n] & 8 != 0 {
+                    1 << bit
+                } else {
+                    0x80 >> bit
+                };
+                return grp & mask != 0;
+            }
+        }
+        false
+    }
+
+    fn missile(&self, n: usize, x: u32) -> bool {
+        if self.registers[ENAM0 + n] & 2 == 0 || self.registers[RESMP0 + n] & 2 != 0 {
+            return false;
+        }
+        let nusiz = self.registers[NUSIZ0 + n];
+        let width = 1 << ((nusiz >> 4) & 3);
+        let d = (x + W - self.position[M0 + n]) % W;
+        copies(nusiz)
+            .iter()
+            .any(|&offset| d >= offset && d - offset < width)
+    }
+
+    fn ball(&self, x: u32) -> bool {
+        let enabled = if self.registers[VDELBL] & 1 != 0 {
+            self.old_enabl
+        } else {
+            self.registers[ENABL]
+        };
+        if enabled & 2 == 0 {
+            return false;
+        }
+        let width = 1 << ((self.registers[CTRLPF] >> 4) & 3);
+        (x + W - self.position[BL]) % W < width
+    }
+
+    fn playfield(&self, x: u32) -> bool {
+        let mut i = x / 4;
+        if i >= 20 {
+            i = if self.registers[CTRLPF] & 1 != 0 {
+                39 - i
+            } else {
+                i - 20
+            };
+        }
+        match i {
+            0..=3 => self.registers[PF0] >> (4 + i) & 1 != 0,
+            4..=11 => self.registers[PF1] >> (11 - i) & 1 != 0,
+            _ => self.registers[PF2] >> (i - 12) & 1 != 0,
+        }
+    }
+
+    fn pixel(&mut self, x: u32) -> u8 {
+        if self.registers[VBLANK] & 2 != 0 || (self.hmove_blank && x < 8) {
+            return 0;
+        }
+        let p0 = self.player(0, x);
+        let p1 = self.player(1, x);
+        let m0 = self.missile(0, x);
+        let m1 = self.missile(1, x);
+        let bl = self.ball(x);
+        let pf = self.playfield(x);
+
+        let cx = &mut self.collisions;
+        cx[0] |= bits(m0 && p1, m0 && p0);
+        cx[1] |= bits(m1 && p0, m1 && p1);
+        cx[2] |= bits(p0 && pf, p0 && bl);
+        cx[3] |= bits(p1 && pf, p1 && bl);
+        cx[4] |= bits(m0 && pf, m0 && bl);
+        cx[5] |= bits(m1 && pf, m1 && bl);
+        cx[6] |= bits(bl && pf, false);
+        cx[7] |= bits(p0 && p1, m0 && m1);
+
+        let ctrlpf = self.registers[CTRLPF];
+        let pf_color = if ctrlpf & 6 == 2 {
+            // Score mode
+            if x < 80 {
+                self.registers[COLUP0]
+            } else {
+                self.registers[COLUP1]
+            }
+        } else {
+            self.registers[COLUPF]
+        };
+        let color = if ctrlpf & 4 != 0 && (pf || bl) {
+            self.registers[COLUPF]
+        } else if p0 || m0 {
+            self.registers[COLUP0]
+        } else if p1 || m1 {
+            self.registers[COLUP1]
+        } else if pf {
+            pf_color
+        } else if bl {
+            self.registers[COLUPF]
+        } else {
+            self.registers[COLUBK]
+        };
+        color & 0xfe
+    }
+
+    pub fn read(&mut self, address: u16) -> u8 {
+        match address & 0x0f {
+            a @ 0..=7 => self.collisions[a as usize],
+            a @ 8..=11 => {
+                let dumped = self.registers[VBLANK] & 0x80 != 0;
+                let charge = self.total_lines - self.dump_release;
+                if !dumped && charge >= self.paddles[a as usize - 8] as u64 {
+                    0x80
+                } else {
+                    0
+                }
+            }
+            a @ 12..=13 => {
+                if self.buttons[a as usize - 12] {
+                    0
+                } else {
+                    0x80
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        let register = (address & 0x3f) as usize;
+        if self.log_writes {
+            self.writes.push(TiaWrite {
+                frame: self.frame,
+                line: self.line,
+                clock: self.clock,
+                register: register as u8,
+                value,
+            });
+        }
+        match register {
+            VSYNC if value & 2 != 0 && self.registers[VSYNC] & 2 == 0 => self.end_frame(true),
+            VBLANK if value & 0x80 == 0 && self.registers[VBLANK] & 0x80 != 0 => {
+                self.dump_release = self.total_lines;
+            }
+            WSYNC => self.wsync = true,
+            GRP0 => self.old_grp[1] = self.registers[GRP1],
+            GRP1 => {
+                self.old_grp[0] = self.registers[GRP0];
+                self.old_enabl = self.registers[ENABL];
+            }
+            RESP0..=RESBL => {
+                let object = register - RESP0;
+                let (start, delay) = if object <= P1 { (3, 5) } else { (2, 4) };
+                self.position[object] = if self.clock < HBLANK {
+                    start
+                } else {
+                    (self.clock - HBLANK + delay) % W
+                };
+            }
+            HMOVE => {
+                for (object, position) in self.position.iter_mut().enumerate() {
+                    let motion = (self.registers[HMP0 + object] as i8 >> 4) as i32;
+                    *position = (*position as i32 - motion).rem_euclid(W as i32) as u32;
+                }
+                self.hmove_blank = true;
+            }
+            HMCLR => self.registers[HMP0..=HMBL].fill(0),
+            CXCLR => self.collisions = [0; 8],
+            _ => (),
+        }
+        self.registers[register] = value;
+        // Missiles locked on their player
+        for n in [0, 1] {
+            if self.registers[RESMP0 + n] & 2 != 0 {
+                let center = match self.registers[NUSIZ0 + n] & 7 {
+                    5 => 6,
+                    7 => 10,
+                    _ => 3,
+                };
+                self.position[M0 + n] = (self.position[P0 + n] + center) % W;
+            }
+        }
+    }
+}
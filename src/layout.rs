@@ -0,0 +1,180 @@
+/*
+    cc2600 - a subset of C compiler for the Atari 2600
+    Copyright (C) 2023-2024 Bruno STEUX
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+    Contact info: bruno.steux@gmail.com
+*/
+
+// Exact layout of the cartridge banks.
+//
+// The code of each bank is assembled (with the built-in assembler) before being written, so that
+// the address and size of every function, table and assembler insert are known and overflows
+// can be reported with the name of the item that didn't fit.
+
+use std::fmt::Write;
+
+use crate::assembler::{Assembler, Assembly};
+use cc6502::error::Error;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ItemKind {
+    Startup,
+    Assembler,
+    Function,
+    Table,
+}
+
+impl ItemKind {
+    pub fn description(&self) -> &'static str {
+        match self {
+            ItemKind::Startup => "code",
+            ItemKind::Assembler => "assembler code",
+            ItemKind::Function => "function",
+            ItemKind::Table => "array",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LayoutItem {
+    pub name: String,
+    pub kind: ItemKind,
+    pub code: String,
+    /// Address of the item, as seen by the 6502 (RORG)
+    pub address: u32,
+    pub size: u32,
+    /// Line of the first line of code of the item in the layout source
+    pub line: usize,
+}
+
+/// A memory area filled sequentially: a ROM bank, or DPC/DPC+ display or frequency memory
+#[derive(Debug)]
+pub struct Bank {
+    pub name: String,
+    /// Bank number (None for DPC/DPC+ display and frequency memory)
+    pub bank: Option<u32>,
+    /// Offset in the cartridge image
+    pub org: u32,
+    /// Address of the bank, as seen by the 6502
+    pub rorg: u32,
+    /// End of the memory available for the items (as seen by the 6502)
+    pub end: u32,
+    pub items: Vec<LayoutItem>,
+}
+
+impl Bank {
+    pub fn new(name: String, bank: Option<u32>, org: u32, rorg: u32, end: u32) -> Bank {
+        Bank {
+            name,
+            bank,
+            org,
+            rorg,
+            end,
+            items: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, name: &str, kind: ItemKind, code: String) {
+        self.items.push(LayoutItem {
+            name: name.to_string(),
+            kind,
+            code,
+            address: 0,
+            size: 0,
+            line: 0,
+        });
+    }
+
+    pub fn capacity(&self) -> u32 {
+        self.end - self.rorg
+    }
+
+    /// Number of bytes used up to the end of the given item
+    pub fn filled_after(&self, item: &LayoutItem) -> u32 {
+        item.address + item.size - self.rorg
+    }
+
+    pub fn filled(&self) -> u32 {
+        self.items.last().map_or(0, |i| self.filled_after(i))
+    }
+}
+
+/// Computes the address and size of all the items of the given banks. `header` is the assembler
+/// code preceding the banks (variables, constants and macros definitions). The assembly is
+/// returned for the symbols it defines.
+pub fn compute_layout(header: &str, banks: &mut [Bank]) -> Result<Assembly, Error> {
+    let mut code = String::from(header);
+    for (i, bank) in banks.iter_mut().enumerate() {
+        write!(
+            code,
+            "\n\tSEG.U __LAYOUT_{}\n\tORG ${:04x}\n\tRORG ${:04x}\n",
+            i, bank.org, bank.rorg
+        )
+        .unwrap();
+        for (j, item) in bank.items.iter_mut().enumerate() {
+            writeln!(code, "__LAYOUT_{}_{}", i, j).unwrap();
+            item.line = code.matches('\n').count() + 1;
+            writeln!(code, "{}", item.code).unwrap();
+        }
+        writeln!(code, "__LAYOUT_{}_{}", i, bank.items.len()).unwrap();
+    }
+    code.push_str("\tEND\n");
+
+    let assembly = Assembler::new()
+        .allow_undefined(true)
+        .assemble("layout", &code)
+        .map_err(|e| Error::Configuration {
+            error: format!("Layout computation failed. {}", e),
+        })?;
+
+    for (i, bank) in banks.iter_mut().enumerate() {
+        let address = |j: usize| {
+            assembly
+                .symbol(&format!("__LAYOUT_{}_{}", i, j))
+                .map_or(0, |s| s.value as u32)
+        };
+        let mut start = address(0);
+        for j in 0..bank.items.len() {
+            let end = address(j + 1);
+            bank.items[j].address = start;
+            bank.items[j].size = end - start;
+            start = end;
+        }
+    }
+    Ok(assembly)
+}
+
+/// Checks that all the items fit in their bank. The error names the first item that didn't fit.
+pub fn check_layout(banks: &[Bank]) -> Result<(), Error> {
+    for bank in banks {
+        if let Some(item) = bank.items.iter().find(|i| i.address + i.size > bank.end) {
+            return Err(Error::Configuration {
+                error: format!(
+                    "Memory full. {} {} (${:04x}, {} bytes) doesn't fit in {} ({} bytes over, {}/{} bytes used)",
+                    item.name,
+                    item.kind.description(),
+                    item.address,
+                    item.size,
+                    bank.name,
+                    bank.filled().saturating_sub(bank.capacity()),
+                    bank.filled(),
+                    bank.capacity()
+                ),
+            });
+        }
+    }
+    Ok(())
+}
@@ -26,15 +26,60 @@ use std::io;
 use std::io::BufReader;
 use std::path::Path;
 
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser};
 
+mod assembler;
 mod build;
-use build::build_cartridge;
+mod callgraph;
+mod cycles;
+mod dwarf;
+mod emulator;
+mod layout;
+mod placement;
+mod report;
+mod stella;
+use build::{build_cartridge, cartridge_info, set_options, BuildOptions};
 use regex::Regex;
 
+#[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    args: Args,
+
+    /// Use the external DASM assembler instead of the built-in one
+    #[arg(long, default_value = "false")]
+    dasm: bool,
+    #[command(flatten)]
+    build: BuildOptions,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Run a cartridge in the built-in headless emulator
+    Emulate(emulator::EmulateOptions),
+}
+
 fn main() -> Result<(), std::io::Error> {
     env_logger::init();
-    let args = Args::parse();
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Some(Command::Emulate(options)) = &cli.command {
+        if let Err(e) = emulator::emulate(options) {
+            eprintln!("{}", e);
+            std::process::exit(1)
+        }
+        return Ok(());
+    }
+    let args = &cli.args;
+    // Not public in cc6502 Args, but needed to locate the included files in the debug information
+    let include_directories: Vec<String> = matches
+        .get_many::<String>("include_directories")
+        .map_or(Vec::new(), |d| d.cloned().collect());
+    set_options(cli.build.clone());
     if args.version {
         const VERSION: &str = env!("CARGO_PKG_VERSION");
         println!(
@@ -58,75 +103,182 @@ fn main() -> Result<(), std::io::Error> {
 
     if args.input == "stdin" {
         let reader = io::stdin().lock();
-        if let Err(e) = compile(reader, &mut writer, &args, build_cartridge) {
+        if let Err(e) = compile(reader, &mut writer, args, build_cartridge) {
             eprintln!("{}", e);
             std::process::exit(1)
         }
     } else {
         let f = File::open(&args.input)?;
         let reader = BufReader::new(f);
-        if let Err(e) = compile(reader, &mut writer, &args, build_cartridge) {
+        if let Err(e) = compile(reader, &mut writer, args, build_cartridge) {
             eprintln!("{}", e);
             std::process::exit(1)
         }
     }
 
     if !args.assembler_output {
-        // Call DASM to produce the output file
-        let filenames = ["dasm", "./dasm", "dasm.exe", ".\\dasm.exe"];
-        let mut output = None;
-        for f in filenames {
-            let command = if args.debug {
-                let mut lst_filename = String::from(prefix.to_str().unwrap());
-                lst_filename.push_str(".lst");
-                let mut sym_filename = String::from(prefix.to_str().unwrap());
-                sym_filename.push_str(".sym");
-                std::process::Command::new(f)
-                    .arg(assembler_filename.clone())
-                    .arg("-f3")
-                    .arg(&format!("-o{}", &args.output))
-                    .arg(&format!("-l{}", &lst_filename))
-                    .arg(&format!("-s{}", &sym_filename))
-                    .output()
-            } else {
-                std::process::Command::new(f)
-                    .arg(assembler_filename.clone())
-                    .arg("-f3")
-                    .arg(&format!("-o{}", &args.output))
-                    .output()
-            };
-            if let Ok(x) = command {
-                output = Some(x);
-                break;
+        if cli.dasm {
+            return assemble_with_dasm(
+                args,
+                prefix.to_str().unwrap(),
+                &assembler_filename,
+                &include_directories,
+            );
+        }
+        let source = std::fs::read_to_string(&assembler_filename)?;
+        match assembler::Assembler::new().assemble(&assembler_filename, &source) {
+            Ok(assembly) => {
+                std::fs::write(&args.output, &assembly.binary)?;
+                if args.debug {
+                    let mut lst_filename = String::from(prefix.to_str().unwrap());
+                    lst_filename.push_str(".lst");
+                    let mut sym_filename = String::from(prefix.to_str().unwrap());
+                    sym_filename.push_str(".sym");
+                    std::fs::write(lst_filename, &assembly.listing)?;
+                    std::fs::write(
+                        sym_filename,
+                        stella::symbol_file(&assembly.symbol_table(), &cartridge_info().c_names),
+                    )?;
+                    write_properties_file(prefix.to_str().unwrap(), &assembly.binary)?;
+                    write_debug_file(
+                        args,
+                        prefix.to_str().unwrap(),
+                        &source,
+                        &assembly,
+                        &include_directories,
+                    )?;
+                } else {
+                    std::fs::remove_file(&assembler_filename)?;
+                }
+                if args.verbose {
+                    println!("{}", assembly.echo);
+                    println!("Cartridge successfully compiled");
+                }
+                Ok(())
+            }
+            Err(e) => {
+                let re = Regex::new(r"(-\d+ bytes free in bank \d+)").unwrap();
+                if let Some(caps) = re.captures(&e.echo) {
+                    eprintln!("Out of memory: {}", &caps[0]);
+                } else {
+                    eprintln!("{}", e);
+                }
+                std::process::exit(1)
             }
         }
-        if output.is_none() {
-            eprintln!("Can't find DASM. Exiting.");
-            std::process::exit(1)
+    } else {
+        Ok(())
+    }
+}
+
+fn assemble_with_dasm(
+    args: &Args,
+    prefix: &str,
+    assembler_filename: &str,
+    include_directories: &[String],
+) -> Result<(), std::io::Error> {
+    // Call DASM to produce the output file
+    let filenames = ["dasm", "./dasm", "dasm.exe", ".\\dasm.exe"];
+    let mut output = None;
+    for f in filenames {
+        let command = if args.debug {
+            let mut lst_filename = String::from(prefix);
+            lst_filename.push_str(".lst");
+            let mut sym_filename = String::from(prefix);
+            sym_filename.push_str(".sym");
+            std::process::Command::new(f)
+                .arg(assembler_filename)
+                .arg("-f3")
+                .arg(format!("-o{}", &args.output))
+                .arg(format!("-l{}", &lst_filename))
+                .arg(format!("-s{}", &sym_filename))
+                .output()
+        } else {
+            std::process::Command::new(f)
+                .arg(assembler_filename)
+                .arg("-f3")
+                .arg(format!("-o{}", &args.output))
+                .output()
+        };
+        if let Ok(x) = command {
+            output = Some(x);
+            break;
         }
+    }
+    if output.is_none() {
+        eprintln!("Can't find DASM. Exiting.");
+        std::process::exit(1)
+    }
 
-        let output = output.unwrap();
-        if output.status.success() {
-            if args.verbose {
-                let output = String::from_utf8(output.stdout).unwrap();
-                println!("{}", output);
-                println!("Cartridge successfully compiled with DASM");
-            }
-            if !args.debug {
-                std::fs::remove_file(&assembler_filename)?;
+    let output = output.unwrap();
+    if output.status.success() {
+        if args.verbose {
+            let output = String::from_utf8(output.stdout).unwrap();
+            println!("{}", output);
+            println!("Cartridge successfully compiled with DASM");
+        }
+        if args.debug {
+            let mut sym_filename = String::from(prefix);
+            sym_filename.push_str(".sym");
+            let symbols = std::fs::read_to_string(&sym_filename)?;
+            std::fs::write(
+                &sym_filename,
+                stella::symbol_file(&symbols, &cartridge_info().c_names),
+            )?;
+            write_properties_file(prefix, &std::fs::read(&args.output)?)?;
+            // DASM doesn't give the address of each line: use the built-in assembler for that
+            let source = std::fs::read_to_string(assembler_filename)?;
+            if let Ok(assembly) = assembler::Assembler::new().assemble(assembler_filename, &source)
+            {
+                write_debug_file(args, prefix, &source, &assembly, include_directories)?;
             }
-            Ok(())
         } else {
-            let err = String::from_utf8(output.stdout).unwrap();
-            let re = Regex::new(r"(-\d+ bytes free in bank \d)").unwrap();
-            if let Some(caps) = re.captures(&err) {
-                eprintln!("Out of memory: {}", &caps[0]);
-            } else {
-                eprintln!("{}", err);
-            }
-            std::process::exit(1)
+            std::fs::remove_file(assembler_filename)?;
         }
-    } else {
         Ok(())
+    } else {
+        let err = String::from_utf8(output.stdout).unwrap();
+        let re = Regex::new(r"(-\d+ bytes free in bank \d+)").unwrap();
+        if let Some(caps) = re.captures(&err) {
+            eprintln!("Out of memory: {}", &caps[0]);
+        } else {
+            eprintln!("{}", err);
+        }
+        std::process::exit(1)
     }
 }
+
+// Stella properties file, so that the emulator selects the right bankswitching scheme
+fn write_properties_file(prefix: &str, binary: &[u8]) -> Result<(), std::io::Error> {
+    let mut pro_filename = String::from(prefix);
+    pro_filename.push_str(".pro");
+    std::fs::write(
+        pro_filename,
+        stella::properties_file(binary, prefix, &cartridge_info().bankswitching_scheme),
+    )
+}
+
+// DWARF debug information, for Gopher2600
+fn write_debug_file(
+    args: &Args,
+    prefix: &str,
+    source: &str,
+    assembly: &assembler::Assembly,
+    include_directories: &[String],
+) -> Result<(), std::io::Error> {
+    let mut elf_filename = String::from(prefix);
+    elf_filename.push_str(".elf");
+    let comp_dir = std::env::current_dir()?;
+    std::fs::write(
+        elf_filename,
+        dwarf::debug_file(
+            source,
+            &assembly.lines,
+            &assembly.symbols,
+            &cartridge_info(),
+            &args.input,
+            &comp_dir.to_string_lossy(),
+            include_directories,
+        ),
+    )
+}
@@ -0,0 +1,240 @@
+/*
+    cc2600 - a subset of C compiler for the Atari 2600
+    Copyright (C) 2023-2024 Bruno STEUX
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+    Contact info: bruno.steux@gmail.com
+*/
+
+// Automatic placement of functions and ROM tables in banks.
+//
+// Movable items (functions and tables not assigned to a bank) that reference the same tables are
+// grouped into clusters that are placed as a whole, since a table must be in the bank of the code
+// using it. Clusters are then placed by decreasing size in the bank with enough room that needs
+// the fewest new bankswitching stubs (each call between two banks takes a stub in every bank).
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::layout::ItemKind;
+
+#[derive(Debug)]
+pub struct PlacementItem {
+    pub name: String,
+    pub kind: ItemKind,
+    /// Size in bytes, alignment padding included
+    pub size: u32,
+    /// Declared bank, or None if the item can be placed anywhere
+    pub bank: Option<u32>,
+}
+
+/// Bank of every item after placement
+pub type Placement = HashMap<String, u32>;
+
+struct Cluster {
+    members: Vec<usize>,
+    size: u32,
+    allowed: BTreeSet<u32>,
+}
+
+fn find(parent: &mut [usize], i: usize) -> usize {
+    let mut root = i;
+    while parent[root] != root {
+        root = parent[root];
+    }
+    let mut i = i;
+    while parent[i] != root {
+        let next = parent[i];
+        parent[i] = root;
+        i = next;
+    }
+    root
+}
+
+/// Places the movable items. `calls` gives the functions called by each function, `references`
+/// the tables referenced by each function or table. `free[b]` is the room left in bank b by the
+/// items that can't move, and `stub_size` the room taken in every bank by each function called
+/// from another bank (one stub per calling bank).
+pub fn place(
+    items: &[PlacementItem],
+    calls: &HashMap<String, Vec<String>>,
+    references: &HashMap<String, HashSet<String>>,
+    free: &[u32],
+    stub_size: u32,
+) -> Result<Placement, String> {
+    let index: HashMap<&str, usize> = items
+        .iter()
+        .enumerate()
+        .map(|(i, item)| (item.name.as_str(), i))
+        .collect();
+    let nb_banks = free.len() as u32;
+
+    let mut call_edges = Vec::new();
+    for (caller, callees) in calls {
+        if let Some(&a) = index.get(caller.as_str()) {
+            for callee in callees {
+                if let Some(&b) = index.get(callee.as_str()) {
+                    call_edges.push((a, b));
+                }
+            }
+        }
+    }
+    call_edges.sort();
+    call_edges.dedup();
+    let mut edges = Vec::new();
+    for (user, tables) in references {
+        if let Some(&a) = index.get(user.as_str()) {
+            for table in tables {
+                if let Some(&b) = index.get(table.as_str()) {
+                    if a != b {
+                        edges.push((a, b));
+                    }
+                }
+            }
+        }
+    }
+    edges.sort();
+    edges.dedup();
+
+    // Group the movable items
+    let mut parent: Vec<usize> = (0..items.len()).collect();
+    for &(a, b) in &edges {
+        if items[a].bank.is_none() && items[b].bank.is_none() {
+            let (ra, rb) = (find(&mut parent, a), find(&mut parent, b));
+            parent[ra] = rb;
+        }
+    }
+    let mut clusters: Vec<Cluster> = Vec::new();
+    let mut cluster_of: HashMap<usize, usize> = HashMap::new();
+    for (i, item) in items.iter().enumerate() {
+        if item.bank.is_none() {
+            let root = find(&mut parent, i);
+            let c = *cluster_of.entry(root).or_insert_with(|| {
+                clusters.push(Cluster {
+                    members: Vec::new(),
+                    size: 0,
+                    allowed: (0..nb_banks).collect(),
+                });
+                clusters.len() - 1
+            });
+            cluster_of.insert(i, c);
+            clusters[c].members.push(i);
+            clusters[c].size += item.size;
+        }
+    }
+
+    // Tables must be in the bank of the code using them
+    let describe = |i: usize| format!("{} {}", items[i].name, items[i].kind.description());
+    for &(a, b) in &edges {
+        match (items[a].bank, items[b].bank) {
+            (Some(ba), None) => clusters[cluster_of[&b]].allowed.retain(|x| *x == ba),
+            (None, Some(bb)) => clusters[cluster_of[&a]].allowed.retain(|x| *x == bb),
+            _ => (),
+        }
+    }
+
+    for c in &clusters {
+        if c.allowed.is_empty() {
+            let names: Vec<String> = c.members.iter().map(|i| describe(*i)).collect();
+            return Err(format!(
+                "No bank satisfies the table accesses of {}",
+                names.join(", ")
+            ));
+        }
+    }
+
+    // Clusters with a single possible bank first, then by decreasing size
+    let mut order: Vec<usize> = (0..clusters.len()).collect();
+    order.sort_by(|a, b| {
+        let (ca, cb) = (&clusters[*a], &clusters[*b]);
+        (ca.allowed.len() != 1)
+            .cmp(&(cb.allowed.len() != 1))
+            .then(cb.size.cmp(&ca.size))
+            .then(items[ca.members[0]].name.cmp(&items[cb.members[0]].name))
+    });
+
+    // Bankswitching stubs, as (called function, calling bank)
+    let mut placement = Placement::new();
+    for item in items {
+        if let Some(bank) = item.bank {
+            placement.insert(item.name.clone(), bank);
+        }
+    }
+    let mut stubs = HashSet::new();
+    let mut used = vec![0u32; free.len()];
+    let new_stubs = |c: usize, bank: u32, placement: &Placement, stubs: &HashSet<(usize, u32)>| {
+        let bank_of = |i: usize| {
+            if cluster_of.get(&i) == Some(&c) {
+                Some(bank)
+            } else {
+                placement.get(&items[i].name).copied()
+            }
+        };
+        let mut ret = HashSet::new();
+        for &(a, b) in &call_edges {
+            if let (Some(ba), Some(bb)) = (bank_of(a), bank_of(b)) {
+                if ba != bb && !stubs.contains(&(b, ba)) {
+                    ret.insert((b, ba));
+                }
+            }
+        }
+        ret
+    };
+    for &(a, b) in &call_edges {
+        if let (Some(ba), Some(bb)) = (items[a].bank, items[b].bank) {
+            if ba != bb {
+                stubs.insert((b, ba));
+            }
+        }
+    }
+    for i in order {
+        let c = &clusters[i];
+        let mut candidates: Vec<(usize, u32)> = c
+            .allowed
+            .iter()
+            .map(|b| (new_stubs(i, *b, &placement, &stubs).len(), *b))
+            .collect();
+        candidates.sort();
+        let fits = |bank: u32, nb_stubs: usize| {
+            (0..free.len()).all(|b| {
+                let size = used[b] + if b as u32 == bank { c.size } else { 0 };
+                size + nb_stubs as u32 * stub_size <= free[b]
+            })
+        };
+        let Some(&(_, bank)) = candidates.iter().find(|(n, b)| fits(*b, stubs.len() + n)) else {
+            let largest = c
+                .members
+                .iter()
+                .copied()
+                .max_by_key(|i| items[*i].size)
+                .unwrap();
+            return Err(format!(
+                "Not enough room to place {} ({} bytes{})",
+                describe(largest),
+                c.size,
+                if c.members.len() > 1 {
+                    format!(" with the {} items it is used with", c.members.len() - 1)
+                } else {
+                    String::new()
+                }
+            ));
+        };
+        stubs.extend(new_stubs(i, bank, &placement, &stubs));
+        used[bank as usize] += c.size;
+        for m in &c.members {
+            placement.insert(items[*m].name.clone(), bank);
+        }
+    }
+    Ok(placement)
+}
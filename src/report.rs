@@ -0,0 +1,406 @@
+/*
+    cc2600 - a subset of C compiler for the Atari 2600
+    Copyright (C) 2023-2024 Bruno STEUX
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+    Contact info: bruno.steux@gmail.com
+*/
+
+// Machine readable (JSON) reports
+
+use crate::layout::Bank;
+
+/// Minimal JSON document
+pub enum Json {
+    Number(i64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+fn object(fields: Vec<(&str, Json)>) -> Json {
+    Json::Object(
+        fields
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect(),
+    )
+}
+
+// Recursive descent parser of the JSON documents written by cc2600 (integer numbers only)
+struct Parser<'a> {
+    text: &'a [u8],
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self) -> String {
+        format!("JSON syntax error at offset {}", self.position)
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        while self
+            .text
+            .get(self.position)
+            .is_some_and(|c| c.is_ascii_whitespace())
+        {
+            self.position += 1;
+        }
+        self.text.get(self.position).copied()
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), String> {
+        if self.next() == Some(c) {
+            self.position += 1;
+            Ok(())
+        } else {
+            Err(self.error())
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        let mut s = Vec::new();
+        loop {
+            let c = *self.text.get(self.position).ok_or_else(|| self.error())?;
+            self.position += 1;
+            match c {
+                b'"' => return Ok(String::from_utf8_lossy(&s).into_owned()),
+                b'\\' => {
+                    let e = *self.text.get(self.position).ok_or_else(|| self.error())?;
+                    self.position += 1;
+                    match e {
+                        b'n' => s.push(b'\n'),
+                        b't' => s.push(b'\t'),
+                        b'u' => {
+                            let code = self
+                                .text
+                                .get(self.position..self.position + 4)
+                                .and_then(|h| std::str::from_utf8(h).ok())
+                                .and_then(|h| u32::from_str_radix(h, 16).ok())
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| self.error())?;
+                            self.position += 4;
+                            s.extend_from_slice(code.to_string().as_bytes());
+                        }
+                        e => s.push(e),
+                    }
+                }
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        match self.next() {
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b'[') => {
+                self.position += 1;
+                let mut items = Vec::new();
+                if self.next() == Some(b']') {
+                    self.position += 1;
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    if self.next() == Some(b',') {
+                        self.position += 1;
+                    } else {
+                        self.expect(b']')?;
+                        return Ok(Json::Array(items));
+                    }
+                }
+            }
+            Some(b'{') => {
+                self.position += 1;
+                let mut fields = Vec::new();
+                if self.next() == Some(b'}') {
+                    self.position += 1;
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    fields.push((key, self.value()?));
+                    if self.next() == Some(b',') {
+                        self.position += 1;
+                    } else {
+                        self.expect(b'}')?;
+                        return Ok(Json::Object(fields));
+                    }
+                }
+            }
+            Some(c) if c == b'-' || c.is_ascii_digit() => {
+                let start = self.position;
+                self.position += 1;
+                while self
+                    .text
+                    .get(self.position)
+                    .is_some_and(|c| c.is_ascii_digit())
+                {
+                    self.position += 1;
+                }
+                std::str::from_utf8(&self.text[start..self.position])
+                    .ok()
+                    .and_then(|n| n.parse().ok())
+                    .map(Json::Number)
+                    .ok_or_else(|| self.error())
+            }
+            _ => Err(self.error()),
+        }
+    }
+}
+
+impl Json {
+    /// Parses a JSON document written by cc2600 (a memory map for instance)
+    pub fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = Parser {
+            text: text.as_bytes(),
+            position: 0,
+        };
+        let json = parser.value()?;
+        match parser.next() {
+            None => Ok(json),
+            Some(_) => Err(parser.error()),
+        }
+    }
+
+    /// Field of an object
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn number(&self) -> Option<i64> {
+        match self {
+            Json::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn string(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Items of an array (none if not an array)
+    pub fn items(&self) -> &[Json] {
+        match self {
+            Json::Array(items) => items,
+            _ => &[],
+        }
+    }
+
+    fn write(&self, out: &mut String, indent: usize) {
+        match self {
+            Json::Number(n) => out.push_str(&n.to_string()),
+            Json::String(s) => {
+                out.push('"');
+                for c in s.chars() {
+                    match c {
+                        '"' => out.push_str("\\\""),
+                        '\\' => out.push_str("\\\\"),
+                        '\n' => out.push_str("\\n"),
+                        c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+                        c => out.push(c),
+                    }
+                }
+                out.push('"');
+            }
+            Json::Array(items) if items.is_empty() => out.push_str("[]"),
+            Json::Array(items) => {
+                out.push_str("[\n");
+                for (i, item) in items.iter().enumerate() {
+                    out.push_str(&" ".repeat(indent + 2));
+                    item.write(out, indent + 2);
+                    out.push_str(if i + 1 < items.len() { ",\n" } else { "\n" });
+                }
+                out.push_str(&" ".repeat(indent));
+                out.push(']');
+            }
+            Json::Object(fields) => {
+                out.push_str("{\n");
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    out.push_str(&format!("{}\"{}\": ", " ".repeat(indent + 2), key));
+                    value.write(out, indent + 2);
+                    out.push_str(if i + 1 < fields.len() { ",\n" } else { "\n" });
+                }
+                out.push_str(&" ".repeat(indent));
+                out.push('}');
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Json {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut out = String::new();
+        self.write(&mut out, 0);
+        writeln!(f, "{}", out)
+    }
+}
+
+pub struct RamVariable {
+    pub name: String,
+    pub address: u32,
+    pub size: u32,
+}
+
+/// Local variables of functions sharing the same memory (LOCAL_VARIABLES)
+pub struct Overlay {
+    pub name: String,
+    pub address: u32,
+    /// Size of the shared memory
+    pub size: u32,
+    /// Local variables of each function
+    pub functions: Vec<(String, Vec<RamVariable>)>,
+}
+
+/// Zeropage, superchip or RAM bank
+pub struct RamRegion {
+    pub name: String,
+    pub start: u32,
+    pub size: u32,
+    pub variables: Vec<RamVariable>,
+    pub overlays: Vec<Overlay>,
+}
+
+impl RamRegion {
+    pub fn new(name: &str, start: u32, size: u32) -> RamRegion {
+        RamRegion {
+            name: name.to_string(),
+            start,
+            size,
+            variables: Vec::new(),
+            overlays: Vec::new(),
+        }
+    }
+
+    pub fn used(&self) -> u32 {
+        self.variables.iter().map(|v| v.size).sum::<u32>()
+            + self.overlays.iter().map(|o| o.size).sum::<u32>()
+    }
+}
+
+fn variables(variables: &[RamVariable]) -> Json {
+    Json::Array(
+        variables
+            .iter()
+            .map(|v| {
+                object(vec![
+                    ("name", Json::String(v.name.clone())),
+                    ("address", Json::Number(v.address as i64)),
+                    ("size", Json::Number(v.size as i64)),
+                ])
+            })
+            .collect(),
+    )
+}
+
+/// Memory map of the cartridge: ROM banks with their functions and tables, and RAM usage
+pub fn memory_map(bankswitching_scheme: &str, banks: &[Bank], ram: &[RamRegion]) -> String {
+    let banks = banks
+        .iter()
+        .map(|bank| {
+            object(vec![
+                ("name", Json::String(bank.name.clone())),
+                ("bank", Json::Number(bank.bank.map_or(-1, |b| b as i64))),
+                ("org", Json::Number(bank.org as i64)),
+                ("rorg", Json::Number(bank.rorg as i64)),
+                ("size", Json::Number(bank.capacity() as i64)),
+                ("used", Json::Number(bank.filled() as i64)),
+                (
+                    "free",
+                    Json::Number(bank.capacity() as i64 - bank.filled() as i64),
+                ),
+                (
+                    "items",
+                    Json::Array(
+                        bank.items
+                            .iter()
+                            .map(|item| {
+                                object(vec![
+                                    ("name", Json::String(item.name.clone())),
+                                    ("kind", Json::String(item.kind.description().to_string())),
+                                    ("address", Json::Number(item.address as i64)),
+                                    ("size", Json::Number(item.size as i64)),
+                                ])
+                            })
+                            .collect(),
+                    ),
+                ),
+            ])
+        })
+        .collect();
+    let ram = ram
+        .iter()
+        .map(|region| {
+            object(vec![
+                ("name", Json::String(region.name.clone())),
+                ("start", Json::Number(region.start as i64)),
+                ("size", Json::Number(region.size as i64)),
+                ("used", Json::Number(region.used() as i64)),
+                (
+                    "free",
+                    Json::Number(region.size as i64 - region.used() as i64),
+                ),
+                ("variables", variables(&region.variables)),
+                (
+                    "overlays",
+                    Json::Array(
+                        region
+                            .overlays
+                            .iter()
+                            .map(|o| {
+                                object(vec![
+                                    ("name", Json::String(o.name.clone())),
+                                    ("address", Json::Number(o.address as i64)),
+                                    ("size", Json::Number(o.size as i64)),
+                                    (
+                                        "functions",
+                                        Json::Array(
+                                            o.functions
+                                                .iter()
+                                                .map(|(f, v)| {
+                                                    object(vec![
+                                                        ("name", Json::String(f.clone())),
+                                                        ("variables", variables(v)),
+                                                    ])
+                                                })
+                                                .collect(),
+                                        ),
+                                    ),
+                                ])
+                            })
+                            .collect(),
+                    ),
+                ),
+            ])
+        })
+        .collect();
+    object(vec![
+        (
+            "bankswitching",
+            Json::String(bankswitching_scheme.to_string()),
+        ),
+        ("banks", Json::Array(banks)),
+        ("ram", Json::Array(ram)),
+    ])
+    .to_string()
+}
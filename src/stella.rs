@@ -0,0 +1,104 @@
+/*
+    cc2600 - a subset of C compiler for the Atari 2600
+    Copyright (C) 2023-2024 Bruno STEUX
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+    Contact info: bruno.steux@gmail.com
+*/
+
+// Debugging files for the Stella emulator: symbol file with the C names of the variables, and
+// properties file, so that Stella opens the cartridge with the right bankswitching scheme.
+
+use std::collections::HashMap;
+
+/// Rewrites a DASM symbol file, replacing the assembler names of the local variables and
+/// parameters by their C names (function.variable)
+pub fn symbol_file(symbols: &str, c_names: &HashMap<String, String>) -> String {
+    let mut entries = Vec::new();
+    for line in symbols.lines() {
+        if line.starts_with("---") || line.trim().is_empty() {
+            continue;
+        }
+        let (name, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let name = c_names.get(name).map(|s| s.as_str()).unwrap_or(name);
+        entries.push((name.to_string(), rest.trim_start().to_string()));
+    }
+    entries.sort();
+    let mut s = String::from("--- Symbol List (sorted by symbol)\n");
+    for (name, rest) in entries {
+        s.push_str(&format!("{:<24} {}\n", name, rest));
+    }
+    s.push_str("--- End of Symbol List.\n");
+    s
+}
+
+/// Stella properties file of the cartridge
+pub fn properties_file(binary: &[u8], name: &str, bankswitching_scheme: &str) -> String {
+    format!(
+        "\"Cart.MD5\" \"{}\"\n\"Cart.Name\" \"{}\"\n\"Cart.Type\" \"{}\"\n\"\"\n",
+        md5(binary),
+        name,
+        bankswitching_scheme
+    )
+}
+
+// MD5 digest (RFC 1321), used by Stella to identify the cartridge
+fn md5(data: &[u8]) -> String {
+    const S: [u32; 64] = [
+        7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 5, 9, 14, 20, 5, 9, 14, 20, 5,
+        9, 14, 20, 5, 9, 14, 20, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 6, 10,
+        15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+    ];
+    let k: Vec<u32> = (0..64)
+        .map(|i| ((i as f64 + 1.0).sin().abs() * 4294967296.0) as u32)
+        .collect();
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    let mut h: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for chunk in message.chunks(64) {
+        let m: Vec<u32> = chunk
+            .chunks(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = h;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let f = f.wrapping_add(a).wrapping_add(k[i]).wrapping_add(m[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(f.rotate_left(S[i]));
+        }
+        h[0] = h[0].wrapping_add(a);
+        h[1] = h[1].wrapping_add(b);
+        h[2] = h[2].wrapping_add(c);
+        h[3] = h[3].wrapping_add(d);
+    }
+    h.iter()
+        .flat_map(|x| x.to_le_bytes())
+        .map(|x| format!("{:02x}", x))
+        .collect()
+}
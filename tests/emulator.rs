@@ -0,0 +1,853 @@
+/*
+    cc2600 - a subset of C compiler for the Atari 2600
+    Copyright (C) 2023-2024 Bruno STEUX
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+    Contact info: bruno.steux@gmail.com
+*/
+
+// Builds C programs with cc2600 and runs them in the built-in headless emulator
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+// Empty working directory of a test (cc2600 writes its assembler file in the current directory)
+fn work_dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cc2600-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn cc2600(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_cc2600"))
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .unwrap()
+}
+
+// Compiles a C file into dir/name.bin, returning the compiler output
+fn compile(dir: &Path, source: &Path, name: &str, options: &[&str]) -> Output {
+    let headers = Path::new(env!("CARGO_MANIFEST_DIR")).join("headers");
+    let output = format!("{}.bin", name);
+    let mut args = vec![
+        "-o",
+        &output,
+        source.to_str().unwrap(),
+        "-I",
+        headers.to_str().unwrap(),
+    ];
+    args.extend_from_slice(options);
+    cc2600(dir, &args)
+}
+
+fn examples_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("examples")
+}
+
+// Compiles an example, or fails
+fn build_example(dir: &Path, name: &str, options: &[&str]) {
+    let source = examples_dir().join(format!("{}.c", name));
+    // The examples include files of the examples directory
+    let examples = examples_dir();
+    let mut args = vec!["-I", examples.to_str().unwrap()];
+    args.extend_from_slice(options);
+    let output = compile(dir, &source, name, &args);
+    assert!(
+        output.status.success(),
+        "{} doesn't compile: {}",
+        name,
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+// Compiles a C program given as a string
+fn build_program(dir: &Path, name: &str, code: &str, options: &[&str]) -> Output {
+    let source = dir.join(format!("{}.c", name));
+    fs::write(&source, code).unwrap();
+    compile(dir, &source, name, options)
+}
+
+struct Run {
+    scheme: String,
+    scanlines: Vec<u32>,
+    // Zeropage RAM. $80 is cctmp, the global variables follow in alphabetical order (X and Y are
+    // the 6502 registers)
+    ram: [u8; 128],
+}
+
+// Runs a cartridge, returning its bankswitching scheme, the scanline count of every frame and
+// the RAM content at the end
+fn emulate(dir: &Path, name: &str, options: &[&str]) -> Run {
+    let cartridge = format!("{}.bin", name);
+    let mut args = vec!["emulate", &cartridge];
+    args.extend_from_slice(options);
+    let output = cc2600(dir, &args);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{} doesn't run: {}{}",
+        name,
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let mut run = Run {
+        scheme: String::new(),
+        scanlines: Vec::new(),
+        ram: [0; 128],
+    };
+    for line in stdout.lines() {
+        if let Some(rest) = line.strip_prefix("Cartridge ") {
+            match rest.split_once(": ") {
+                Some((_, rest)) => run.scheme = rest.split_whitespace().next().unwrap().to_string(),
+                // Cartridge RAM dump, after the console RAM
+                None => break,
+            }
+        } else if let Some(rest) = line.strip_prefix("Frame ") {
+            let (_, rest) = rest.split_once(": ").unwrap();
+            run.scanlines
+                .push(rest.split_whitespace().next().unwrap().parse().unwrap());
+        } else if let Some(rest) = line.strip_prefix('$') {
+            let (address, bytes) = rest.split_once(": ").unwrap();
+            let address = u32::from_str_radix(address, 16).unwrap() as usize - 0x80;
+            for (i, b) in bytes.split_whitespace().enumerate() {
+                run.ram[address + i] = u8::from_str_radix(b, 16).unwrap();
+            }
+        }
+    }
+    run
+}
+
+// Frame loop calling the given function once per frame, with 262 scanlines
+const FRAME_LOOP: &str = "
+void main()
+{
+    while(1) {
+        *VBLANK = 2;
+        *VSYNC = 2;
+        strobe(WSYNC);
+        strobe(WSYNC);
+        strobe(WSYNC);
+        *VSYNC = 0;
+        logic();
+        for (Y = 0; Y != 255; Y++) strobe(WSYNC);
+        for (Y = 0; Y != 4; Y++) strobe(WSYNC);
+    }
+}
+";
+
+#[test]
+fn far_calls_in_large_banked_cartridges() {
+    // next_color() and next_speed() are called through bankswitching stubs every frame.
+    // After 5 frames, speed went through 1, 2, 3, 1, 2 and color is their sum.
+    for (name, scheme) in [("test_ef", "EF"), ("test_df", "DF"), ("test_bf", "BF")] {
+        let dir = work_dir(name);
+        build_example(&dir, name, &[]);
+        let run = emulate(&dir, name, &["--frames", "5"]);
+        assert_eq!(run.scheme, scheme);
+        assert_eq!(run.scanlines, [262; 5], "{}", name);
+        assert_eq!(run.ram[1], 9, "{} color", name);
+        assert_eq!(run.ram[2], 2, "{} speed", name);
+    }
+}
+
+#[test]
+fn far_calls_in_fa_cartridge() {
+    // CBS RAM+ variables and calls between the three banks
+    let dir = work_dir("fa");
+    let code = format!(
+        "#include \"vcs.h\"\n#include \"fa.h\"\nunsigned char a, b, Y;\nsuperchip unsigned char c;\nbank2 void g() {{ b += 2; c = b; }}\nbank1 void f() {{ a++; g(); }}\nvoid logic() {{ f(); a = a + c - b; }}\n{}",
+        FRAME_LOOP
+    );
+    let output = build_program(&dir, "fa", &code, &[]);
+    assert!(output.status.success());
+    let run = emulate(&dir, "fa", &["--frames", "4"]);
+    assert_eq!(run.scheme, "FA");
+    // The calls between banks take more than the scanline given to logic()
+    assert_eq!(run.scanlines, [263; 4]);
+    assert_eq!(run.ram[1], 4, "a");
+    assert_eq!(run.ram[2], 8, "b");
+}
+
+// Examples that are not visual regression tests
+const NOT_CHECKED: [(&str, &str); 4] = [
+    // Its bank 0 is 83 bytes over the 4KB of an F8 bank
+    ("example_gas_paddles", "doesn't fit in its cartridge"),
+    ("example_gas_paddles_gfx", "included by example_gas_paddles"),
+    (
+        "example_gas_paddles_gfx2",
+        "included by example_gas_paddles",
+    ),
+    ("example_shmup_gfx", "included by example_shmup"),
+];
+
+// Names of the files of dir with the given extension, without it
+fn file_stems(dir: &Path, extension: &str) -> Vec<String> {
+    let mut stems: Vec<String> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|e| e == extension))
+        .map(|path| path.file_stem().unwrap().to_str().unwrap().to_string())
+        .collect();
+    stems.sort();
+    stems
+}
+
+#[test]
+fn every_example_has_golden_hashes() {
+    let goldens = file_stems(&examples_dir().join("golden"), "hashes");
+    for name in file_stems(&examples_dir(), "c") {
+        match NOT_CHECKED.iter().find(|(n, _)| *n == name) {
+            Some((_, reason)) => assert!(
+                !goldens.contains(&name),
+                "{} has golden hashes, but isn't checked since it's {}",
+                name,
+                reason
+            ),
+            None => assert!(
+                goldens.contains(&name),
+                "{} has no golden hashes (add it to REGRESSION in examples/Makefile and run make golden), nor is it listed in NOT_CHECKED",
+                name
+            ),
+        }
+    }
+}
+
+#[test]
+fn examples_match_golden_hashes() {
+    for name in file_stems(&examples_dir().join("golden"), "hashes") {
+        let dir = work_dir(&name);
+        build_example(&dir, &name, &[]);
+        let golden = examples_dir()
+            .join("golden")
+            .join(format!("{}.hashes", name));
+        let run = emulate(
+            &dir,
+            &name,
+            &["--frames", "60", "--check-hashes", golden.to_str().unwrap()],
+        );
+        assert_eq!(run.scanlines.len(), 60, "{}", name);
+    }
+}
+
+#[test]
+fn examples_match_golden_images() {
+    for (golden, options, palette, scanlines) in [
+        ("test_superchip.png", &[][..], "ntsc", 262),
+        ("test_superchip_pal.png", &["-DPAL"][..], "pal", 312),
+    ] {
+        let dir = work_dir(golden);
+        build_example(&dir, "test_superchip", options);
+        let golden = examples_dir().join("golden").join(golden);
+        let run = emulate(
+            &dir,
+            "test_superchip",
+            &[
+                "--frames",
+                "60",
+                "--palette",
+                palette,
+                "--check-png",
+                golden.to_str().unwrap(),
+            ],
+        );
+        assert_eq!(run.scanlines, [scanlines; 60]);
+    }
+}
+
+#[test]
+fn sliced_images_have_their_full_size() {
+    // Only the always mapped bank0, or a high slice, is used
+    for (header, bank, scheme, size) in [
+        ("e0.h", "bank5", "E0", 8192),
+        ("e7.h", "bank0", "E7", 16384),
+        ("e7.h", "bank3", "E7", 16384),
+    ] {
+        let name = format!("{}_{}", scheme, bank).to_lowercase();
+        let dir = work_dir(&name);
+        let code = format!(
+            "#include \"vcs.h\"\n#include \"{}\"\nunsigned char a, Y;\n{} void logic() {{ a++; }}\n{}",
+            header, bank, FRAME_LOOP
+        );
+        let output = build_program(&dir, &name, &code, &[]);
+        assert!(output.status.success(), "{}", name);
+        let image = fs::read(dir.join(format!("{}.bin", name))).unwrap();
+        assert_eq!(image.len(), size, "{}", name);
+        let run = emulate(&dir, &name, &["--frames", "3"]);
+        assert_eq!(run.scheme, scheme, "{}", name);
+        assert_eq!(run.scanlines, [262; 3], "{}", name);
+        assert_eq!(run.ram[1], 3, "{} a", name);
+    }
+}
+
+#[test]
+fn empty_3e_bank1_keeps_the_full_image() {
+    // Bank 0 is the last bank of a 3E image, after the empty bank 1
+    let dir = work_dir("3e_bank1");
+    let code = format!(
+        "#include \"vcs.h\"\n#include \"3e.h\"\nunsigned char a, Y;\nvoid logic() {{ *ROM_SELECT = 1; a++; }}\n{}",
+        FRAME_LOOP
+    );
+    assert!(build_program(&dir, "3e", &code, &[]).status.success());
+    assert_eq!(fs::read(dir.join("3e.bin")).unwrap().len(), 16384);
+    let run = emulate(&dir, "3e", &["--frames", "3"]);
+    assert_eq!(run.scheme, "3E");
+    assert_eq!(run.ram[1], 3);
+}
+
+#[test]
+fn segment_conflicts_through_call_paths_are_rejected() {
+    for (header, banks) in [
+        ("e0.h", ["bank1", "bank2", "bank4"]),
+        ("e7.h", ["bank1", "bank0", "bank2"]),
+    ] {
+        let dir = work_dir(header);
+        let code = format!(
+            "#include \"vcs.h\"\n#include \"{}\"\nunsigned char a, b, Y;\n{} void h() {{ b++; }}\n{} void g() {{ h(); }}\n{} void logic() {{ g(); a++; }}\n{}",
+            header, banks[2], banks[1], banks[0], FRAME_LOOP
+        );
+        let output = build_program(&dir, "conflict", &code, &[]);
+        assert!(!output.status.success(), "{}", header);
+        assert!(
+            String::from_utf8_lossy(&output.stderr).contains("call path logic -> g -> h"),
+            "{}",
+            header
+        );
+    }
+}
+
+#[test]
+fn forced_3e_requires_its_header() {
+    let dir = work_dir("forced_3e");
+    let code = format!(
+        "#include \"vcs.h\"\nunsigned char a, Y;\nbank1 void logic() {{ a++; }}\n{}",
+        FRAME_LOOP
+    );
+    for scheme in ["3E", "3E+"] {
+        let output = build_program(&dir, "forced", &code, &["--bankswitching", scheme]);
+        assert!(!output.status.success(), "{}", scheme);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("header"));
+    }
+    let output = build_program(&dir, "forced", &code, &["--rom-size", "12"]);
+    assert!(output.status.success());
+    assert_eq!(emulate(&dir, "forced", &[]).scheme, "FA");
+}
+
+#[test]
+fn stack_depth_with_recursive_calls() {
+    let dir = work_dir("recursion");
+    let code = "#include \"vcs.h\"\nunsigned char a;\nvoid g();\nvoid f() { if (a) { a--; g(); } }\nvoid g() { f(); }\nvoid main() { a = 3; f(); g(); while(1); }\n";
+    let output = build_program(
+        &dir,
+        "recursion",
+        code,
+        &["-v", "--allow-recursion", "f", "--allow-recursion", "g"],
+    );
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let depth = stdout
+        .lines()
+        .find(|l| l.starts_with("Worst-case stack depth"))
+        .unwrap();
+    assert!(
+        depth.ends_with(", not counting recursive calls"),
+        "{}",
+        depth
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains("recursive calls is not checked"));
+}
+
+#[test]
+fn paddle_buttons_from_input_script() {
+    let dir = work_dir("paddles");
+    let code = format!(
+        "#include \"vcs.h\"\nunsigned char a, b, Y;\nvoid logic() {{ if (!(*SWCHA & 0x80)) a++; if (!(*SWCHA & 0x40)) b++; }}\n{}",
+        FRAME_LOOP
+    );
+    assert!(build_program(&dir, "paddles", &code, &[]).status.success());
+    // paddle0 button held during frames 2 and 3, paddle1 button during frame 4
+    fs::write(
+        dir.join("paddles.txt"),
+        "2 paddle0.fire\n4 paddle1.fire paddle0=100\n5\n",
+    )
+    .unwrap();
+    let run = emulate(
+        &dir,
+        "paddles",
+        &["--frames", "6", "--input", "paddles.txt"],
+    );
+    assert_eq!(run.scanlines, [262; 6]);
+    assert_eq!(run.ram[1], 2, "paddle0 button");
+    assert_eq!(run.ram[2], 1, "paddle1 button");
+}
+
+// Builds a program with its memory map and runs it for 2 frames with the sanitizer, which must
+// fail. Returns the errors reported, without the address of the instruction
+fn sanitizer_errors(name: &str, code: &str) -> Vec<String> {
+    let dir = work_dir(name);
+    let code = format!("#include \"vcs.h\"\n{}\n{}", code, FRAME_LOOP);
+    let map = format!("{}.json", name);
+    let output = build_program(&dir, name, &code, &["--memory-map", &map]);
+    assert!(output.status.success(), "{}", name);
+    let cartridge = format!("{}.bin", name);
+    let output = cc2600(
+        &dir,
+        &["emulate", &cartridge, "--frames", "2", "--sanitize", &map],
+    );
+    assert!(!output.status.success(), "{}", name);
+    String::from_utf8_lossy(&output.stderr)
+        .lines()
+        .filter(|l| l.starts_with("Sanitizer: "))
+        .map(|l| match l.rfind(" at $") {
+            Some(i) if l[i..].contains(" (frame ") => format!("{}{}", &l[..i], &l[i + 9..]),
+            _ => l.to_string(),
+        })
+        .collect()
+}
+
+#[test]
+fn sanitizer_reports_superchip_and_rom_errors() {
+    // Errors are reported once per instruction, whatever the number of frames
+    let errors = sanitizer_errors(
+        "superchip",
+        "unsigned char a, Y;\nsuperchip unsigned char s, t;\nvoid logic() { s = 1; asm(\"LDA $1000\"); a = t; asm(\"STA $1800\"); }",
+    );
+    assert_eq!(
+        errors,
+        [
+            "Sanitizer: Read of the RAM write port $1000 (s) in logic (bank 0) (frame 1, line 3)",
+            "Sanitizer: Read of uninitialized RAM $1081 (t) in logic (bank 0) (frame 1, line 3)",
+            "Sanitizer: Write into ROM at $1800 in logic (bank 0) (frame 1, line 3)",
+            "Sanitizer: 3 memory access errors",
+        ]
+    );
+}
+
+#[test]
+fn sanitizer_reports_stack_overflow_into_local_variables() {
+    // The 127 bytes pushed overwrite $82 to $FF, logic's local variable l being at $82
+    let errors = sanitizer_errors(
+        "stack",
+        "unsigned char a, X, Y;\nvoid logic() { char l; l = a; for (X = 0; X != 127; X++) asm(\"PHA\"); for (X = 0; X != 127; X++) asm(\"PLA\"); a = l + 1; }",
+    );
+    assert_eq!(
+        errors,
+        [
+            "Sanitizer: Stack overflow into $0082 (LOCAL_VARIABLES, logic_1_l) in logic (bank 0) (frame 1, line 19)",
+            "Sanitizer: 1 memory access error",
+        ]
+    );
+}
+
+#[test]
+fn sanitizer_reports_unselected_ram_banks() {
+    // The startup code clearing all the RAM banks is not checked, but functions are: only the
+    // last read of r, while RAM bank 2 is selected, is an error
+    let errors = sanitizer_errors(
+        "3e_ram",
+        "#include \"3e.h\"\nunsigned char a, Y;\nbank1 unsigned char r;\nvoid logic() { *ROM_SELECT = 1; select(0); r = a; a = r + 1; select(1); a = r; }",
+    );
+    assert_eq!(
+        errors,
+        [
+            "Sanitizer: Read of $1000 while r (3E RAM bank 1) is not selected in logic (bank 0) (frame 1, line 3)",
+            "Sanitizer: 1 memory access error",
+        ]
+    );
+}
+
+#[test]
+fn coverage_shows_branches_never_taken() {
+    // count() runs from bank 1 of an F8 cartridge, and a never gets back to 0 in 3 frames
+    let dir = work_dir("coverage");
+    let code = format!(
+        "#include \"vcs.h\"\nunsigned char a, b, Y;\nbank1 void count()\n{{\n    a++;\n    if (a == 0) {{\n        b++;\n    }}\n}}\nvoid logic() {{ count(); }}\n{}",
+        FRAME_LOOP
+    );
+    assert!(build_program(&dir, "coverage", &code, &["-g"])
+        .status
+        .success());
+    let run = emulate(
+        &dir,
+        "coverage",
+        &["--frames", "3", "--coverage", "coverage.info"],
+    );
+    assert_eq!(run.scheme, "F8");
+    let info = fs::read_to_string(dir.join("coverage.info")).unwrap();
+    let records: Vec<&str> = info.lines().collect();
+    assert_eq!(records[0], "TN:coverage");
+    assert_eq!(
+        records[1],
+        format!("SF:{}", dir.join("coverage.c").to_str().unwrap())
+    );
+    for record in [
+        "FNDA:3,count",
+        "FNDA:3,logic",
+        "DA:5,3",
+        "DA:6,3",
+        "DA:7,0",
+        "DA:10,3",
+    ] {
+        assert!(records.contains(&record), "{} not in {}", record, info);
+    }
+    assert!(records
+        .iter()
+        .all(|r| !r.starts_with("DA:8,") && !r.starts_with("DA:9,")));
+    assert_eq!(records.last(), Some(&"end_of_record"));
+}
+
+#[test]
+fn profile_by_frame_section_and_far_calls() {
+    // update() is in bank 1, called from bank 0 through the Callupdate trampoline during the
+    // vertical blank, and overscan() during the overscan
+    let dir = work_dir("profile");
+    let code = "#include \"vcs.h\"
+unsigned char a, X, Y;
+bank1 void update() { for (X = 0; X != 10; X++) a++; }
+void overscan() { a = 0; }
+void main()
+{
+    while(1) {
+        *VBLANK = 2;
+        *VSYNC = 2;
+        strobe(WSYNC);
+        strobe(WSYNC);
+        strobe(WSYNC);
+        *VSYNC = 0;
+        update();
+        for (Y = 0; Y != 37; Y++) strobe(WSYNC);
+        *VBLANK = 0;
+        for (Y = 0; Y != 192; Y++) strobe(WSYNC);
+        *VBLANK = 2;
+        overscan();
+        for (Y = 0; Y != 30; Y++) strobe(WSYNC);
+    }
+}
+";
+    let output = build_program(&dir, "profile", code, &["--memory-map", "profile.json"]);
+    assert!(output.status.success());
+    let output = cc2600(
+        &dir,
+        &[
+            "emulate",
+            "profile.bin",
+            "--frames",
+            "4",
+            "--profile",
+            "profile.json",
+            "--flamegraph",
+            "profile.folded",
+        ],
+    );
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let row = |name: &str| -> Vec<String> {
+        let line = stdout
+            .lines()
+            .find(|l| l.split_whitespace().next() == Some(name))
+            .unwrap_or_else(|| panic!("No {} in {}", name, stdout));
+        line.split_whitespace().skip(1).map(String::from).collect()
+    };
+
+    // update() takes 2 lines of the vertical blank
+    assert_eq!(row("Scanlines"), ["42/42", "192/192", "31/31", "265/265"]);
+    let cycles: Vec<u64> = row("Cycles")
+        .iter()
+        .map(|c| c.split_once('/').unwrap().0.parse().unwrap())
+        .collect();
+    assert_eq!(cycles[0] + cycles[1] + cycles[2], cycles[3]);
+    assert_eq!(row("main")[2..], row("Cycles"));
+    // LDX #0 (2), 10 x INC a, INX, CPX #10 (9), 9 BNE taken (3) and the last one (2), RTS (6):
+    // 127 cycles, and 20 cycles of trampoline from the LDX $1FF9 of bank 0 to the RTS back to main
+    assert_eq!(
+        row("update"),
+        ["1.0", "147", "147/147", "0/0", "0/0", "147/147"]
+    );
+    // LDA #0, STA a, RTS
+    assert_eq!(
+        row("overscan"),
+        ["1.0", "11", "0/0", "0/0", "11/11", "11/11"]
+    );
+
+    let folded = fs::read_to_string(dir.join("profile.folded")).unwrap();
+    let stacks: Vec<(&str, u64)> = folded
+        .lines()
+        .map(|l| {
+            let (stack, cycles) = l.rsplit_once(' ').unwrap();
+            (stack, cycles.parse().unwrap())
+        })
+        .collect();
+    assert_eq!(
+        stacks.iter().map(|s| s.0).collect::<Vec<_>>(),
+        ["main", "main;[WSYNC]", "main;overscan", "main;update"]
+    );
+    assert!(stacks.contains(&("main;update", 4 * 147)));
+    assert!(stacks.contains(&("main;overscan", 4 * 11)));
+}
+
+// Kernel of 192 lines, each taking exactly 76 cycles without WSYNC, with the given loop annotation
+fn cycle_exact_kernel(annotation: &str) -> String {
+    format!(
+        "#include \"vcs.h\"
+unsigned char Y;
+void kernel()
+{{
+    for (Y = 0; Y != 192; Y++) {{
+        asm(\";@loop {}\", 0);
+        *COLUBK = Y;
+        {}
+    }}
+}}
+void main()
+{{
+    while(1) {{
+        *VBLANK = 2;
+        *VSYNC = 2;
+        strobe(WSYNC);
+        strobe(WSYNC);
+        strobe(WSYNC);
+        *VSYNC = 0;
+        for (Y = 0; Y != 37; Y++) strobe(WSYNC);
+        *VBLANK = 0;
+        strobe(WSYNC);
+        kernel();
+        *VBLANK = 2;
+        for (Y = 0; Y != 30; Y++) strobe(WSYNC);
+    }}
+}}
+",
+        annotation,
+        "asm(\"NOP\");".repeat(33)
+    )
+}
+
+#[test]
+fn cycle_annotations_are_checked() {
+    let dir = work_dir("cycles");
+    // STY COLUBK (3), 33 NOPs (66), INY, CPY #192 and BNE taken (7)
+    let code = cycle_exact_kernel("== 76");
+    let output = build_program(&dir, "cycles", &code, &["--cycle-report", "cycles.txt"]);
+    assert!(output.status.success());
+    let report = fs::read_to_string(dir.join("cycles.txt")).unwrap();
+    let check = report.lines().find(|l| l.starts_with(";@loop")).unwrap();
+    assert!(
+        check.ends_with(": 76 cycles per iteration, OK"),
+        "{}",
+        check
+    );
+    // The kernel ends in the middle of a line, finished by the first WSYNC of the overscan
+    let run = emulate(&dir, "cycles", &["--frames", "3"]);
+    assert_eq!(run.scanlines, [263; 3]);
+
+    let code = cycle_exact_kernel("== 75");
+    let output = build_program(&dir, "cycles", &code, &[]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Cycle check failed. ;@loop == 75 in kernel at $")
+            && stderr.contains(": 76 cycles per iteration"),
+        "{}",
+        stderr
+    );
+}
+
+// Kernel reading a 100 bytes table, after a function of the given number of NOPs
+fn page_crossing_kernel(padding: usize, annotation: &str) -> String {
+    let table: Vec<String> = (0..100).map(|i| i.to_string()).collect();
+    format!(
+        "#include \"vcs.h\"
+unsigned char X, Y;
+const unsigned char table[100] = {{{}}};
+void pad()
+{{
+    {}
+}}
+void kernel()
+{{
+    for (Y = 0; Y != 192; Y++) {{
+        asm(\";@{}\", 0);
+        X = Y;
+        *COLUBK = table[X];
+    }}
+}}
+void main()
+{{
+    pad();
+    kernel();
+    while(1);
+}}
+",
+        table.join(","),
+        "asm(\"NOP\");".repeat(padding),
+        annotation
+    )
+}
+
+// Builds a program with the given --page-crossing action, returning the compiler output and the
+// check of the loop annotation
+fn page_crossing(name: &str, code: &str, action: &str) -> (Output, Option<String>) {
+    let dir = work_dir(&format!("{}_{}", name, action));
+    let output = build_program(
+        &dir,
+        name,
+        code,
+        &[
+            "-v",
+            "--page-crossing",
+            action,
+            "--cycle-report",
+            "cycles.txt",
+        ],
+    );
+    let check = fs::read_to_string(dir.join("cycles.txt"))
+        .ok()
+        .and_then(|r| {
+            r.lines()
+                .find(|l| l.starts_with(";@loop"))
+                .map(String::from)
+        });
+    (output, check)
+}
+
+#[test]
+fn branches_crossing_a_page() {
+    // With 228 bytes of padding, the BNE of the kernel loop ($10F8) is at $1102
+    let code = page_crossing_kernel(228, "loop <= 20");
+    let warning = "BNE at $1102 in kernel branches to another page ($10f8)";
+
+    let (output, check) = page_crossing("branch", &code, "warn");
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains(&format!("Warning: {}", warning)));
+    // The taken branch takes 4 cycles instead of 3
+    assert_eq!(
+        check.unwrap(),
+        ";@loop <= 20 in kernel at $10f8: 19 cycles per iteration, OK"
+    );
+
+    let (output, _) = page_crossing("branch", &code, "error");
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains(&format!("Page crossing. {}", warning))
+    );
+
+    // The function is moved to $1100
+    let (output, check) = page_crossing("branch", &code, "align");
+    assert!(output.status.success());
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("Warning"));
+    assert!(String::from_utf8_lossy(&output.stdout)
+        .contains("Realigned kernel function on a page boundary (10 bytes of padding)"));
+    assert_eq!(
+        check.unwrap(),
+        ";@loop <= 20 in kernel at $1102: 18 cycles per iteration, OK"
+    );
+}
+
+#[test]
+fn indexed_tables_crossing_a_page() {
+    // With 150 bytes of padding, the table is at $10C1-$1124
+    let warning =
+        "table table ($10c1, 100 bytes) read by LDA at $10ac in kernel crosses a page boundary";
+    let code = page_crossing_kernel(150, "loop <= 20");
+    let (output, check) = page_crossing("table", &code, "warn");
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains(&format!("Warning: {}", warning)));
+    // LDA table,X takes 5 cycles instead of 4 when crossing the page boundary
+    assert_eq!(
+        check.unwrap(),
+        ";@loop <= 20 in kernel at $10aa: 18 to 19 cycles per iteration, OK"
+    );
+
+    let (output, check) = page_crossing("table", &code, "align");
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout)
+        .contains("Realigned table array on a page boundary (63 bytes of padding)"));
+    assert_eq!(
+        check.unwrap(),
+        ";@loop <= 20 in kernel at $10aa: 18 cycles per iteration, OK"
+    );
+
+    // ;@critical checks the page crossings without any cycle check
+    let code = page_crossing_kernel(150, "critical");
+    let (output, check) = page_crossing("critical", &code, "error");
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains(&format!("Page crossing. {}", warning))
+    );
+    assert_eq!(check, None);
+}
+
+// Address of a variable in a memory map
+fn variable_address(map: &str, name: &str) -> u32 {
+    let start = map
+        .find(&format!("\"name\": \"{}\",", name))
+        .unwrap_or_else(|| panic!("No {} in {}", name, map));
+    let rest = &map[start..];
+    let rest = &rest[rest.find("\"address\": ").unwrap() + 11..];
+    rest[..rest.find(|c: char| !c.is_ascii_digit()).unwrap()]
+        .parse()
+        .unwrap()
+}
+
+#[test]
+fn local_variables_share_memory_when_not_on_the_same_call_path() {
+    // a and c never run at the same time, though c is called one level deeper (by b). main runs
+    // with both of them
+    for (memory, header, declaration, expected) in [
+        (
+            "zeropage",
+            "",
+            "char",
+            "Local variables: 40 bytes (20 bytes saved by sharing memory between functions that never run at the same time)",
+        ),
+        (
+            "superchip",
+            "",
+            "const superchip char",
+            " - LOCAL_SUPERVARS (40 bytes shared by the local variables of main, a, c)",
+        ),
+        (
+            "3e",
+            "#include \"3e.h\"",
+            "const bank1 char",
+            " - LOCAL_RAM_3E_1 (40 bytes shared by the local variables of main, a, c)",
+        ),
+    ] {
+        let dir = work_dir(&format!("locals_{}", memory));
+        let code = format!(
+            "#include \"vcs.h\"
+{header}
+unsigned char r, X;
+void c() {{ {declaration} z[20]; z[0] = r; r = z[0]; }}
+void b() {{ c(); }}
+void a() {{ {declaration} x[30]; x[0] = r; r = x[0]; }}
+void main() {{ {declaration} m[10]; m[0] = r; a(); b(); r = m[0]; while(1); }}
+"
+        );
+        let output = build_program(&dir, "locals", &code, &["-v", "--memory-map", "locals.json"]);
+        assert!(output.status.success(), "{}", memory);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.lines().any(|l| l == expected), "{}: {}", memory, stdout);
+        let map = fs::read_to_string(dir.join("locals.json")).unwrap();
+        let (m, x, z) = (
+            variable_address(&map, "main_1_m"),
+            variable_address(&map, "a_1_x"),
+            variable_address(&map, "c_1_z"),
+        );
+        assert_eq!(x, z, "{}", memory);
+        assert_eq!(x, m + 10, "{}", memory);
+    }
+}